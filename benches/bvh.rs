@@ -0,0 +1,115 @@
+// Ray casts and box queries through the BVH against a linear scan over all
+// triangles. Run with cargo bench --bench bvh.
+
+use rugl::bvh::Bvh;
+use rugl::math::*;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+// A bumpy heightfield of size x size quads, two triangles each.
+fn terrain(size: u32) -> (Vec<Vector3>, Vec<u32>) {
+    let mut positions = Vec::new();
+    for y in 0..=size {
+        for x in 0..=size {
+            let (u, v) = (x as f32 / size as f32, y as f32 / size as f32);
+            let z = 0.1 * (12. * u).sin() * (9. * v).cos();
+            positions.push(Vector3::new(u - 0.5, v - 0.5, z));
+        }
+    }
+    let mut indices = Vec::new();
+    for y in 0..size {
+        for x in 0..size {
+            let i = y * (size + 1) + x;
+            indices.extend_from_slice(&[i, i + size + 1, i + 1, i + 1, i + size + 1, i + size + 2]);
+        }
+    }
+    (positions, indices)
+}
+
+// Rays from above, in a fixed pseudo random pattern.
+fn rays(count: usize) -> Vec<Ray> {
+    let mut state = 0x9e3779b97f4a7c15u64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+    };
+    (0..count)
+        .map(|_| {
+            let origin = Vector3::new(next(), next(), 1.);
+            let direction = Vector3::new(0.3 * next(), 0.3 * next(), -1.).normalized();
+            Ray::new(origin, direction)
+        })
+        .collect()
+}
+
+fn linear_raycast(positions: &[Vector3], indices: &[u32], ray: &Ray) -> Option<f32> {
+    indices
+        .chunks(3)
+        .filter_map(|t| {
+            let triangle = [positions[t[0] as usize], positions[t[1] as usize], positions[t[2] as usize]];
+            ray.triangle_intersect(&triangle).map(|(distance, _)| distance)
+        })
+        .min_by(|a, b| a.total_cmp(b))
+}
+
+fn linear_query(positions: &[Vector3], indices: &[u32], aabb: &Aabb) -> usize {
+    indices
+        .chunks(3)
+        .filter(|t| aabb.intersects_triangle(&[positions[t[0] as usize], positions[t[1] as usize], positions[t[2] as usize]]))
+        .count()
+}
+
+fn time<F: FnMut()>(name: &str, iterations: usize, mut f: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let per_iteration = start.elapsed() / iterations as u32;
+    println!("{:<32} {:>12.3?}", name, per_iteration);
+    per_iteration
+}
+
+fn main() {
+    for &size in [32, 128, 256].iter() {
+        let (positions, indices) = terrain(size);
+        println!("{} triangles", indices.len() / 3);
+        let build = time("  build", 3, || {
+            black_box(Bvh::new(&positions, &indices));
+        });
+        let mut bvh = Bvh::new(&positions, &indices);
+        time("  refit", 10, || bvh.refit(black_box(&positions)));
+        let rays = rays(256);
+        let bvh_ray = time("  raycast, bvh", 1, || {
+            for ray in rays.iter() {
+                black_box(bvh.raycast(ray, f32::INFINITY));
+            }
+        }) / rays.len() as u32;
+        time("  raycast_any, bvh", 1, || {
+            for ray in rays.iter() {
+                black_box(bvh.raycast_any(ray, f32::INFINITY));
+            }
+        });
+        let linear_ray = time("  raycast, linear", 1, || {
+            for ray in rays.iter() {
+                black_box(linear_raycast(&positions, &indices, ray));
+            }
+        }) / rays.len() as u32;
+        let aabb = Aabb::new(Vector3::new(-0.05, -0.05, -1.), Vector3::new(0.05, 0.05, 1.));
+        let bvh_query = time("  query_aabb, bvh", 100, || {
+            black_box(bvh.query_aabb(&aabb));
+        });
+        let linear_query = time("  query_aabb, linear", 10, || {
+            black_box(linear_query(&positions, &indices, &aabb));
+        });
+        println!(
+            "  per ray {:?} vs {:?}, speedup {:.0}x, query speedup {:.0}x, build costs {:.0} linear rays",
+            bvh_ray,
+            linear_ray,
+            linear_ray.as_secs_f64() / bvh_ray.as_secs_f64(),
+            linear_query.as_secs_f64() / bvh_query.as_secs_f64(),
+            build.as_secs_f64() / linear_ray.as_secs_f64(),
+        );
+    }
+}
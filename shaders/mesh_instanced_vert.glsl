@@ -0,0 +1,27 @@
+#version 300 es
+
+layout (location = 0) in vec3 a_position;
+layout (location = 1) in vec2 a_tex_coords;
+layout (location = 2) in vec3 a_normal;
+layout (location = 4) in mat4 a_instance_transform;
+layout (location = 8) in vec4 a_instance_color;
+layout (location = 9) in vec4 a_instance_data;
+
+uniform mat4 T_model;
+uniform mat4 T_view_projection;
+
+out vec3 position;
+out vec2 tex_coords;
+out vec3 normal;
+out vec4 instance_color;
+out vec4 instance_data;
+
+void main() {
+    vec4 instance_position = a_instance_transform * vec4(a_position, 1.0);
+    position = instance_position.xyz;
+    tex_coords = a_tex_coords;
+    normal = normalize(mat3(a_instance_transform) * a_normal);
+    instance_color = a_instance_color;
+    instance_data = a_instance_data;
+    gl_Position = T_view_projection * T_model * instance_position;
+}
@@ -0,0 +1,36 @@
+#version 300 es
+
+layout (location = 0) in vec3 a_position;
+layout (location = 1) in vec2 a_tex_coords;
+layout (location = 2) in vec3 a_normal;
+layout (location = 12) in vec3 a_morph0;
+layout (location = 13) in vec3 a_morph1;
+layout (location = 14) in vec3 a_morph2;
+layout (location = 15) in vec3 a_morph3;
+
+uniform mat4 T_model;
+uniform mat4 T_view_projection;
+// Weights of the bound targets. With morph_normals set, there are two
+// targets, with position deltas in slots 0 and 2 and normal deltas in 1 and 3.
+uniform vec4 morph_weights;
+uniform int morph_normals;
+
+out vec3 position;
+out vec2 tex_coords;
+out vec3 normal;
+
+void main() {
+    vec3 morphed_position = a_position;
+    vec3 morphed_normal = a_normal;
+    if (morph_normals != 0) {
+        morphed_position += morph_weights.x * a_morph0 + morph_weights.y * a_morph2;
+        morphed_normal += morph_weights.x * a_morph1 + morph_weights.y * a_morph3;
+    } else {
+        morphed_position += morph_weights.x * a_morph0 + morph_weights.y * a_morph1
+            + morph_weights.z * a_morph2 + morph_weights.w * a_morph3;
+    }
+    position = morphed_position;
+    tex_coords = a_tex_coords;
+    normal = normalize(morphed_normal);
+    gl_Position = T_view_projection * T_model * vec4(morphed_position, 1.0);
+}
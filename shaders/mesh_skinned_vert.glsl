@@ -0,0 +1,31 @@
+#version 300 es
+
+layout (location = 0) in vec3 a_position;
+layout (location = 1) in vec2 a_tex_coords;
+layout (location = 2) in vec3 a_normal;
+layout (location = 10) in vec4 a_joints;
+layout (location = 11) in vec4 a_weights;
+
+// Must match skeleton::MAX_JOINTS.
+const int MAX_JOINTS = 64;
+
+uniform mat4 T_model;
+uniform mat4 T_view_projection;
+uniform mat4 T_joints[MAX_JOINTS];
+
+out vec3 position;
+out vec2 tex_coords;
+out vec3 normal;
+
+void main() {
+    mat4 T_skin =
+        a_weights.x * T_joints[int(a_joints.x)] +
+        a_weights.y * T_joints[int(a_joints.y)] +
+        a_weights.z * T_joints[int(a_joints.z)] +
+        a_weights.w * T_joints[int(a_joints.w)];
+    vec4 skinned_position = T_skin * vec4(a_position, 1.0);
+    position = skinned_position.xyz;
+    tex_coords = a_tex_coords;
+    normal = normalize(mat3(T_skin) * a_normal);
+    gl_Position = T_view_projection * T_model * skinned_position;
+}
@@ -0,0 +1,283 @@
+use crate::math::*;
+use crate::skeleton::{Pose, Skeleton};
+use std::ops::{Add, Mul};
+
+// Keyframed animation clips for skeletons, and blending between them.
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Interpolation {
+    // Holds each key until the next one.
+    Step,
+    // Straight lines, and slerp for rotations.
+    Linear,
+    // Hermite splines. Every key has three values: the incoming tangent, the
+    // value and the outgoing tangent, as in glTF.
+    Cubic,
+}
+
+#[derive(Debug, Clone)]
+pub enum ChannelValues {
+    Translation(Vec<Vector3>),
+    Rotation(Vec<Quaternion>),
+    Scale(Vec<Vector3>),
+}
+
+// Animates one property of one joint.
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub joint: usize,
+    pub interpolation: Interpolation,
+    // Increasing, in seconds.
+    pub times: Vec<f32>,
+    pub values: ChannelValues,
+}
+
+impl Channel {
+    pub fn new(joint: usize, interpolation: Interpolation, times: Vec<f32>, values: ChannelValues) -> Channel {
+        let per_key = if interpolation == Interpolation::Cubic { 3 } else { 1 };
+        let count = match &values {
+            ChannelValues::Translation(v) | ChannelValues::Scale(v) => v.len(),
+            ChannelValues::Rotation(v) => v.len(),
+        };
+        assert!(!times.is_empty() && count == per_key * times.len());
+        assert!(times.windows(2).all(|w| w[0] <= w[1]), "key times must increase");
+        Channel { joint, interpolation, times, values }
+    }
+
+    pub fn duration(&self) -> f32 {
+        *self.times.last().unwrap()
+    }
+
+    // Sets the animated property in the pose at the given time. Times
+    // outside of the keys hold the first or last one.
+    pub fn apply(&self, time: f32, pose: &mut Pose) {
+        let transform = &mut pose.local[self.joint];
+        match &self.values {
+            ChannelValues::Translation(values) => {
+                transform.translation = sample(&self.times, values, self.interpolation, time, lerp3);
+            }
+            ChannelValues::Scale(values) => {
+                transform.scale = sample(&self.times, values, self.interpolation, time, lerp3);
+            }
+            ChannelValues::Rotation(values) => {
+                let rotation = sample(&self.times, values, self.interpolation, time, Quaternion::slerp);
+                transform.rotation = rotation.normalized();
+            }
+        }
+    }
+}
+
+fn lerp3(a: Vector3, b: Vector3, t: f32) -> Vector3 {
+    (1. - t) * a + t * b
+}
+
+fn sample<T>(times: &[f32], values: &[T], interpolation: Interpolation, time: f32, lerp: fn(T, T, f32) -> T) -> T
+where
+    T: Copy + Add<Output = T>,
+    f32: Mul<T, Output = T>,
+{
+    let cubic = interpolation == Interpolation::Cubic;
+    let value = |key: usize| if cubic { values[3 * key + 1] } else { values[key] };
+    let last = times.len() - 1;
+    if time <= times[0] {
+        return value(0);
+    }
+    if time >= times[last] {
+        return value(last);
+    }
+    // The key before time.
+    let k = times.partition_point(|&t| t <= time) - 1;
+    let dt = times[k + 1] - times[k];
+    let t = if dt > 0. { (time - times[k]) / dt } else { 0. };
+    match interpolation {
+        Interpolation::Step => value(k),
+        Interpolation::Linear => lerp(value(k), value(k + 1), t),
+        Interpolation::Cubic => {
+            let out_tangent = values[3 * k + 2];
+            let in_tangent = values[3 * (k + 1)];
+            let [h00, h10, h01, h11] = hermite_basis(t, dt);
+            h00 * value(k) + h10 * out_tangent + h01 * value(k + 1) + h11 * in_tangent
+        }
+    }
+}
+
+// Weights of the start value, start tangent, end value and end tangent.
+// Tangents are per second, so they are scaled by the key interval.
+fn hermite_basis(t: f32, dt: f32) -> [f32; 4] {
+    let (t2, t3) = (t * t, t * t * t);
+    [
+        2. * t3 - 3. * t2 + 1.,
+        (t3 - 2. * t2 + t) * dt,
+        3. * t2 - 2. * t3,
+        (t3 - t2) * dt,
+    ]
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationClip {
+    pub name: String,
+    pub channels: Vec<Channel>,
+    duration: f32,
+}
+
+impl AnimationClip {
+    pub fn new(name: &str, channels: Vec<Channel>) -> AnimationClip {
+        let duration = channels.iter().map(|c| c.duration()).fold(0., f32::max);
+        AnimationClip { name: name.to_string(), channels, duration }
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    // Joints without channels keep their transforms in the pose.
+    pub fn apply(&self, time: f32, pose: &mut Pose) {
+        for channel in self.channels.iter() {
+            channel.apply(time, pose);
+        }
+    }
+
+    // The clip's pose at a time, starting from the skeleton's rest pose.
+    pub fn sample(&self, skeleton: &Skeleton, time: f32) -> Pose {
+        let mut pose = skeleton.rest_pose();
+        self.apply(time, &mut pose);
+        pose
+    }
+
+    // Like sample, wrapping time around the clip's duration.
+    pub fn sample_looped(&self, skeleton: &Skeleton, time: f32) -> Pose {
+        let time = if self.duration > 0. { time.rem_euclid(self.duration) } else { 0. };
+        self.sample(skeleton, time)
+    }
+}
+
+// A clip playing at some time and weight, for blend.
+#[derive(Debug, Copy, Clone)]
+pub struct ClipState<'a> {
+    pub clip: &'a AnimationClip,
+    pub time: f32,
+    pub weight: f32,
+    pub looped: bool,
+}
+
+// Weighted average of the poses of several clips. Weights need not add up to
+// one. With no weight at all, the result is the rest pose.
+pub fn blend(skeleton: &Skeleton, states: &[ClipState]) -> Pose {
+    let mut pose = skeleton.rest_pose();
+    let mut total_weight = 0.;
+    for state in states.iter().filter(|s| s.weight > 0.) {
+        let clip_pose = if state.looped {
+            state.clip.sample_looped(skeleton, state.time)
+        } else {
+            state.clip.sample(skeleton, state.time)
+        };
+        total_weight += state.weight;
+        pose = Pose::blend(&pose, &clip_pose, state.weight / total_weight);
+    }
+    pose
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::skeleton::Transform;
+
+    fn assert_close(a: Vector3, b: Vector3) {
+        assert!((a - b).norm() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    fn v(x: f32) -> Vector3 {
+        Vector3::new(x, 0., 0.)
+    }
+
+    fn translation_at(channel: &Channel, time: f32) -> Vector3 {
+        let mut pose = Pose { local: vec![Transform::IDENTITY] };
+        channel.apply(time, &mut pose);
+        pose.local[0].translation
+    }
+
+    fn translations(interpolation: Interpolation, times: &[f32], values: &[f32]) -> Channel {
+        let values = values.iter().map(|&x| v(x)).collect();
+        Channel::new(0, interpolation, times.to_vec(), ChannelValues::Translation(values))
+    }
+
+    #[test]
+    fn step() {
+        let channel = translations(Interpolation::Step, &[0., 1., 2.], &[1., 2., 4.]);
+        assert_close(translation_at(&channel, 0.5), v(1.));
+        assert_close(translation_at(&channel, 0.99), v(1.));
+        assert_close(translation_at(&channel, 1.), v(2.));
+        assert_close(translation_at(&channel, 1.5), v(2.));
+    }
+
+    #[test]
+    fn linear() {
+        let channel = translations(Interpolation::Linear, &[0., 1., 3.], &[1., 2., 4.]);
+        assert_close(translation_at(&channel, 0.25), v(1.25));
+        assert_close(translation_at(&channel, 2.), v(3.));
+
+        let quarter = Quaternion::rotation(Vector3::Z, std::f32::consts::FRAC_PI_2);
+        let values = ChannelValues::Rotation(vec![Quaternion::ONE, quarter]);
+        let channel = Channel::new(0, Interpolation::Linear, vec![0., 1.], values);
+        let mut pose = Pose { local: vec![Transform::IDENTITY] };
+        channel.apply(0.5, &mut pose);
+        let eighth = Quaternion::rotation(Vector3::Z, std::f32::consts::FRAC_PI_4);
+        assert!((pose.local[0].rotation - eighth).norm() < 1e-5);
+    }
+
+    #[test]
+    fn endpoints() {
+        for &interpolation in [Interpolation::Step, Interpolation::Linear].iter() {
+            let channel = translations(interpolation, &[1., 2.], &[3., 5.]);
+            assert_close(translation_at(&channel, -1.), v(3.));
+            assert_close(translation_at(&channel, 1.), v(3.));
+            assert_close(translation_at(&channel, 2.), v(5.));
+            assert_close(translation_at(&channel, 10.), v(5.));
+        }
+        // The values, not the tangents around them.
+        let channel = translations(Interpolation::Cubic, &[1., 2.], &[-7., 3., -7., -7., 5., -7.]);
+        assert_close(translation_at(&channel, -1.), v(3.));
+        assert_close(translation_at(&channel, 1.), v(3.));
+        assert_close(translation_at(&channel, 2.), v(5.));
+        assert_close(translation_at(&channel, 10.), v(5.));
+        assert_eq!(channel.duration(), 2.);
+    }
+
+    #[test]
+    fn cubic() {
+        // t^3 from 0 to 2, whose derivative is 3 t^2. Hermite splines are
+        // exact on cubics. The tangents that aren't used are way off, so
+        // mixing up incoming and outgoing shows.
+        let channel = translations(Interpolation::Cubic, &[0., 2.], &[100., 0., 0., 12., 8., -100.]);
+        for &t in [0.25, 0.5, 1., 1.5, 1.9].iter() {
+            assert_close(translation_at(&channel, t), v(t * t * t));
+        }
+        // Tangents are per second, over a key interval that isn't one.
+        let channel = translations(Interpolation::Cubic, &[1., 3., 4.], &[0., 1., 1., 1., 3., 1., 1., 4., 0.]);
+        assert_close(translation_at(&channel, 2.), v(2.));
+        assert_close(translation_at(&channel, 3.5), v(3.5));
+    }
+
+    #[test]
+    fn blend_by_weight() {
+        let skeleton = Skeleton::from_rest_pose(vec![("root".to_string(), None, Transform::IDENTITY)]);
+        let clip = |name: &str, x: f32| {
+            AnimationClip::new(name, vec![translations(Interpolation::Linear, &[0., 1.], &[0., x])])
+        };
+        let (a, b) = (clip("a", 1.), clip("b", 3.));
+        let state = |clip, time, weight| ClipState { clip, time, weight, looped: false };
+
+        let pose = blend(&skeleton, &[state(&a, 1., 1.), state(&b, 1., 3.)]);
+        assert_close(pose.local[0].translation, v(2.5));
+        // Weights are relative.
+        let pose = blend(&skeleton, &[state(&a, 1., 0.25), state(&b, 1., 0.75)]);
+        assert_close(pose.local[0].translation, v(2.5));
+        let pose = blend(&skeleton, &[state(&a, 0.5, 1.), state(&b, 1., 0.)]);
+        assert_close(pose.local[0].translation, v(0.5));
+        assert_eq!(blend(&skeleton, &[state(&a, 1., 0.)]), skeleton.rest_pose());
+        assert_eq!(blend(&skeleton, &[]), skeleton.rest_pose());
+
+        let looped = ClipState { clip: &a, time: 2.25, weight: 1., looped: true };
+        assert_close(blend(&skeleton, &[looped]).local[0].translation, v(0.25));
+    }
+}
@@ -0,0 +1,365 @@
+use crate::gpu::{self, Attr, PointerConfig, TextureFormat, TextureMagFilterMode, TextureMinFilterMode, UniformValue};
+use crate::image;
+use crate::math::*;
+use crate::mesh::MeshData;
+use crate::software::SoftwareProgram;
+use crate::text::{Font, Text};
+
+// What drawing needs from a renderer, so that the same code can draw with
+// OpenGL, through gpu, or without any GPU, with software::SoftwareBackend.
+// Draws are indexed triangle lists of float attributes.
+pub trait Backend {
+    type Buffer;
+    type IndexBuffer;
+    type Texture;
+    type Program;
+
+    fn width(&self) -> i32;
+    fn height(&self) -> i32;
+
+    fn create_vertex_buffer(&mut self, data: &[f32]) -> Self::Buffer;
+    fn create_index_buffer(&mut self, indices: &[u32]) -> Self::IndexBuffer;
+    // Data has one byte per component. The filter is used for minification
+    // and magnification, without mipmaps, and coordinates clamp to the edges.
+    fn create_texture(&mut self, width: i32, height: i32, format: TextureFormat, data: &[u8], filter: TextureMagFilterMode) -> Result<Self::Texture, String>;
+    fn create_program(&mut self, source: ProgramSource) -> Result<Self::Program, String>;
+
+    // Clears the color to the given one and the depth to the far plane.
+    fn clear(&mut self, color: Color4);
+    fn draw(&mut self, call: &DrawCall<Self>);
+    // The whole target, first row at the top.
+    fn read_pixels(&mut self) -> Result<image::RgbaImage, String>;
+}
+
+// A program in each backend's terms: shader files for OpenGL and closures
+// for the software backend, which should compute the same thing.
+pub struct ProgramSource {
+    pub vertex_shader_path: String,
+    pub fragment_shader_path: String,
+    pub software: SoftwareProgram,
+}
+
+pub enum UniformData<'a, B: Backend + ?Sized> {
+    Float(f32),
+    Vector2(Vector2),
+    Vector3(Vector3),
+    Vector4(Vector4),
+    Matrix4(Matrix4),
+    Texture(&'a B::Texture),
+}
+
+impl<'a, B: Backend + ?Sized> UniformData<'a, B> {
+    pub fn as_float(&self) -> Option<f32> {
+        if let UniformData::Float(v) = self { Some(*v) } else { None }
+    }
+
+    pub fn as_vector2(&self) -> Option<Vector2> {
+        if let UniformData::Vector2(v) = self { Some(*v) } else { None }
+    }
+
+    pub fn as_matrix4(&self) -> Option<&Matrix4> {
+        if let UniformData::Matrix4(m) = self { Some(m) } else { None }
+    }
+
+    pub fn as_texture(&self) -> Option<&'a B::Texture> {
+        if let UniformData::Texture(t) = self { Some(*t) } else { None }
+    }
+}
+
+pub fn find_uniform<'a, 'b, B: Backend + ?Sized>(uniforms: &'b [(&str, UniformData<'a, B>)], name: &str) -> Option<&'b UniformData<'a, B>> {
+    uniforms.iter().find(|(n, _)| *n == name).map(|(_, value)| value)
+}
+
+// The pipeline state the draws of the crate need. The default is the state
+// gpu::setup leaves.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DrawState {
+    // Less than, writing depth.
+    pub depth_test: bool,
+    // Source alpha over the target.
+    pub blend: bool,
+    // Discards triangles that are counterclockwise on screen.
+    pub cull: bool,
+}
+
+impl Default for DrawState {
+    fn default() -> Self {
+        DrawState { depth_test: true, blend: true, cull: true }
+    }
+}
+
+pub struct DrawCall<'a, B: Backend + ?Sized> {
+    pub program: &'a B::Program,
+    // Floats per vertex of each buffer. The software backend passes them to
+    // the vertex shader in this order, OpenGL at the Attr locations.
+    pub attributes: &'a [(Attr, usize, &'a B::Buffer)],
+    pub indices: &'a B::IndexBuffer,
+    pub index_count: usize,
+    pub uniforms: &'a [(&'a str, UniformData<'a, B>)],
+    pub state: DrawState,
+}
+
+// Draws to the framebuffer bound when drawing, of the given size.
+pub struct GlBackend {
+    width: i32,
+    height: i32,
+}
+
+impl GlBackend {
+    pub fn new(width: i32, height: i32) -> GlBackend {
+        GlBackend { width, height }
+    }
+}
+
+// OpenGL keeps the index buffer in the vertex array, and the attributes are
+// pointed at the buffers on each draw.
+pub struct GlIndexBuffer {
+    vertex_array: gpu::VertexArray,
+}
+
+impl Backend for GlBackend {
+    type Buffer = gpu::Buffer;
+    type IndexBuffer = GlIndexBuffer;
+    type Texture = gpu::Texture;
+    type Program = gpu::Program;
+
+    fn width(&self) -> i32 {
+        self.width
+    }
+
+    fn height(&self) -> i32 {
+        self.height
+    }
+
+    fn create_vertex_buffer(&mut self, data: &[f32]) -> gpu::Buffer {
+        let buffer = gpu::Buffer::new();
+        gpu::load_buffer_data(&buffer, data);
+        buffer
+    }
+
+    fn create_index_buffer(&mut self, indices: &[u32]) -> GlIndexBuffer {
+        let mut vertex_array = gpu::VertexArray::new();
+        vertex_array.load_indices(indices);
+        GlIndexBuffer { vertex_array }
+    }
+
+    fn create_texture(&mut self, width: i32, height: i32, format: TextureFormat, data: &[u8], filter: TextureMagFilterMode) -> Result<gpu::Texture, String> {
+        let mut texture = gpu::Texture::new();
+        texture.load_data(width, height, format, data)?;
+        texture.set_mag_filter_mode(filter);
+        texture.set_min_filter_mode(match filter {
+            TextureMagFilterMode::Nearest => TextureMinFilterMode::Nearest,
+            TextureMagFilterMode::Linear => TextureMinFilterMode::Linear,
+        });
+        Ok(texture)
+    }
+
+    fn create_program(&mut self, source: ProgramSource) -> Result<gpu::Program, String> {
+        gpu::Program::from_files(&source.vertex_shader_path, &source.fragment_shader_path).map_err(|e| e.to_string())
+    }
+
+    fn clear(&mut self, color: Color4) {
+        gpu::clear(color.r, color.g, color.b, color.a);
+    }
+
+    fn draw(&mut self, call: &DrawCall<Self>) {
+        let set = |flag, on| if on { gpu::enable(flag) } else { gpu::disable(flag) };
+        set(gl::DEPTH_TEST, call.state.depth_test);
+        set(gl::BLEND, call.state.blend);
+        set(gl::CULL_FACE, call.state.cull);
+        call.program.activate();
+        let vertex_array = &call.indices.vertex_array;
+        for (attr, size, buffer) in call.attributes.iter() {
+            let config = PointerConfig { size: *size, stride: (*size * std::mem::size_of::<f32>()) as isize, ..PointerConfig::vector4() };
+            vertex_array.setup_attribute(*attr, buffer, config);
+        }
+        let mut texture_unit = 0;
+        for (name, value) in call.uniforms.iter() {
+            let uniform = match call.program.find_uniform(name) {
+                Some(uniform) => uniform,
+                None => continue,
+            };
+            match value {
+                UniformData::Float(v) => v.set_uniform(uniform),
+                UniformData::Vector2(v) => v.set_uniform(uniform),
+                UniformData::Vector3(v) => v.set_uniform(uniform),
+                UniformData::Vector4(v) => v.set_uniform(uniform),
+                UniformData::Matrix4(m) => m.set_uniform(uniform),
+                UniformData::Texture(texture) => {
+                    let unit = gpu::TextureUnit(texture_unit);
+                    unit.bind_texture(texture.handle());
+                    unit.set_uniform(uniform);
+                    texture_unit += 1;
+                }
+            }
+        }
+        vertex_array.draw(call.index_count, 0);
+    }
+
+    fn read_pixels(&mut self) -> Result<image::RgbaImage, String> {
+        gpu::read_pixels(RectangleI::new(Vector2I::ZERO, self.width, self.height))
+    }
+}
+
+fn flatten2(values: &[Vector2]) -> Vec<f32> {
+    values.iter().flat_map(|v| [v.x, v.y]).collect()
+}
+
+fn flatten3(values: &[Vector3]) -> Vec<f32> {
+    values.iter().flat_map(|v| [v.x, v.y, v.z]).collect()
+}
+
+// A mesh's buffers on a backend, drawn with mesh_program.
+pub struct BackendMesh<B: Backend> {
+    positions: B::Buffer,
+    tex_coords: B::Buffer,
+    normals: B::Buffer,
+    indices: B::IndexBuffer,
+    index_count: usize,
+}
+
+impl<B: Backend> BackendMesh<B> {
+    // Missing texture coordinates and normals are zero.
+    pub fn new(backend: &mut B, data: &MeshData) -> BackendMesh<B> {
+        let count = data.positions.len();
+        let mut tex_coords = data.tex_coords.clone();
+        tex_coords.resize(count, Vector2::ZERO);
+        let mut normals = data.normals.clone();
+        normals.resize(count, Vector3::ZERO);
+        BackendMesh {
+            positions: backend.create_vertex_buffer(&flatten3(&data.positions)),
+            tex_coords: backend.create_vertex_buffer(&flatten2(&tex_coords)),
+            normals: backend.create_vertex_buffer(&flatten3(&normals)),
+            indices: backend.create_index_buffer(&data.indices),
+            index_count: data.indices.len(),
+        }
+    }
+
+    pub fn draw(&self, backend: &mut B, program: &B::Program, view_projection: &Matrix4, model: &Matrix4) {
+        let attributes = [
+            (Attr::Position, 3, &self.positions),
+            (Attr::TextureCoords, 2, &self.tex_coords),
+            (Attr::Normal, 3, &self.normals),
+        ];
+        let uniforms = [
+            ("T_view_projection", UniformData::Matrix4(*view_projection)),
+            ("T_model", UniformData::Matrix4(*model)),
+        ];
+        backend.draw(&DrawCall {
+            program,
+            attributes: &attributes,
+            indices: &self.indices,
+            index_count: self.index_count,
+            uniforms: &uniforms,
+            state: DrawState::default(),
+        });
+    }
+}
+
+// A Text's glyphs on a backend, one part per atlas page, drawn with
+// text_program.
+pub struct BackendText<B: Backend> {
+    pages: Vec<BackendTextPage<B>>,
+}
+
+struct BackendTextPage<B: Backend> {
+    texture: B::Texture,
+    positions: B::Buffer,
+    tex_coords: B::Buffer,
+    indices: B::IndexBuffer,
+    index_count: usize,
+}
+
+impl<B: Backend> BackendText<B> {
+    // From a layout, see Text::left_aligned_layout.
+    pub fn new(backend: &mut B, text: &Text, font: &Font) -> Result<BackendText<B>, String> {
+        let mut pages = Vec::new();
+        for page in text.pages() {
+            let (width, height, pixels) = font.page_pixels(page.unicode_page()).ok_or("atlas page is not loaded")?;
+            pages.push(BackendTextPage {
+                texture: backend.create_texture(width, height, TextureFormat::Alpha, pixels, TextureMagFilterMode::Nearest)?,
+                positions: backend.create_vertex_buffer(&flatten2(page.positions())),
+                tex_coords: backend.create_vertex_buffer(&flatten2(page.tex_coords())),
+                indices: backend.create_index_buffer(page.indices()),
+                index_count: page.indices().len(),
+            });
+        }
+        Ok(BackendText { pages })
+    }
+
+    pub fn draw(&self, backend: &mut B, program: &B::Program) {
+        let screen_size = Vector2::new(backend.width() as f32, backend.height() as f32);
+        for page in self.pages.iter() {
+            let attributes = [(Attr::Position, 2, &page.positions), (Attr::TextureCoords, 2, &page.tex_coords)];
+            let uniforms = [("screen_size", UniformData::Vector2(screen_size)), ("texture0", UniformData::Texture(&page.texture))];
+            backend.draw(&DrawCall {
+                program,
+                attributes: &attributes,
+                indices: &page.indices,
+                index_count: page.index_count,
+                uniforms: &uniforms,
+                state: DrawState::default(),
+            });
+        }
+    }
+}
+
+// shaders/mesh_vert.glsl and mesh_frag.glsl.
+pub fn mesh_program() -> ProgramSource {
+    ProgramSource {
+        vertex_shader_path: "shaders/mesh_vert.glsl".to_string(),
+        fragment_shader_path: "shaders/mesh_frag.glsl".to_string(),
+        software: SoftwareProgram {
+            // position, tex_coords, normal
+            varyings: 8,
+            vertex: Box::new(|attributes, uniforms, varyings| {
+                varyings[..3].copy_from_slice(attributes[0]);
+                varyings[3..5].copy_from_slice(attributes[1]);
+                varyings[5..8].copy_from_slice(attributes[2]);
+                let uniform = |name| find_uniform(uniforms, name).and_then(|u| u.as_matrix4()).copied().unwrap_or_else(Matrix4::id);
+                let p = attributes[0];
+                let world = uniform("T_model").transform(Vector4::new(p[0], p[1], p[2], 1.));
+                uniform("T_view_projection").transform(world)
+            }),
+            fragment: Box::new(|varyings, _| {
+                let position = Vector3::new(varyings[0], varyings[1], varyings[2]);
+                let normal = Vector3::new(varyings[5], varyings[6], varyings[7]);
+                let ambient = col3(0.1, 0.1, 0.3);
+                let light_pos = Vector3::new(4.0, 6.0, -3.0);
+                let backlight_pos = Vector3::new(-4.0, 5.0, 10.0);
+                let d = (position - light_pos).norm();
+                let d2 = (position - backlight_pos).norm();
+                let diffuse = 25.0 * Vector3::dot(normal, (light_pos - position).normalized()).max(0.) / (d * d);
+                let diffuse_back = 25.0 * Vector3::dot(normal, (backlight_pos - position).normalized()).max(0.) / (d2 * d2);
+                let color = ambient + diffuse_back * col3(0.0, 0.1, 1.0) + diffuse * col3(1.0, 1.0, 0.0);
+                Some(Color4::opaque(color))
+            }),
+        },
+    }
+}
+
+// shaders/text_vert.glsl and text_frag.glsl.
+pub fn text_program() -> ProgramSource {
+    ProgramSource {
+        vertex_shader_path: "shaders/text_vert.glsl".to_string(),
+        fragment_shader_path: "shaders/text_frag.glsl".to_string(),
+        software: SoftwareProgram {
+            // tex_coords
+            varyings: 2,
+            vertex: Box::new(|attributes, uniforms, varyings| {
+                varyings.copy_from_slice(attributes[1]);
+                let screen_size = find_uniform(uniforms, "screen_size").and_then(|u| u.as_vector2()).unwrap_or(Vector2::new(1., 1.));
+                let p = attributes[0];
+                Vector4::new(-1.0 + 2.0 * p[0] / screen_size.x, 1.0 - 2.0 * p[1] / screen_size.y, -1.0, 1.0)
+            }),
+            fragment: Box::new(|varyings, uniforms| {
+                let texture = find_uniform(uniforms, "texture0")?.as_texture()?;
+                let a = texture.sample(Vector2::new(varyings[0], varyings[1])).a;
+                if a < 0.01 {
+                    return None;
+                }
+                Some(col4(0., 0., 0., a))
+            }),
+        },
+    }
+}
@@ -0,0 +1,422 @@
+use crate::math::*;
+use crate::mesh::{Mesh, MeshPoint};
+
+// Bounding volume hierarchy over the triangles of a mesh, for ray casts and
+// overlap queries. Built with a binned surface area heuristic.
+//
+// The BVH keeps its own copy of the positions. After animating the vertices
+// (without changing the topology) call refit, which is much cheaper than a
+// rebuild but degrades the tree if the triangles move a lot.
+#[derive(Debug, Clone)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    // Triangle indices, ordered so that every leaf covers a contiguous range.
+    order: Vec<u32>,
+    triangles: Vec<[u32; 3]>,
+    positions: Vec<Vector3>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct BvhNode {
+    bounds: Aabb,
+    // For leaves (count > 0), the start of the range in `order`.
+    // For interior nodes, the index of the left child. The right one follows it.
+    first: u32,
+    count: u32,
+}
+
+const SAH_BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+// Relative costs of visiting a node and intersecting a triangle.
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+
+struct BuildTriangle {
+    bounds: Aabb,
+    centroid: Vector3,
+}
+
+impl Bvh {
+    pub fn new(positions: &[Vector3], indices: &[u32]) -> Bvh {
+        assert_eq!(indices.len() % 3, 0);
+        let triangles: Vec<[u32; 3]> = indices
+            .chunks(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .collect();
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * triangles.len() / MAX_LEAF_SIZE + 1),
+            order: (0..triangles.len() as u32).collect(),
+            triangles,
+            positions: positions.to_vec(),
+        };
+        let build: Vec<BuildTriangle> = (0..bvh.triangles.len())
+            .map(|i| {
+                let bounds = Aabb::from_points(&bvh.triangle(i));
+                BuildTriangle { bounds, centroid: bounds.center() }
+            })
+            .collect();
+        bvh.nodes.push(BvhNode { bounds: Aabb::EMPTY, first: 0, count: bvh.order.len() as u32 });
+        bvh.subdivide(0, &build);
+        bvh
+    }
+
+    pub fn from_mesh(mesh: &Mesh) -> Bvh {
+        Bvh::new(mesh.positions(), mesh.triangle_indices())
+    }
+
+    pub fn triangle(&self, i: usize) -> [Vector3; 3] {
+        let t = self.triangles[i];
+        [
+            self.positions[t[0] as usize],
+            self.positions[t[1] as usize],
+            self.positions[t[2] as usize],
+        ]
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes[0].bounds
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn subdivide(&mut self, node_index: usize, build: &[BuildTriangle]) {
+        let BvhNode { first, count, .. } = self.nodes[node_index];
+        let range = first as usize..(first + count) as usize;
+
+        let mut bounds = Aabb::EMPTY;
+        let mut centroid_bounds = Aabb::EMPTY;
+        for &t in &self.order[range.clone()] {
+            bounds = Aabb::union(bounds, build[t as usize].bounds);
+            centroid_bounds.extend(build[t as usize].centroid);
+        }
+        self.nodes[node_index].bounds = bounds;
+        if (count as usize) <= MAX_LEAF_SIZE {
+            return;
+        }
+
+        let area = bounds.surface_area();
+        let split = match Self::find_split(&self.order[range.clone()], build, &centroid_bounds, area) {
+            Some(split) => split,
+            None => return,
+        };
+        let leaf_cost = INTERSECTION_COST * count as f32 * area;
+        if split.cost >= leaf_cost && (count as usize) <= 4 * MAX_LEAF_SIZE {
+            return;
+        }
+
+        // Partition the range in place by bin.
+        let order = &mut self.order[range.clone()];
+        let mut mid = 0;
+        for i in 0..order.len() {
+            if split.bin_of(build[order[i] as usize].centroid) < split.bin {
+                order.swap(i, mid);
+                mid += 1;
+            }
+        }
+        if mid == 0 || mid == order.len() {
+            return;
+        }
+
+        let left = self.nodes.len();
+        self.nodes.push(BvhNode { bounds: Aabb::EMPTY, first, count: mid as u32 });
+        self.nodes.push(BvhNode { bounds: Aabb::EMPTY, first: first + mid as u32, count: count - mid as u32 });
+        self.nodes[node_index].first = left as u32;
+        self.nodes[node_index].count = 0;
+        self.subdivide(left, build);
+        self.subdivide(left + 1, build);
+    }
+
+    // Cheapest binned split over the three axes. Costs are left multiplied by
+    // the parent's surface area.
+    fn find_split(order: &[u32], build: &[BuildTriangle], centroid_bounds: &Aabb, area: f32) -> Option<Split> {
+        let extent = centroid_bounds.size();
+        let mut best: Option<Split> = None;
+        for axis in 0..3 {
+            let (lo, len) = match axis {
+                0 => (centroid_bounds.min.x, extent.x),
+                1 => (centroid_bounds.min.y, extent.y),
+                _ => (centroid_bounds.min.z, extent.z),
+            };
+            if len <= 0. {
+                continue;
+            }
+            let mut bins = [(Aabb::EMPTY, 0usize); SAH_BINS];
+            let candidate = Split { axis, bin: 0, lo, scale: SAH_BINS as f32 / len, cost: 0. };
+            for &t in order {
+                let b = &mut bins[candidate.bin_of(build[t as usize].centroid)];
+                b.0 = Aabb::union(b.0, build[t as usize].bounds);
+                b.1 += 1;
+            }
+
+            // Sweep from the right to get the cost of every right side, then from the left.
+            let mut right_area = [0.; SAH_BINS];
+            let mut right_count = [0; SAH_BINS];
+            let mut acc = (Aabb::EMPTY, 0);
+            for i in (1..SAH_BINS).rev() {
+                acc = (Aabb::union(acc.0, bins[i].0), acc.1 + bins[i].1);
+                right_area[i] = acc.0.surface_area();
+                right_count[i] = acc.1;
+            }
+            let mut acc = (Aabb::EMPTY, 0);
+            for i in 1..SAH_BINS {
+                acc = (Aabb::union(acc.0, bins[i - 1].0), acc.1 + bins[i - 1].1);
+                if acc.1 == 0 || right_count[i] == 0 {
+                    continue;
+                }
+                let cost = TRAVERSAL_COST * area
+                    + INTERSECTION_COST * (acc.0.surface_area() * acc.1 as f32 + right_area[i] * right_count[i] as f32);
+                if best.as_ref().is_none_or(|b| cost < b.cost) {
+                    best = Some(Split { bin: i, cost, ..candidate });
+                }
+            }
+        }
+        best
+    }
+
+    // Bottom up bounds update after the positions changed.
+    pub fn refit(&mut self, positions: &[Vector3]) {
+        assert_eq!(positions.len(), self.positions.len());
+        self.positions.copy_from_slice(positions);
+        // Children are always stored after their parents.
+        for i in (0..self.nodes.len()).rev() {
+            let node = self.nodes[i];
+            self.nodes[i].bounds = if node.count > 0 {
+                let mut bounds = Aabb::EMPTY;
+                for &t in &self.order[node.first as usize..(node.first + node.count) as usize] {
+                    for &p in self.triangle(t as usize).iter() {
+                        bounds.extend(p);
+                    }
+                }
+                bounds
+            } else {
+                let left = node.first as usize;
+                Aabb::union(self.nodes[left].bounds, self.nodes[left + 1].bounds)
+            };
+        }
+    }
+
+    // Nearest hit along the ray closer than max_distance.
+    pub fn raycast(&self, ray: &Ray, max_distance: f32) -> Option<MeshPoint> {
+        let mut nearest: Option<MeshPoint> = None;
+        let mut max_distance = max_distance;
+        self.traverse_ray(ray, &mut max_distance, |t, distance, barycentric| {
+            nearest = Some(MeshPoint { triangle: t, barycentric, position: ray.at(distance), distance });
+            false
+        });
+        nearest
+    }
+
+    // Whether anything is hit closer than max_distance. Stops at the first hit,
+    // which makes it the cheaper choice for visibility and shadow tests.
+    pub fn raycast_any(&self, ray: &Ray, max_distance: f32) -> bool {
+        let mut hit = false;
+        let mut max_distance = max_distance;
+        self.traverse_ray(ray, &mut max_distance, |_, _, _| {
+            hit = true;
+            true
+        });
+        hit
+    }
+
+    // Calls on_hit for hits closer than max_distance, which shrinks to each
+    // hit's distance. Stops early if on_hit returns true.
+    fn traverse_ray<F>(&self, ray: &Ray, max_distance: &mut f32, mut on_hit: F)
+        where F: FnMut(usize, f32, Vector3) -> bool
+    {
+        if self.order.is_empty() {
+            return;
+        }
+        let mut stack: Vec<u32> = Vec::with_capacity(64);
+        if self.nodes[0].bounds.ray_intersect(ray, *max_distance).is_some() {
+            stack.push(0);
+        }
+        while let Some(i) = stack.pop() {
+            let node = self.nodes[i as usize];
+            if node.count > 0 {
+                for &t in &self.order[node.first as usize..(node.first + node.count) as usize] {
+                    if let Some((distance, barycentric)) = ray.triangle_intersect(&self.triangle(t as usize)) {
+                        if distance < *max_distance {
+                            *max_distance = distance;
+                            if on_hit(t as usize, distance, barycentric) {
+                                return;
+                            }
+                        }
+                    }
+                }
+                continue;
+            }
+            // Push the farther child first, so the nearer one is visited first.
+            let left = node.first;
+            let right = left + 1;
+            let t_left = self.nodes[left as usize].bounds.ray_intersect(ray, *max_distance);
+            let t_right = self.nodes[right as usize].bounds.ray_intersect(ray, *max_distance);
+            match (t_left, t_right) {
+                (Some(tl), Some(tr)) => {
+                    if tl <= tr {
+                        stack.push(right);
+                        stack.push(left);
+                    } else {
+                        stack.push(left);
+                        stack.push(right);
+                    }
+                }
+                (Some(_), None) => stack.push(left),
+                (None, Some(_)) => stack.push(right),
+                (None, None) => {}
+            }
+        }
+    }
+
+    // Indices of the triangles that overlap the box.
+    pub fn query_aabb(&self, aabb: &Aabb) -> Vec<usize> {
+        let mut result = Vec::new();
+        if self.order.is_empty() {
+            return result;
+        }
+        let mut stack: Vec<u32> = vec![0];
+        while let Some(i) = stack.pop() {
+            let node = self.nodes[i as usize];
+            if !node.bounds.intersects(aabb) {
+                continue;
+            }
+            if node.count > 0 {
+                for &t in &self.order[node.first as usize..(node.first + node.count) as usize] {
+                    if aabb.intersects_triangle(&self.triangle(t as usize)) {
+                        result.push(t as usize);
+                    }
+                }
+            } else {
+                stack.push(node.first);
+                stack.push(node.first + 1);
+            }
+        }
+        result
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Split {
+    axis: usize,
+    // Triangles in bins below this one go to the left child.
+    bin: usize,
+    lo: f32,
+    scale: f32,
+    cost: f32,
+}
+
+impl Split {
+    fn bin_of(&self, centroid: Vector3) -> usize {
+        let c = match self.axis {
+            0 => centroid.x,
+            1 => centroid.y,
+            _ => centroid.z,
+        };
+        (((c - self.lo) * self.scale) as usize).min(SAH_BINS - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Xorshift, so that failures reproduce.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> f32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 40) as f32 / (1u64 << 24) as f32
+        }
+
+        fn vector(&mut self, lo: f32, hi: f32) -> Vector3 {
+            let mut c = || lo + (hi - lo) * self.next();
+            Vector3::new(c(), c(), c())
+        }
+    }
+
+    // Small triangles scattered in [-1, 1]^3, some overlapping.
+    fn triangle_soup(rng: &mut Rng, count: usize) -> (Vec<Vector3>, Vec<u32>) {
+        let mut positions = Vec::with_capacity(3 * count);
+        for _ in 0..count {
+            let center = rng.vector(-1., 1.);
+            for _ in 0..3 {
+                positions.push(center + rng.vector(-0.2, 0.2));
+            }
+        }
+        let indices = (0..positions.len() as u32).collect();
+        (positions, indices)
+    }
+
+    fn brute_raycast(bvh: &Bvh, ray: &Ray, max_distance: f32) -> Option<f32> {
+        (0..bvh.triangles.len())
+            .filter_map(|t| ray.triangle_intersect(&bvh.triangle(t)).map(|(distance, _)| distance))
+            .filter(|&distance| distance < max_distance)
+            .min_by(|a, b| a.total_cmp(b))
+    }
+
+    fn brute_query(bvh: &Bvh, aabb: &Aabb) -> Vec<usize> {
+        (0..bvh.triangles.len()).filter(|&t| aabb.intersects_triangle(&bvh.triangle(t))).collect()
+    }
+
+    fn check_against_brute_force(bvh: &Bvh, rng: &mut Rng) {
+        let mut hits = 0;
+        for _ in 0..500 {
+            let origin = rng.vector(-2., 2.);
+            let ray = Ray::new(origin, (rng.vector(-1., 1.) - origin).normalized());
+            let max_distance = if rng.next() < 0.5 { f32::INFINITY } else { 3. * rng.next() };
+            let expected = brute_raycast(bvh, &ray, max_distance);
+            let hit = bvh.raycast(&ray, max_distance);
+            assert_eq!(hit.as_ref().map(|h| h.distance), expected);
+            if let Some(hit) = hit {
+                assert_eq!(ray.triangle_intersect(&bvh.triangle(hit.triangle)).unwrap().0, hit.distance);
+                hits += 1;
+            }
+            assert_eq!(bvh.raycast_any(&ray, max_distance), expected.is_some());
+        }
+        assert!(hits > 50, "only {} rays hit", hits);
+
+        for _ in 0..200 {
+            let a = rng.vector(-1.5, 1.5);
+            let b = a + rng.vector(0., 1.);
+            let aabb = Aabb::new(a, b);
+            let mut result = bvh.query_aabb(&aabb);
+            result.sort_unstable();
+            assert_eq!(result, brute_query(bvh, &aabb));
+        }
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        let (positions, indices) = triangle_soup(&mut rng, 2000);
+        let bvh = Bvh::new(&positions, &indices);
+        assert!(bvh.node_count() > 1);
+        check_against_brute_force(&bvh, &mut rng);
+    }
+
+    #[test]
+    fn matches_brute_force_after_refit() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        let (mut positions, indices) = triangle_soup(&mut rng, 2000);
+        let mut bvh = Bvh::new(&positions, &indices);
+        for p in positions.iter_mut() {
+            *p = Vector3::new(p.x * 1.5, p.y, -p.z) + rng.vector(-0.3, 0.3);
+        }
+        bvh.refit(&positions);
+        assert_eq!(bvh.bounds(), Aabb::from_points(&positions));
+        check_against_brute_force(&bvh, &mut rng);
+    }
+
+    #[test]
+    fn empty() {
+        let bvh = Bvh::new(&[], &[]);
+        let ray = Ray::new(Vector3::ZERO, Vector3::new(0., 0., 1.));
+        assert!(bvh.raycast(&ray, f32::INFINITY).is_none());
+        assert!(!bvh.raycast_any(&ray, f32::INFINITY));
+        assert!(bvh.query_aabb(&Aabb::new(-Vector3::new(1., 1., 1.), Vector3::new(1., 1., 1.))).is_empty());
+    }
+}
@@ -1,5 +1,7 @@
 use crate::math::*;
 
+const FOV_X: f32 = FRAC_PI_3;
+
 pub struct Camera {
     pub position: Vector3,
     pub orientation: Quaternion,
@@ -15,13 +17,23 @@ impl Camera {
     }
 
     pub fn projection_matrix(&self) -> Matrix4 {
-        Matrix4::perspective(FRAC_PI_3, self.aspect_ratio, 0.5, 10.0)
+        Matrix4::perspective(FOV_X, self.aspect_ratio, 0.5, 10.0)
     }
 
     pub fn view_projection_matrix(&self) -> Matrix4 {
         self.projection_matrix() * self.view_matrix()
     }
 
+    // Height of the sphere on screen, as a fraction of the viewport height.
+    pub fn projected_size(&self, sphere: &Sphere) -> f32 {
+        let distance = (sphere.center - self.position).norm();
+        if distance <= sphere.radius {
+            return f32::INFINITY;
+        }
+        let tan_half_fov_y = (FOV_X / 2.).tan() / self.aspect_ratio;
+        sphere.radius / (distance * tan_half_fov_y)
+    }
+
     pub fn rotate(&mut self, axis: Vector3, angle: f32) {
         self.orientation = Quaternion::rotation(axis, angle) * self.orientation;
     }
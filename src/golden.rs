@@ -0,0 +1,290 @@
+use crate::camera::Camera;
+use crate::headless;
+use crate::image::{self, RgbaImage};
+use crate::math::*;
+use crate::mesh::{Mesh, MeshShader};
+use crate::text::{self, Font, Text, TextShader};
+use std::fs;
+use std::path::Path;
+
+// Regression tests of rendering output. Each scene is rendered headless and
+// compared with a reference image in resources/golden. Failures write the
+// rendered image and a diff to target/golden. Run with rugl --golden, and
+// rugl --golden --bless to replace the references after an intended change.
+
+const REFERENCE_DIR: &str = "resources/golden";
+const OUTPUT_DIR: &str = "target/golden";
+
+#[derive(Copy, Clone, Debug)]
+pub struct Tolerance {
+    // Largest difference in any channel for a pixel to still match.
+    pub channel: u8,
+    // Fraction of the pixels that may not match.
+    pub max_mismatched: f32,
+    // Lowest mean structural similarity of the luminance, from 0 to 1. Low
+    // when edges move or blur, even if few pixels change much.
+    pub min_ssim: f32,
+}
+
+impl Tolerance {
+    pub const EXACT: Tolerance = Tolerance { channel: 0, max_mismatched: 0., min_ssim: 1. };
+
+    // Absorbs rounding differences between drivers.
+    pub const DEFAULT: Tolerance = Tolerance { channel: 3, max_mismatched: 0.001, min_ssim: 0.99 };
+
+    // Antialiased glyph edges vary more between rasterizers.
+    pub const TEXT: Tolerance = Tolerance { channel: 8, max_mismatched: 0.01, min_ssim: 0.97 };
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Comparison {
+    // Pixels with a channel differing by more than the tolerance.
+    pub mismatched: usize,
+    pub pixel_count: usize,
+    pub max_difference: u8,
+    pub ssim: f32,
+}
+
+impl Comparison {
+    pub fn passes(&self, tolerance: &Tolerance) -> bool {
+        self.mismatched as f32 <= tolerance.max_mismatched * self.pixel_count as f32 && self.ssim >= tolerance.min_ssim
+    }
+}
+
+pub fn compare(expected: &RgbaImage, actual: &RgbaImage, tolerance: &Tolerance) -> Result<Comparison, String> {
+    if expected.dimensions() != actual.dimensions() {
+        return Err(format!("size is {:?}, the reference's {:?}", actual.dimensions(), expected.dimensions()));
+    }
+    let mut mismatched = 0;
+    let mut max_difference = 0;
+    for (e, a) in expected.pixels().zip(actual.pixels()) {
+        let difference = pixel_difference(e.0, a.0);
+        max_difference = max_difference.max(difference);
+        if difference > tolerance.channel {
+            mismatched += 1;
+        }
+    }
+    let pixel_count = (expected.width() * expected.height()) as usize;
+    Ok(Comparison { mismatched, pixel_count, max_difference, ssim: ssim(expected, actual) })
+}
+
+fn pixel_difference(a: [u8; 4], b: [u8; 4]) -> u8 {
+    (0..4).map(|k| (a[k] as i32 - b[k] as i32).unsigned_abs() as u8).max().unwrap()
+}
+
+// The reference faded to gray, with mismatched pixels in red, brighter the
+// larger the difference.
+pub fn diff_image(expected: &RgbaImage, actual: &RgbaImage, tolerance: &Tolerance) -> RgbaImage {
+    let (width, height) = expected.dimensions();
+    RgbaImage::from_fn(width, height, |x, y| {
+        let e = expected.get_pixel(x, y).0;
+        let difference = pixel_difference(e, actual.get_pixel(x, y).0);
+        if difference > tolerance.channel {
+            image::Rgba([128 + difference / 2, 0, 0, 255])
+        } else {
+            let gray = (luminance(e) / 4. + 160.) as u8;
+            image::Rgba([gray, gray, gray, 255])
+        }
+    })
+}
+
+fn luminance(p: [u8; 4]) -> f32 {
+    0.2126 * p[0] as f32 + 0.7152 * p[1] as f32 + 0.0722 * p[2] as f32
+}
+
+// Mean SSIM over 8x8 windows of the luminance, not overlapping. Windows at
+// the right and bottom edges may be smaller.
+pub fn ssim(expected: &RgbaImage, actual: &RgbaImage) -> f32 {
+    const WINDOW: u32 = 8;
+    const C1: f32 = (0.01 * 255.) * (0.01 * 255.);
+    const C2: f32 = (0.03 * 255.) * (0.03 * 255.);
+    let (width, height) = expected.dimensions();
+    let mut total = 0.;
+    let mut windows = 0;
+    for y0 in (0..height).step_by(WINDOW as usize) {
+        for x0 in (0..width).step_by(WINDOW as usize) {
+            let mut values = Vec::with_capacity((WINDOW * WINDOW) as usize);
+            for y in y0..(y0 + WINDOW).min(height) {
+                for x in x0..(x0 + WINDOW).min(width) {
+                    values.push((luminance(expected.get_pixel(x, y).0), luminance(actual.get_pixel(x, y).0)));
+                }
+            }
+            let n = values.len() as f32;
+            let mean_e = values.iter().map(|v| v.0).sum::<f32>() / n;
+            let mean_a = values.iter().map(|v| v.1).sum::<f32>() / n;
+            let (mut var_e, mut var_a, mut covariance) = (0., 0., 0.);
+            for (e, a) in values.iter() {
+                var_e += (e - mean_e) * (e - mean_e) / n;
+                var_a += (a - mean_a) * (a - mean_a) / n;
+                covariance += (e - mean_e) * (a - mean_a) / n;
+            }
+            total += (2. * mean_e * mean_a + C1) * (2. * covariance + C2)
+                / ((mean_e * mean_e + mean_a * mean_a + C1) * (var_e + var_a + C2));
+            windows += 1;
+        }
+    }
+    if windows == 0 { 1. } else { total / windows as f32 }
+}
+
+pub struct GoldenScene {
+    pub name: &'static str,
+    pub width: i32,
+    pub height: i32,
+    pub tolerance: Tolerance,
+    // Creates what it draws and drops it before returning, see
+    // headless::render. An error fails the scene.
+    pub draw: fn(i32, i32) -> Result<(), String>,
+}
+
+pub fn scenes() -> Vec<GoldenScene> {
+    vec![
+        GoldenScene { name: "text_left_aligned", width: 320, height: 120, tolerance: Tolerance::TEXT, draw: draw_text },
+        GoldenScene { name: "mesh_cube", width: 256, height: 256, tolerance: Tolerance::DEFAULT, draw: draw_cube },
+        GoldenScene { name: "mesh_ico_obj", width: 256, height: 256, tolerance: Tolerance::DEFAULT, draw: draw_ico },
+    ]
+}
+
+// Fira Sans is in the tree, under the SIL Open Font License, so that the
+// reference does not depend on the fonts installed.
+const FONT_PATH: &str = "resources/fonts/FiraSans-Regular.ttf";
+
+fn draw_text(width: i32, height: i32) -> Result<(), String> {
+    let freetype = text::init_library().map_err(|e| e.to_string())?;
+    let mut font = Font::open(FONT_PATH, 18, &freetype).map_err(|e| format!("{}: {}", FONT_PATH, e))?;
+    let lorem = "Lorem ipsum dolor sit amet, consectetur adipisicing elit, sed do eiusmod";
+    let text = Text::left_aligned(lorem, Vector2::new(10., 30.), 200., &mut font);
+    let mut shader = TextShader::new().map_err(|e| e.to_string())?;
+    shader.set_screen_size(Vector2::new(width as f32, height as f32));
+    crate::gpu::clear(1.0, 1.0, 1.0, 1.0);
+    shader.draw(&text, &font);
+    Ok(())
+}
+
+fn draw_cube(width: i32, height: i32) -> Result<(), String> {
+    draw_mesh(Mesh::new_cube(), width, height)
+}
+
+fn draw_ico(width: i32, height: i32) -> Result<(), String> {
+    let source = fs::read_to_string("resources/ico.obj").map_err(|e| format!("resources/ico.obj: {}", e))?;
+    draw_mesh(Mesh::load_obj(&source), width, height)
+}
+
+// Seen from above the +X and +Y sides, where mesh_frag.glsl's lights are,
+// so that the faces get different shades.
+fn draw_mesh(mesh: Mesh, width: i32, height: i32) -> Result<(), String> {
+    let mut shader = MeshShader::new("shaders/mesh_frag.glsl").map_err(|e| e.to_string())?;
+    let position = Vector3::new(2.5, 2.5, 1.8);
+    let pitch = position.z.atan2((position.x * position.x + position.y * position.y).sqrt());
+    let camera = Camera {
+        position,
+        // Looking along +Y, tilted down to the origin, then turned towards it.
+        orientation: Quaternion::rotation(Vector3::new(0., 0., 1.), 3. * FRAC_PI_4)
+            * Quaternion::rotation(Vector3::new(1., 0., 0.), FRAC_PI_2 - pitch),
+        aspect_ratio: width as f32 / height as f32,
+    };
+    crate::gpu::clear(0.0, 0.0, 0.0, 1.0);
+    shader.set_view_matrix(&camera.view_projection_matrix());
+    shader.set_model_transform(&Matrix4::id());
+    shader.draw(&mesh);
+    Ok(())
+}
+
+// Renders the scenes whose names contain filter and compares them with their
+// references, or with bless, replaces the references. Prints a line per
+// scene and returns whether all passed.
+pub fn run(filter: Option<&str>, bless: bool) -> bool {
+    let mut passed = true;
+    for scene in scenes().iter().filter(|s| filter.is_none_or(|f| s.name.contains(f))) {
+        match run_scene(scene, bless) {
+            Ok(message) => println!("golden {} ... {}", scene.name, message),
+            Err(e) => {
+                println!("golden {} ... FAILED: {}", scene.name, e);
+                passed = false;
+            }
+        }
+    }
+    passed
+}
+
+fn run_scene(scene: &GoldenScene, bless: bool) -> Result<String, String> {
+    let mut drawn = Ok(());
+    let actual = headless::render(scene.width, scene.height, || drawn = (scene.draw)(scene.width, scene.height))?;
+    drawn?;
+    let reference_path = format!("{}/{}.png", REFERENCE_DIR, scene.name);
+    if bless {
+        save(&actual, &reference_path)?;
+        return Ok(format!("blessed {}", reference_path));
+    }
+    let expected = match image::open(&reference_path) {
+        Ok(expected) => expected.to_rgba(),
+        Err(e) => {
+            let actual_path = save_output(&actual, scene.name, "")?;
+            return Err(format!("{}: {}, wrote {}, bless it if it is right", reference_path, e, actual_path));
+        }
+    };
+    let comparison = compare(&expected, &actual, &scene.tolerance).inspect_err(|_| {
+        let _ = save_output(&actual, scene.name, "");
+    })?;
+    if comparison.passes(&scene.tolerance) {
+        return Ok(format!("ok (ssim {:.4}, max difference {})", comparison.ssim, comparison.max_difference));
+    }
+    let actual_path = save_output(&actual, scene.name, "")?;
+    let diff_path = save_output(&diff_image(&expected, &actual, &scene.tolerance), scene.name, ".diff")?;
+    Err(format!(
+        "{} of {} pixels differ by more than {}, up to {}, ssim {:.4}, wrote {} and {}",
+        comparison.mismatched, comparison.pixel_count, scene.tolerance.channel, comparison.max_difference, comparison.ssim, actual_path, diff_path
+    ))
+}
+
+fn save_output(image: &RgbaImage, name: &str, suffix: &str) -> Result<String, String> {
+    let path = format!("{}/{}{}.png", OUTPUT_DIR, name, suffix);
+    save(image, &path)?;
+    Ok(path)
+}
+
+fn save(image: &RgbaImage, path: &str) -> Result<(), String> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    }
+    image.save_with_format(path, image::ImageFormat::Png).map_err(|e| format!("{}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_scene(name: &str) {
+        let scene = scenes().into_iter().find(|s| s.name == name).unwrap();
+        if let Err(e) = run_scene(&scene, false) {
+            panic!("golden {}: {}", name, e);
+        }
+    }
+
+    #[test]
+    fn text_left_aligned() {
+        check_scene("text_left_aligned");
+    }
+
+    #[test]
+    fn mesh_cube() {
+        check_scene("mesh_cube");
+    }
+
+    #[test]
+    fn mesh_ico_obj() {
+        check_scene("mesh_ico_obj");
+    }
+
+    #[test]
+    fn setup_failure_fails_the_scene() {
+        let scene = GoldenScene {
+            name: "missing_file",
+            width: 4,
+            height: 4,
+            tolerance: Tolerance::EXACT,
+            draw: |_, _| Err("no such file".to_string()),
+        };
+        assert_eq!(run_scene(&scene, false), Err("no such file".to_string()));
+    }
+
+}
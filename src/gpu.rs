@@ -1,6 +1,10 @@
+use crate::image::{self, DynamicImage, GenericImageView};
 use crate::math::*;
 use gl::types::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{CString};
+use std::marker::PhantomData;
 use std::fs;
 use std::mem::{size_of, size_of_val};
 use std::ptr;
@@ -42,11 +46,112 @@ pub fn clear(r: f32, g: f32, b: f32, a: f32) {
     }
 }
 
-#[allow(dead_code)]
+// GL objects are owned by the types below, which delete them on drop. They
+// must be dropped while their context is still current.
+//
+// Debug builds keep track of the live objects, so that the ones that were
+// never deleted can be reported before the context goes away.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ObjectKind {
+    Buffer,
+    VertexArray,
+    Texture,
+    Shader,
+    Program,
+    Renderbuffer,
+    Framebuffer,
+    Sampler,
+}
+
+thread_local! {
+    static LIVE_OBJECTS: RefCell<HashSet<(ObjectKind, u32)>> = RefCell::new(HashSet::new());
+}
+
+fn track(kind: ObjectKind, id: u32) {
+    if cfg!(debug_assertions) && id != 0 {
+        LIVE_OBJECTS.with(|live| live.borrow_mut().insert((kind, id)));
+    }
+}
+
+fn untrack(kind: ObjectKind, id: u32) {
+    if cfg!(debug_assertions) && id != 0 {
+        LIVE_OBJECTS.with(|live| live.borrow_mut().remove(&(kind, id)));
+    }
+}
+
+// Objects created and not yet deleted. Always empty in release builds.
+pub fn live_objects() -> Vec<(ObjectKind, u32)> {
+    let mut objects: Vec<(ObjectKind, u32)> = LIVE_OBJECTS.with(|live| live.borrow().iter().cloned().collect());
+    objects.sort();
+    objects
+}
+
+// Prints the objects that are still alive and returns how many there are.
+// Call it at context teardown, after everything should have been dropped.
+pub fn report_leaks() -> usize {
+    let objects = live_objects();
+    if !objects.is_empty() {
+        eprintln!("gpu: {} objects were not deleted:", objects.len());
+        for (kind, id) in objects.iter() {
+            eprintln!("    {:?} {}", kind, id);
+        }
+    }
+    objects.len()
+}
+
 pub struct Program {
     id: GLuint,
     vert_shader: GLuint,
     frag_shader: GLuint,
+    // Read from the linked program.
+    uniforms: Vec<ActiveUniform>,
+    uniform_blocks: Vec<UniformBlock>,
+    attributes: Vec<ActiveAttribute>,
+    // Locations by name, -1 for names that are not in the program.
+    uniform_locations: RefCell<HashMap<String, i32>>,
+}
+
+// Arrays have the name without "[0]" and size the element count.
+#[derive(Clone, Debug)]
+pub struct ActiveUniform {
+    pub name: String,
+    pub type_: GlslType,
+    pub size: i32,
+    // -1 for uniforms in blocks.
+    pub location: i32,
+    // Index in uniform_blocks and byte offset in the block.
+    pub block: Option<usize>,
+    pub offset: i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct UniformBlock {
+    pub name: String,
+    pub index: u32,
+    pub data_size: i32,
+    pub binding: i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct ActiveAttribute {
+    pub name: String,
+    pub type_: GlslType,
+    pub size: i32,
+    pub location: i32,
+}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteShader(self.vert_shader);
+            gl::DeleteShader(self.frag_shader);
+            gl::DeleteProgram(self.id);
+        }
+        untrack(ObjectKind::Shader, self.vert_shader);
+        untrack(ObjectKind::Shader, self.frag_shader);
+        untrack(ObjectKind::Program, self.id);
+    }
 }
 
 impl Program {
@@ -57,111 +162,617 @@ impl Program {
     pub fn from_files(
         vertex_shader_path: &str,
         fragment_shader_path: &str,
-    ) -> Result<Self, String> {
-        let vsrc = fs::read_to_string(vertex_shader_path).map_err(|e| e.to_string())?;
-        let fsrc = fs::read_to_string(fragment_shader_path).map_err(|e| e.to_string())?;
-        Self::from_sources(vsrc, fsrc)
+    ) -> Result<Self, ShaderError> {
+        let read = |path: &str| {
+            fs::read_to_string(path).map_err(|e| ShaderError::Io { path: path.to_string(), message: e.to_string() })
+        };
+        let vsrc = read(vertex_shader_path)?;
+        let fsrc = read(fragment_shader_path)?;
+        Self::build(&vsrc, Some(vertex_shader_path), &fsrc, Some(fragment_shader_path))
     }
 
     pub fn from_sources(
         vertex_shader_source: String,
         fragment_shader_source: String,
-    ) -> Result<Self, String> {
-        let id = unsafe { gl::CreateProgram() };
-        let vert_shader = unsafe { gl::CreateShader(gl::VERTEX_SHADER) };
-        let frag_shader = unsafe { gl::CreateShader(gl::FRAGMENT_SHADER) };
-        Self::compile_shader(vert_shader, vertex_shader_source)?;
-        Self::compile_shader(frag_shader, fragment_shader_source)?;
-        unsafe {
-            gl::AttachShader(id, vert_shader);
-            gl::AttachShader(id, frag_shader);
-            gl::LinkProgram(id);
-            gl::UseProgram(id);
-        }
-        Ok(Self {
-            id,
-            vert_shader,
-            frag_shader,
-        })
+    ) -> Result<Self, ShaderError> {
+        Self::build(&vertex_shader_source, None, &fragment_shader_source, None)
+    }
+
+    fn build(vsrc: &str, vpath: Option<&str>, fsrc: &str, fpath: Option<&str>) -> Result<Self, ShaderError> {
+        // Built first so that its objects are deleted if compilation fails.
+        let mut program = unsafe {
+            Self {
+                id: gl::CreateProgram(),
+                vert_shader: gl::CreateShader(gl::VERTEX_SHADER),
+                frag_shader: gl::CreateShader(gl::FRAGMENT_SHADER),
+                uniforms: Vec::new(),
+                uniform_blocks: Vec::new(),
+                attributes: Vec::new(),
+                uniform_locations: RefCell::new(HashMap::new()),
+            }
+        };
+        track(ObjectKind::Program, program.id);
+        track(ObjectKind::Shader, program.vert_shader);
+        track(ObjectKind::Shader, program.frag_shader);
+        Self::compile_shader(program.vert_shader, ShaderStage::Vertex, vpath, vsrc)?;
+        Self::compile_shader(program.frag_shader, ShaderStage::Fragment, fpath, fsrc)?;
+        let mut status: i32 = 0;
+        unsafe {
+            gl::AttachShader(program.id, program.vert_shader);
+            gl::AttachShader(program.id, program.frag_shader);
+            gl::LinkProgram(program.id);
+            gl::GetProgramiv(program.id, gl::LINK_STATUS, &mut status);
+        }
+        if status != gl::TRUE as i32 {
+            let log = info_log(program.id, gl::GetProgramiv, gl::GetProgramInfoLog);
+            return Err(ShaderError::Link { log });
+        }
+        program.reflect();
+        program.check_attributes()?;
+        program.activate();
+        Ok(program)
+    }
+
+    fn reflect(&mut self) {
+        let id = self.id;
+        let get = |pname: GLenum| {
+            let mut value: i32 = 0;
+            unsafe { gl::GetProgramiv(id, pname, &mut value) };
+            value
+        };
+
+        let name_len = get(gl::ACTIVE_UNIFORM_BLOCK_MAX_NAME_LENGTH);
+        for index in 0..get(gl::ACTIVE_UNIFORM_BLOCKS) as u32 {
+            let name = read_name(name_len, |len, written, buffer| unsafe {
+                gl::GetActiveUniformBlockName(id, index, len, written, buffer)
+            });
+            let get_block = |pname: GLenum| {
+                let mut value: i32 = 0;
+                unsafe { gl::GetActiveUniformBlockiv(id, index, pname, &mut value) };
+                value
+            };
+            let data_size = get_block(gl::UNIFORM_BLOCK_DATA_SIZE);
+            let binding = get_block(gl::UNIFORM_BLOCK_BINDING);
+            self.uniform_blocks.push(UniformBlock { name, index, data_size, binding });
+        }
+
+        let name_len = get(gl::ACTIVE_UNIFORM_MAX_LENGTH);
+        for index in 0..get(gl::ACTIVE_UNIFORMS) as u32 {
+            let (mut size, mut type_) = (0, 0);
+            let name = read_name(name_len, |len, written, buffer| unsafe {
+                gl::GetActiveUniform(id, index, len, written, &mut size, &mut type_, buffer)
+            });
+            let name = name.strip_suffix("[0]").map(str::to_string).unwrap_or(name);
+            let get_uniform = |pname: GLenum| {
+                let mut value: i32 = 0;
+                unsafe { gl::GetActiveUniformsiv(id, 1, &index, pname, &mut value) };
+                value
+            };
+            let block_index = get_uniform(gl::UNIFORM_BLOCK_INDEX);
+            let block = self.uniform_blocks.iter().position(|b| b.index as i32 == block_index);
+            let offset = get_uniform(gl::UNIFORM_OFFSET);
+            let location = match block {
+                Some(_) => -1,
+                None => {
+                    let c_name = CString::new(name.as_str()).unwrap();
+                    unsafe { gl::GetUniformLocation(id, c_name.as_ptr()) }
+                }
+            };
+            let type_ = GlslType::from_gl(type_);
+            self.uniforms.push(ActiveUniform { name, type_, size, location, block, offset });
+        }
+
+        let name_len = get(gl::ACTIVE_ATTRIBUTE_MAX_LENGTH);
+        for index in 0..get(gl::ACTIVE_ATTRIBUTES) as u32 {
+            let (mut size, mut type_) = (0, 0);
+            let name = read_name(name_len, |len, written, buffer| unsafe {
+                gl::GetActiveAttrib(id, index, len, written, &mut size, &mut type_, buffer)
+            });
+            let c_name = CString::new(name.as_str()).unwrap();
+            let location = unsafe { gl::GetAttribLocation(id, c_name.as_ptr()) };
+            let type_ = GlslType::from_gl(type_);
+            self.attributes.push(ActiveAttribute { name, type_, size, location });
+        }
+    }
+
+    // Vertex arrays feed attributes by Attr, so every attribute of the
+    // program must be one, at its location and with a type it can take.
+    fn check_attributes(&self) -> Result<(), ShaderError> {
+        for attribute in self.attributes.iter().filter(|a| !a.name.starts_with("gl_")) {
+            let reason = match Attr::ALL.iter().find(|attr| attr.name() == attribute.name) {
+                None => "is not a gpu::Attr".to_string(),
+                Some(attr) if attribute.location != attr.location() as i32 => {
+                    format!("is at location {}, not {}", attribute.location, attr.location())
+                }
+                Some(attr) if !attr.accepts(attribute.type_) => format!("has type {:?}", attribute.type_),
+                Some(_) => continue,
+            };
+            return Err(ShaderError::Attribute { name: attribute.name.clone(), reason });
+        }
+        Ok(())
+    }
+
+    pub fn uniforms(&self) -> &[ActiveUniform] {
+        &self.uniforms
+    }
+
+    pub fn uniform_blocks(&self) -> &[UniformBlock] {
+        &self.uniform_blocks
+    }
+
+    pub fn attributes(&self) -> &[ActiveAttribute] {
+        &self.attributes
+    }
+
+    pub fn active_uniform(&self, name: &str) -> Option<&ActiveUniform> {
+        self.uniforms.iter().find(|u| u.name == name)
+    }
+
+    pub fn uniform_block(&self, name: &str) -> Option<&UniformBlock> {
+        self.uniform_blocks.iter().find(|b| b.name == name)
+    }
+
+    // Reads the block from the buffer, after checking that every member of
+    // the block is in T at the same offset and with the same type.
+    pub fn bind_uniform_block<T: Std140>(&mut self, name: &str, buffer: &UniformBuffer<T>) -> Result<(), ShaderError> {
+        let block = self.uniform_block(name).ok_or_else(|| ShaderError::UnknownUniform { name: name.to_string() })?;
+        let block_index = self.uniform_blocks.iter().position(|b| b.name == name);
+        let fields = std140_fields::<T>();
+        let layout_error = |reason: String| ShaderError::BlockLayout { name: name.to_string(), reason };
+        if block.data_size as usize > T::std140_size() {
+            return Err(layout_error(format!("is {} bytes, more than {}", block.data_size, T::std140_size())));
+        }
+        let prefix = format!("{}.", name);
+        for uniform in self.uniforms.iter().filter(|u| u.block == block_index) {
+            let member = uniform.name.strip_prefix(&prefix).unwrap_or(&uniform.name);
+            match fields.iter().find(|f| f.name == member) {
+                None => return Err(layout_error(format!("member {} is not in the struct", member))),
+                Some(field) if field.offset as i32 != uniform.offset => {
+                    return Err(layout_error(format!("member {} is at {}, not {}", member, uniform.offset, field.offset)));
+                }
+                Some(field) if field.type_ != uniform.type_ => {
+                    return Err(layout_error(format!("member {} is a {:?}, not a {:?}", member, uniform.type_, field.type_)));
+                }
+                Some(_) => {}
+            }
+        }
+        unsafe {
+            gl::UniformBlockBinding(self.id, block.index, buffer.binding());
+        }
+        Ok(())
     }
 
-    pub fn get_uniform(&self, name: &str) -> Result<Uniform, String> {
+    // Checks that the program can run in the current state, textures and
+    // uniforms included. Samplers of different types on the same unit fail,
+    // so call it with everything set up for a draw.
+    pub fn validate(&self) -> Result<(), ShaderError> {
+        let mut status: i32 = 0;
         unsafe {
-            let name = CString::new(name).map_err(|e| e.to_string())?;
-            let location = gl::GetUniformLocation(self.id, name.as_ptr() as *const GLchar);
-            Ok(Uniform { location })
+            gl::ValidateProgram(self.id);
+            gl::GetProgramiv(self.id, gl::VALIDATE_STATUS, &mut status);
+        }
+        if status != gl::TRUE as i32 {
+            let log = info_log(self.id, gl::GetProgramiv, gl::GetProgramInfoLog);
+            return Err(ShaderError::Validate { log });
+        }
+        Ok(())
+    }
+
+    // Fails for uniforms that are not in the program, including those the
+    // compiler removed because they are unused.
+    pub fn get_uniform(&self, name: &str) -> Result<Uniform, ShaderError> {
+        self.find_uniform(name).ok_or_else(|| ShaderError::UnknownUniform { name: name.to_string() })
+    }
+
+    // Like get_uniform, also checking that the uniform can be set to a T,
+    // for checking the Rust side against the shader at load time.
+    pub fn get_uniform_as<T: UniformValue>(&self, name: &str) -> Result<Uniform, ShaderError> {
+        let uniform = self.get_uniform(name)?;
+        let type_ = self.active_uniform(name).map_or(GlslType::Other(0), |u| u.type_);
+        if !T::accepts(type_) {
+            return Err(ShaderError::UniformType { name: name.to_string(), type_ });
+        }
+        Ok(uniform)
+    }
+
+    // For uniforms that only some versions of a shader have. Locations are
+    // cached, also for array elements like "T_joints[3]".
+    pub fn find_uniform(&self, name: &str) -> Option<Uniform> {
+        let cached = self.uniform_locations.borrow().get(name).copied();
+        let location = match cached {
+            Some(location) => location,
+            None => {
+                let c_name = CString::new(name).ok()?;
+                let location = unsafe { gl::GetUniformLocation(self.id, c_name.as_ptr() as *const GLchar) };
+                self.uniform_locations.borrow_mut().insert(name.to_string(), location);
+                location
+            }
+        };
+        if location < 0 {
+            return None;
         }
+        Some(Uniform { location })
     }
 
     pub fn set_uniform<T: UniformValue>(&mut self, uniform: Uniform, value: T) {
         value.set_uniform(uniform);
     }
 
-    fn compile_shader(shader: GLuint, source: String) -> Result<(), String> {
-        let src = CString::new(source).map_err(|e| e.to_string())?;
+    fn compile_shader(shader: GLuint, stage: ShaderStage, path: Option<&str>, source: &str) -> Result<(), ShaderError> {
+        let compile_error = |log: String| {
+            let diagnostics = parse_compile_log(&log, source);
+            ShaderError::Compile { stage, path: path.map(str::to_string), log, diagnostics }
+        };
+        let src = CString::new(source).map_err(|e| compile_error(e.to_string()))?;
+        let mut status: i32 = 0;
         unsafe {
             gl::ShaderSource(shader, 1, &src.as_ptr(), ptr::null());
             gl::CompileShader(shader);
-            let mut status: i32 = 0;
             gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut status);
-            if status != gl::TRUE as i32 {
-                let mut log_len: i32 = 0;
-                gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut log_len);
-                assert!(log_len >= 0);
-                let log_buffer: Vec<u8> = vec![0; log_len as usize + 1];
-                let log: CString = CString::from_vec_unchecked(log_buffer);
-                gl::GetShaderInfoLog(
-                    shader,
-                    log_len,
-                    ptr::null_mut(),
-                    log.as_ptr() as *mut GLchar,
-                );
-                return Err(log.into_string().unwrap());
-            }
+        }
+        if status != gl::TRUE as i32 {
+            return Err(compile_error(info_log(shader, gl::GetShaderiv, gl::GetShaderInfoLog)));
         }
         Ok(())
     }
 }
 
+// Reads a name of at most max_len bytes, nul included, with a call taking
+// the buffer length, a pointer to the written length and the buffer.
+fn read_name(max_len: i32, read: impl FnOnce(GLsizei, *mut GLsizei, *mut GLchar)) -> String {
+    let mut name: Vec<u8> = vec![0; max_len.max(1) as usize];
+    let mut written: GLsizei = 0;
+    read(name.len() as GLsizei, &mut written, name.as_mut_ptr() as *mut GLchar);
+    name.truncate(written.max(0) as usize);
+    String::from_utf8_lossy(&name).to_string()
+}
+
+type GetIv = unsafe fn(GLuint, GLenum, *mut GLint);
+type GetInfoLog = unsafe fn(GLuint, GLsizei, *mut GLsizei, *mut GLchar);
+
+// The info log of a shader or a program.
+fn info_log(id: GLuint, get_iv: GetIv, get_info_log: GetInfoLog) -> String {
+    let mut log_len: i32 = 0;
+    unsafe { get_iv(id, gl::INFO_LOG_LENGTH, &mut log_len) };
+    let mut log: Vec<u8> = vec![0; log_len.max(1) as usize];
+    let mut written: GLsizei = 0;
+    unsafe { get_info_log(id, log.len() as GLsizei, &mut written, log.as_mut_ptr() as *mut GLchar) };
+    log.truncate(written.max(0) as usize);
+    String::from_utf8_lossy(&log).trim_end().to_string()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+}
+
+#[derive(Clone, Debug)]
+pub enum ShaderError {
+    // A shader file could not be read.
+    Io { path: String, message: String },
+    // Path is None for shaders built from sources. Diagnostics are the log's
+    // lines, with their locations when the driver's format is recognized.
+    Compile { stage: ShaderStage, path: Option<String>, log: String, diagnostics: Vec<ShaderDiagnostic> },
+    Link { log: String },
+    Validate { log: String },
+    UnknownUniform { name: String },
+    // The uniform's type does not match the value it is set to.
+    UniformType { name: String, type_: GlslType },
+    Attribute { name: String, reason: String },
+    // The Rust struct does not match the uniform block.
+    BlockLayout { name: String, reason: String },
+}
+
+#[derive(Clone, Debug)]
+pub struct ShaderDiagnostic {
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub message: String,
+    // The source lines around line, numbered, empty without a line.
+    pub excerpt: String,
+}
+
+impl std::fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShaderError::Io { path, message } => write!(f, "{}: {}", path, message),
+            ShaderError::Compile { stage, path, log, diagnostics } => {
+                let path = path.as_deref().unwrap_or("<source>");
+                writeln!(f, "{:?} shader {} failed to compile", stage, path)?;
+                if diagnostics.is_empty() {
+                    return write!(f, "{}", log);
+                }
+                for d in diagnostics.iter() {
+                    match (d.line, d.column) {
+                        (Some(line), Some(column)) => writeln!(f, "{}:{}:{}: {}", path, line, column, d.message)?,
+                        (Some(line), None) => writeln!(f, "{}:{}: {}", path, line, d.message)?,
+                        _ => writeln!(f, "{}: {}", path, d.message)?,
+                    }
+                    write!(f, "{}", d.excerpt)?;
+                }
+                Ok(())
+            }
+            ShaderError::Link { log } => write!(f, "program failed to link\n{}", log),
+            ShaderError::Validate { log } => write!(f, "program failed to validate\n{}", log),
+            ShaderError::UnknownUniform { name } => write!(f, "no active uniform named {}", name),
+            ShaderError::UniformType { name, type_ } => write!(f, "uniform {} has unexpected type {:?}", name, type_),
+            ShaderError::Attribute { name, reason } => write!(f, "attribute {} {}", name, reason),
+            ShaderError::BlockLayout { name, reason } => write!(f, "uniform block {} {}", name, reason),
+        }
+    }
+}
+
+impl std::error::Error for ShaderError {}
+
+fn parse_compile_log(log: &str, source: &str) -> Vec<ShaderDiagnostic> {
+    let source_lines: Vec<&str> = source.lines().collect();
+    log.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|message| {
+            let (line, column) = match parse_log_location(message) {
+                Some((line, column)) => (Some(line), column),
+                None => (None, None),
+            };
+            let mut excerpt = String::new();
+            if let Some(line) = line.filter(|&l| l >= 1 && l as usize <= source_lines.len()) {
+                let first = line.max(2) - 1;
+                let last = (line + 1).min(source_lines.len() as u32);
+                for n in first..=last {
+                    let marker = if n == line { '>' } else { ' ' };
+                    excerpt += &format!("{} {:4} | {}\n", marker, n, source_lines[n as usize - 1]);
+                }
+            }
+            ShaderDiagnostic { line, column, message: message.to_string(), excerpt }
+        })
+        .collect()
+}
+
+// Line and column of a log line in the common driver formats:
+// "0:12(5): error: ..." (Mesa), "0(12) : error ..." (NVIDIA) and
+// "ERROR: 0:12: ..." (ANGLE, AMD, Apple).
+fn parse_log_location(message: &str) -> Option<(u32, Option<u32>)> {
+    let rest = ["ERROR:", "WARNING:"].iter().find_map(|p| message.strip_prefix(p)).unwrap_or(message);
+    let (_source, rest) = split_number(rest.trim_start())?;
+    if let Some(rest) = rest.strip_prefix(':') {
+        let (line, rest) = split_number(rest)?;
+        let column = rest.strip_prefix('(').and_then(split_number).map(|(column, _)| column);
+        Some((line, column))
+    } else {
+        let (line, _) = split_number(rest.strip_prefix('(')?)?;
+        Some((line, None))
+    }
+}
+
+fn split_number(s: &str) -> Option<(u32, &str)> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    Some((s[..end].parse().ok()?, &s[end..]))
+}
+
+// Types of uniforms and attributes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GlslType {
+    Float,
+    Vec2,
+    Vec3,
+    Vec4,
+    Int,
+    IVec2,
+    IVec3,
+    IVec4,
+    UInt,
+    UVec2,
+    UVec3,
+    UVec4,
+    Bool,
+    Mat2,
+    Mat3,
+    Mat4,
+    Sampler2D,
+    Sampler3D,
+    SamplerCube,
+    Sampler2DArray,
+    Sampler2DShadow,
+    // Any other type, by its GL enum.
+    Other(u32),
+}
+
+impl GlslType {
+    pub fn from_gl(type_: GLenum) -> GlslType {
+        match type_ {
+            gl::FLOAT => GlslType::Float,
+            gl::FLOAT_VEC2 => GlslType::Vec2,
+            gl::FLOAT_VEC3 => GlslType::Vec3,
+            gl::FLOAT_VEC4 => GlslType::Vec4,
+            gl::INT => GlslType::Int,
+            gl::INT_VEC2 => GlslType::IVec2,
+            gl::INT_VEC3 => GlslType::IVec3,
+            gl::INT_VEC4 => GlslType::IVec4,
+            gl::UNSIGNED_INT => GlslType::UInt,
+            gl::UNSIGNED_INT_VEC2 => GlslType::UVec2,
+            gl::UNSIGNED_INT_VEC3 => GlslType::UVec3,
+            gl::UNSIGNED_INT_VEC4 => GlslType::UVec4,
+            gl::BOOL => GlslType::Bool,
+            gl::FLOAT_MAT2 => GlslType::Mat2,
+            gl::FLOAT_MAT3 => GlslType::Mat3,
+            gl::FLOAT_MAT4 => GlslType::Mat4,
+            gl::SAMPLER_2D => GlslType::Sampler2D,
+            gl::SAMPLER_3D => GlslType::Sampler3D,
+            gl::SAMPLER_CUBE => GlslType::SamplerCube,
+            gl::SAMPLER_2D_ARRAY => GlslType::Sampler2DArray,
+            gl::SAMPLER_2D_SHADOW => GlslType::Sampler2DShadow,
+            other => GlslType::Other(other),
+        }
+    }
+
+    pub fn is_sampler(self) -> bool {
+        matches!(
+            self,
+            GlslType::Sampler2D
+                | GlslType::Sampler3D
+                | GlslType::SamplerCube
+                | GlslType::Sampler2DArray
+                | GlslType::Sampler2DShadow
+        )
+    }
+}
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct Uniform {
     pub location: i32,
 }
 
+impl Uniform {
+    // Setting it does nothing, for uniforms a shader may not have.
+    pub const NONE: Uniform = Uniform { location: -1 };
+}
+
 pub trait UniformValue {
     fn set_uniform(&self, uniform: Uniform);
+
+    // Whether a uniform of the type can be set to this kind of value.
+    fn accepts(type_: GlslType) -> bool
+    where
+        Self: Sized;
 }
 
-impl UniformValue for f32 {
-    fn set_uniform(&self, uniform: Uniform) {
-        unsafe {
-            gl::Uniform1f(uniform.location, *self);
+// Scalars and vectors, and slices of them for array uniforms, set starting
+// at the uniform's first element. The types are repr(C), so slices are
+// passed as they are.
+macro_rules! uniform_value {
+    ($t:ty, $scalar:ty, $set:ident, $($glsl:ident)|+) => {
+        impl UniformValue for $t {
+            fn set_uniform(&self, uniform: Uniform) {
+                unsafe {
+                    gl::$set(uniform.location, 1, self as *const $t as *const $scalar);
+                }
+            }
+
+            fn accepts(type_: GlslType) -> bool {
+                matches!(type_, $(GlslType::$glsl)|+)
+            }
+        }
+
+        impl UniformValue for &[$t] {
+            fn set_uniform(&self, uniform: Uniform) {
+                if self.is_empty() {
+                    return;
+                }
+                unsafe {
+                    gl::$set(uniform.location, self.len() as i32, self.as_ptr() as *const $scalar);
+                }
+            }
+
+            fn accepts(type_: GlslType) -> bool {
+                <$t>::accepts(type_)
+            }
+        }
+    };
+}
+
+uniform_value!(f32, GLfloat, Uniform1fv, Float);
+uniform_value!(Vector2, GLfloat, Uniform2fv, Vec2);
+uniform_value!(Vector3, GLfloat, Uniform3fv, Vec3);
+uniform_value!(Vector4, GLfloat, Uniform4fv, Vec4);
+uniform_value!(Color3, GLfloat, Uniform3fv, Vec3);
+uniform_value!(Color4, GLfloat, Uniform4fv, Vec4);
+uniform_value!(i32, GLint, Uniform1iv, Int | Bool);
+uniform_value!(Vector2I, GLint, Uniform2iv, IVec2);
+uniform_value!(Vector3I, GLint, Uniform3iv, IVec3);
+uniform_value!(u32, GLuint, Uniform1uiv, UInt | Bool);
+
+// Matrices are column major, like GLSL's.
+macro_rules! uniform_matrix {
+    ($t:ty, $set:ident, $glsl:ident) => {
+        impl UniformValue for &$t {
+            fn set_uniform(&self, uniform: Uniform) {
+                unsafe {
+                    gl::$set(uniform.location, 1, gl::FALSE, self.as_ptr() as *const GLfloat);
+                }
+            }
+
+            fn accepts(type_: GlslType) -> bool {
+                type_ == GlslType::$glsl
+            }
+        }
+
+        impl UniformValue for &[$t] {
+            fn set_uniform(&self, uniform: Uniform) {
+                if self.is_empty() {
+                    return;
+                }
+                unsafe {
+                    gl::$set(uniform.location, self.len() as i32, gl::FALSE, self[0].as_ptr() as *const GLfloat);
+                }
+            }
+
+            fn accepts(type_: GlslType) -> bool {
+                type_ == GlslType::$glsl
+            }
         }
+    };
+}
+
+uniform_matrix!(Matrix3, UniformMatrix3fv, Mat3);
+uniform_matrix!(Matrix4, UniformMatrix4fv, Mat4);
+
+impl UniformValue for bool {
+    fn set_uniform(&self, uniform: Uniform) {
+        (*self as i32).set_uniform(uniform);
+    }
+
+    fn accepts(type_: GlslType) -> bool {
+        type_ == GlslType::Bool
     }
 }
 
-impl UniformValue for Vector2 {
+impl UniformValue for &[bool] {
     fn set_uniform(&self, uniform: Uniform) {
-        unsafe {
-            gl::Uniform2f(uniform.location, self.x, self.y);
-        }
+        let values: Vec<i32> = self.iter().map(|&b| b as i32).collect();
+        (&values[..]).set_uniform(uniform);
+    }
+
+    fn accepts(type_: GlslType) -> bool {
+        type_ == GlslType::Bool
     }
 }
 
-impl UniformValue for Vector3 {
+// As a vec4 with the scalar part in w, the usual layout in shaders.
+impl UniformValue for Quaternion {
     fn set_uniform(&self, uniform: Uniform) {
-        unsafe {
-            gl::Uniform3f(uniform.location, self.x, self.y, self.z);
-        }
+        Vector4::new(self.x, self.y, self.z, self.s).set_uniform(uniform);
+    }
+
+    fn accepts(type_: GlslType) -> bool {
+        type_ == GlslType::Vec4
     }
 }
 
-impl UniformValue for &Matrix4 {
+impl UniformValue for &[Quaternion] {
     fn set_uniform(&self, uniform: Uniform) {
-        unsafe {
-            gl::UniformMatrix4fv(uniform.location, 1, gl::FALSE, self.as_ptr() as *const GLfloat);
-        }
+        let values: Vec<Vector4> = self.iter().map(|q| Vector4::new(q.x, q.y, q.z, q.s)).collect();
+        (&values[..]).set_uniform(uniform);
+    }
+
+    fn accepts(type_: GlslType) -> bool {
+        type_ == GlslType::Vec4
+    }
+}
+
+// Arrays, as slices.
+impl<'a, T, const N: usize> UniformValue for &'a [T; N]
+where
+    &'a [T]: UniformValue,
+{
+    fn set_uniform(&self, uniform: Uniform) {
+        let slice: &'a [T] = &self[..];
+        slice.set_uniform(uniform);
+    }
+
+    fn accepts(type_: GlslType) -> bool {
+        <&'a [T]>::accepts(type_)
     }
 }
 
@@ -171,6 +782,10 @@ impl UniformValue for TextureUnit {
             gl::Uniform1i(uniform.location, self.0 as i32);
         }
     }
+
+    fn accepts(type_: GlslType) -> bool {
+        type_.is_sampler()
+    }
 }
 
 
@@ -180,15 +795,60 @@ pub enum Attr {
     Color,
     TextureCoords,
     Normal,
+    // Per instance attributes. The transform is a mat4, which takes up four
+    // locations, one per column.
+    InstanceTransform,
+    InstanceColor,
+    InstanceData,
+    // Skinning: the indices of up to four joints and their weights.
+    Joints,
+    Weights,
+    // Deltas of morph targets bound to slots 0 to 3.
+    Morph(u32),
 }
 
 impl Attr {
+    pub const ALL: [Attr; 13] = [
+        Attr::Position,
+        Attr::TextureCoords,
+        Attr::Normal,
+        Attr::Color,
+        Attr::InstanceTransform,
+        Attr::InstanceColor,
+        Attr::InstanceData,
+        Attr::Joints,
+        Attr::Weights,
+        Attr::Morph(0),
+        Attr::Morph(1),
+        Attr::Morph(2),
+        Attr::Morph(3),
+    ];
+
     pub fn name(self) -> &'static str {
         match self {
             Attr::Position => "a_position",
-            Attr::TextureCoords => "a_texture_coords",
+            Attr::TextureCoords => "a_tex_coords",
             Attr::Normal => "a_normal",
             Attr::Color => "a_color",
+            Attr::InstanceTransform => "a_instance_transform",
+            Attr::InstanceColor => "a_instance_color",
+            Attr::InstanceData => "a_instance_data",
+            Attr::Joints => "a_joints",
+            Attr::Weights => "a_weights",
+            Attr::Morph(slot) => ["a_morph0", "a_morph1", "a_morph2", "a_morph3"][slot as usize],
+        }
+    }
+
+    // Types a shader may declare the attribute with.
+    fn accepts(self, type_: GlslType) -> bool {
+        use GlslType::*;
+        match self {
+            Attr::Position => matches!(type_, Vec2 | Vec3 | Vec4),
+            Attr::Color => matches!(type_, Vec3 | Vec4),
+            Attr::TextureCoords => type_ == Vec2,
+            Attr::Normal | Attr::Morph(_) => type_ == Vec3,
+            Attr::InstanceTransform => type_ == Mat4,
+            Attr::InstanceColor | Attr::InstanceData | Attr::Joints | Attr::Weights => type_ == Vec4,
         }
     }
 
@@ -198,6 +858,15 @@ impl Attr {
             Attr::TextureCoords => 1,
             Attr::Normal => 2,
             Attr::Color => 3,
+            Attr::InstanceTransform => 4,
+            Attr::InstanceColor => 8,
+            Attr::InstanceData => 9,
+            Attr::Joints => 10,
+            Attr::Weights => 11,
+            Attr::Morph(slot) => {
+                assert!(slot < 4);
+                12 + slot
+            }
         }
     }
 }
@@ -217,6 +886,18 @@ pub enum Type {
     U2_10_10_10Rev = gl::UNSIGNED_INT_2_10_10_10_REV,
 }
 
+impl Type {
+    // Size of one component. The packed types are a whole vector.
+    pub fn size(&self) -> usize {
+        match self {
+            Type::F32 | Type::I32 | Type::U32 | Type::Fixed => 4,
+            Type::F16 | Type::I16 | Type::U16 => 2,
+            Type::I8 | Type::U8 => 1,
+            Type::I2_10_10_10Rev | Type::U2_10_10_10Rev => 4,
+        }
+    }
+}
+
 pub struct PointerConfig {
     pub type_: Type,
     pub size: usize,
@@ -241,222 +922,1591 @@ impl PointerConfig {
             offset: 0,
         }
     }
-}
-
-#[derive(Debug)]
-pub struct VertexArray {
-    pub id: u32,
-    pub index_buffer_id: u32,
-}
-
-impl VertexArray {
-    pub fn new() -> Self {
-        let mut id: u32 = 0;
-        let mut index_buffer_id: u32 = 0;
-        unsafe {
-            gl::GenVertexArrays(1, &mut id as *mut GLuint);
-            gl::BindVertexArray(id);
-            gl::GenBuffers(1, &mut index_buffer_id as *mut GLuint);
+    pub fn vector4() -> Self {
+        Self {
+            type_: Type::F32,
+            size: 4,
+            stride: 4 * size_of::<f32>() as isize,
+            offset: 0,
         }
+    }
+    // Column major, one column per attribute location.
+    pub fn matrix4() -> Self {
         Self {
-            id,
-            index_buffer_id,
+            type_: Type::F32,
+            size: 16,
+            stride: 16 * size_of::<f32>() as isize,
+            offset: 0,
         }
     }
+}
 
-    pub fn setup_attribute(&self, attr: Attr, buffer_id: u32, config: PointerConfig) {
-        unsafe {
-            gl::BindVertexArray(self.id);
-            gl::BindBuffer(gl::ARRAY_BUFFER, buffer_id);
-            let attr_loc = attr.location();
-            gl::VertexAttribPointer(
-                attr_loc,
-                config.size as GLint,
-                config.type_ as GLenum,
-                gl::FALSE, // normalized
-                config.stride as i32,
-                config.offset as *const GLvoid,
-            );
-            gl::EnableVertexAttribArray(attr_loc);
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Primitive {
+    Points = gl::POINTS,
+    Lines = gl::LINES,
+    LineStrip = gl::LINE_STRIP,
+    LineLoop = gl::LINE_LOOP,
+    Triangles = gl::TRIANGLES,
+    TriangleStrip = gl::TRIANGLE_STRIP,
+    TriangleFan = gl::TRIANGLE_FAN,
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IndexType {
+    U8 = gl::UNSIGNED_BYTE,
+    U16 = gl::UNSIGNED_SHORT,
+    U32 = gl::UNSIGNED_INT,
+}
+
+impl IndexType {
+    pub fn size(self) -> usize {
+        match self {
+            IndexType::U8 => 1,
+            IndexType::U16 => 2,
+            IndexType::U32 => 4,
         }
     }
 
-    pub fn draw(&self, n_indices: usize, offset: isize) {
-        unsafe {
-            gl::BindVertexArray(self.id);
-            gl::DrawElements(
-                gl::TRIANGLES,
-                n_indices as i32,
-                gl::UNSIGNED_INT,
-                offset as *const GLvoid,
-            );
+    // Smallest type that can index that many vertices.
+    pub fn for_vertex_count(n: usize) -> IndexType {
+        if n <= 1 << 8 {
+            IndexType::U8
+        } else if n <= 1 << 16 {
+            IndexType::U16
+        } else {
+            IndexType::U32
         }
     }
 }
 
-pub fn gen_buffer() -> u32 {
-    let mut id: u32 = 0;
-    unsafe {
-        gl::GenBuffers(1, &mut id as *mut GLuint);
-    }
-    // TODO: Check failure
-    id
+pub trait IndexValue: Copy {
+    const INDEX_TYPE: IndexType;
 }
 
-pub fn load_index_buffer_data<T>(buffer_id: u32, data: &[T]) {
-    load_buffer_data_impl(gl::ELEMENT_ARRAY_BUFFER, buffer_id, data);
+impl IndexValue for u8 {
+    const INDEX_TYPE: IndexType = IndexType::U8;
 }
 
-pub fn load_buffer_data<T>(buffer_id: u32, data: &[T]) {
-    load_buffer_data_impl(gl::ARRAY_BUFFER, buffer_id, data);
+impl IndexValue for u16 {
+    const INDEX_TYPE: IndexType = IndexType::U16;
 }
 
-fn load_buffer_data_impl<T>(kind: GLenum, buffer_id: u32, data: &[T]) {
-    unsafe {
-        gl::BindBuffer(kind, buffer_id);
-        gl::BufferData(
-            kind,
-            size_of_val(data) as isize,
-            data.as_ptr() as *const GLvoid,
-            gl::STATIC_DRAW,
-        );
-    }
+impl IndexValue for u32 {
+    const INDEX_TYPE: IndexType = IndexType::U32;
 }
 
-#[derive(Copy, Clone)]
-pub struct TextureUnit(pub u32);
+#[derive(Debug)]
+pub struct Buffer {
+    id: u32,
+}
 
-impl TextureUnit {
-    pub fn bind_texture(self, texture: Texture) {
-        let TextureUnit(slot) = self;
-        assert!(slot < 16);
+impl Buffer {
+    pub fn new() -> Buffer {
+        let mut id: u32 = 0;
         unsafe {
-            gl::ActiveTexture(gl::TEXTURE0 + slot as GLenum);
-            gl::BindTexture(gl::TEXTURE_2D, texture.id);
+            gl::GenBuffers(1, &mut id as *mut GLuint);
         }
+        track(ObjectKind::Buffer, id);
+        Buffer { id }
     }
-}
 
-
-#[derive(Copy, Clone, Debug, Default)]
-pub struct Texture {
-    id: u32,
+    pub fn id(&self) -> u32 {
+        self.id
+    }
 }
 
-impl Texture {
-    pub const NONE: Texture = Texture { id: 0 };
+impl Default for Buffer {
+    fn default() -> Buffer {
+        Buffer::new()
+    }
+}
 
-    pub fn new() -> Texture {
-        let mut id: u32 = 0;
+impl Drop for Buffer {
+    fn drop(&mut self) {
         unsafe {
-            gl::GenTextures(1, &mut id as *mut GLuint);
+            gl::DeleteBuffers(1, &self.id);
         }
-        let mut texture = Texture { id };
-        texture.set_min_filter_mode(TextureMinFilterMode::Linear);
-        texture.set_mag_filter_mode(TextureMagFilterMode::Linear);
-        texture.set_s_wrap_mode(TextureWrapMode::ClampToEdge);
-        texture.set_t_wrap_mode(TextureWrapMode::ClampToEdge);
-        texture
+        untrack(ObjectKind::Buffer, self.id);
     }
+}
 
+#[derive(Debug)]
+pub struct VertexArray {
+    id: u32,
+    index_buffer: Buffer,
+    // Type of the indices in the index buffer, set by load_indices.
+    index_type: IndexType,
+}
+
+impl VertexArray {
+    pub fn new() -> Self {
+        let mut id: u32 = 0;
+        unsafe {
+            gl::GenVertexArrays(1, &mut id as *mut GLuint);
+            gl::BindVertexArray(id);
+        }
+        track(ObjectKind::VertexArray, id);
+        Self {
+            id,
+            index_buffer: Buffer::new(),
+            index_type: IndexType::U32,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn index_type(&self) -> IndexType {
+        self.index_type
+    }
+
+    pub fn load_indices<T: IndexValue>(&mut self, data: &[T]) {
+        unsafe {
+            gl::BindVertexArray(self.id);
+        }
+        load_index_buffer_data(&self.index_buffer, data);
+        self.index_type = T::INDEX_TYPE;
+    }
+
+    pub fn setup_attribute(&self, attr: Attr, buffer: &Buffer, config: PointerConfig) {
+        self.setup_instance_attribute(attr, buffer, config, 0);
+    }
+
+    // Disabled attributes read as (0, 0, 0, 1) in the shader.
+    pub fn disable_attribute(&self, attr: Attr) {
+        unsafe {
+            gl::BindVertexArray(self.id);
+            gl::DisableVertexAttribArray(attr.location());
+        }
+    }
+
+    // Attributes with a divisor of n advance once every n instances instead
+    // of once per vertex. Matrices (sizes over 4) take one location per column.
+    pub fn setup_instance_attribute(&self, attr: Attr, buffer: &Buffer, config: PointerConfig, divisor: u32) {
+        let columns = config.size.div_ceil(4);
+        let column_size = config.size.min(4);
+        let column_bytes = column_size * config.type_.size();
+        let type_ = config.type_ as GLenum;
+        unsafe {
+            gl::BindVertexArray(self.id);
+            gl::BindBuffer(gl::ARRAY_BUFFER, buffer.id);
+            for column in 0..columns {
+                let attr_loc = attr.location() + column as GLuint;
+                let offset = config.offset + (column * column_bytes) as isize;
+                gl::VertexAttribPointer(
+                    attr_loc,
+                    column_size as GLint,
+                    type_,
+                    gl::FALSE, // normalized
+                    config.stride as i32,
+                    offset as *const GLvoid,
+                );
+                gl::EnableVertexAttribArray(attr_loc);
+                gl::VertexAttribDivisor(attr_loc, divisor);
+            }
+        }
+    }
+
+    // Indexed triangles. Offset is in bytes into the index buffer.
+    pub fn draw(&self, n_indices: usize, offset: isize) {
+        self.draw_elements(Primitive::Triangles, n_indices, offset);
+    }
+
+    pub fn draw_instanced(&self, n_indices: usize, offset: isize, n_instances: usize) {
+        self.draw_elements_instanced(Primitive::Triangles, n_indices, offset, n_instances);
+    }
+
+    pub fn draw_elements(&self, primitive: Primitive, n_indices: usize, offset: isize) {
+        unsafe {
+            gl::BindVertexArray(self.id);
+            gl::DrawElements(
+                primitive as GLenum,
+                n_indices as i32,
+                self.index_type as GLenum,
+                offset as *const GLvoid,
+            );
+        }
+    }
+
+    pub fn draw_elements_instanced(&self, primitive: Primitive, n_indices: usize, offset: isize, n_instances: usize) {
+        unsafe {
+            gl::BindVertexArray(self.id);
+            gl::DrawElementsInstanced(
+                primitive as GLenum,
+                n_indices as i32,
+                self.index_type as GLenum,
+                offset as *const GLvoid,
+                n_instances as i32,
+            );
+        }
+    }
+
+    // Without indices, vertices first..first + n_vertices in order.
+    pub fn draw_arrays(&self, primitive: Primitive, first: usize, n_vertices: usize) {
+        unsafe {
+            gl::BindVertexArray(self.id);
+            gl::DrawArrays(primitive as GLenum, first as i32, n_vertices as i32);
+        }
+    }
+
+    pub fn draw_arrays_instanced(&self, primitive: Primitive, first: usize, n_vertices: usize, n_instances: usize) {
+        unsafe {
+            gl::BindVertexArray(self.id);
+            gl::DrawArraysInstanced(primitive as GLenum, first as i32, n_vertices as i32, n_instances as i32);
+        }
+    }
+}
+
+impl Drop for VertexArray {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteVertexArrays(1, &self.id);
+        }
+        untrack(ObjectKind::VertexArray, self.id);
+    }
+}
+
+pub fn load_index_buffer_data<T>(buffer: &Buffer, data: &[T]) {
+    load_buffer_data_impl(gl::ELEMENT_ARRAY_BUFFER, buffer.id, data, gl::STATIC_DRAW);
+}
+
+pub fn load_buffer_data<T>(buffer: &Buffer, data: &[T]) {
+    load_buffer_data_impl(gl::ARRAY_BUFFER, buffer.id, data, gl::STATIC_DRAW);
+}
+
+// For buffers that are rewritten often, like per instance data.
+pub fn load_dynamic_buffer_data<T>(buffer: &Buffer, data: &[T]) {
+    load_buffer_data_impl(gl::ARRAY_BUFFER, buffer.id, data, gl::DYNAMIC_DRAW);
+}
+
+fn load_buffer_data_impl<T>(kind: GLenum, buffer_id: u32, data: &[T], usage: GLenum) {
+    unsafe {
+        gl::BindBuffer(kind, buffer_id);
+        gl::BufferData(
+            kind,
+            size_of_val(data) as isize,
+            data.as_ptr() as *const GLvoid,
+            usage,
+        );
+    }
+}
+
+// Uniform buffers hold the data of uniform blocks, shared by every program
+// that binds the block to the buffer's binding point. The data is written
+// with the std140 layout, so blocks must be declared layout(std140).
+//
+// Structs are declared with std140_struct!, which also lists their members
+// so that Program::bind_uniform_block can check them against the block.
+//
+//     std140_struct! {
+//         pub struct Frame {
+//             pub view_projection: Matrix4,
+//             pub light_positions: [Vector3; 4],
+//             pub time: f32,
+//         }
+//     }
+pub trait Std140 {
+    // Base alignment and size in bytes, by the std140 rules.
+    fn std140_align() -> usize;
+    fn std140_size() -> usize;
+    // None for structs.
+    fn glsl_type() -> Option<GlslType>;
+    // Writes the value at the writer's position, which is aligned.
+    fn write_std140(&self, writer: &mut Std140Writer);
+
+    // Adds the members that are not structs or arrays of structs, named as
+    // the driver reports them, at offset.
+    fn std140_fields(name: &str, offset: usize, fields: &mut Vec<Std140Field>) {
+        if let Some(type_) = Self::glsl_type() {
+            fields.push(Std140Field { name: name.to_string(), offset, type_ });
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Std140Field {
+    pub name: String,
+    pub offset: usize,
+    pub type_: GlslType,
+}
+
+#[derive(Debug, Default)]
+pub struct Std140Writer {
+    bytes: Vec<u8>,
+}
+
+impl Std140Writer {
+    pub fn new() -> Std140Writer {
+        Std140Writer::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn write<T: Std140>(&mut self, value: &T) {
+        self.pad_to(self.len().next_multiple_of(T::std140_align()));
+        value.write_std140(self);
+    }
+
+    pub fn pad_to(&mut self, len: usize) {
+        assert!(len >= self.bytes.len());
+        self.bytes.resize(len, 0);
+    }
+
+    pub fn put_f32(&mut self, value: f32) {
+        self.bytes.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    pub fn put_i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    pub fn put_f32s(&mut self, values: &[f32]) {
+        values.iter().for_each(|&v| self.put_f32(v));
+    }
+
+    pub fn put_i32s(&mut self, values: &[i32]) {
+        values.iter().for_each(|&v| self.put_i32(v));
+    }
+}
+
+macro_rules! std140_value {
+    ($t:ty, $align:expr, $size:expr, $glsl:ident, |$value:ident, $writer:ident| $write:expr) => {
+        impl Std140 for $t {
+            fn std140_align() -> usize {
+                $align
+            }
+
+            fn std140_size() -> usize {
+                $size
+            }
+
+            fn glsl_type() -> Option<GlslType> {
+                Some(GlslType::$glsl)
+            }
+
+            fn write_std140(&self, $writer: &mut Std140Writer) {
+                let $value = self;
+                $write
+            }
+        }
+    };
+}
+
+std140_value!(f32, 4, 4, Float, |v, w| w.put_f32(*v));
+std140_value!(i32, 4, 4, Int, |v, w| w.put_i32(*v));
+std140_value!(u32, 4, 4, UInt, |v, w| w.put_u32(*v));
+std140_value!(bool, 4, 4, Bool, |v, w| w.put_u32(*v as u32));
+std140_value!(Vector2, 8, 8, Vec2, |v, w| w.put_f32s(&[v.x, v.y]));
+std140_value!(Vector2I, 8, 8, IVec2, |v, w| w.put_i32s(&[v.x, v.y]));
+std140_value!(Vector3, 16, 12, Vec3, |v, w| w.put_f32s(&[v.x, v.y, v.z]));
+std140_value!(Vector3I, 16, 12, IVec3, |v, w| w.put_i32s(&[v.x, v.y, v.z]));
+std140_value!(Color3, 16, 12, Vec3, |v, w| w.put_f32s(&[v.r, v.g, v.b]));
+std140_value!(Vector4, 16, 16, Vec4, |v, w| w.put_f32s(&[v.x, v.y, v.z, v.w]));
+std140_value!(Color4, 16, 16, Vec4, |v, w| w.put_f32s(&[v.r, v.g, v.b, v.a]));
+// As for uniforms, the scalar part goes in w.
+std140_value!(Quaternion, 16, 16, Vec4, |v, w| w.put_f32s(&[v.x, v.y, v.z, v.s]));
+// Matrices are arrays of column vectors, each padded to 16 bytes.
+std140_value!(Matrix3, 16, 48, Mat3, |m, w| {
+    for column in m.coords.iter() {
+        w.put_f32s(column);
+        w.put_f32(0.);
+    }
+});
+std140_value!(Matrix4, 16, 64, Mat4, |m, w| w.put_f32s(m.coords.as_flattened()));
+
+// Array elements are aligned to 16 bytes. Arrays of basic types are one
+// member, arrays of structs one per element.
+impl<T: Std140, const N: usize> Std140 for [T; N] {
+    fn std140_align() -> usize {
+        T::std140_align().next_multiple_of(16)
+    }
+
+    fn std140_size() -> usize {
+        N * T::std140_size().next_multiple_of(Self::std140_align())
+    }
+
+    fn glsl_type() -> Option<GlslType> {
+        T::glsl_type()
+    }
+
+    fn write_std140(&self, writer: &mut Std140Writer) {
+        let start = writer.len();
+        let stride = T::std140_size().next_multiple_of(Self::std140_align());
+        for (i, element) in self.iter().enumerate() {
+            writer.pad_to(start + i * stride);
+            element.write_std140(writer);
+        }
+        writer.pad_to(start + N * stride);
+    }
+
+    fn std140_fields(name: &str, offset: usize, fields: &mut Vec<Std140Field>) {
+        if let Some(type_) = T::glsl_type() {
+            fields.push(Std140Field { name: name.to_string(), offset, type_ });
+            return;
+        }
+        let stride = T::std140_size().next_multiple_of(Self::std140_align());
+        for i in 0..N {
+            T::std140_fields(&format!("{}[{}]", name, i), offset + i * stride, fields);
+        }
+    }
+}
+
+// Declares a struct and implements Std140 for it. Structs are aligned to 16
+// bytes, and so is their size.
+#[macro_export]
+macro_rules! std140_struct {
+    ($(#[$attr:meta])* $vis:vis struct $name:ident { $($field_vis:vis $field:ident: $t:ty),* $(,)? }) => {
+        $(#[$attr])*
+        $vis struct $name {
+            $($field_vis $field: $t),*
+        }
+
+        impl $crate::gpu::Std140 for $name {
+            fn std140_align() -> usize {
+                let mut align: usize = 16;
+                $(align = align.max(<$t as $crate::gpu::Std140>::std140_align());)*
+                align
+            }
+
+            fn std140_size() -> usize {
+                let mut size: usize = 0;
+                $(size = size.next_multiple_of(<$t as $crate::gpu::Std140>::std140_align())
+                    + <$t as $crate::gpu::Std140>::std140_size();)*
+                size.next_multiple_of(<Self as $crate::gpu::Std140>::std140_align())
+            }
+
+            fn glsl_type() -> Option<$crate::gpu::GlslType> {
+                None
+            }
+
+            fn write_std140(&self, writer: &mut $crate::gpu::Std140Writer) {
+                let start = writer.len();
+                $(
+                    let offset = (writer.len() - start).next_multiple_of(<$t as $crate::gpu::Std140>::std140_align());
+                    writer.pad_to(start + offset);
+                    $crate::gpu::Std140::write_std140(&self.$field, writer);
+                )*
+                writer.pad_to(start + <Self as $crate::gpu::Std140>::std140_size());
+            }
+
+            fn std140_fields(name: &str, offset: usize, fields: &mut Vec<$crate::gpu::Std140Field>) {
+                let mut field_offset: usize = 0;
+                $(
+                    field_offset = field_offset.next_multiple_of(<$t as $crate::gpu::Std140>::std140_align());
+                    let field_name = match name {
+                        "" => stringify!($field).to_string(),
+                        _ => format!("{}.{}", name, stringify!($field)),
+                    };
+                    <$t as $crate::gpu::Std140>::std140_fields(&field_name, offset + field_offset, fields);
+                    field_offset += <$t as $crate::gpu::Std140>::std140_size();
+                )*
+                let _ = field_offset;
+            }
+        }
+    };
+}
+
+// The members of a struct, as checked against a block.
+pub fn std140_fields<T: Std140>() -> Vec<Std140Field> {
+    let mut fields = Vec::new();
+    T::std140_fields("", 0, &mut fields);
+    fields
+}
+
+pub fn std140_bytes<T: Std140>(value: &T) -> Vec<u8> {
+    let mut writer = Std140Writer::new();
+    writer.write(value);
+    writer.bytes
+}
+
+// A uniform buffer bound to a binding point. Binding points are global, so
+// each buffer should have its own, below max_uniform_buffer_bindings.
+#[derive(Debug)]
+pub struct UniformBuffer<T: Std140> {
+    buffer: Buffer,
+    binding: u32,
+    _value: PhantomData<T>,
+}
+
+impl<T: Std140> UniformBuffer<T> {
+    pub fn new(binding: u32, value: &T) -> UniformBuffer<T> {
+        assert!(binding < max_uniform_buffer_bindings(), "binding point {} is out of range", binding);
+        let buffer = UniformBuffer { buffer: Buffer::new(), binding, _value: PhantomData };
+        buffer.update(value);
+        buffer
+    }
+
+    pub fn binding(&self) -> u32 {
+        self.binding
+    }
+
+    // Rewrites the whole buffer, and binds it again.
+    pub fn update(&self, value: &T) {
+        let bytes = std140_bytes(value);
+        load_buffer_data_impl(gl::UNIFORM_BUFFER, self.buffer.id, &bytes, gl::DYNAMIC_DRAW);
+        self.bind();
+    }
+
+    // Binds the buffer to its binding point, in case another buffer was
+    // bound there.
+    pub fn bind(&self) {
+        unsafe {
+            gl::BindBufferBase(gl::UNIFORM_BUFFER, self.binding, self.buffer.id);
+        }
+    }
+}
+
+pub fn max_uniform_buffer_bindings() -> u32 {
+    let mut value: i32 = 0;
+    unsafe {
+        gl::GetIntegerv(gl::MAX_UNIFORM_BUFFER_BINDINGS, &mut value);
+    }
+    value as u32
+}
+
+#[derive(Copy, Clone)]
+pub struct TextureUnit(pub u32);
+
+impl TextureUnit {
+    pub fn bind_texture(self, texture: TextureHandle) {
+        let TextureUnit(slot) = self;
+        assert!(slot < 16);
+        unsafe {
+            gl::ActiveTexture(gl::TEXTURE0 + slot as GLenum);
+            gl::BindTexture(texture.target as GLenum, texture.id);
+        }
+    }
+
+    // With None, textures on the unit use their own sampling state again.
+    pub fn bind_sampler(self, sampler: Option<&Sampler>) {
+        let TextureUnit(slot) = self;
+        assert!(slot < 16);
+        unsafe {
+            gl::BindSampler(slot, sampler.map_or(0, |s| s.id));
+        }
+    }
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum TextureTarget {
+    #[default]
+    Texture2D = gl::TEXTURE_2D,
+    CubeMap = gl::TEXTURE_CUBE_MAP,
+    // Layers of the same size, sampled with a layer index, as for sprite
+    // sheets. Not filtered across layers.
+    Texture2DArray = gl::TEXTURE_2D_ARRAY,
+    Texture3D = gl::TEXTURE_3D,
+}
+
+// In GL's order. Looking at a face from the inside of the cube, s goes
+// right and t down, as in the GL spec's table of cube map faces.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CubeFace {
+    PositiveX = gl::TEXTURE_CUBE_MAP_POSITIVE_X,
+    NegativeX = gl::TEXTURE_CUBE_MAP_NEGATIVE_X,
+    PositiveY = gl::TEXTURE_CUBE_MAP_POSITIVE_Y,
+    NegativeY = gl::TEXTURE_CUBE_MAP_NEGATIVE_Y,
+    PositiveZ = gl::TEXTURE_CUBE_MAP_POSITIVE_Z,
+    NegativeZ = gl::TEXTURE_CUBE_MAP_NEGATIVE_Z,
+}
+
+impl CubeFace {
+    pub const ALL: [CubeFace; 6] = [
+        CubeFace::PositiveX,
+        CubeFace::NegativeX,
+        CubeFace::PositiveY,
+        CubeFace::NegativeY,
+        CubeFace::PositiveZ,
+        CubeFace::NegativeZ,
+    ];
+
+    // The direction through a point of the face, with s and t from -1 to 1.
+    pub fn direction(self, s: f32, t: f32) -> Vector3 {
+        match self {
+            CubeFace::PositiveX => Vector3::new(1., -t, -s),
+            CubeFace::NegativeX => Vector3::new(-1., -t, s),
+            CubeFace::PositiveY => Vector3::new(s, 1., t),
+            CubeFace::NegativeY => Vector3::new(s, -1., -t),
+            CubeFace::PositiveZ => Vector3::new(s, -t, 1.),
+            CubeFace::NegativeZ => Vector3::new(-s, -t, -1.),
+        }
+    }
+}
+
+// Refers to a texture without owning it, for binding. It borrows the
+// Texture it came from, so it can't outlive it.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TextureHandle<'a> {
+    id: u32,
+    target: TextureTarget,
+    texture: PhantomData<&'a Texture>,
+}
+
+impl<'a> TextureHandle<'a> {
+    pub const NONE: TextureHandle<'a> = TextureHandle { id: 0, target: TextureTarget::Texture2D, texture: PhantomData };
+
+    pub fn id(self) -> u32 {
+        self.id
+    }
+
+    pub fn target(self) -> TextureTarget {
+        self.target
+    }
+}
+
+// An id of 0 is no texture, and is not deleted.
+#[derive(Debug, Default)]
+pub struct Texture {
+    id: u32,
+    target: TextureTarget,
+    // Set by allocate and load_data. Depth is the number of layers of
+    // arrays, and 1 for other 2D textures.
+    format: Option<TextureFormat>,
+    width: i32,
+    height: i32,
+    depth: i32,
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        if self.id != 0 {
+            unsafe {
+                gl::DeleteTextures(1, &self.id);
+            }
+            untrack(ObjectKind::Texture, self.id);
+        }
+    }
+}
+
+impl Texture {
+    pub fn new() -> Texture {
+        Texture::with_target(TextureTarget::Texture2D)
+    }
+
+    pub fn with_target(target: TextureTarget) -> Texture {
+        let mut id: u32 = 0;
+        unsafe {
+            gl::GenTextures(1, &mut id as *mut GLuint);
+        }
+        track(ObjectKind::Texture, id);
+        let mut texture = Texture { id, target, ..Default::default() };
+        texture.set_min_filter_mode(TextureMinFilterMode::Linear);
+        texture.set_mag_filter_mode(TextureMagFilterMode::Linear);
+        texture.set_s_wrap_mode(TextureWrapMode::ClampToEdge);
+        texture.set_t_wrap_mode(TextureWrapMode::ClampToEdge);
+        if target != TextureTarget::Texture2D {
+            texture.set_r_wrap_mode(TextureWrapMode::ClampToEdge);
+        }
+        texture
+    }
+
+    // Faces are in CubeFace order and square, with rows from t = -1.
+    pub fn cube_map_from_faces<T: PixelData>(size: i32, format: TextureFormat, faces: [&[T]; 6]) -> Result<Texture, String> {
+        let mut texture = Texture::with_target(TextureTarget::CubeMap);
+        texture.allocate(size, size, format);
+        for (face, data) in CubeFace::ALL.iter().zip(faces.iter()) {
+            texture.load_face_data(*face, data)?;
+        }
+        Ok(texture)
+    }
+
+    // From a panorama, see equirect_to_cube_faces.
+    pub fn cube_map_from_equirect(width: i32, height: i32, data: &[[u8; 4]], face_size: i32, srgb: bool) -> Result<Texture, String> {
+        let faces = equirect_to_cube_faces(width, height, data, face_size, lerp_rgba8);
+        let format = if srgb { TextureFormat::Srgb8Alpha8 } else { TextureFormat::Rgba8 };
+        let [f0, f1, f2, f3, f4, f5] = &faces;
+        Texture::cube_map_from_faces(face_size, format, [&f0[..], &f1[..], &f2[..], &f3[..], &f4[..], &f5[..]])
+    }
+
+    pub fn handle(&self) -> TextureHandle<'_> {
+        TextureHandle { id: self.id, target: self.target, texture: PhantomData }
+    }
+
+    pub fn target(&self) -> TextureTarget {
+        self.target
+    }
+
+    pub fn format(&self) -> Option<TextureFormat> {
+        self.format
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn depth(&self) -> i32 {
+        self.depth
+    }
+
+    // For 2D textures and cube maps, whose faces are all allocated.
+    // Formats that cannot be filtered get nearest filtering.
     pub fn allocate(&mut self, width: i32, height: i32, format: TextureFormat) {
+        assert!(matches!(self.target, TextureTarget::Texture2D | TextureTarget::CubeMap), "allocate_3d for {:?}", self.target);
+        assert!(self.target != TextureTarget::CubeMap || width == height, "cube map faces must be square");
+        self.image(width, height, 1, format, ptr::null());
+    }
+
+    // For arrays, with depth layers, and 3D textures.
+    pub fn allocate_3d(&mut self, width: i32, height: i32, depth: i32, format: TextureFormat) {
+        assert!(matches!(self.target, TextureTarget::Texture2DArray | TextureTarget::Texture3D), "allocate for {:?}", self.target);
+        self.image(width, height, depth, format, ptr::null());
+    }
+
+    // Fails when the data does not have the format's component type, or is
+    // too short. For 2D textures.
+    pub fn load_data<T: PixelData>(&mut self, width: i32, height: i32, format: TextureFormat, data: &[T]) -> Result<(), String> {
+        self.check_target(TextureTarget::Texture2D)?;
+        format.check_data(width, height, data)?;
+        self.image(width, height, 1, format, data.as_ptr() as *const GLvoid);
+        Ok(())
+    }
+
+    // For arrays and 3D textures, layer after layer.
+    pub fn load_data_3d<T: PixelData>(&mut self, width: i32, height: i32, depth: i32, format: TextureFormat, data: &[T]) -> Result<(), String> {
+        if !matches!(self.target, TextureTarget::Texture2DArray | TextureTarget::Texture3D) {
+            return Err(format!("load_data_3d on a {:?} texture", self.target));
+        }
+        format.check_data(width, height * depth, data)?;
+        self.image(width, height, depth, format, data.as_ptr() as *const GLvoid);
+        Ok(())
+    }
+
+    fn image(&mut self, width: i32, height: i32, depth: i32, format: TextureFormat, data: *const GLvoid) {
+        if !format.is_filterable() {
+            self.set_min_filter_mode(TextureMinFilterMode::Nearest);
+            self.set_mag_filter_mode(TextureMagFilterMode::Nearest);
+        }
+        let target = self.target as GLenum;
+        let (internal_format, pixel_format, pixel_type) = (format.internal_format() as i32, format.pixel_format(), format.pixel_type());
         unsafe {
-            gl::BindTexture(gl::TEXTURE_2D, self.id);
-            gl::TexImage2D(
+            gl::BindTexture(target, self.id);
+            match self.target {
+                TextureTarget::Texture2D => {
+                    gl::TexImage2D(target, 0, internal_format, width, height, 0, pixel_format, pixel_type, data);
+                }
+                TextureTarget::CubeMap => {
+                    for face in CubeFace::ALL {
+                        gl::TexImage2D(face as GLenum, 0, internal_format, width, height, 0, pixel_format, pixel_type, data);
+                    }
+                }
+                TextureTarget::Texture2DArray | TextureTarget::Texture3D => {
+                    gl::TexImage3D(target, 0, internal_format, width, height, depth, 0, pixel_format, pixel_type, data);
+                }
+            }
+        }
+        self.format = Some(format);
+        self.width = width;
+        self.height = height;
+        self.depth = depth;
+    }
+
+    fn check_target(&self, target: TextureTarget) -> Result<(), String> {
+        if self.target != target {
+            return Err(format!("{:?} operation on a {:?} texture", target, self.target));
+        }
+        Ok(())
+    }
+
+    // Data is in the texture's format, which allocate or load_data set. For
+    // 2D textures.
+    pub fn load_region_data<T: PixelData>(&mut self, region: RectangleI, data: &[T], texture_unit: TextureUnit) -> Result<(), String> {
+        self.check_target(TextureTarget::Texture2D)?;
+        let format = self.format.ok_or("texture is not allocated")?;
+        if region.min.x < 0 || region.min.y < 0 || region.max.x > self.width || region.max.y > self.height {
+            return Err(format!("region {:?} is outside of the {}x{} texture", region, self.width, self.height));
+        }
+        format.check_data(region.width(), region.height(), data)?;
+        unsafe {
+            // TO DO: Is texture_unit necessary? docs.gl notes say so...
+            texture_unit.bind_texture(self.handle());
+            gl::TexSubImage2D(
                 gl::TEXTURE_2D,
                 0,
-                format as i32,
-                width as GLsizei,
-                height as GLsizei,
+                region.min.x,
+                region.min.y,
+                region.width(),
+                region.height(),
+                format.pixel_format(),
+                format.pixel_type(),
+                data.as_ptr() as *const GLvoid
+            );
+        }
+        Ok(())
+    }
+
+    // A whole face of an allocated cube map.
+    pub fn load_face_data<T: PixelData>(&mut self, face: CubeFace, data: &[T]) -> Result<(), String> {
+        self.check_target(TextureTarget::CubeMap)?;
+        let format = self.format.ok_or("texture is not allocated")?;
+        format.check_data(self.width, self.height, data)?;
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_CUBE_MAP, self.id);
+            gl::TexSubImage2D(
+                face as GLenum,
+                0,
                 0,
-                format as u32,
-                gl::UNSIGNED_BYTE,
-                std::ptr::null() as *const GLvoid
+                0,
+                self.width,
+                self.height,
+                format.pixel_format(),
+                format.pixel_type(),
+                data.as_ptr() as *const GLvoid
             );
         }
+        Ok(())
     }
 
-    pub fn load_data<T>(&mut self, width: i32, height: i32, format: TextureFormat, data: &[T]) {
+    // A whole layer of an allocated array, or slice of a 3D texture.
+    pub fn load_layer_data<T: PixelData>(&mut self, layer: i32, data: &[T]) -> Result<(), String> {
+        if !matches!(self.target, TextureTarget::Texture2DArray | TextureTarget::Texture3D) {
+            return Err(format!("load_layer_data on a {:?} texture", self.target));
+        }
+        let format = self.format.ok_or("texture is not allocated")?;
+        if layer < 0 || layer >= self.depth {
+            return Err(format!("layer {} of a texture with {}", layer, self.depth));
+        }
+        format.check_data(self.width, self.height, data)?;
+        unsafe {
+            gl::BindTexture(self.target as GLenum, self.id);
+            gl::TexSubImage3D(
+                self.target as GLenum,
+                0,
+                0,
+                0,
+                layer,
+                self.width,
+                self.height,
+                1,
+                format.pixel_format(),
+                format.pixel_type(),
+                data.as_ptr() as *const GLvoid
+            );
+        }
+        Ok(())
+    }
+
+    // Also sets max_level to the last level. Arrays keep their layers, 3D
+    // textures are halved in depth too.
+    pub fn generate_mipmaps(&mut self) -> Result<(), String> {
+        let format = self.format.ok_or("texture is not allocated")?;
+        if !format.is_filterable() {
+            return Err(format!("cannot generate mipmaps for {:?} textures", format));
+        }
+        let depth = if self.target == TextureTarget::Texture3D { self.depth } else { 1 };
+        let levels = mip_level_count(self.width, self.height.max(depth));
+        self.set_level_range(0, levels - 1);
+        unsafe {
+            gl::BindTexture(self.target as GLenum, self.id);
+            gl::GenerateMipmap(self.target as GLenum);
+        }
+        Ok(())
+    }
+
+    // Uploads a precomputed level of a 2D texture, half the size of the
+    // previous one, rounding down, and at least 1. Levels after the last one
+    // uploaded should be left out with set_level_range.
+    pub fn load_level_data<T: PixelData>(&mut self, level: u32, data: &[T]) -> Result<(), String> {
+        self.check_target(TextureTarget::Texture2D)?;
+        let format = self.format.ok_or("texture is not allocated")?;
+        if level >= mip_level_count(self.width, self.height) {
+            return Err(format!("level {} of a {}x{} texture", level, self.width, self.height));
+        }
+        let (width, height) = mip_level_size(self.width, self.height, level);
+        format.check_data(width, height, data)?;
         unsafe {
             gl::BindTexture(gl::TEXTURE_2D, self.id);
             gl::TexImage2D(
                 gl::TEXTURE_2D,
+                level as GLint,
+                format.internal_format() as i32,
+                width,
+                height,
                 0,
-                format as i32,
-                width as GLsizei,
-                height as GLsizei,
-                0,
-                format as u32,
-                gl::UNSIGNED_BYTE,
+                format.pixel_format(),
+                format.pixel_type(),
                 data.as_ptr() as *const GLvoid
             );
         }
+        Ok(())
+    }
+
+    // An RGBA8 texture with every level downsampled on the CPU, see
+    // mip_chain_rgba8.
+    pub fn load_rgba8_mip_chain(&mut self, width: i32, height: i32, data: &[[u8; 4]], srgb: bool) -> Result<(), String> {
+        let format = if srgb { TextureFormat::Srgb8Alpha8 } else { TextureFormat::Rgba8 };
+        self.load_data(width, height, format, data)?;
+        let chain = mip_chain_rgba8(width, height, data, srgb);
+        for (level, data) in chain.iter().enumerate().skip(1) {
+            self.load_level_data(level as u32, data)?;
+        }
+        self.set_level_range(0, chain.len() as u32 - 1);
+        Ok(())
+    }
+
+    // A float texture with every level downsampled on the CPU, see
+    // mip_chain_f32.
+    pub fn load_float_mip_chain(&mut self, width: i32, height: i32, format: TextureFormat, data: &[f32]) -> Result<(), String> {
+        if format.component_type() != ComponentType::F32 {
+            return Err(format!("{:?} is not a float format", format));
+        }
+        self.load_data(width, height, format, data)?;
+        let chain = mip_chain_f32(width, height, format.components(), data);
+        for (level, data) in chain.iter().enumerate().skip(1) {
+            self.load_level_data(level as u32, data)?;
+        }
+        self.set_level_range(0, chain.len() as u32 - 1);
+        Ok(())
+    }
+
+    pub fn from_file(path: &str, options: ImageOptions) -> Result<Texture, String> {
+        let img = image::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        Texture::from_image(&img, options).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // 8 bit images keep their channels when linear. sRGB ones become
+    // Srgb8Alpha8, as GLES has no sRGB formats with fewer channels, and get
+    // their mipmaps on the CPU, since it cannot generate them for Srgb8. 16
+    // bit images become half floats, with alpha for RGB, and get their
+    // mipmaps on the CPU too, since GLES can only generate them for half
+    // floats with the color buffer float extensions. Mipmapped textures are
+    // filtered trilinearly.
+    pub fn from_image(img: &DynamicImage, options: ImageOptions) -> Result<Texture, String> {
+        let flipped;
+        let img = if options.flip_vertically {
+            flipped = img.flipv();
+            &flipped
+        } else {
+            img
+        };
+        let (width, height) = img.dimensions();
+        let (width, height) = (width as i32, height as i32);
+        let mut texture = Texture::new();
+        let (format, mut data) = match img {
+            DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_) | DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_) => {
+                let (format, data) = float_image_data(img, options);
+                if options.mipmaps {
+                    texture.load_float_mip_chain(width, height, format, &data)?;
+                    texture.set_min_filter_mode(TextureMinFilterMode::LinearMipmapLinear);
+                } else {
+                    texture.load_data(width, height, format, &data)?;
+                }
+                return Ok(texture);
+            }
+            _ if options.srgb => (TextureFormat::Srgb8Alpha8, img.to_rgba().into_raw()),
+            DynamicImage::ImageLuma8(_) => (TextureFormat::R8, img.to_luma().into_raw()),
+            DynamicImage::ImageLumaA8(_) => (TextureFormat::Rg8, img.to_luma_alpha().into_raw()),
+            DynamicImage::ImageRgb8(_) | DynamicImage::ImageBgr8(_) => (TextureFormat::Rgb8, img.to_rgb().into_raw()),
+            _ => (TextureFormat::Rgba8, img.to_rgba().into_raw()),
+        };
+        let components = format.components();
+        if options.premultiply_alpha && (components == 2 || components == 4) {
+            premultiply_alpha(&mut data, components, options.srgb);
+        }
+        if options.srgb && options.mipmaps {
+            let pixels: Vec<[u8; 4]> = data.chunks(4).map(|p| [p[0], p[1], p[2], p[3]]).collect();
+            texture.load_rgba8_mip_chain(width, height, &pixels, true)?;
+        } else {
+            texture.load_data(width, height, format, &data)?;
+            if options.mipmaps {
+                texture.generate_mipmaps()?;
+            }
+        }
+        if options.mipmaps {
+            texture.set_min_filter_mode(TextureMinFilterMode::LinearMipmapLinear);
+        }
+        Ok(texture)
+    }
+
+    // The levels that are sampled, of those that were loaded.
+    pub fn set_level_range(&mut self, base: u32, max: u32) {
+        self.parameter_i(gl::TEXTURE_BASE_LEVEL, base as i32);
+        self.parameter_i(gl::TEXTURE_MAX_LEVEL, max as i32);
+    }
+
+    sampling_parameters!();
+
+    fn parameter_i(&mut self, name: GLenum, value: i32) {
+        unsafe {
+            gl::BindTexture(self.target as GLenum, self.id);
+            gl::TexParameteri(self.target as GLenum, name, value);
+        }
+    }
+
+    fn parameter_f(&mut self, name: GLenum, value: f32) {
+        unsafe {
+            gl::BindTexture(self.target as GLenum, self.id);
+            gl::TexParameterf(self.target as GLenum, name, value);
+        }
+    }
+
+    fn parameter_fv(&mut self, name: GLenum, values: &[f32]) {
+        unsafe {
+            gl::BindTexture(self.target as GLenum, self.id);
+            gl::TexParameterfv(self.target as GLenum, name, values.as_ptr());
+        }
+    }
+}
+
+// How to turn an image into a texture. There is no default, since color
+// images are usually sRGB and data such as normal maps is linear.
+#[derive(Copy, Clone, Debug)]
+pub struct ImageOptions {
+    pub srgb: bool,
+    // Images start at the top row, and GL textures at the bottom one.
+    pub flip_vertically: bool,
+    // Done in linear space for sRGB images.
+    pub premultiply_alpha: bool,
+    pub mipmaps: bool,
+}
+
+impl ImageOptions {
+    pub fn color() -> ImageOptions {
+        ImageOptions { srgb: true, flip_vertically: true, premultiply_alpha: false, mipmaps: true }
+    }
+
+    pub fn linear() -> ImageOptions {
+        ImageOptions { srgb: false, ..ImageOptions::color() }
+    }
+}
+
+// Alpha is the last component of each pixel.
+fn premultiply_alpha(data: &mut [u8], components: usize, srgb: bool) {
+    for pixel in data.chunks_mut(components) {
+        let (color, alpha) = pixel.split_at_mut(components - 1);
+        let alpha = alpha[0] as f32 / 255.;
+        for c in color.iter_mut() {
+            *c = if srgb { linear_to_srgb(srgb_to_linear(*c) * alpha) } else { (*c as f32 * alpha).round() as u8 };
+        }
+    }
+}
+
+// Data of a 16 bit image, decoded to linear and premultiplied as asked.
+fn float_image_data(img: &DynamicImage, options: ImageOptions) -> (TextureFormat, Vec<f32>) {
+    let (format, components, values): (_, usize, &[u16]) = match img {
+        DynamicImage::ImageLuma16(buffer) => (TextureFormat::R16F, 1, buffer),
+        DynamicImage::ImageLumaA16(buffer) => (TextureFormat::Rg16F, 2, buffer),
+        DynamicImage::ImageRgb16(buffer) => (TextureFormat::Rgba16F, 3, buffer),
+        DynamicImage::ImageRgba16(buffer) => (TextureFormat::Rgba16F, 4, buffer),
+        _ => unreachable!(),
+    };
+    let has_alpha = components == 2 || components == 4;
+    let color_components = if has_alpha { components - 1 } else { components };
+    let mut data = Vec::with_capacity(values.len() / components * format.components());
+    for pixel in values.chunks(components) {
+        let alpha = if has_alpha { pixel[components - 1] as f32 / 65535. } else { 1. };
+        for &v in pixel[..color_components].iter() {
+            let v = v as f32 / 65535.;
+            let v = if options.srgb { decode_srgb(v) } else { v };
+            data.push(if options.premultiply_alpha { v * alpha } else { v });
+        }
+        if components == 3 || has_alpha {
+            data.push(alpha);
+        }
+    }
+    (format, data)
+}
+
+// Resamples a panorama with longitude across and latitude down into the six
+// faces of a cube map, each face_size square, in CubeFace order. Z is up,
+// as in the rest of the crate: the first row of the panorama is +Z, and its
+// middle column looks along +X. Further right in the panorama is further to
+// the viewer's right, towards -Y. Pixels are interpolated with lerp.
+pub fn equirect_to_cube_faces<T: Copy>(width: i32, height: i32, data: &[T], face_size: i32, lerp: fn(T, T, f32) -> T) -> [Vec<T>; 6] {
+    assert_eq!(data.len(), (width * height) as usize);
+    let pixel = |x: i32, y: i32| data[(y.clamp(0, height - 1) * width + x.rem_euclid(width)) as usize];
+    // Bilinear, wrapping around in longitude.
+    let sample = |u: f32, v: f32| {
+        let (x, y) = (u * width as f32 - 0.5, v * height as f32 - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i32, y0 as i32);
+        let top = lerp(pixel(x0, y0), pixel(x0 + 1, y0), fx);
+        let bottom = lerp(pixel(x0, y0 + 1), pixel(x0 + 1, y0 + 1), fx);
+        lerp(top, bottom, fy)
+    };
+    CubeFace::ALL.map(|face| {
+        let mut pixels = Vec::with_capacity((face_size * face_size) as usize);
+        for j in 0..face_size {
+            for i in 0..face_size {
+                let s = 2. * (i as f32 + 0.5) / face_size as f32 - 1.;
+                let t = 2. * (j as f32 + 0.5) / face_size as f32 - 1.;
+                let d = face.direction(s, t).normalized();
+                let u = 0.5 - d.y.atan2(d.x) / TAU;
+                let v = d.z.clamp(-1., 1.).acos() / PI;
+                pixels.push(sample(u, v));
+            }
+        }
+        pixels
+    })
+}
+
+pub fn lerp_rgba8(a: [u8; 4], b: [u8; 4], t: f32) -> [u8; 4] {
+    [0, 1, 2, 3].map(|k| (a[k] as f32 + (b[k] as f32 - a[k] as f32) * t).round() as u8)
+}
+
+// Setters of the sampling state, which textures have and samplers override.
+// Expands in the impls of types with parameter_i, parameter_f and
+// parameter_fv. GLES has no LOD bias parameter, shaders pass a bias to
+// texture() instead.
+macro_rules! sampling_parameters {
+    () => {
+        pub fn set_s_wrap_mode(&mut self, mode: TextureWrapMode) {
+            self.parameter_i(gl::TEXTURE_WRAP_S, mode as i32);
+        }
+
+        pub fn set_t_wrap_mode(&mut self, mode: TextureWrapMode) {
+            self.parameter_i(gl::TEXTURE_WRAP_T, mode as i32);
+        }
+
+        // For cube maps and 3D textures.
+        pub fn set_r_wrap_mode(&mut self, mode: TextureWrapMode) {
+            self.parameter_i(gl::TEXTURE_WRAP_R, mode as i32);
+        }
+
+        pub fn set_min_filter_mode(&mut self, mode: TextureMinFilterMode) {
+            self.parameter_i(gl::TEXTURE_MIN_FILTER, mode as i32);
+        }
+
+        pub fn set_mag_filter_mode(&mut self, mode: TextureMagFilterMode) {
+            self.parameter_i(gl::TEXTURE_MAG_FILTER, mode as i32);
+        }
+
+        // Limits the level of detail, in levels from the base one.
+        pub fn set_lod_range(&mut self, min: f32, max: f32) {
+            self.parameter_f(gl::TEXTURE_MIN_LOD, min);
+            self.parameter_f(gl::TEXTURE_MAX_LOD, max);
+        }
+
+        // Clamped to what the driver supports. Returns the anisotropy set,
+        // 1 without the anisotropic filtering extension.
+        pub fn set_max_anisotropy(&mut self, anisotropy: f32) -> f32 {
+            let supported = max_anisotropy();
+            if supported <= 1. {
+                return 1.;
+            }
+            let anisotropy = anisotropy.clamp(1., supported);
+            self.parameter_f(TEXTURE_MAX_ANISOTROPY, anisotropy);
+            anisotropy
+        }
+
+        // For TextureWrapMode::ClampToBorder.
+        pub fn set_border_color(&mut self, color: Color4) -> Result<(), String> {
+            if !has_border_clamp() {
+                return Err("border colors need GLES 3.2 or the texture border clamp extension".to_string());
+            }
+            self.parameter_fv(gl::TEXTURE_BORDER_COLOR, &[color.r, color.g, color.b, color.a]);
+            Ok(())
+        }
+    };
+}
+use sampling_parameters;
+
+// GL_EXT_texture_filter_anisotropic.
+const TEXTURE_MAX_ANISOTROPY: GLenum = 0x84FE;
+const MAX_TEXTURE_MAX_ANISOTROPY: GLenum = 0x84FF;
+
+// 1 when anisotropic filtering is not supported.
+pub fn max_anisotropy() -> f32 {
+    if !has_extension("GL_EXT_texture_filter_anisotropic") && !has_extension("GL_ARB_texture_filter_anisotropic") {
+        return 1.;
+    }
+    let mut value: f32 = 1.;
+    unsafe {
+        gl::GetFloatv(MAX_TEXTURE_MAX_ANISOTROPY, &mut value);
+    }
+    value
+}
+
+fn has_border_clamp() -> bool {
+    version() >= (3, 2) || has_extension("GL_EXT_texture_border_clamp") || has_extension("GL_OES_texture_border_clamp")
+}
+
+pub fn has_extension(name: &str) -> bool {
+    let mut count: i32 = 0;
+    unsafe {
+        gl::GetIntegerv(gl::NUM_EXTENSIONS, &mut count);
+    }
+    (0..count.max(0) as u32).any(|i| {
+        let extension = unsafe { gl::GetStringi(gl::EXTENSIONS, i) };
+        !extension.is_null() && unsafe { std::ffi::CStr::from_ptr(extension as *const std::os::raw::c_char) }.to_bytes() == name.as_bytes()
+    })
+}
+
+// Major and minor version of the context, GL or GLES.
+pub fn version() -> (u32, u32) {
+    let (mut major, mut minor) = (0, 0);
+    unsafe {
+        gl::GetIntegerv(gl::MAJOR_VERSION, &mut major);
+        gl::GetIntegerv(gl::MINOR_VERSION, &mut minor);
+    }
+    (major as u32, minor as u32)
+}
+
+// Levels down to 1x1.
+pub fn mip_level_count(width: i32, height: i32) -> u32 {
+    32 - (width.max(height).max(1) as u32).leading_zeros()
+}
+
+pub fn mip_level_size(width: i32, height: i32, level: u32) -> (i32, i32) {
+    ((width >> level).max(1), (height >> level).max(1))
+}
+
+// Every level of an image, from the image itself down to 1x1, each the
+// average of 2x2 pixels of the previous one. With srgb, colors are averaged
+// in linear space and alpha as it is, so that levels do not get darker.
+pub fn mip_chain_rgba8(width: i32, height: i32, data: &[[u8; 4]], srgb: bool) -> Vec<Vec<[u8; 4]>> {
+    assert_eq!(data.len(), (width * height) as usize);
+    let mut chain = vec![data.to_vec()];
+    for level in 1..mip_level_count(width, height) {
+        let (w, h) = mip_level_size(width, height, level - 1);
+        let next = downsample_rgba8(w, h, chain.last().unwrap(), srgb);
+        chain.push(next);
+    }
+    chain
+}
+
+// Halves an image, rounding down. Odd edges fold into the last pixel.
+pub fn downsample_rgba8(width: i32, height: i32, data: &[[u8; 4]], srgb: bool) -> Vec<[u8; 4]> {
+    let (w, h) = mip_level_size(width, height, 1);
+    let decode = |c: u8, k: usize| if srgb && k < 3 { srgb_to_linear(c) } else { c as f32 / 255. };
+    let encode = |v: f32, k: usize| if srgb && k < 3 { linear_to_srgb(v) } else { (v * 255.).round() as u8 };
+    let mut result = Vec::with_capacity((w * h) as usize);
+    for y in 0..h {
+        for x in 0..w {
+            // Source pixels covered by this one, 3 across at the last column
+            // of odd widths, 1 for a width of 1.
+            let xs = 2 * x..if x == w - 1 { width } else { 2 * x + 2 };
+            let ys = 2 * y..if y == h - 1 { height } else { 2 * y + 2 };
+            let mut sum = [0.; 4];
+            let mut n = 0.;
+            for sy in ys.clone() {
+                for sx in xs.clone() {
+                    let pixel = data[(sy * width + sx) as usize];
+                    for k in 0..4 {
+                        sum[k] += decode(pixel[k], k);
+                    }
+                    n += 1.;
+                }
+            }
+            result.push([0, 1, 2, 3].map(|k| encode(sum[k] / n, k)));
+        }
+    }
+    result
+}
+
+// Like mip_chain_rgba8, for float pixels of any number of components, all
+// averaged as they are.
+pub fn mip_chain_f32(width: i32, height: i32, components: usize, data: &[f32]) -> Vec<Vec<f32>> {
+    assert_eq!(data.len(), (width * height) as usize * components);
+    let mut chain = vec![data.to_vec()];
+    for level in 1..mip_level_count(width, height) {
+        let (w, h) = mip_level_size(width, height, level - 1);
+        let next = downsample_f32(w, h, components, chain.last().unwrap());
+        chain.push(next);
+    }
+    chain
+}
+
+// Like downsample_rgba8.
+pub fn downsample_f32(width: i32, height: i32, components: usize, data: &[f32]) -> Vec<f32> {
+    let (w, h) = mip_level_size(width, height, 1);
+    let mut result = Vec::with_capacity((w * h) as usize * components);
+    for y in 0..h {
+        for x in 0..w {
+            let xs = 2 * x..if x == w - 1 { width } else { 2 * x + 2 };
+            let ys = 2 * y..if y == h - 1 { height } else { 2 * y + 2 };
+            let mut sum = vec![0.; components];
+            let mut n = 0.;
+            for sy in ys.clone() {
+                for sx in xs.clone() {
+                    let pixel = (sy * width + sx) as usize * components;
+                    for k in 0..components {
+                        sum[k] += data[pixel + k];
+                    }
+                    n += 1.;
+                }
+            }
+            result.extend(sum.iter().map(|v| v / n));
+        }
+    }
+    result
+}
+
+pub fn srgb_to_linear(c: u8) -> f32 {
+    decode_srgb(c as f32 / 255.)
+}
+
+fn decode_srgb(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+pub fn linear_to_srgb(v: f32) -> u8 {
+    let v = v.clamp(0., 1.);
+    let c = if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1. / 2.4) - 0.055 };
+    (c * 255.).round() as u8
+}
+
+// Sampling state that replaces the state of textures bound to the same
+// units, so that one sampler can be shared by many textures.
+#[derive(Debug)]
+pub struct Sampler {
+    id: u32,
+}
+
+impl Sampler {
+    // Linear filtering without mipmaps, clamped to the edges, like a new
+    // texture.
+    pub fn new() -> Sampler {
+        let mut id: u32 = 0;
+        unsafe {
+            gl::GenSamplers(1, &mut id as *mut GLuint);
+        }
+        track(ObjectKind::Sampler, id);
+        let mut sampler = Sampler { id };
+        sampler.set_min_filter_mode(TextureMinFilterMode::Linear);
+        sampler.set_mag_filter_mode(TextureMagFilterMode::Linear);
+        sampler.set_s_wrap_mode(TextureWrapMode::ClampToEdge);
+        sampler.set_t_wrap_mode(TextureWrapMode::ClampToEdge);
+        sampler.set_r_wrap_mode(TextureWrapMode::ClampToEdge);
+        sampler
+    }
+
+    sampling_parameters!();
+
+    fn parameter_i(&mut self, name: GLenum, value: i32) {
+        unsafe {
+            gl::SamplerParameteri(self.id, name, value);
+        }
+    }
+
+    fn parameter_f(&mut self, name: GLenum, value: f32) {
+        unsafe {
+            gl::SamplerParameterf(self.id, name, value);
+        }
+    }
+
+    fn parameter_fv(&mut self, name: GLenum, values: &[f32]) {
+        unsafe {
+            gl::SamplerParameterfv(self.id, name, values.as_ptr());
+        }
+    }
+}
+
+impl Default for Sampler {
+    fn default() -> Sampler {
+        Sampler::new()
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteSamplers(1, &self.id);
+        }
+        untrack(ObjectKind::Sampler, self.id);
+    }
+}
+
+// Sized formats, uploaded from data of their component type: u8 for the
+// normalized, sRGB and 8 bit integer ones, f32 for the float ones, 16 bit
+// included, and u32 or i32 for the others. Depth24Stencil8 packs depth in
+// the upper 24 bits of a u32.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TextureFormat {
+    // Unsized, for the text atlas.
+    Alpha,
+    R8,
+    Rg8,
+    Rgb8,
+    Rgba8,
+    Srgb8,
+    Srgb8Alpha8,
+    R16F,
+    Rg16F,
+    Rgba16F,
+    R32F,
+    Rg32F,
+    Rgba32F,
+    R8UI,
+    Rgba8UI,
+    R32UI,
+    Rgba32UI,
+    R32I,
+    Rgba32I,
+    Depth24,
+    Depth24Stencil8,
+    Depth32F,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ComponentType {
+    U8,
+    U32,
+    I32,
+    F32,
+}
+
+impl TextureFormat {
+    pub fn internal_format(self) -> GLenum {
+        use TextureFormat::*;
+        match self {
+            Alpha => gl::ALPHA,
+            R8 => gl::R8,
+            Rg8 => gl::RG8,
+            Rgb8 => gl::RGB8,
+            Rgba8 => gl::RGBA8,
+            Srgb8 => gl::SRGB8,
+            Srgb8Alpha8 => gl::SRGB8_ALPHA8,
+            R16F => gl::R16F,
+            Rg16F => gl::RG16F,
+            Rgba16F => gl::RGBA16F,
+            R32F => gl::R32F,
+            Rg32F => gl::RG32F,
+            Rgba32F => gl::RGBA32F,
+            R8UI => gl::R8UI,
+            Rgba8UI => gl::RGBA8UI,
+            R32UI => gl::R32UI,
+            Rgba32UI => gl::RGBA32UI,
+            R32I => gl::R32I,
+            Rgba32I => gl::RGBA32I,
+            Depth24 => gl::DEPTH_COMPONENT24,
+            Depth24Stencil8 => gl::DEPTH24_STENCIL8,
+            Depth32F => gl::DEPTH_COMPONENT32F,
+        }
     }
 
-    pub fn load_region_data<T>(&mut self, region: RectangleI, format: TextureFormat, data: &[T], texture_unit: TextureUnit) {
-        unsafe {
-            // TO DO: Is texture_unit necessary? docs.gl notes say so...
-            texture_unit.bind_texture(*self);
-            gl::TexSubImage2D(
-                gl::TEXTURE_2D,
-                0,
-                region.min.x,
-                region.min.y,
-                region.width(),
-                region.height(),
-                format as u32,
-                gl::UNSIGNED_BYTE,
-                data.as_ptr() as *const GLvoid
-            );
+    pub fn pixel_format(self) -> GLenum {
+        use TextureFormat::*;
+        match self {
+            Alpha => gl::ALPHA,
+            R8 | R16F | R32F => gl::RED,
+            Rg8 | Rg16F | Rg32F => gl::RG,
+            Rgb8 | Srgb8 => gl::RGB,
+            Rgba8 | Srgb8Alpha8 | Rgba16F | Rgba32F => gl::RGBA,
+            R8UI | R32UI | R32I => gl::RED_INTEGER,
+            Rgba8UI | Rgba32UI | Rgba32I => gl::RGBA_INTEGER,
+            Depth24 | Depth32F => gl::DEPTH_COMPONENT,
+            Depth24Stencil8 => gl::DEPTH_STENCIL,
         }
     }
 
-    pub fn set_s_wrap_mode(&mut self, mode: TextureWrapMode) {
-        unsafe {
-            gl::BindTexture(gl::TEXTURE_2D, self.id);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, mode as i32);
+    pub fn pixel_type(self) -> GLenum {
+        match (self, self.component_type()) {
+            (TextureFormat::Depth24Stencil8, _) => gl::UNSIGNED_INT_24_8,
+            (_, ComponentType::U8) => gl::UNSIGNED_BYTE,
+            (_, ComponentType::U32) => gl::UNSIGNED_INT,
+            (_, ComponentType::I32) => gl::INT,
+            (_, ComponentType::F32) => gl::FLOAT,
         }
     }
 
-    pub fn set_t_wrap_mode(&mut self, mode: TextureWrapMode) {
-        unsafe {
-            gl::BindTexture(gl::TEXTURE_2D, self.id);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, mode as i32);
+    pub fn component_type(self) -> ComponentType {
+        use TextureFormat::*;
+        match self {
+            Alpha | R8 | Rg8 | Rgb8 | Rgba8 | Srgb8 | Srgb8Alpha8 | R8UI | Rgba8UI => ComponentType::U8,
+            R16F | Rg16F | Rgba16F | R32F | Rg32F | Rgba32F | Depth32F => ComponentType::F32,
+            R32UI | Rgba32UI | Depth24 | Depth24Stencil8 => ComponentType::U32,
+            R32I | Rgba32I => ComponentType::I32,
         }
     }
 
-    pub fn set_min_filter_mode(&mut self, mode: TextureMinFilterMode) {
-        unsafe {
-            gl::BindTexture(gl::TEXTURE_2D, self.id);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, mode as i32);
+    // Components per pixel in uploaded data.
+    pub fn components(self) -> usize {
+        match self.pixel_format() {
+            gl::RG => 2,
+            gl::RGB => 3,
+            gl::RGBA | gl::RGBA_INTEGER => 4,
+            _ => 1,
         }
     }
 
-    pub fn set_mag_filter_mode(&mut self, mode: TextureMagFilterMode) {
-        unsafe {
-            gl::BindTexture(gl::TEXTURE_2D, self.id);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, mode as i32);
+    pub fn bytes_per_pixel(self) -> usize {
+        let component_size = if self.component_type() == ComponentType::U8 { 1 } else { 4 };
+        self.components() * component_size
+    }
+
+    pub fn is_depth(self) -> bool {
+        matches!(self, TextureFormat::Depth24 | TextureFormat::Depth24Stencil8 | TextureFormat::Depth32F)
+    }
+
+    pub fn is_integer(self) -> bool {
+        matches!(self.pixel_format(), gl::RED_INTEGER | gl::RGBA_INTEGER)
+    }
+
+    // Stored as 8 bit fixed point and read as values from 0 to 1.
+    pub fn is_normalized(self) -> bool {
+        self.component_type() == ComponentType::U8 && !self.is_integer()
+    }
+
+    // Linear filtering of 32 bit floats needs an extension in GLES.
+    pub fn is_filterable(self) -> bool {
+        use TextureFormat::*;
+        !(self.is_depth() || self.is_integer() || matches!(self, R32F | Rg32F | Rgba32F))
+    }
+
+    fn check_data<T: PixelData>(self, width: i32, height: i32, data: &[T]) -> Result<(), String> {
+        if T::COMPONENT_TYPE != self.component_type() {
+            return Err(format!("{:?} texture data must be {:?}, not {:?}", self, self.component_type(), T::COMPONENT_TYPE));
         }
+        let size = width.max(0) as usize * height.max(0) as usize * self.bytes_per_pixel();
+        if size_of_val(data) < size {
+            return Err(format!("{} bytes of data for {}x{} {:?} pixels, which take {}", size_of_val(data), width, height, self, size));
+        }
+        Ok(())
     }
+}
 
-    // TODO: Mipmaps.
-    // pub fn make_mipmaps() {}
+// Types of texture data, by their component type. Vectors and colors are
+// several components.
+pub trait PixelData {
+    const COMPONENT_TYPE: ComponentType;
 }
 
-#[repr(u32)]
-#[derive(Copy, Clone)]
-pub enum TextureFormat {
-    Alpha = gl::ALPHA,
-    Rgb = gl::RGB,
-    Rgba = gl::RGBA,
+macro_rules! pixel_data {
+    ($component_type:ident: $($t:ty),+) => {
+        $(impl PixelData for $t {
+            const COMPONENT_TYPE: ComponentType = ComponentType::$component_type;
+        })+
+    };
 }
 
+pixel_data!(U8: u8, [u8; 2], [u8; 3], [u8; 4]);
+pixel_data!(U32: u32, [u32; 4]);
+pixel_data!(I32: i32, [i32; 4]);
+pixel_data!(F32: f32, Vector2, Vector3, Vector4, Color3, Color4);
+
 #[repr(u32)]
 #[derive(Copy, Clone)]
 pub enum TextureWrapMode {
     Repeat = gl::REPEAT,
     ClampToEdge = gl::CLAMP_TO_EDGE,
     MirroredRepeat = gl::MIRRORED_REPEAT,
+    // Needs GLES 3.2 or the texture border clamp extension.
+    ClampToBorder = gl::CLAMP_TO_BORDER,
 }
 
 #[repr(u32)]
@@ -476,3 +2526,782 @@ pub enum TextureMagFilterMode {
     Nearest = gl::NEAREST,
     Linear = gl::LINEAR,
 }
+
+// Storage for framebuffer attachments that are not sampled, and the only
+// kind of multisampled attachment.
+#[derive(Debug)]
+pub struct Renderbuffer {
+    id: u32,
+}
+
+impl Renderbuffer {
+    // Samples are 0 for a renderbuffer that is not multisampled.
+    pub fn new(width: i32, height: i32, format: TextureFormat, samples: u32) -> Renderbuffer {
+        let mut id: u32 = 0;
+        unsafe {
+            gl::GenRenderbuffers(1, &mut id as *mut GLuint);
+            gl::BindRenderbuffer(gl::RENDERBUFFER, id);
+            gl::RenderbufferStorageMultisample(gl::RENDERBUFFER, samples as GLsizei, format.internal_format(), width, height);
+        }
+        track(ObjectKind::Renderbuffer, id);
+        Renderbuffer { id }
+    }
+}
+
+impl Drop for Renderbuffer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteRenderbuffers(1, &self.id);
+        }
+        untrack(ObjectKind::Renderbuffer, self.id);
+    }
+}
+
+#[derive(Debug)]
+pub enum Attachment {
+    Texture(Texture),
+    Renderbuffer(Renderbuffer),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DepthAttachment {
+    None,
+    Depth,
+    DepthStencil,
+    // Depth in a texture, to sample it later, as for shadow maps. Not for
+    // multisampled framebuffers.
+    DepthTexture,
+}
+
+#[derive(Clone, Debug)]
+pub struct FramebufferConfig {
+    pub width: i32,
+    pub height: i32,
+    // One attachment per render target, at COLOR_ATTACHMENT0 and up, read
+    // by the fragment shader's outputs at the same locations. Alpha, Rgb16F
+    // and the 32 bit float formats are not renderable without extensions.
+    pub color: Vec<TextureFormat>,
+    pub depth: DepthAttachment,
+    // Above 1, attachments are multisampled renderbuffers, and the result
+    // must be resolved into a framebuffer with textures to be used.
+    pub samples: u32,
+}
+
+// Rendering target other than the window. Attachments are owned by the
+// framebuffer and deleted with it.
+#[derive(Debug)]
+pub struct Framebuffer {
+    id: u32,
+    width: i32,
+    height: i32,
+    samples: u32,
+    color: Vec<Attachment>,
+    depth: Option<Attachment>,
+    // Some color attachment holds integers, which blits cannot filter.
+    integer: bool,
+}
+
+impl Framebuffer {
+    pub fn new(config: &FramebufferConfig) -> Result<Framebuffer, String> {
+        let multisampled = config.samples > 1;
+        if config.color.len() > max_color_attachments() as usize {
+            return Err(format!("{} color attachments, at most {} are supported", config.color.len(), max_color_attachments()));
+        }
+        if multisampled && config.depth == DepthAttachment::DepthTexture {
+            return Err("multisampled framebuffers cannot have a depth texture".to_string());
+        }
+        let mut id: u32 = 0;
+        unsafe {
+            gl::GenFramebuffers(1, &mut id as *mut GLuint);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, id);
+        }
+        track(ObjectKind::Framebuffer, id);
+        let mut framebuffer = Framebuffer {
+            id,
+            width: config.width,
+            height: config.height,
+            samples: if multisampled { config.samples } else { 0 },
+            color: Vec::new(),
+            depth: None,
+            integer: config.color.iter().any(|format| format.is_integer()),
+        };
+
+        let (width, height) = (config.width, config.height);
+        for (i, &format) in config.color.iter().enumerate() {
+            let attachment = if multisampled {
+                Attachment::Renderbuffer(Renderbuffer::new(width, height, format, config.samples))
+            } else {
+                let mut texture = Texture::new();
+                texture.allocate(width, height, format);
+                Attachment::Texture(texture)
+            };
+            framebuffer.attach(gl::COLOR_ATTACHMENT0 + i as GLenum, &attachment);
+            framebuffer.color.push(attachment);
+        }
+
+        let depth = match config.depth {
+            DepthAttachment::None => None,
+            DepthAttachment::Depth => Some((TextureFormat::Depth24, gl::DEPTH_ATTACHMENT)),
+            DepthAttachment::DepthStencil => Some((TextureFormat::Depth24Stencil8, gl::DEPTH_STENCIL_ATTACHMENT)),
+            DepthAttachment::DepthTexture => {
+                let mut texture = Texture::new();
+                texture.allocate(width, height, TextureFormat::Depth24);
+                let attachment = Attachment::Texture(texture);
+                framebuffer.attach(gl::DEPTH_ATTACHMENT, &attachment);
+                framebuffer.depth = Some(attachment);
+                None
+            }
+        };
+        if let Some((format, attachment_point)) = depth {
+            let attachment = Attachment::Renderbuffer(Renderbuffer::new(width, height, format, framebuffer.samples));
+            framebuffer.attach(attachment_point, &attachment);
+            framebuffer.depth = Some(attachment);
+        }
+
+        framebuffer.set_draw_buffers();
+        let status = unsafe { gl::CheckFramebufferStatus(gl::FRAMEBUFFER) };
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            return Err(format!("framebuffer is incomplete: {}", framebuffer_status_name(status)));
+        }
+        Ok(framebuffer)
+    }
+
+    fn attach(&self, attachment_point: GLenum, attachment: &Attachment) {
+        unsafe {
+            match attachment {
+                Attachment::Texture(texture) => {
+                    gl::FramebufferTexture2D(gl::FRAMEBUFFER, attachment_point, gl::TEXTURE_2D, texture.id, 0)
+                }
+                Attachment::Renderbuffer(renderbuffer) => {
+                    gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, attachment_point, gl::RENDERBUFFER, renderbuffer.id)
+                }
+            }
+        }
+    }
+
+    fn set_draw_buffers(&self) {
+        let buffers: Vec<GLenum> = (0..self.color.len() as GLenum).map(|i| gl::COLOR_ATTACHMENT0 + i).collect();
+        unsafe {
+            if buffers.is_empty() {
+                gl::DrawBuffers(1, &gl::NONE);
+                gl::ReadBuffer(gl::NONE);
+            } else {
+                gl::DrawBuffers(buffers.len() as GLsizei, buffers.as_ptr());
+            }
+        }
+    }
+
+    // Renders into the framebuffer until another one is bound, and sets the
+    // viewport to cover it.
+    pub fn bind(&self) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.id);
+            gl::Viewport(0, 0, self.width, self.height);
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    // 0 when not multisampled.
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    // The texture of a color attachment, for MeshShader::set_texture. None
+    // for multisampled framebuffers, which must be resolved first.
+    pub fn texture(&self, index: usize) -> Option<TextureHandle<'_>> {
+        match self.color.get(index)? {
+            Attachment::Texture(texture) => Some(texture.handle()),
+            Attachment::Renderbuffer(_) => None,
+        }
+    }
+
+    pub fn depth_texture(&self) -> Option<TextureHandle<'_>> {
+        match self.depth.as_ref()? {
+            Attachment::Texture(texture) => Some(texture.handle()),
+            Attachment::Renderbuffer(_) => None,
+        }
+    }
+
+    // Copies every color attachment, and depth when both have it, into the
+    // target, resolving samples. Sizes must match when multisampled, and
+    // depth formats always.
+    pub fn resolve(&self, target: &Framebuffer) {
+        assert!(self.color.len() <= target.color.len(), "target has fewer color attachments");
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.id);
+            gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, target.id);
+        }
+        for i in 0..self.color.len() as GLenum {
+            let mut draw_buffers = vec![gl::NONE; i as usize + 1];
+            draw_buffers[i as usize] = gl::COLOR_ATTACHMENT0 + i;
+            unsafe {
+                gl::ReadBuffer(gl::COLOR_ATTACHMENT0 + i);
+                gl::DrawBuffers(draw_buffers.len() as GLsizei, draw_buffers.as_ptr());
+                self.blit(target.width, target.height, gl::COLOR_BUFFER_BIT, target.integer);
+            }
+        }
+        if self.depth.is_some() && target.depth.is_some() {
+            self.blit(target.width, target.height, gl::DEPTH_BUFFER_BIT, target.integer);
+        }
+        // Restores the target's draw buffers and this one's read buffer.
+        target.set_draw_buffers();
+        unsafe {
+            if !self.color.is_empty() {
+                gl::ReadBuffer(gl::COLOR_ATTACHMENT0);
+            }
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+    }
+
+    // Copies the first color attachment to the window, resolving samples.
+    // GLES cannot blit into a multisampled window.
+    pub fn resolve_to_default(&self, width: i32, height: i32) {
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.id);
+            gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, 0);
+            gl::ReadBuffer(gl::COLOR_ATTACHMENT0);
+        }
+        self.blit(width, height, gl::COLOR_BUFFER_BIT, false);
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+    }
+
+    // From a color attachment of a normalized format. Multisampled
+    // framebuffers must be resolved first.
+    pub fn read_pixels(&self, attachment: usize, region: RectangleI) -> Result<image::RgbaImage, String> {
+        if self.samples > 0 {
+            return Err("cannot read a multisampled framebuffer, resolve it first".to_string());
+        }
+        let format = match self.color.get(attachment) {
+            Some(Attachment::Texture(texture)) => texture.format(),
+            Some(Attachment::Renderbuffer(_)) => None,
+            None => return Err(format!("no color attachment {}", attachment)),
+        };
+        if let Some(format) = format.filter(|f| !f.is_normalized()) {
+            return Err(format!("cannot read {:?} pixels as RGBA8, only normalized formats", format));
+        }
+        check_read_region(region, self.width, self.height)?;
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.id);
+            gl::ReadBuffer(gl::COLOR_ATTACHMENT0 + attachment as GLenum);
+        }
+        let image = read_pixels(region);
+        unsafe {
+            gl::ReadBuffer(gl::COLOR_ATTACHMENT0);
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+        }
+        image
+    }
+
+    // Multisampled blits must not scale, depth and integer blits must not
+    // filter, and blits that don't scale need not.
+    fn blit(&self, width: i32, height: i32, mask: GLbitfield, target_integer: bool) {
+        let scaled = (width, height) != (self.width, self.height);
+        let filtered = scaled && self.samples == 0 && mask == gl::COLOR_BUFFER_BIT && !self.integer && !target_integer;
+        let filter = if filtered { gl::LINEAR } else { gl::NEAREST };
+        unsafe {
+            gl::BlitFramebuffer(0, 0, self.width, self.height, 0, 0, width, height, mask, filter);
+        }
+    }
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.id);
+        }
+        untrack(ObjectKind::Framebuffer, self.id);
+    }
+}
+
+// Renders to the window again, with a viewport of the given size.
+pub fn bind_default_framebuffer(width: i32, height: i32) {
+    unsafe {
+        gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        gl::Viewport(0, 0, width, height);
+    }
+}
+
+// Reads RGBA8 pixels from the framebuffer and buffer bound for reading. The
+// image's first row is the region's top one, unlike GL's. Parts of the region
+// outside of the framebuffer are undefined.
+pub fn read_pixels(region: RectangleI) -> Result<image::RgbaImage, String> {
+    check_read_size(region)?;
+    let (width, height) = (region.width(), region.height());
+    let mut data = vec![0u8; (width * height * 4) as usize];
+    unsafe {
+        gl::ReadPixels(region.min.x, region.min.y, width, height, gl::RGBA, gl::UNSIGNED_BYTE, data.as_mut_ptr() as *mut GLvoid);
+    }
+    image_from_rows(width, height, &data).ok_or_else(|| format!("could not read region {:?}", region))
+}
+
+// The back buffer, before it is swapped.
+pub fn read_default_framebuffer(region: RectangleI) -> Result<image::RgbaImage, String> {
+    unsafe {
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+        gl::ReadBuffer(gl::BACK);
+    }
+    read_pixels(region)
+}
+
+// Saves the whole back buffer as a PNG. Call it after drawing a frame and
+// before swapping buffers, as the back buffer is undefined after the swap.
+pub fn save_screenshot(path: &str, width: i32, height: i32) -> Result<(), String> {
+    let image = read_default_framebuffer(RectangleI::new(Vector2I::ZERO, width, height))?;
+    image.save_with_format(path, image::ImageFormat::Png).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn check_read_region(region: RectangleI, width: i32, height: i32) -> Result<(), String> {
+    check_read_size(region)?;
+    if region.max.x > width || region.max.y > height {
+        return Err(format!("region {:?} is outside of the {}x{} framebuffer", region, width, height));
+    }
+    Ok(())
+}
+
+// Without the framebuffer's size, only what GL would reject or what could not
+// be allocated.
+fn check_read_size(region: RectangleI) -> Result<(), String> {
+    if region.min.x < 0 || region.min.y < 0 || region.width() <= 0 || region.height() <= 0 {
+        return Err(format!("cannot read region {:?}", region));
+    }
+    Ok(())
+}
+
+// Flips GL's bottom-up rows of RGBA8 pixels. None if the data does not hold
+// a width by height image.
+fn image_from_rows(width: i32, height: i32, data: &[u8]) -> Option<image::RgbaImage> {
+    if width <= 0 || height <= 0 {
+        return None;
+    }
+    let row = width as usize * 4;
+    let flipped: Vec<u8> = data.chunks_exact(row).rev().flatten().copied().collect();
+    image::RgbaImage::from_raw(width as u32, height as u32, flipped)
+}
+
+// Reads pixels into pixel buffer objects, so that requests return at once
+// and the data is copied frames later, when the GPU is done with it. Keeps
+// up to as many reads in flight as it has buffers.
+pub struct AsyncReadback {
+    slots: Vec<ReadbackSlot>,
+    oldest: usize,
+    pending: usize,
+}
+
+struct ReadbackSlot {
+    buffer: Buffer,
+    fence: GLsync,
+    width: i32,
+    height: i32,
+}
+
+impl AsyncReadback {
+    pub fn new(buffer_count: usize) -> AsyncReadback {
+        assert!(buffer_count > 0);
+        let slots = (0..buffer_count)
+            .map(|_| ReadbackSlot { buffer: Buffer::new(), fence: ptr::null(), width: 0, height: 0 })
+            .collect();
+        AsyncReadback { slots, oldest: 0, pending: 0 }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    // Starts reading RGBA8 pixels from the framebuffer and buffer bound for
+    // reading. Returns false, reading nothing, when every buffer is in
+    // flight or the region cannot be read.
+    pub fn request(&mut self, region: RectangleI) -> bool {
+        if self.pending == self.slots.len() || check_read_size(region).is_err() {
+            return false;
+        }
+        let index = (self.oldest + self.pending) % self.slots.len();
+        let slot = &mut self.slots[index];
+        slot.width = region.width();
+        slot.height = region.height();
+        unsafe {
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, slot.buffer.id());
+            gl::BufferData(gl::PIXEL_PACK_BUFFER, (slot.width * slot.height * 4) as GLsizeiptr, ptr::null(), gl::STREAM_READ);
+            gl::ReadPixels(region.min.x, region.min.y, slot.width, slot.height, gl::RGBA, gl::UNSIGNED_BYTE, ptr::null_mut());
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+            slot.fence = gl::FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0);
+        }
+        self.pending += 1;
+        true
+    }
+
+    // The oldest read, once the GPU has finished it. Reads come out in the
+    // order they were requested.
+    pub fn poll(&mut self) -> Option<image::RgbaImage> {
+        if self.pending == 0 {
+            return None;
+        }
+        let slot = &mut self.slots[self.oldest];
+        let status = unsafe { gl::ClientWaitSync(slot.fence, gl::SYNC_FLUSH_COMMANDS_BIT, 0) };
+        if status != gl::ALREADY_SIGNALED && status != gl::CONDITION_SATISFIED {
+            return None;
+        }
+        let size = (slot.width * slot.height * 4) as usize;
+        let image = unsafe {
+            gl::DeleteSync(slot.fence);
+            slot.fence = ptr::null();
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, slot.buffer.id());
+            let data = gl::MapBufferRange(gl::PIXEL_PACK_BUFFER, 0, size as GLsizeiptr, gl::MAP_READ_BIT) as *const u8;
+            let image = if data.is_null() {
+                None
+            } else {
+                image_from_rows(slot.width, slot.height, std::slice::from_raw_parts(data, size))
+            };
+            gl::UnmapBuffer(gl::PIXEL_PACK_BUFFER);
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+            image
+        };
+        self.oldest = (self.oldest + 1) % self.slots.len();
+        self.pending -= 1;
+        image
+    }
+}
+
+impl Drop for AsyncReadback {
+    fn drop(&mut self) {
+        for slot in self.slots.iter().filter(|s| !s.fence.is_null()) {
+            unsafe {
+                gl::DeleteSync(slot.fence);
+            }
+        }
+    }
+}
+
+pub fn max_color_attachments() -> u32 {
+    let mut value: i32 = 0;
+    unsafe {
+        gl::GetIntegerv(gl::MAX_COLOR_ATTACHMENTS, &mut value);
+    }
+    value as u32
+}
+
+fn framebuffer_status_name(status: GLenum) -> String {
+    let name = match status {
+        gl::FRAMEBUFFER_UNDEFINED => "undefined",
+        gl::FRAMEBUFFER_INCOMPLETE_ATTACHMENT => "an attachment is incomplete",
+        gl::FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT => "no attachments",
+        gl::FRAMEBUFFER_UNSUPPORTED => "the attachment formats are not supported together",
+        gl::FRAMEBUFFER_INCOMPLETE_MULTISAMPLE => "attachments have different sample counts",
+        // GL_FRAMEBUFFER_INCOMPLETE_DIMENSIONS, only in GLES.
+        0x8CD9 => "attachments have different sizes",
+        _ => return format!("status {:#x}", status),
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    crate::std140_struct! {
+        struct Inner {
+            d: i32,
+            e: Vector2I,
+        }
+    }
+
+    // After the example in the std140 section of the GL specification.
+    crate::std140_struct! {
+        struct Example {
+            a: f32,
+            b: Vector2,
+            c: Vector3,
+            f: Inner,
+            g: f32,
+            h: [f32; 2],
+            m: Matrix3,
+            o: [Inner; 2],
+            last: f32,
+        }
+    }
+
+    #[test]
+    fn mip_chain_sizes() {
+        let data = vec![[255, 0, 0, 255]; 5 * 3];
+        let chain = mip_chain_rgba8(5, 3, &data, false);
+        let lengths: Vec<usize> = chain.iter().map(|level| level.len()).collect();
+        assert_eq!(lengths, [15, 2, 1]);
+        assert!(chain.iter().flatten().all(|&p| p == [255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn float_mip_chain() {
+        // Two components, 3x2, so the odd column folds into the last pixel.
+        let data = [0., 1., 2., 1., 4., 1., 6., 1., 8., 1., 10., 1.];
+        let chain = mip_chain_f32(3, 2, 2, &data);
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[1], [5., 1.]);
+        assert_eq!(mip_chain_f32(5, 3, 1, &[0.; 15]).iter().map(|level| level.len()).collect::<Vec<_>>(), [15, 2, 1]);
+    }
+
+    #[test]
+    fn log_locations() {
+        assert_eq!(parse_log_location("0:12(5): error: `x' undeclared"), Some((12, Some(5))));
+        assert_eq!(parse_log_location("0(12) : error C0000: syntax error"), Some((12, None)));
+        assert_eq!(parse_log_location("ERROR: 0:12: 'x' : undeclared identifier"), Some((12, None)));
+        assert_eq!(parse_log_location("WARNING: 1:3: extension not supported"), Some((3, None)));
+        assert_eq!(parse_log_location("Fragment info"), None);
+        assert_eq!(parse_log_location("ERROR: 1 compilation errors.  No code generated."), None);
+    }
+
+    #[test]
+    fn downsample_averages() {
+        #[rustfmt::skip]
+        let data = [
+            [0, 0, 0, 0], [255, 255, 255, 255], [0, 40, 0, 255], [0, 80, 0, 255],
+            [255, 255, 255, 255], [0, 0, 0, 0], [0, 120, 0, 255], [0, 160, 0, 255],
+        ];
+        let half = downsample_rgba8(4, 2, &data, false);
+        assert_eq!(half, [[128, 128, 128, 128], [0, 100, 0, 255]]);
+    }
+
+    #[test]
+    fn downsample_folds_odd_edges() {
+        // 3x3 to 1x1 takes all nine pixels, not just the first four.
+        let mut data = vec![[0, 0, 0, 255]; 9];
+        data[8] = [90, 90, 90, 255];
+        assert_eq!(downsample_rgba8(3, 3, &data, false), [[10, 10, 10, 255]]);
+        // Widths of 1 stay 1.
+        let column = [[10, 0, 0, 255], [30, 0, 0, 255], [50, 0, 0, 255], [70, 0, 0, 255]];
+        assert_eq!(downsample_rgba8(1, 4, &column, false), [[20, 0, 0, 255], [60, 0, 0, 255]]);
+    }
+
+    #[test]
+    fn downsample_srgb_in_linear_space() {
+        let data = [[0, 0, 0, 0], [255, 255, 255, 255], [255, 255, 255, 255], [0, 0, 0, 0]];
+        // Half of white's light, which is brighter than the encoded average,
+        // 128. Alpha is linear either way.
+        assert_eq!(downsample_rgba8(2, 2, &data, true), [[188, 188, 188, 128]]);
+        assert_eq!(downsample_rgba8(2, 2, &data, false), [[128, 128, 128, 128]]);
+    }
+
+    #[test]
+    fn srgb_round_trip() {
+        for c in 0..=255u8 {
+            assert_eq!(linear_to_srgb(srgb_to_linear(c)), c);
+        }
+        assert_eq!(srgb_to_linear(0), 0.);
+        assert_eq!(srgb_to_linear(255), 1.);
+    }
+
+    #[test]
+    fn equirect_faces() {
+        // Black, with a white first row, a red block where the panorama
+        // looks right (-Y) and a green one where it looks left (+Y), both on
+        // the horizon.
+        let (width, height) = (16, 8);
+        let mut panorama = vec![[0, 0, 0, 255]; (width * height) as usize];
+        for x in 0..width {
+            panorama[x as usize] = [255, 255, 255, 255];
+        }
+        for &(x, color) in [(12, [255, 0, 0, 255]), (4, [0, 255, 0, 255])].iter() {
+            for (dx, dy) in [(-1, -1), (0, -1), (-1, 0), (0, 0)].iter() {
+                panorama[((height / 2 + dy) * width + x + dx) as usize] = color;
+            }
+        }
+        let faces = equirect_to_cube_faces(width, height, &panorama, 3, lerp_rgba8);
+        // The texel at the center of each face.
+        let center = |face: CubeFace| faces[CubeFace::ALL.iter().position(|&f| f == face).unwrap()][4];
+        assert_eq!(center(CubeFace::NegativeY), [255, 0, 0, 255]);
+        assert_eq!(center(CubeFace::PositiveY), [0, 255, 0, 255]);
+        assert_eq!(center(CubeFace::PositiveZ), [255, 255, 255, 255]);
+        assert_eq!(center(CubeFace::PositiveX), [0, 0, 0, 255]);
+        assert_eq!(center(CubeFace::NegativeX), [0, 0, 0, 255]);
+        assert_eq!(center(CubeFace::NegativeZ), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn premultiplies_alpha() {
+        let mut rgba = [200, 100, 50, 128, 10, 20, 30, 255, 10, 20, 30, 0];
+        premultiply_alpha(&mut rgba, 4, false);
+        assert_eq!(rgba, [100, 50, 25, 128, 10, 20, 30, 255, 0, 0, 0, 0]);
+        let mut luma_alpha = [255, 64, 255, 255];
+        premultiply_alpha(&mut luma_alpha, 2, false);
+        assert_eq!(luma_alpha, [64, 64, 255, 255]);
+        // Half of white's light, not half its encoded value.
+        let mut srgb = [255, 255, 255, 128, 0, 0, 0, 128];
+        premultiply_alpha(&mut srgb, 4, true);
+        assert_eq!(srgb, [188, 188, 188, 128, 0, 0, 0, 128]);
+    }
+
+    fn assert_floats_near(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-4, "{:?} is not {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn float_image_formats() {
+        let half = 32768. / 65535.;
+        let rgb = DynamicImage::ImageRgb16(image::ImageBuffer::from_raw(1, 1, vec![65535, 0, 32768]).unwrap());
+        let (format, data) = float_image_data(&rgb, ImageOptions::linear());
+        assert_eq!(format, TextureFormat::Rgba16F);
+        // Alpha is added, since there is no three component half float
+        // format that can be rendered to.
+        assert_floats_near(&data, &[1., 0., half, 1.]);
+
+        let luma_alpha = DynamicImage::ImageLumaA16(image::ImageBuffer::from_raw(2, 1, vec![65535, 32768, 0, 65535]).unwrap());
+        let (format, data) = float_image_data(&luma_alpha, ImageOptions::linear());
+        assert_eq!(format, TextureFormat::Rg16F);
+        assert_floats_near(&data, &[1., half, 0., 1.]);
+
+        let luma = DynamicImage::ImageLuma16(image::ImageBuffer::from_raw(2, 1, vec![0, 65535]).unwrap());
+        let (format, data) = float_image_data(&luma, ImageOptions::linear());
+        assert_eq!(format, TextureFormat::R16F);
+        assert_floats_near(&data, &[0., 1.]);
+    }
+
+    #[test]
+    fn float_image_srgb_and_premultiplied() {
+        let half = 32768. / 65535.;
+        let rgba = DynamicImage::ImageRgba16(image::ImageBuffer::from_raw(1, 1, vec![32768, 65535, 0, 32768]).unwrap());
+        let options = ImageOptions { premultiply_alpha: true, ..ImageOptions::color() };
+        let (format, data) = float_image_data(&rgba, options);
+        assert_eq!(format, TextureFormat::Rgba16F);
+        // Decoded to linear before premultiplying. Alpha stays linear.
+        assert_floats_near(&data, &[decode_srgb(half) * half, half, 0., half]);
+        assert!((decode_srgb(half) - 0.2140).abs() < 1e-3);
+    }
+
+    #[test]
+    fn read_regions() {
+        let region = |x, y, width, height| RectangleI::new(Vector2I::new(x, y), width, height);
+        assert!(check_read_region(region(0, 0, 4, 3), 4, 3).is_ok());
+        assert!(check_read_region(region(1, 2, 3, 1), 4, 3).is_ok());
+        assert!(check_read_region(region(0, 0, 0, 3), 4, 3).is_err());
+        assert!(check_read_region(region(0, 0, 4, 0), 4, 3).is_err());
+        assert!(check_read_region(region(0, 0, -4, 3), 4, 3).is_err());
+        assert!(check_read_region(region(-1, 0, 2, 2), 4, 3).is_err());
+        assert!(check_read_region(region(1, 0, 4, 3), 4, 3).is_err());
+        assert!(check_read_region(region(0, 1, 4, 3), 4, 3).is_err());
+        assert!(check_read_size(region(0, 0, 0, 0)).is_err());
+        assert!(check_read_size(region(100, 100, 1, 1)).is_ok());
+    }
+
+    #[test]
+    fn image_rows_flipped() {
+        let data = [1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4];
+        let image = image_from_rows(2, 2, &data).unwrap();
+        assert_eq!(image.into_raw(), [3, 3, 3, 3, 4, 4, 4, 4, 1, 1, 1, 1, 2, 2, 2, 2]);
+        assert!(image_from_rows(0, 0, &[]).is_none());
+        assert!(image_from_rows(-2, -2, &data).is_none());
+        assert!(image_from_rows(2, 3, &data).is_none());
+    }
+
+    #[test]
+    fn normalized_formats() {
+        assert!(TextureFormat::Rgba8.is_normalized());
+        assert!(TextureFormat::Srgb8Alpha8.is_normalized());
+        assert!(!TextureFormat::Rgba16F.is_normalized());
+        assert!(!TextureFormat::Rgba8UI.is_normalized());
+        assert!(!TextureFormat::R32I.is_normalized());
+        assert!(!TextureFormat::Depth24.is_normalized());
+    }
+
+    fn read_f32(bytes: &[u8], offset: usize) -> f32 {
+        f32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_i32(bytes: &[u8], offset: usize) -> i32 {
+        i32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn std140_basic_types() {
+        assert_eq!((f32::std140_align(), f32::std140_size()), (4, 4));
+        assert_eq!((Vector2::std140_align(), Vector2::std140_size()), (8, 8));
+        assert_eq!((Vector3::std140_align(), Vector3::std140_size()), (16, 12));
+        assert_eq!((Vector4::std140_align(), Vector4::std140_size()), (16, 16));
+        assert_eq!((Matrix3::std140_align(), Matrix3::std140_size()), (16, 48));
+        assert_eq!((Matrix4::std140_align(), Matrix4::std140_size()), (16, 64));
+        // Array elements are rounded up to vec4s.
+        assert_eq!((<[f32; 3]>::std140_align(), <[f32; 3]>::std140_size()), (16, 48));
+        assert_eq!((<[Vector3; 2]>::std140_align(), <[Vector3; 2]>::std140_size()), (16, 32));
+        assert_eq!((<[Matrix4; 2]>::std140_align(), <[Matrix4; 2]>::std140_size()), (16, 128));
+    }
+
+    #[test]
+    fn std140_struct_offsets() {
+        assert_eq!((Inner::std140_align(), Inner::std140_size()), (16, 16));
+        assert_eq!((Example::std140_align(), Example::std140_size()), (16, 192));
+        let fields: Vec<(String, usize, GlslType)> =
+            std140_fields::<Example>().into_iter().map(|f| (f.name, f.offset, f.type_)).collect();
+        let expected = [
+            ("a", 0, GlslType::Float),
+            ("b", 8, GlslType::Vec2),
+            ("c", 16, GlslType::Vec3),
+            ("f.d", 32, GlslType::Int),
+            ("f.e", 40, GlslType::IVec2),
+            ("g", 48, GlslType::Float),
+            // Arrays of basic types are one member, at their first element.
+            ("h", 64, GlslType::Float),
+            ("m", 96, GlslType::Mat3),
+            ("o[0].d", 144, GlslType::Int),
+            ("o[0].e", 152, GlslType::IVec2),
+            ("o[1].d", 160, GlslType::Int),
+            ("o[1].e", 168, GlslType::IVec2),
+            ("last", 176, GlslType::Float),
+        ];
+        let expected: Vec<(String, usize, GlslType)> = expected.iter().map(|&(n, o, t)| (n.to_string(), o, t)).collect();
+        assert_eq!(fields, expected);
+    }
+
+    #[test]
+    fn std140_struct_bytes() {
+        let example = Example {
+            a: 1.,
+            b: Vector2::new(2., 3.),
+            c: Vector3::new(4., 5., 6.),
+            f: Inner { d: 7, e: Vector2I::new(8, 9) },
+            g: 10.,
+            h: [11., 12.],
+            m: mat3(
+                13., 16., 19.,
+                14., 17., 20.,
+                15., 18., 21.,
+            ),
+            o: [Inner { d: 22, e: Vector2I::new(23, 24) }, Inner { d: 25, e: Vector2I::new(26, 27) }],
+            last: 28.,
+        };
+        let bytes = std140_bytes(&example);
+        assert_eq!(bytes.len(), 192);
+        let floats = [(0, 1.), (8, 2.), (12, 3.), (16, 4.), (20, 5.), (24, 6.), (48, 10.), (64, 11.), (80, 12.), (176, 28.)];
+        for &(offset, value) in floats.iter() {
+            assert_eq!(read_f32(&bytes, offset), value, "at {}", offset);
+        }
+        let ints = [(32, 7), (40, 8), (44, 9), (144, 22), (152, 23), (156, 24), (160, 25), (168, 26), (172, 27)];
+        for &(offset, value) in ints.iter() {
+            assert_eq!(read_i32(&bytes, offset), value, "at {}", offset);
+        }
+        // Columns of 3 floats, each padded to 16 bytes.
+        for column in 0..3 {
+            for row in 0..3 {
+                let offset = 96 + 16 * column + 4 * row;
+                assert_eq!(read_f32(&bytes, offset), (13 + 3 * column + row) as f32, "at {}", offset);
+            }
+            assert_eq!(read_f32(&bytes, 96 + 16 * column + 12), 0.);
+        }
+        // Padding is zero.
+        for offset in [4, 28, 36, 52, 68, 84, 148, 164, 180, 188].iter() {
+            assert_eq!(read_i32(&bytes, *offset), 0, "at {}", offset);
+        }
+    }
+}
@@ -0,0 +1,879 @@
+use crate::math::*;
+use crate::mesh::{Corner, MeshData, PolyMesh};
+use std::collections::HashMap;
+
+// Half-edge representation of a polygon mesh, for walking its topology and
+// editing it. Every half-edge has a twin: edges on a border are paired with a
+// boundary half-edge that has no face, and the boundary half-edges of each
+// hole are linked into a loop.
+//
+// Edges shared by more than two faces can't be represented. They are cut
+// (as if they were borders) and listed by validate().
+//
+// Removed elements are only marked as such, so ids stay valid until the mesh
+// is converted back.
+#[derive(Debug, Clone, Default)]
+pub struct HalfEdgeMesh {
+    vertices: Vec<Vertex>,
+    half_edges: Vec<HalfEdge>,
+    faces: Vec<Face>,
+    non_manifold_edges: Vec<(VertexId, VertexId)>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct VertexId(pub u32);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct HalfEdgeId(pub u32);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct FaceId(pub u32);
+
+#[derive(Debug, Clone)]
+struct Vertex {
+    position: Vector3,
+    // Outgoing. For border vertices, a boundary half-edge.
+    half_edge: Option<HalfEdgeId>,
+    removed: bool,
+}
+
+#[derive(Debug, Clone)]
+struct HalfEdge {
+    origin: VertexId,
+    twin: HalfEdgeId,
+    next: HalfEdgeId,
+    prev: HalfEdgeId,
+    face: Option<FaceId>,
+    // Of the origin, in this half-edge's face.
+    tex_coord: Option<Vector2>,
+    removed: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Face {
+    half_edge: HalfEdgeId,
+    removed: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TopologyReport {
+    pub non_manifold_edges: Vec<(VertexId, VertexId)>,
+    // Vertices where several fans of faces meet at a single point.
+    pub non_manifold_vertices: Vec<VertexId>,
+    // Vertices around each hole.
+    pub boundary_loops: Vec<Vec<VertexId>>,
+}
+
+impl TopologyReport {
+    pub fn is_manifold(&self) -> bool {
+        self.non_manifold_edges.is_empty() && self.non_manifold_vertices.is_empty()
+    }
+
+    pub fn is_watertight(&self) -> bool {
+        self.is_manifold() && self.boundary_loops.is_empty()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EditError {
+    BoundaryEdge,
+    NotTriangle,
+    // The edit would create a duplicate edge or pinch the surface.
+    Topology,
+}
+
+const NONE: HalfEdgeId = HalfEdgeId(u32::MAX);
+
+impl HalfEdgeMesh {
+    pub fn from_poly(mesh: &PolyMesh) -> HalfEdgeMesh {
+        let mut hem = HalfEdgeMesh::default();
+        for &position in mesh.positions.iter() {
+            hem.vertices.push(Vertex { position, half_edge: None, removed: false });
+        }
+
+        let mut directed: HashMap<(u32, u32), Vec<HalfEdgeId>> = HashMap::new();
+        for face in mesh.faces.iter() {
+            let k = face.len();
+            if k < 3 || (0..k).any(|i| face[i].position == face[(i + 1) % k].position) {
+                continue;
+            }
+            let f = FaceId(hem.faces.len() as u32);
+            let h0 = hem.half_edges.len() as u32;
+            hem.faces.push(Face { half_edge: HalfEdgeId(h0), removed: false });
+            for i in 0..k as u32 {
+                let corner = face[i as usize];
+                let next = face[(i as usize + 1) % k].position;
+                hem.half_edges.push(HalfEdge {
+                    origin: VertexId(corner.position),
+                    twin: NONE,
+                    next: HalfEdgeId(h0 + (i + 1) % k as u32),
+                    prev: HalfEdgeId(h0 + (i + k as u32 - 1) % k as u32),
+                    face: Some(f),
+                    tex_coord: corner.tex_coord.map(|t| mesh.tex_coords[t as usize]),
+                    removed: false,
+                });
+                directed.entry((corner.position, next)).or_default().push(HalfEdgeId(h0 + i));
+            }
+        }
+
+        for (&(a, b), hs) in directed.iter() {
+            if a > b && directed.contains_key(&(b, a)) {
+                continue;
+            }
+            let ts = directed.get(&(b, a)).map_or(&[][..], |v| &v[..]);
+            if hs.len() == 1 && ts.len() == 1 {
+                hem.half_edges[hs[0].0 as usize].twin = ts[0];
+                hem.half_edges[ts[0].0 as usize].twin = hs[0];
+            } else if hs.len() + ts.len() > 1 {
+                hem.non_manifold_edges.push((VertexId(a.min(b)), VertexId(a.max(b))));
+            }
+        }
+
+        // Boundary half-edges for everything left unpaired.
+        let interior_count = hem.half_edges.len();
+        for i in 0..interior_count {
+            if hem.half_edges[i].twin != NONE {
+                continue;
+            }
+            let h = HalfEdgeId(i as u32);
+            let b = HalfEdgeId(hem.half_edges.len() as u32);
+            hem.half_edges.push(HalfEdge {
+                origin: hem.origin(hem.next(h)),
+                twin: h,
+                next: NONE,
+                prev: NONE,
+                face: None,
+                tex_coord: None,
+                removed: false,
+            });
+            hem.half_edges[i].twin = b;
+        }
+
+        // Link each boundary half-edge to the next one along the hole, found
+        // by turning around its destination until leaving the faces.
+        for i in interior_count..hem.half_edges.len() {
+            let b = HalfEdgeId(i as u32);
+            let mut h = hem.twin(b);
+            for _ in 0..hem.half_edges.len() {
+                let out = hem.twin(hem.prev(h));
+                if hem.face(out).is_none() {
+                    h = out;
+                    break;
+                }
+                h = out;
+            }
+            hem.half_edges[i].next = h;
+            hem.half_edges[h.0 as usize].prev = b;
+        }
+
+        for i in 0..hem.half_edges.len() {
+            let h = &hem.half_edges[i];
+            let v = &mut hem.vertices[h.origin.0 as usize];
+            if v.half_edge.is_none() || h.face.is_none() {
+                v.half_edge = Some(HalfEdgeId(i as u32));
+            }
+        }
+        hem
+    }
+
+    // Welds vertices by position.
+    pub fn from_data(data: &MeshData) -> HalfEdgeMesh {
+        HalfEdgeMesh::from_poly(&PolyMesh::from_data(data))
+    }
+
+    // Compacts away removed elements. Normals are recomputed.
+    pub fn to_poly(&self) -> PolyMesh {
+        let mut mesh = PolyMesh::default();
+        let mut vertex_remap = vec![u32::MAX; self.vertices.len()];
+        for (i, v) in self.vertices.iter().enumerate() {
+            if !v.removed {
+                vertex_remap[i] = mesh.positions.len() as u32;
+                mesh.positions.push(v.position);
+            }
+        }
+        for f in self.faces() {
+            let mut face = Vec::new();
+            for h in self.face_half_edges(f) {
+                let he = &self.half_edges[h.0 as usize];
+                let tex_coord = he.tex_coord.map(|uv| {
+                    mesh.tex_coords.push(uv);
+                    mesh.tex_coords.len() as u32 - 1
+                });
+                face.push(Corner { position: vertex_remap[he.origin.0 as usize], tex_coord, normal: None });
+            }
+            mesh.faces.push(face);
+        }
+        mesh.compute_normals();
+        mesh
+    }
+
+    pub fn to_data(&self) -> MeshData {
+        self.to_poly().triangulate()
+    }
+
+    // Element access.
+
+    pub fn vertices(&self) -> impl Iterator<Item = VertexId> + '_ {
+        (0..self.vertices.len() as u32).map(VertexId).filter(move |v| !self.vertices[v.0 as usize].removed)
+    }
+
+    pub fn faces(&self) -> impl Iterator<Item = FaceId> + '_ {
+        (0..self.faces.len() as u32).map(FaceId).filter(move |f| !self.faces[f.0 as usize].removed)
+    }
+
+    pub fn half_edges(&self) -> impl Iterator<Item = HalfEdgeId> + '_ {
+        (0..self.half_edges.len() as u32).map(HalfEdgeId).filter(move |h| !self.half_edges[h.0 as usize].removed)
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.vertices().count()
+    }
+
+    pub fn face_count(&self) -> usize {
+        self.faces().count()
+    }
+
+    pub fn position(&self, v: VertexId) -> Vector3 {
+        self.vertices[v.0 as usize].position
+    }
+
+    pub fn set_position(&mut self, v: VertexId, position: Vector3) {
+        self.vertices[v.0 as usize].position = position;
+    }
+
+    pub fn vertex_half_edge(&self, v: VertexId) -> Option<HalfEdgeId> {
+        self.vertices[v.0 as usize].half_edge
+    }
+
+    pub fn face_half_edge(&self, f: FaceId) -> HalfEdgeId {
+        self.faces[f.0 as usize].half_edge
+    }
+
+    pub fn origin(&self, h: HalfEdgeId) -> VertexId {
+        self.half_edges[h.0 as usize].origin
+    }
+
+    pub fn dest(&self, h: HalfEdgeId) -> VertexId {
+        self.origin(self.twin(h))
+    }
+
+    pub fn twin(&self, h: HalfEdgeId) -> HalfEdgeId {
+        self.half_edges[h.0 as usize].twin
+    }
+
+    pub fn next(&self, h: HalfEdgeId) -> HalfEdgeId {
+        self.half_edges[h.0 as usize].next
+    }
+
+    pub fn prev(&self, h: HalfEdgeId) -> HalfEdgeId {
+        self.half_edges[h.0 as usize].prev
+    }
+
+    pub fn face(&self, h: HalfEdgeId) -> Option<FaceId> {
+        self.half_edges[h.0 as usize].face
+    }
+
+    pub fn tex_coord(&self, h: HalfEdgeId) -> Option<Vector2> {
+        self.half_edges[h.0 as usize].tex_coord
+    }
+
+    pub fn is_boundary(&self, h: HalfEdgeId) -> bool {
+        self.face(h).is_none()
+    }
+
+    pub fn is_boundary_edge(&self, h: HalfEdgeId) -> bool {
+        self.is_boundary(h) || self.is_boundary(self.twin(h))
+    }
+
+    pub fn is_boundary_vertex(&self, v: VertexId) -> bool {
+        self.vertex_half_edge(v).is_none_or(|h| self.is_boundary(h))
+    }
+
+    // Adjacency.
+
+    pub fn outgoing(&self, v: VertexId) -> impl Iterator<Item = HalfEdgeId> + '_ {
+        let start = self.vertex_half_edge(v);
+        let mut h = start;
+        std::iter::from_fn(move || {
+            let current = h?;
+            let next = self.twin(self.prev(current));
+            h = if Some(next) == start { None } else { Some(next) };
+            Some(current)
+        })
+    }
+
+    pub fn neighbors(&self, v: VertexId) -> impl Iterator<Item = VertexId> + '_ {
+        self.outgoing(v).map(move |h| self.dest(h))
+    }
+
+    pub fn vertex_faces(&self, v: VertexId) -> impl Iterator<Item = FaceId> + '_ {
+        self.outgoing(v).filter_map(move |h| self.face(h))
+    }
+
+    pub fn valence(&self, v: VertexId) -> usize {
+        self.outgoing(v).count()
+    }
+
+    // Half-edges around a face, or around a hole if h is a boundary half-edge.
+    pub fn loop_half_edges(&self, h: HalfEdgeId) -> impl Iterator<Item = HalfEdgeId> + '_ {
+        let mut current = Some(h);
+        std::iter::from_fn(move || {
+            let c = current?;
+            let next = self.next(c);
+            current = if next == h { None } else { Some(next) };
+            Some(c)
+        })
+    }
+
+    pub fn face_half_edges(&self, f: FaceId) -> impl Iterator<Item = HalfEdgeId> + '_ {
+        self.loop_half_edges(self.face_half_edge(f))
+    }
+
+    pub fn face_vertices(&self, f: FaceId) -> impl Iterator<Item = VertexId> + '_ {
+        self.face_half_edges(f).map(move |h| self.origin(h))
+    }
+
+    pub fn face_neighbors(&self, f: FaceId) -> impl Iterator<Item = FaceId> + '_ {
+        self.face_half_edges(f).filter_map(move |h| self.face(self.twin(h)))
+    }
+
+    pub fn face_normal(&self, f: FaceId) -> Vector3 {
+        let points: Vec<Vector3> = self.face_vertices(f).map(|v| self.position(v)).collect();
+        let mut n = Vector3::ZERO;
+        for i in 1..points.len() - 1 {
+            n += Vector3::cross(points[i] - points[0], points[i + 1] - points[0]);
+        }
+        n.normalized()
+    }
+
+    pub fn find_half_edge(&self, a: VertexId, b: VertexId) -> Option<HalfEdgeId> {
+        self.outgoing(a).find(|&h| self.dest(h) == b)
+    }
+
+    // Validation.
+
+    pub fn validate(&self) -> TopologyReport {
+        let mut report = TopologyReport {
+            non_manifold_edges: self.non_manifold_edges.clone(),
+            ..Default::default()
+        };
+
+        // All outgoing half-edges of a vertex must be reachable from one fan.
+        let mut outgoing_count = vec![0; self.vertices.len()];
+        for h in self.half_edges() {
+            outgoing_count[self.origin(h).0 as usize] += 1;
+        }
+        for v in self.vertices() {
+            if self.outgoing(v).count() != outgoing_count[v.0 as usize] {
+                report.non_manifold_vertices.push(v);
+            }
+        }
+
+        report.boundary_loops = self.boundary_loops();
+        report
+    }
+
+    pub fn boundary_loops(&self) -> Vec<Vec<VertexId>> {
+        let mut visited = vec![false; self.half_edges.len()];
+        let mut loops = Vec::new();
+        for h in self.half_edges() {
+            if !self.is_boundary(h) || visited[h.0 as usize] {
+                continue;
+            }
+            let mut vertices = Vec::new();
+            for b in self.loop_half_edges(h) {
+                visited[b.0 as usize] = true;
+                vertices.push(self.origin(b));
+            }
+            loops.push(vertices);
+        }
+        loops
+    }
+
+    // Edits.
+
+    // Turns the edge between two triangles into the other diagonal of their quad.
+    pub fn flip_edge(&mut self, h: HalfEdgeId) -> Result<(), EditError> {
+        let t = self.twin(h);
+        let (f1, f2) = match (self.face(h), self.face(t)) {
+            (Some(f1), Some(f2)) => (f1, f2),
+            _ => return Err(EditError::BoundaryEdge),
+        };
+        if self.face_half_edges(f1).count() != 3 || self.face_half_edges(f2).count() != 3 {
+            return Err(EditError::NotTriangle);
+        }
+        // f1 = (a, b, c), f2 = (b, a, d) becomes (c, a, d), (d, b, c).
+        let (h1, h2) = (self.next(h), self.prev(h));
+        let (t1, t2) = (self.next(t), self.prev(t));
+        let (a, b) = (self.origin(h), self.origin(t));
+        let c = self.origin(h2);
+        let d = self.origin(t2);
+        if c == d || self.find_half_edge(c, d).is_some() {
+            return Err(EditError::Topology);
+        }
+
+        let h_uv = self.tex_coord(t2);
+        let t_uv = self.tex_coord(h2);
+        self.link_face(f1, &[h2, t1, h]);
+        self.link_face(f2, &[t2, h1, t]);
+        self.half_edges[h.0 as usize].origin = d;
+        self.half_edges[h.0 as usize].tex_coord = h_uv;
+        self.half_edges[t.0 as usize].origin = c;
+        self.half_edges[t.0 as usize].tex_coord = t_uv;
+        if self.vertex_half_edge(a) == Some(h) {
+            self.vertices[a.0 as usize].half_edge = Some(t1);
+        }
+        if self.vertex_half_edge(b) == Some(t) {
+            self.vertices[b.0 as usize].half_edge = Some(h1);
+        }
+        Ok(())
+    }
+
+    // Inserts a vertex at the middle of the edge, and splits the triangles on
+    // either side of it in two.
+    pub fn split_edge(&mut self, h: HalfEdgeId) -> Result<VertexId, EditError> {
+        let t = self.twin(h);
+        for &side in [h, t].iter() {
+            if let Some(f) = self.face(side) {
+                if self.face_half_edges(f).count() != 3 {
+                    return Err(EditError::NotTriangle);
+                }
+            }
+        }
+        let (a, b) = (self.origin(h), self.origin(t));
+        let m = VertexId(self.vertices.len() as u32);
+        self.vertices.push(Vertex {
+            position: 0.5 * (self.position(a) + self.position(b)),
+            half_edge: None,
+            removed: false,
+        });
+
+        // h: a -> m, h2: m -> b, t: b -> m, t2: m -> a.
+        let h2 = self.insert_after(h, m);
+        let t2 = self.insert_after(t, m);
+        self.set_twins(h, t2);
+        self.set_twins(t, h2);
+        self.vertices[m.0 as usize].half_edge = Some(if self.is_boundary(h2) { h2 } else { t2 });
+
+        for &(first, second) in [(h, h2), (t, t2)].iter() {
+            if self.face(first).is_some() {
+                // Loop is (x, m, y, z) with first: x -> m. Connect m to z.
+                let uv = match (self.tex_coord(first), self.tex_coord(self.next(second))) {
+                    (Some(u0), Some(u1)) => Some(0.5 * (u0 + u1)),
+                    _ => None,
+                };
+                self.half_edges[second.0 as usize].tex_coord = uv;
+                let z = self.prev(first);
+                self.connect(second, z);
+            }
+        }
+        Ok(m)
+    }
+
+    // Merges the two ends of the edge into a vertex at its middle, removing
+    // the triangles on either side of it.
+    pub fn collapse_edge(&mut self, h: HalfEdgeId) -> Result<VertexId, EditError> {
+        let t = self.twin(h);
+        let (a, b) = (self.origin(h), self.origin(t));
+        let mut sides = 0;
+        for &side in [h, t].iter() {
+            if let Some(f) = self.face(side) {
+                if self.face_half_edges(f).count() != 3 {
+                    return Err(EditError::NotTriangle);
+                }
+                sides += 1;
+            }
+        }
+        // Link condition.
+        let a_neighbors: Vec<VertexId> = self.neighbors(a).collect();
+        let common = self.neighbors(b).filter(|v| a_neighbors.contains(v)).count();
+        if common != sides {
+            return Err(EditError::Topology);
+        }
+        if sides == 2 && self.is_boundary_vertex(a) && self.is_boundary_vertex(b) {
+            return Err(EditError::Topology);
+        }
+
+        let b_outgoing: Vec<HalfEdgeId> = self.outgoing(b).collect();
+        let midpoint = 0.5 * (self.position(a) + self.position(b));
+
+        for &side in [h, t].iter() {
+            match self.face(side) {
+                Some(f) => {
+                    // Triangle (x, y, c) with side: x -> y. Its other two edges
+                    // become one, joining their outer twins.
+                    let (n, p) = (self.next(side), self.prev(side));
+                    let (outer_n, outer_p) = (self.twin(n), self.twin(p));
+                    self.set_twins(outer_n, outer_p);
+                    let c = self.origin(p);
+                    for &r in [side, n, p].iter() {
+                        self.half_edges[r.0 as usize].removed = true;
+                    }
+                    self.faces[f.0 as usize].removed = true;
+                    self.fix_vertex_half_edge(c);
+                }
+                None => {
+                    let (n, p) = (self.next(side), self.prev(side));
+                    self.half_edges[p.0 as usize].next = n;
+                    self.half_edges[n.0 as usize].prev = p;
+                    self.half_edges[side.0 as usize].removed = true;
+                }
+            }
+        }
+
+        for r in b_outgoing {
+            if !self.half_edges[r.0 as usize].removed {
+                self.half_edges[r.0 as usize].origin = a;
+            }
+        }
+        self.vertices[b.0 as usize].removed = true;
+        self.vertices[b.0 as usize].half_edge = None;
+        self.vertices[a.0 as usize].position = midpoint;
+        self.vertices[a.0 as usize].half_edge = None;
+        self.fix_vertex_half_edge(a);
+        Ok(a)
+    }
+
+    // Moves the face along its normal, connecting it to where it was with a
+    // ring of quads. Returns the moved face.
+    pub fn extrude_face(&mut self, f: FaceId, distance: f32) -> FaceId {
+        let offset = distance * self.face_normal(f);
+        let ring: Vec<HalfEdgeId> = self.face_half_edges(f).collect();
+        let k = ring.len();
+        let bottom: Vec<VertexId> = ring.iter().map(|&h| self.origin(h)).collect();
+        let top: Vec<VertexId> = bottom
+            .iter()
+            .map(|&v| {
+                self.vertices.push(Vertex { position: self.position(v) + offset, half_edge: None, removed: false });
+                VertexId(self.vertices.len() as u32 - 1)
+            })
+            .collect();
+
+        // Side quad i is (bottom[i], bottom[i+1], top[i+1], top[i]).
+        let mut sides = Vec::with_capacity(k);
+        for i in 0..k {
+            let j = (i + 1) % k;
+            let side_face = FaceId(self.faces.len() as u32);
+            let uv_i = self.tex_coord(ring[i]);
+            let uv_j = self.tex_coord(ring[j]);
+            let s: Vec<HalfEdgeId> = [(bottom[i], uv_i), (bottom[j], uv_j), (top[j], uv_j), (top[i], uv_i)]
+                .iter()
+                .map(|&(origin, tex_coord)| self.push_half_edge(origin, Some(side_face), tex_coord))
+                .collect();
+            self.faces.push(Face { half_edge: s[0], removed: false });
+            self.link_face(side_face, &s);
+            sides.push(s);
+        }
+        for i in 0..k {
+            let j = (i + 1) % k;
+            let outer = self.twin(ring[i]);
+            self.set_twins(sides[i][0], outer);
+            self.set_twins(sides[i][2], ring[i]);
+            self.set_twins(sides[i][1], sides[j][3]);
+            self.half_edges[ring[i].0 as usize].origin = top[i];
+            self.vertices[top[i].0 as usize].half_edge = Some(ring[i]);
+            if self.vertex_half_edge(bottom[i]) == Some(ring[i]) {
+                self.vertices[bottom[i].0 as usize].half_edge = Some(sides[i][0]);
+            }
+        }
+        f
+    }
+
+    // Helpers for the edits.
+
+    fn push_half_edge(&mut self, origin: VertexId, face: Option<FaceId>, tex_coord: Option<Vector2>) -> HalfEdgeId {
+        self.half_edges.push(HalfEdge { origin, twin: NONE, next: NONE, prev: NONE, face, tex_coord, removed: false });
+        HalfEdgeId(self.half_edges.len() as u32 - 1)
+    }
+
+    fn set_twins(&mut self, a: HalfEdgeId, b: HalfEdgeId) {
+        self.half_edges[a.0 as usize].twin = b;
+        self.half_edges[b.0 as usize].twin = a;
+    }
+
+    fn link_face(&mut self, f: FaceId, loop_: &[HalfEdgeId]) {
+        let k = loop_.len();
+        for i in 0..k {
+            let he = &mut self.half_edges[loop_[i].0 as usize];
+            he.next = loop_[(i + 1) % k];
+            he.prev = loop_[(i + k - 1) % k];
+            he.face = Some(f);
+        }
+        self.faces[f.0 as usize].half_edge = loop_[0];
+    }
+
+    // Splits h at a new vertex v, returning the second half (v -> old dest).
+    // Twins are left for the caller to fix.
+    fn insert_after(&mut self, h: HalfEdgeId, v: VertexId) -> HalfEdgeId {
+        let face = self.face(h);
+        let n = self.next(h);
+        let h2 = self.push_half_edge(v, face, None);
+        self.half_edges[h2.0 as usize].next = n;
+        self.half_edges[h2.0 as usize].prev = h;
+        self.half_edges[h.0 as usize].next = h2;
+        self.half_edges[n.0 as usize].prev = h2;
+        h2
+    }
+
+    // Splits the face containing u and w with a new edge between their origins.
+    // The old face keeps the part starting at u -> w.
+    fn connect(&mut self, u: HalfEdgeId, w: HalfEdgeId) {
+        let old_face = self.face(u).unwrap();
+        let new_face = FaceId(self.faces.len() as u32);
+        self.faces.push(Face { half_edge: u, removed: false });
+        let (pu, pw) = (self.prev(u), self.prev(w));
+        let (ou, ow) = (self.origin(u), self.origin(w));
+        let e1 = self.push_half_edge(ou, Some(old_face), self.tex_coord(u));
+        let e2 = self.push_half_edge(ow, Some(new_face), self.tex_coord(w));
+        self.set_twins(e1, e2);
+        self.half_edges[pu.0 as usize].next = e1;
+        self.half_edges[e1.0 as usize].prev = pu;
+        self.half_edges[e1.0 as usize].next = w;
+        self.half_edges[w.0 as usize].prev = e1;
+        self.half_edges[pw.0 as usize].next = e2;
+        self.half_edges[e2.0 as usize].prev = pw;
+        self.half_edges[e2.0 as usize].next = u;
+        self.half_edges[u.0 as usize].prev = e2;
+        self.faces[old_face.0 as usize].half_edge = e1;
+        let new_loop: Vec<HalfEdgeId> = self.loop_half_edges(e2).collect();
+        for h in new_loop {
+            self.half_edges[h.0 as usize].face = Some(new_face);
+        }
+    }
+
+    // Points the vertex at a live outgoing half-edge, preferring boundary ones.
+    fn fix_vertex_half_edge(&mut self, v: VertexId) {
+        let mut best: Option<HalfEdgeId> = None;
+        for (i, h) in self.half_edges.iter().enumerate() {
+            if h.removed || h.origin != v {
+                continue;
+            }
+            if best.is_none() || h.face.is_none() {
+                best = Some(HalfEdgeId(i as u32));
+            }
+        }
+        self.vertices[v.0 as usize].half_edge = best;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ico() -> PolyMesh {
+        PolyMesh::load_obj(&std::fs::read_to_string("resources/ico.obj").unwrap())
+    }
+
+    // Links that validate doesn't look at, since the edits keep them by
+    // construction.
+    fn check_links(hem: &HalfEdgeMesh) {
+        for h in hem.half_edges() {
+            let t = hem.twin(h);
+            assert!(!hem.half_edges[t.0 as usize].removed, "{:?} has a removed twin", h);
+            assert_eq!(hem.twin(t), h);
+            assert_ne!(hem.origin(h), hem.origin(t), "{:?} is a loop", h);
+            assert_eq!(hem.prev(hem.next(h)), h);
+            assert_eq!(hem.origin(hem.next(h)), hem.dest(h));
+            assert_eq!(hem.face(hem.next(h)), hem.face(h));
+        }
+        for f in hem.faces() {
+            assert!(hem.face_half_edges(f).all(|h| hem.face(h) == Some(f)));
+            assert!(hem.face_half_edges(f).count() >= 3);
+        }
+        for v in hem.vertices() {
+            let h = hem.vertex_half_edge(v).unwrap();
+            assert_eq!(hem.origin(h), v);
+        }
+    }
+
+    fn check_closed(hem: &HalfEdgeMesh) {
+        check_links(hem);
+        let report = hem.validate();
+        assert!(report.is_watertight(), "{:?}", report);
+        // A sphere, however it was edited.
+        let edges = hem.half_edges().count() / 2;
+        assert_eq!(hem.vertex_count() as i32 - edges as i32 + hem.face_count() as i32, 2);
+    }
+
+    fn sorted_faces(mesh: &PolyMesh) -> Vec<[[u32; 3]; 3]> {
+        let mut faces: Vec<[[u32; 3]; 3]> = mesh
+            .faces
+            .iter()
+            .map(|face| {
+                let mut points: Vec<[u32; 3]> = face.iter().map(|c| {
+                    let p = mesh.positions[c.position as usize];
+                    [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()]
+                }).collect();
+                // Same winding, from the smallest corner.
+                let first = (0..3).min_by_key(|&i| points[i]).unwrap();
+                points.rotate_left(first);
+                [points[0], points[1], points[2]]
+            })
+            .collect();
+        faces.sort();
+        faces
+    }
+
+    #[test]
+    fn round_trip() {
+        let mesh = ico();
+        let hem = HalfEdgeMesh::from_poly(&mesh);
+        assert_eq!(hem.vertex_count(), 42);
+        assert_eq!(hem.face_count(), 80);
+        check_closed(&hem);
+        assert!(hem.vertices().all(|v| hem.valence(v) == 5 || hem.valence(v) == 6));
+        let back = hem.to_poly();
+        assert_eq!(back.positions, mesh.positions);
+        assert_eq!(sorted_faces(&back), sorted_faces(&mesh));
+        assert_eq!(hem.to_data().triangle_count(), 80);
+        // Texture coordinates stay with their face corners.
+        for (face, back_face) in mesh.faces.iter().zip(back.faces.iter()) {
+            for (c, b) in face.iter().zip(back_face.iter()) {
+                assert_eq!(mesh.tex_coords[c.tex_coord.unwrap() as usize], back.tex_coords[b.tex_coord.unwrap() as usize]);
+            }
+        }
+    }
+
+    #[test]
+    fn one_open_face_is_one_boundary_loop() {
+        let mut mesh = ico();
+        let removed = mesh.faces.remove(7);
+        let hem = HalfEdgeMesh::from_poly(&mesh);
+        check_links(&hem);
+        let report = hem.validate();
+        assert!(report.is_manifold());
+        assert!(!report.is_watertight());
+        assert_eq!(report.boundary_loops.len(), 1);
+        let mut hole: Vec<u32> = report.boundary_loops[0].iter().map(|v| v.0).collect();
+        let mut expected: Vec<u32> = removed.iter().map(|c| c.position).collect();
+        hole.sort();
+        expected.sort();
+        assert_eq!(hole, expected);
+        assert!(hem.vertices().filter(|&v| hem.is_boundary_vertex(v)).count() == 3);
+    }
+
+    #[test]
+    fn non_manifold_edge_reported() {
+        // Three triangles on one edge.
+        let positions = vec![Vector3::ZERO, Vector3::X, Vector3::Y, -Vector3::Y, Vector3::Z];
+        let corner = |position| Corner { position, tex_coord: None, normal: None };
+        let faces = [[0, 1, 2], [1, 0, 3], [0, 1, 4]].iter().map(|f| f.iter().map(|&p| corner(p)).collect()).collect();
+        let hem = HalfEdgeMesh::from_poly(&PolyMesh { positions, faces, ..Default::default() });
+        let report = hem.validate();
+        assert_eq!(report.non_manifold_edges, [(VertexId(0), VertexId(1))]);
+        assert!(!report.is_manifold());
+    }
+
+    fn some_edge(hem: &HalfEdgeMesh) -> HalfEdgeId {
+        hem.half_edges().find(|&h| !hem.is_boundary_edge(h)).unwrap()
+    }
+
+    #[test]
+    fn flip() {
+        let mut hem = HalfEdgeMesh::from_poly(&ico());
+        let h = some_edge(&hem);
+        let (a, b) = (hem.origin(h), hem.dest(h));
+        hem.flip_edge(h).unwrap();
+        check_closed(&hem);
+        assert_eq!(hem.face_count(), 80);
+        assert!(hem.find_half_edge(a, b).is_none());
+        assert_eq!(hem.valence(a) + hem.valence(b), 9);
+        // Flipping back gives the same edge.
+        hem.flip_edge(h).unwrap();
+        check_closed(&hem);
+        assert!(hem.find_half_edge(a, b).is_some());
+    }
+
+    #[test]
+    fn split() {
+        let mut hem = HalfEdgeMesh::from_poly(&ico());
+        let h = some_edge(&hem);
+        let (a, b) = (hem.position(hem.origin(h)), hem.position(hem.dest(h)));
+        let m = hem.split_edge(h).unwrap();
+        check_closed(&hem);
+        assert_eq!(hem.vertex_count(), 43);
+        assert_eq!(hem.face_count(), 82);
+        assert_eq!(hem.valence(m), 4);
+        assert_eq!(hem.position(m), 0.5 * (a + b));
+        // Texture coordinates of the new corners are interpolated.
+        assert!(hem.outgoing(m).filter(|&h| !hem.is_boundary(h)).all(|h| hem.tex_coord(h).is_some()));
+    }
+
+    #[test]
+    fn split_border() {
+        let mut mesh = ico();
+        mesh.faces.remove(0);
+        let mut hem = HalfEdgeMesh::from_poly(&mesh);
+        let h = hem.half_edges().find(|&h| hem.is_boundary(h)).unwrap();
+        assert_eq!(hem.flip_edge(h), Err(EditError::BoundaryEdge));
+        hem.split_edge(h).unwrap();
+        check_links(&hem);
+        assert_eq!(hem.face_count(), 80);
+        let loops = hem.boundary_loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].len(), 4);
+        assert!(hem.validate().is_manifold());
+    }
+
+    #[test]
+    fn collapse() {
+        let mut hem = HalfEdgeMesh::from_poly(&ico());
+        let mut collapsed = 0;
+        let mut rejected = 0;
+        while collapsed < 10 {
+            // Keep collapsing into one corner until it pinches, then move on.
+            let h = hem.half_edges().nth(rejected).unwrap();
+            match hem.collapse_edge(h) {
+                Ok(_) => collapsed += 1,
+                Err(e) => {
+                    assert_eq!(e, EditError::Topology);
+                    rejected += 1;
+                }
+            }
+            check_closed(&hem);
+        }
+        assert!(rejected > 0);
+        assert_eq!(hem.vertex_count(), 32);
+        assert_eq!(hem.face_count(), 60);
+        assert_eq!(hem.to_poly().positions.len(), 32);
+    }
+
+    #[test]
+    fn collapse_rejects_pinching() {
+        // Two tetrahedra on a shared triangle. Collapsing an edge of that
+        // triangle would flatten one of them.
+        let positions = vec![Vector3::X, Vector3::Y, -Vector3::X, Vector3::Z, -Vector3::Z];
+        let corner = |position| Corner { position, tex_coord: None, normal: None };
+        let faces = [[3, 0, 1], [3, 1, 2], [3, 2, 0], [4, 1, 0], [4, 2, 1], [4, 0, 2]]
+            .iter()
+            .map(|f| f.iter().map(|&p| corner(p)).collect())
+            .collect();
+        let mut hem = HalfEdgeMesh::from_poly(&PolyMesh { positions, faces, ..Default::default() });
+        check_closed(&hem);
+        let h = hem.find_half_edge(VertexId(0), VertexId(1)).unwrap();
+        assert_eq!(hem.collapse_edge(h), Err(EditError::Topology));
+        check_closed(&hem);
+        assert_eq!(hem.vertex_count(), 5);
+        // The edges to the apexes are fine.
+        let h = hem.find_half_edge(VertexId(3), VertexId(0)).unwrap();
+        hem.collapse_edge(h).unwrap();
+        check_closed(&hem);
+    }
+
+    #[test]
+    fn extrude() {
+        let mut hem = HalfEdgeMesh::from_poly(&ico());
+        let f = hem.faces().next().unwrap();
+        let before: Vec<Vector3> = hem.face_vertices(f).map(|v| hem.position(v)).collect();
+        let normal = hem.face_normal(f);
+        let top = hem.extrude_face(f, 0.5);
+        check_closed(&hem);
+        assert_eq!(hem.vertex_count(), 45);
+        assert_eq!(hem.face_count(), 83);
+        let after: Vec<Vector3> = hem.face_vertices(top).map(|v| hem.position(v)).collect();
+        for (b, a) in before.iter().zip(after.iter()) {
+            assert!((*a - (*b + 0.5 * normal)).norm() < 1e-6);
+        }
+        assert!(hem.face_neighbors(top).all(|side| hem.face_half_edges(side).count() == 4));
+    }
+}
@@ -0,0 +1,401 @@
+use crate::gpu;
+use crate::image;
+use crate::math::*;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use std::sync::{Mutex, MutexGuard};
+
+// A GLES 3.1 context without a window, through EGL, for offline rendering
+// and for running the gpu code where there is no display, as in CI. With
+// Mesa it works on the llvmpipe software driver, using the surfaceless
+// platform when there is one. Drawing goes to an offscreen framebuffer.
+
+type EGLDisplay = *mut c_void;
+type EGLConfig = *mut c_void;
+type EGLContext = *mut c_void;
+type EGLSurface = *mut c_void;
+type EGLint = i32;
+type EGLBoolean = u32;
+type EGLenum = u32;
+
+const EGL_NO_DISPLAY: EGLDisplay = ptr::null_mut();
+const EGL_NO_CONTEXT: EGLContext = ptr::null_mut();
+const EGL_NO_SURFACE: EGLSurface = ptr::null_mut();
+const EGL_DEFAULT_DISPLAY: *mut c_void = ptr::null_mut();
+
+const EGL_ALPHA_SIZE: EGLint = 0x3021;
+const EGL_BLUE_SIZE: EGLint = 0x3022;
+const EGL_GREEN_SIZE: EGLint = 0x3023;
+const EGL_RED_SIZE: EGLint = 0x3024;
+const EGL_DEPTH_SIZE: EGLint = 0x3025;
+const EGL_SURFACE_TYPE: EGLint = 0x3033;
+const EGL_NONE: EGLint = 0x3038;
+const EGL_RENDERABLE_TYPE: EGLint = 0x3040;
+const EGL_HEIGHT: EGLint = 0x3056;
+const EGL_WIDTH: EGLint = 0x3057;
+const EGL_CONTEXT_MAJOR_VERSION: EGLint = 0x3098;
+const EGL_CONTEXT_MINOR_VERSION: EGLint = 0x30FB;
+const EGL_PBUFFER_BIT: EGLint = 0x0001;
+const EGL_OPENGL_ES3_BIT: EGLint = 0x0040;
+const EGL_EXTENSIONS: EGLint = 0x3055;
+const EGL_OPENGL_ES_API: EGLenum = 0x30A0;
+const EGL_PLATFORM_SURFACELESS_MESA: EGLenum = 0x31DD;
+
+// EGL displays are shared by the whole process, and terminating one ends
+// every context on it, so contexts are made one at a time.
+static CONTEXT_LOCK: Mutex<()> = Mutex::new(());
+
+type GetPlatformDisplay = unsafe extern "C" fn(EGLenum, *mut c_void, *const EGLint) -> EGLDisplay;
+
+#[link(name = "EGL")]
+extern "C" {
+    fn eglGetError() -> EGLint;
+    fn eglGetProcAddress(name: *const c_char) -> *const c_void;
+    fn eglQueryString(display: EGLDisplay, name: EGLint) -> *const c_char;
+    fn eglGetDisplay(display_id: *mut c_void) -> EGLDisplay;
+    fn eglInitialize(display: EGLDisplay, major: *mut EGLint, minor: *mut EGLint) -> EGLBoolean;
+    fn eglTerminate(display: EGLDisplay) -> EGLBoolean;
+    fn eglBindAPI(api: EGLenum) -> EGLBoolean;
+    fn eglChooseConfig(
+        display: EGLDisplay,
+        attributes: *const EGLint,
+        configs: *mut EGLConfig,
+        config_size: EGLint,
+        config_count: *mut EGLint,
+    ) -> EGLBoolean;
+    fn eglCreateContext(display: EGLDisplay, config: EGLConfig, share: EGLContext, attributes: *const EGLint) -> EGLContext;
+    fn eglDestroyContext(display: EGLDisplay, context: EGLContext) -> EGLBoolean;
+    fn eglCreatePbufferSurface(display: EGLDisplay, config: EGLConfig, attributes: *const EGLint) -> EGLSurface;
+    fn eglDestroySurface(display: EGLDisplay, surface: EGLSurface) -> EGLBoolean;
+    fn eglMakeCurrent(display: EGLDisplay, draw: EGLSurface, read: EGLSurface, context: EGLContext) -> EGLBoolean;
+}
+
+pub struct HeadlessContext {
+    display: EGLDisplay,
+    context: EGLContext,
+    // Only without EGL_KHR_surfaceless_context, a 1x1 pbuffer to make the
+    // context current with. Nothing is drawn to it.
+    surface: EGLSurface,
+    // Dropped before the context.
+    framebuffer: Option<gpu::Framebuffer>,
+    // Released after the display is terminated.
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl HeadlessContext {
+    // Makes the context current on this thread, loads GL, and binds a
+    // framebuffer of the given size with an RGBA8 target and depth.
+    pub fn new(width: i32, height: i32) -> Result<HeadlessContext, String> {
+        let mut context = HeadlessContext {
+            display: EGL_NO_DISPLAY,
+            context: EGL_NO_CONTEXT,
+            surface: EGL_NO_SURFACE,
+            framebuffer: None,
+            // A panic while holding it leaves nothing to recover.
+            _lock: CONTEXT_LOCK.lock().unwrap_or_else(|e| e.into_inner()),
+        };
+        unsafe {
+            context.display = platform_display();
+            if context.display == EGL_NO_DISPLAY {
+                return Err(egl_error("eglGetDisplay"));
+            }
+            let (mut major, mut minor) = (0, 0);
+            if eglInitialize(context.display, &mut major, &mut minor) == 0 {
+                // Nothing to terminate.
+                context.display = EGL_NO_DISPLAY;
+                return Err(egl_error("eglInitialize"));
+            }
+            if eglBindAPI(EGL_OPENGL_ES_API) == 0 {
+                return Err(egl_error("eglBindAPI"));
+            }
+            let surfaceless = has_extension(context.display, "EGL_KHR_surfaceless_context");
+            let surface_type = if surfaceless { 0 } else { EGL_PBUFFER_BIT };
+            #[rustfmt::skip]
+            let config_attributes = [
+                EGL_SURFACE_TYPE, surface_type,
+                EGL_RENDERABLE_TYPE, EGL_OPENGL_ES3_BIT,
+                EGL_RED_SIZE, 8,
+                EGL_GREEN_SIZE, 8,
+                EGL_BLUE_SIZE, 8,
+                EGL_ALPHA_SIZE, 8,
+                EGL_DEPTH_SIZE, 24,
+                EGL_NONE,
+            ];
+            let mut config: EGLConfig = ptr::null_mut();
+            let mut config_count = 0;
+            if eglChooseConfig(context.display, config_attributes.as_ptr(), &mut config, 1, &mut config_count) == 0 || config_count == 0 {
+                return Err(egl_error("eglChooseConfig"));
+            }
+            let context_attributes = [EGL_CONTEXT_MAJOR_VERSION, 3, EGL_CONTEXT_MINOR_VERSION, 1, EGL_NONE];
+            context.context = eglCreateContext(context.display, config, EGL_NO_CONTEXT, context_attributes.as_ptr());
+            if context.context == EGL_NO_CONTEXT {
+                return Err(egl_error("eglCreateContext"));
+            }
+            if !surfaceless {
+                let surface_attributes = [EGL_WIDTH, 1, EGL_HEIGHT, 1, EGL_NONE];
+                context.surface = eglCreatePbufferSurface(context.display, config, surface_attributes.as_ptr());
+                if context.surface == EGL_NO_SURFACE {
+                    return Err(egl_error("eglCreatePbufferSurface"));
+                }
+            }
+            if eglMakeCurrent(context.display, context.surface, context.surface, context.context) == 0 {
+                return Err(egl_error("eglMakeCurrent"));
+            }
+        }
+        gl::load_with(|name| {
+            let name = CString::new(name).unwrap();
+            unsafe { eglGetProcAddress(name.as_ptr()) }
+        });
+        gpu::setup();
+        let framebuffer = gpu::Framebuffer::new(&gpu::FramebufferConfig {
+            width,
+            height,
+            color: vec![gpu::TextureFormat::Rgba8],
+            depth: gpu::DepthAttachment::Depth,
+            samples: 0,
+        })?;
+        framebuffer.bind();
+        context.framebuffer = Some(framebuffer);
+        Ok(context)
+    }
+
+    pub fn framebuffer(&self) -> &gpu::Framebuffer {
+        self.framebuffer.as_ref().unwrap()
+    }
+
+    pub fn width(&self) -> i32 {
+        self.framebuffer().width()
+    }
+
+    pub fn height(&self) -> i32 {
+        self.framebuffer().height()
+    }
+
+    // Binds the framebuffer again, after drawing to others.
+    pub fn bind(&self) {
+        self.framebuffer().bind();
+    }
+
+    // Waits for drawing to finish and returns the whole frame, top row first.
+    pub fn read_pixels(&self) -> Result<image::RgbaImage, String> {
+        unsafe {
+            gl::Finish();
+        }
+        let region = RectangleI::new(Vector2I::ZERO, self.width(), self.height());
+        let image = self.framebuffer().read_pixels(0, region);
+        self.bind();
+        image
+    }
+}
+
+impl Drop for HeadlessContext {
+    fn drop(&mut self) {
+        self.framebuffer = None;
+        unsafe {
+            if self.display != EGL_NO_DISPLAY {
+                eglMakeCurrent(self.display, EGL_NO_SURFACE, EGL_NO_SURFACE, EGL_NO_CONTEXT);
+                if self.surface != EGL_NO_SURFACE {
+                    eglDestroySurface(self.display, self.surface);
+                }
+                if self.context != EGL_NO_CONTEXT {
+                    eglDestroyContext(self.display, self.context);
+                }
+                eglTerminate(self.display);
+            }
+        }
+    }
+}
+
+// Renders one frame offscreen and returns its pixels. Everything draw
+// creates on the GPU should be dropped by the time it returns, since the
+// context goes away after it, and leaks are reported like in main.
+pub fn render(width: i32, height: i32, draw: impl FnOnce()) -> Result<image::RgbaImage, String> {
+    let context = HeadlessContext::new(width, height)?;
+    draw();
+    let image = context.read_pixels();
+    // Without the context's own framebuffer, which would be reported too.
+    drop(context);
+    gpu::report_leaks();
+    image
+}
+
+// The surfaceless platform needs no display server at all. Without it, this
+// is the default display, which Mesa can also make surfaceless with
+// EGL_PLATFORM=surfaceless.
+unsafe fn platform_display() -> EGLDisplay {
+    if has_extension(EGL_NO_DISPLAY, "EGL_MESA_platform_surfaceless") {
+        let name = CString::new("eglGetPlatformDisplayEXT").unwrap();
+        let get_platform_display = eglGetProcAddress(name.as_ptr());
+        if !get_platform_display.is_null() {
+            let get_platform_display: GetPlatformDisplay = std::mem::transmute(get_platform_display);
+            let display = get_platform_display(EGL_PLATFORM_SURFACELESS_MESA, EGL_DEFAULT_DISPLAY, ptr::null());
+            if display != EGL_NO_DISPLAY {
+                return display;
+            }
+        }
+    }
+    eglGetDisplay(EGL_DEFAULT_DISPLAY)
+}
+
+// Client extensions with EGL_NO_DISPLAY, display ones otherwise.
+unsafe fn has_extension(display: EGLDisplay, name: &str) -> bool {
+    let extensions = eglQueryString(display, EGL_EXTENSIONS);
+    if extensions.is_null() {
+        // Clears the error, which EGL sets when there are no client
+        // extensions.
+        eglGetError();
+        return false;
+    }
+    CStr::from_ptr(extensions).to_string_lossy().split(' ').any(|e| e == name)
+}
+
+fn egl_error(function: &str) -> String {
+    format!("{} failed with EGL error {:#x}", function, unsafe { eglGetError() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{self, Backend, BackendMesh, GlBackend};
+    use crate::mesh::MeshData;
+
+    fn pixel(image: &image::RgbaImage, x: f32, y: f32) -> [u8; 4] {
+        let (width, height) = image.dimensions();
+        image.get_pixel((x * width as f32) as u32, (y * height as f32) as u32).0
+    }
+
+    #[test]
+    fn clear_and_triangle() {
+        let image = render(64, 48, || {
+            let mut backend = GlBackend::new(64, 48);
+            let program = backend.create_program(backend::mesh_program()).unwrap();
+            // Clockwise, pointing up, in clip space. Without normals it
+            // is the shader's ambient color.
+            let data = MeshData {
+                positions: vec![Vector3::new(-0.5, -0.5, 0.), Vector3::new(0., 0.5, 0.), Vector3::new(0.5, -0.5, 0.)],
+                indices: vec![0, 1, 2],
+                ..MeshData::default()
+            };
+            let mesh = BackendMesh::new(&mut backend, &data);
+            backend.clear(col4(1.0, 1.0, 0.0, 1.0));
+            mesh.draw(&mut backend, &program, &Matrix4::id(), &Matrix4::id());
+        })
+        .unwrap();
+        assert_eq!(image.dimensions(), (64, 48));
+        assert_eq!(gpu::live_objects(), []);
+
+        let clear = [255, 255, 0, 255];
+        let ambient = [26, 26, 77, 255];
+        assert_eq!(pixel(&image, 0., 0.), clear);
+        assert_eq!(pixel(&image, 0.99, 0.99), clear);
+        // The first row is the top one.
+        assert_eq!(pixel(&image, 0.3, 0.3), clear);
+        for &(x, y) in [(0.5, 0.5), (0.5, 0.3), (0.35, 0.7), (0.65, 0.7)].iter() {
+            let p = pixel(&image, x, y);
+            for c in 0..4 {
+                assert!((p[c] as i32 - ambient[c]).abs() <= 1, "{:?} at {}, {}", p, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn resolve_integer_framebuffer() {
+        render(4, 4, || {
+            let config = |width, height| gpu::FramebufferConfig {
+                width,
+                height,
+                color: vec![gpu::TextureFormat::R32UI],
+                depth: gpu::DepthAttachment::None,
+                samples: 0,
+            };
+            let source = gpu::Framebuffer::new(&config(4, 4)).unwrap();
+            let target = gpu::Framebuffer::new(&config(4, 4)).unwrap();
+            let smaller = gpu::Framebuffer::new(&config(2, 2)).unwrap();
+            source.bind();
+            let mut pixel = [0u32; 4];
+            unsafe {
+                // Setting up the context may leave some, unrelated to blits.
+                while gl::GetError() != gl::NO_ERROR {}
+                gl::ClearBufferuiv(gl::COLOR, 0, [7, 0, 0, 0].as_ptr());
+            }
+            // Integers are never filtered, even when scaling.
+            for framebuffer in [&target, &smaller].iter() {
+                source.resolve(framebuffer);
+                framebuffer.bind();
+                unsafe {
+                    assert_eq!(gl::GetError(), gl::NO_ERROR);
+                    gl::ReadPixels(0, 0, 1, 1, gl::RGBA_INTEGER, gl::UNSIGNED_INT, pixel.as_mut_ptr() as *mut c_void);
+                }
+                assert_eq!(pixel[0], 7);
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn minified_textures_are_averaged() {
+        use crate::gpu::UniformValue;
+        // A texel checkerboard, 16 texels across each pixel below.
+        let size = 64;
+        let white = |x: u32, y: u32| (x + y) % 2 == 0;
+        let checker8 = image::ImageBuffer::from_fn(size, size, |x, y| {
+            let v = if white(x, y) { 255 } else { 0 };
+            image::Rgba([v, v, v, 255])
+        });
+        let checker16 = image::ImageBuffer::from_fn(size, size, |x, y| {
+            let v = if white(x, y) { 65535 } else { 0 };
+            image::Rgba([v, v, v, 65535u16])
+        });
+        let images = [image::DynamicImage::ImageRgba8(checker8), image::DynamicImage::ImageRgba16(checker16)];
+        for img in images.iter() {
+            for &mipmaps in [false, true].iter() {
+                let options = gpu::ImageOptions { mipmaps, ..gpu::ImageOptions::linear() };
+                let frame = render(4, 4, || {
+                    let texture = gpu::Texture::from_image(img, options).unwrap();
+                    let vertex = "#version 300 es
+                        out vec2 tex_coords;
+                        void main() {
+                            vec2 p = vec2(gl_VertexID & 1, gl_VertexID >> 1);
+                            // On texel centers, rather than between texels.
+                            tex_coords = 2.0 * p + 0.5 / 64.0;
+                            gl_Position = vec4(4.0 * p - 1.0, 0.0, 1.0);
+                        }";
+                    let fragment = "#version 300 es
+                        precision highp float;
+                        in vec2 tex_coords;
+                        uniform sampler2D texture0;
+                        out vec4 color;
+                        void main() {
+                            color = texture(texture0, tex_coords);
+                        }";
+                    let program = gpu::Program::from_sources(vertex.to_string(), fragment.to_string()).unwrap();
+                    let unit = gpu::TextureUnit(0);
+                    unit.bind_texture(texture.handle());
+                    unit.set_uniform(program.find_uniform("texture0").unwrap());
+                    gpu::disable(gl::CULL_FACE);
+                    gpu::disable(gl::DEPTH_TEST);
+                    gpu::disable(gl::BLEND);
+                    gpu::VertexArray::new().draw_arrays(gpu::Primitive::Triangles, 0, 3);
+                })
+                .unwrap();
+                for p in frame.pixels() {
+                    let gray = p.0[0] as i32;
+                    if mipmaps {
+                        assert!((gray - 128).abs() <= 1, "{:?} mipmapped to {}", img.color(), gray);
+                    } else {
+                        assert!(gray == 0 || gray == 255, "{:?} sampled to {}", img.color(), gray);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn contexts_one_after_another() {
+        for &(width, height) in [(8, 8), (3, 5)].iter() {
+            let image = render(width, height, || gpu::clear(0., 0., 1., 1.)).unwrap();
+            assert!(image.pixels().all(|p| p.0 == [0, 0, 255, 255]));
+        }
+    }
+}
@@ -0,0 +1,624 @@
+use crate::math::*;
+use crate::mesh::MeshData;
+use std::collections::HashMap;
+
+// Surfaces of scalar fields, by marching cubes and dual contouring.
+//
+// The inside is where the field is below the iso value, as with signed
+// distance functions, and normals point along the gradient (outwards).
+// Vertices are shared between neighboring cells.
+
+#[derive(Debug, Clone)]
+pub struct ScalarGrid {
+    // Number of samples along each axis.
+    pub size: [usize; 3],
+    pub origin: Vector3,
+    pub spacing: Vector3,
+    // x varies fastest, then y, then z.
+    pub values: Vec<f32>,
+}
+
+impl ScalarGrid {
+    pub fn new(size: [usize; 3], origin: Vector3, spacing: Vector3) -> ScalarGrid {
+        ScalarGrid { size, origin, spacing, values: vec![0.; size[0] * size[1] * size[2]] }
+    }
+
+    // Samples f over the box, with cells no bigger than cell_size.
+    pub fn sample<F: Fn(Vector3) -> f32>(bounds: &Aabb, cell_size: f32, f: F) -> ScalarGrid {
+        let extent = bounds.size();
+        let cells = |len: f32| ((len / cell_size).ceil() as usize).max(1);
+        let size = [cells(extent.x) + 1, cells(extent.y) + 1, cells(extent.z) + 1];
+        let spacing = Vector3::new(
+            extent.x / (size[0] - 1) as f32,
+            extent.y / (size[1] - 1) as f32,
+            extent.z / (size[2] - 1) as f32,
+        );
+        let mut grid = ScalarGrid::new(size, bounds.min, spacing);
+        for k in 0..size[2] {
+            for j in 0..size[1] {
+                for i in 0..size[0] {
+                    let v = f(grid.point(i, j, k));
+                    grid.set(i, j, k, v);
+                }
+            }
+        }
+        grid
+    }
+
+    fn index(&self, i: usize, j: usize, k: usize) -> usize {
+        (k * self.size[1] + j) * self.size[0] + i
+    }
+
+    pub fn get(&self, i: usize, j: usize, k: usize) -> f32 {
+        self.values[self.index(i, j, k)]
+    }
+
+    pub fn set(&mut self, i: usize, j: usize, k: usize, value: f32) {
+        let index = self.index(i, j, k);
+        self.values[index] = value;
+    }
+
+    pub fn point(&self, i: usize, j: usize, k: usize) -> Vector3 {
+        self.origin + Vector3::new(
+            i as f32 * self.spacing.x,
+            j as f32 * self.spacing.y,
+            k as f32 * self.spacing.z,
+        )
+    }
+
+    // Central differences, one sided at the edges of the grid.
+    pub fn gradient(&self, i: usize, j: usize, k: usize) -> Vector3 {
+        let diff = |axis: usize, spacing: f32| -> f32 {
+            let mut lo = [i, j, k];
+            let mut hi = [i, j, k];
+            if lo[axis] > 0 {
+                lo[axis] -= 1;
+            }
+            if hi[axis] + 1 < self.size[axis] {
+                hi[axis] += 1;
+            }
+            let steps = (hi[axis] - lo[axis]) as f32;
+            if steps == 0. {
+                return 0.;
+            }
+            (self.get(hi[0], hi[1], hi[2]) - self.get(lo[0], lo[1], lo[2])) / (steps * spacing)
+        };
+        Vector3::new(diff(0, self.spacing.x), diff(1, self.spacing.y), diff(2, self.spacing.z))
+    }
+
+    // Trilinear interpolation of the gradients at the samples around p.
+    pub fn gradient_at(&self, p: Vector3) -> Vector3 {
+        let local = p - self.origin;
+        let coords = [local.x / self.spacing.x, local.y / self.spacing.y, local.z / self.spacing.z];
+        let mut base = [0; 3];
+        let mut t = [0.; 3];
+        for axis in 0..3 {
+            let max = self.size[axis].saturating_sub(2) as f32;
+            let c = coords[axis].max(0.).min(max.max(0.));
+            base[axis] = c.floor() as usize;
+            t[axis] = (coords[axis] - base[axis] as f32).clamp(0., 1.);
+        }
+        let mut g = Vector3::ZERO;
+        for corner in 0..8 {
+            let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+            let mut w = 1.;
+            let mut index = [0; 3];
+            for axis in 0..3 {
+                w *= if offset[axis] == 1 { t[axis] } else { 1. - t[axis] };
+                index[axis] = (base[axis] + offset[axis]).min(self.size[axis] - 1);
+            }
+            g += w * self.gradient(index[0], index[1], index[2]);
+        }
+        g
+    }
+}
+
+pub fn marching_cubes(grid: &ScalarGrid, iso: f32) -> MeshData {
+    marching_cubes_impl(grid, iso, &|p| grid.gradient_at(p))
+}
+
+// Samples the distance function over the box first. Normals come from the
+// function itself, which is more accurate than the sampled grid.
+pub fn marching_cubes_sdf<F: Fn(Vector3) -> f32>(sdf: F, bounds: &Aabb, cell_size: f32) -> MeshData {
+    let grid = ScalarGrid::sample(bounds, cell_size, &sdf);
+    let h = 0.01 * cell_size;
+    marching_cubes_impl(&grid, 0., &|p| sdf_gradient(&sdf, p, h))
+}
+
+pub fn dual_contouring(grid: &ScalarGrid, iso: f32) -> MeshData {
+    dual_contouring_impl(grid, iso, &|p| grid.gradient_at(p))
+}
+
+pub fn dual_contouring_sdf<F: Fn(Vector3) -> f32>(sdf: F, bounds: &Aabb, cell_size: f32) -> MeshData {
+    let grid = ScalarGrid::sample(bounds, cell_size, &sdf);
+    let h = 0.01 * cell_size;
+    dual_contouring_impl(&grid, 0., &|p| sdf_gradient(&sdf, p, h))
+}
+
+fn sdf_gradient<F: Fn(Vector3) -> f32>(sdf: &F, p: Vector3, h: f32) -> Vector3 {
+    Vector3::new(
+        sdf(p + h * Vector3::X) - sdf(p - h * Vector3::X),
+        sdf(p + h * Vector3::Y) - sdf(p - h * Vector3::Y),
+        sdf(p + h * Vector3::Z) - sdf(p - h * Vector3::Z),
+    ) / (2. * h)
+}
+
+// Cube corners are numbered by their offset bits, x + 2y + 4z.
+const CORNER_OFFSETS: [[usize; 3]; 8] = [
+    [0, 0, 0], [1, 0, 0], [0, 1, 0], [1, 1, 0],
+    [0, 0, 1], [1, 0, 1], [0, 1, 1], [1, 1, 1],
+];
+
+const CUBE_EDGES: [[usize; 2]; 12] = [
+    [0, 1], [2, 3], [4, 5], [6, 7], // x
+    [0, 2], [1, 3], [4, 6], [5, 7], // y
+    [0, 4], [1, 5], [2, 6], [3, 7], // z
+];
+
+// Corners of each face, counter-clockwise seen from outside the cube.
+const CUBE_FACES: [[usize; 4]; 6] = [
+    [0, 4, 6, 2], [1, 3, 7, 5], // x
+    [0, 1, 5, 4], [2, 6, 7, 3], // y
+    [0, 2, 3, 1], [4, 5, 7, 6], // z
+];
+
+fn cube_edge(a: usize, b: usize) -> usize {
+    CUBE_EDGES.iter().position(|e| (e[0] == a && e[1] == b) || (e[0] == b && e[1] == a)).unwrap()
+}
+
+struct SurfaceBuilder<'a> {
+    data: MeshData,
+    gradient: &'a dyn Fn(Vector3) -> Vector3,
+}
+
+impl<'a> SurfaceBuilder<'a> {
+    fn new(gradient: &'a dyn Fn(Vector3) -> Vector3) -> SurfaceBuilder<'a> {
+        SurfaceBuilder { data: MeshData::default(), gradient }
+    }
+
+    fn vertex(&mut self, p: Vector3) -> u32 {
+        let g = (self.gradient)(p);
+        self.data.positions.push(p);
+        self.data.tex_coords.push(Vector2::ZERO);
+        self.data.normals.push(if g.norm() > 0. { g.normalized() } else { Vector3::ZERO });
+        self.data.positions.len() as u32 - 1
+    }
+
+    // Takes triangles counter-clockwise seen from outside and stores them the
+    // way the rest of rugl's meshes are wound, clockwise.
+    fn triangle(&mut self, a: u32, b: u32, c: u32) {
+        if a == b || b == c || c == a {
+            return;
+        }
+        self.data.indices.extend_from_slice(&[a, c, b]);
+    }
+}
+
+fn marching_cubes_impl(grid: &ScalarGrid, iso: f32, gradient: &dyn Fn(Vector3) -> Vector3) -> MeshData {
+    let mut builder = SurfaceBuilder::new(gradient);
+    // Vertices on grid edges, keyed by the edge's first sample and axis.
+    let mut edge_vertices: HashMap<(usize, u8), u32> = HashMap::new();
+    let [nx, ny, nz] = grid.size;
+    if nx < 2 || ny < 2 || nz < 2 {
+        return builder.data;
+    }
+
+    for k in 0..nz - 1 {
+        for j in 0..ny - 1 {
+            for i in 0..nx - 1 {
+                let mut values = [0.; 8];
+                let mut inside_count = 0;
+                for c in 0..8 {
+                    let o = CORNER_OFFSETS[c];
+                    values[c] = grid.get(i + o[0], j + o[1], k + o[2]);
+                    if values[c] < iso {
+                        inside_count += 1;
+                    }
+                }
+                if inside_count == 0 || inside_count == 8 {
+                    continue;
+                }
+
+                // Segments of the surface on each face, as pairs of cube edges,
+                // and the faces they are on.
+                let mut segments: Vec<[usize; 2]> = Vec::new();
+                let mut segment_faces = Vec::new();
+                for (f, face) in CUBE_FACES.iter().enumerate() {
+                    face_segments(face, &values, iso, &mut segments);
+                    segment_faces.resize(segments.len(), f);
+                }
+
+                // Vertex for each crossed cube edge.
+                let mut vertex_of_edge = [u32::MAX; 12];
+                for seg in segments.iter() {
+                    for &e in seg.iter() {
+                        if vertex_of_edge[e] != u32::MAX {
+                            continue;
+                        }
+                        let [a, b] = CUBE_EDGES[e];
+                        let (oa, ob) = (CORNER_OFFSETS[a], CORNER_OFFSETS[b]);
+                        let (ia, ja, ka) = (i + oa[0], j + oa[1], k + oa[2]);
+                        let axis = (e / 4) as u8;
+                        let key = (grid.index(ia, ja, ka), axis);
+                        vertex_of_edge[e] = *edge_vertices.entry(key).or_insert_with(|| {
+                            let (va, vb) = (values[a], values[b]);
+                            let t = ((iso - va) / (vb - va)).clamp(0., 1.);
+                            let pa = grid.point(ia, ja, ka);
+                            let pb = grid.point(i + ob[0], j + ob[1], k + ob[2]);
+                            builder.vertex(pa + t * (pb - pa))
+                        });
+                    }
+                }
+
+                // Every crossed edge is on two faces, where the segments enter
+                // and leave it, so they chain into closed loops.
+                let mut used = vec![false; segments.len()];
+                for start in 0..segments.len() {
+                    if used[start] {
+                        continue;
+                    }
+                    used[start] = true;
+                    let mut polygon = vec![segments[start][0]];
+                    let mut faces = vec![segment_faces[start]];
+                    let mut current = segments[start][1];
+                    while current != polygon[0] {
+                        polygon.push(current);
+                        let next = (0..segments.len()).find(|&s| !used[s] && segments[s][0] == current);
+                        match next {
+                            Some(s) => {
+                                used[s] = true;
+                                faces.push(segment_faces[s]);
+                                current = segments[s][1];
+                            }
+                            None => break,
+                        }
+                    }
+                    let polygon: Vec<u32> = polygon.iter().map(|&e| vertex_of_edge[e]).collect();
+                    faces.sort_unstable();
+                    faces.dedup();
+                    if faces.len() == polygon.len() {
+                        for t in 1..polygon.len().saturating_sub(1) {
+                            builder.triangle(polygon[0], polygon[t], polygon[t + 1]);
+                        }
+                    } else {
+                        // The loop crosses an ambiguous face twice. A fan could
+                        // put a diagonal on that face, which the neighboring
+                        // cube may use too, so go around a center vertex.
+                        let mut center = Vector3::ZERO;
+                        for &v in polygon.iter() {
+                            center += builder.data.positions[v as usize];
+                        }
+                        let center = builder.vertex(center / polygon.len() as f32);
+                        for t in 0..polygon.len() {
+                            builder.triangle(center, polygon[t], polygon[(t + 1) % polygon.len()]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    builder.data
+}
+
+// Adds the segments where the surface crosses a face of the cube, directed
+// from the edge where the face's boundary goes inside to the one where it
+// leaves, so the loops they form wind the same way in every cube.
+// Faces with two diagonally opposite inside corners are ambiguous. They are
+// resolved with the asymptotic decider (Nielson and Hamann), which only looks
+// at the face's values, so neighboring cubes always agree and the surface has
+// no cracks.
+fn face_segments(face: &[usize; 4], values: &[f32; 8], iso: f32, segments: &mut Vec<[usize; 2]>) {
+    let v: Vec<f32> = face.iter().map(|&c| values[c]).collect();
+    let inside: Vec<bool> = v.iter().map(|&x| x < iso).collect();
+    // Edge i of the face goes from corner i to corner i + 1.
+    let edge = |i: usize| cube_edge(face[i % 4], face[(i + 1) % 4]);
+    let entering: Vec<usize> = (0..4).filter(|&i| !inside[i] && inside[(i + 1) % 4]).collect();
+    let leaving: Vec<usize> = (0..4).filter(|&i| inside[i] && !inside[(i + 1) % 4]).collect();
+    match entering.len() {
+        1 => segments.push([edge(entering[0]), edge(leaving[0])]),
+        2 => {
+            // Value of the bilinear interpolant at its saddle point.
+            let denom = v[0] + v[2] - v[1] - v[3];
+            let saddle = if denom != 0. { (v[0] * v[2] - v[1] * v[3]) / denom } else { 0.25 * (v[0] + v[1] + v[2] + v[3]) };
+            // Cut off the corners on the other side than the saddle.
+            let saddle_inside = saddle < iso;
+            for (c, &corner_inside) in inside.iter().enumerate() {
+                if corner_inside != saddle_inside {
+                    if corner_inside {
+                        segments.push([edge(c + 3), edge(c)]);
+                    } else {
+                        segments.push([edge(c), edge(c + 3)]);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn dual_contouring_impl(grid: &ScalarGrid, iso: f32, gradient: &dyn Fn(Vector3) -> Vector3) -> MeshData {
+    let mut builder = SurfaceBuilder::new(gradient);
+    let [nx, ny, nz] = grid.size;
+    if nx < 2 || ny < 2 || nz < 2 {
+        return builder.data;
+    }
+    let cell_index = |i: usize, j: usize, k: usize| (k * (ny - 1) + j) * (nx - 1) + i;
+    let mut cell_vertices = vec![u32::MAX; (nx - 1) * (ny - 1) * (nz - 1)];
+
+    // One vertex per cell crossed by the surface, minimizing the distance to
+    // the tangent planes at the crossings.
+    for k in 0..nz - 1 {
+        for j in 0..ny - 1 {
+            for i in 0..nx - 1 {
+                let mut points = Vec::new();
+                let mut normals = Vec::new();
+                for e in CUBE_EDGES.iter() {
+                    let (oa, ob) = (CORNER_OFFSETS[e[0]], CORNER_OFFSETS[e[1]]);
+                    let va = grid.get(i + oa[0], j + oa[1], k + oa[2]);
+                    let vb = grid.get(i + ob[0], j + ob[1], k + ob[2]);
+                    if (va < iso) == (vb < iso) {
+                        continue;
+                    }
+                    let t = ((iso - va) / (vb - va)).clamp(0., 1.);
+                    let pa = grid.point(i + oa[0], j + oa[1], k + oa[2]);
+                    let pb = grid.point(i + ob[0], j + ob[1], k + ob[2]);
+                    let p = pa + t * (pb - pa);
+                    let g = gradient(p);
+                    points.push(p);
+                    normals.push(if g.norm() > 0. { g.normalized() } else { Vector3::ZERO });
+                }
+                if points.is_empty() {
+                    continue;
+                }
+                let cell = Aabb::new(grid.point(i, j, k), grid.point(i + 1, j + 1, k + 1));
+                let p = solve_qef(&points, &normals, &cell);
+                cell_vertices[cell_index(i, j, k)] = builder.vertex(p);
+            }
+        }
+    }
+
+    // A quad around every crossed grid edge, joining the four cells around it.
+    for k in 0..nz {
+        for j in 0..ny {
+            for i in 0..nx {
+                let here = [i, j, k];
+                for axis in 0..3 {
+                    let (u, w) = ((axis + 1) % 3, (axis + 2) % 3);
+                    let mut there = here;
+                    there[axis] += 1;
+                    if there[axis] >= grid.size[axis] || here[u] == 0 || here[w] == 0 ||
+                       here[u] >= grid.size[u] - 1 || here[w] >= grid.size[w] - 1 {
+                        continue;
+                    }
+                    let va = grid.get(i, j, k);
+                    let vb = grid.get(there[0], there[1], there[2]);
+                    if (va < iso) == (vb < iso) {
+                        continue;
+                    }
+                    let mut quad = [0; 4];
+                    for (q, &(du, dw)) in [(1, 1), (0, 1), (0, 0), (1, 0)].iter().enumerate() {
+                        let mut c = here;
+                        c[u] -= du;
+                        c[w] -= dw;
+                        quad[q] = cell_vertices[cell_index(c[0], c[1], c[2])];
+                    }
+                    // The quad goes counter-clockwise around the axis.
+                    if va >= iso {
+                        quad.reverse();
+                    }
+                    builder.triangle(quad[0], quad[1], quad[2]);
+                    builder.triangle(quad[0], quad[2], quad[3]);
+                }
+            }
+        }
+    }
+    builder.data
+}
+
+// Least squares point closest to the planes through the points, pulled
+// slightly towards their average to stay stable on flat areas and edges.
+// Falls back to the average if the result leaves the cell.
+fn solve_qef(points: &[Vector3], normals: &[Vector3], cell: &Aabb) -> Vector3 {
+    let mut mass_point = Vector3::ZERO;
+    for &p in points {
+        mass_point += p;
+    }
+    mass_point /= points.len() as f32;
+
+    const REGULARIZATION: f32 = 0.05;
+    let mut ata = [[0.; 3]; 3];
+    let mut atb = [0.; 3];
+    for (p, n) in points.iter().zip(normals.iter()) {
+        let n = [n.x, n.y, n.z];
+        let b = Vector3::dot(Vector3::new(n[0], n[1], n[2]), *p - mass_point);
+        for r in 0..3 {
+            for c in 0..3 {
+                ata[r][c] += n[r] * n[c];
+            }
+            atb[r] += n[r] * b;
+        }
+    }
+    for (r, row) in ata.iter_mut().enumerate() {
+        row[r] += REGULARIZATION;
+    }
+
+    let det3 = |m: &[[f32; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let det = det3(&ata);
+    if det.abs() < 1e-12 {
+        return mass_point;
+    }
+    // Cramer's rule.
+    let mut x = [0.; 3];
+    for (col, x) in x.iter_mut().enumerate() {
+        let mut m = ata;
+        for r in 0..3 {
+            m[r][col] = atb[r];
+        }
+        *x = det3(&m) / det;
+    }
+    let p = mass_point + Vector3::new(x[0], x[1], x[2]);
+    if cell.contains(p) { p } else { mass_point }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::half_edge::HalfEdgeMesh;
+
+    const RADIUS: f32 = 0.8;
+    const CELL: f32 = 0.1;
+
+    fn sphere(p: Vector3) -> f32 {
+        p.norm() - RADIUS
+    }
+
+    fn bounds() -> Aabb {
+        Aabb::new(Vector3::new(-1., -1., -1.), Vector3::new(1., 1., 1.))
+    }
+
+    // Every edge has one triangle on each side, going opposite ways, and
+    // around every vertex is a single fan. Returns the number of pieces.
+    fn check_closed(data: &MeshData) -> usize {
+        let indices = &data.indices;
+        assert!(!indices.is_empty());
+        let mut edges: HashMap<(u32, u32), usize> = HashMap::new();
+        for t in indices.chunks(3) {
+            for e in 0..3 {
+                *edges.entry((t[e], t[(e + 1) % 3])).or_insert(0) += 1;
+            }
+        }
+        for (&(a, b), &count) in edges.iter() {
+            assert_eq!(count, 1, "edge {} {} is used {} times", a, b, count);
+            assert!(edges.contains_key(&(b, a)), "edge {} {} is open", a, b);
+        }
+        assert!(HalfEdgeMesh::from_data(data).validate().is_watertight());
+
+        // Pieces, joining triangles that share vertices.
+        let mut parent: Vec<usize> = (0..data.positions.len()).collect();
+        fn root(parent: &mut Vec<usize>, mut i: usize) -> usize {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+        for t in indices.chunks(3) {
+            for e in 1..3 {
+                let (a, b) = (root(&mut parent, t[0] as usize), root(&mut parent, t[e] as usize));
+                parent[a] = b;
+            }
+        }
+        let mut roots: Vec<usize> = indices.iter().map(|&v| root(&mut parent, v as usize)).collect();
+        roots.sort_unstable();
+        roots.dedup();
+        roots.len()
+    }
+
+    // Triangles are clockwise, so this is positive when they face outwards.
+    fn volume(data: &MeshData) -> f32 {
+        data.indices
+            .chunks(3)
+            .map(|t| {
+                let [a, b, c] = [t[0], t[1], t[2]].map(|v| data.positions[v as usize]);
+                Vector3::dot(a, Vector3::cross(c, b)) / 6.
+            })
+            .sum()
+    }
+
+    fn check_sphere(data: &MeshData) {
+        assert_eq!(check_closed(data), 1);
+        let edges = data.indices.len() / 2;
+        let euler = data.positions.len() as i32 - edges as i32 + data.triangle_count() as i32;
+        assert_eq!(euler, 2);
+        for p in data.positions.iter() {
+            assert!(sphere(*p).abs() < CELL, "{:?} is {} from the surface", p, sphere(*p));
+        }
+        let expected = 4. / 3. * std::f32::consts::PI * RADIUS.powi(3);
+        assert!((volume(data) - expected).abs() < 0.02 * expected, "volume {}", volume(data));
+        for (p, n) in data.positions.iter().zip(data.normals.iter()) {
+            assert!(Vector3::dot(p.normalized(), *n) > 0.99);
+        }
+    }
+
+    #[test]
+    fn marching_cubes_sphere() {
+        check_sphere(&marching_cubes_sdf(sphere, &bounds(), CELL));
+        let grid = ScalarGrid::sample(&bounds(), CELL, sphere);
+        check_sphere(&marching_cubes(&grid, 0.));
+    }
+
+    #[test]
+    fn dual_contouring_sphere() {
+        check_sphere(&dual_contouring_sdf(sphere, &bounds(), CELL));
+        let grid = ScalarGrid::sample(&bounds(), CELL, sphere);
+        check_sphere(&dual_contouring(&grid, 0.));
+    }
+
+    // A grid that is outside everywhere except the given samples.
+    fn grid(size: usize, inside: &[([usize; 3], f32)]) -> ScalarGrid {
+        let mut grid = ScalarGrid::new([size; 3], Vector3::ZERO, Vector3::new(1., 1., 1.));
+        grid.values.iter_mut().for_each(|v| *v = 1.);
+        for &([i, j, k], v) in inside {
+            grid.set(i, j, k, v);
+        }
+        grid
+    }
+
+    #[test]
+    fn ambiguous_face() {
+        // Two inside samples diagonally across a face. The saddle of the face
+        // is (v0 v2 - v1 v3) / (v0 + v2 - v1 - v3), which is inside when
+        // they are deep enough, joining them.
+        let joined = grid(4, &[([1, 1, 1], -3.), ([2, 2, 1], -3.)]);
+        assert_eq!(check_closed(&marching_cubes(&joined, 0.)), 1);
+        let apart = grid(4, &[([1, 1, 1], -0.5), ([2, 2, 1], -0.5)]);
+        assert_eq!(check_closed(&marching_cubes(&apart, 0.)), 2);
+        // Diagonally across a cube, they don't share a face.
+        let corners = grid(4, &[([1, 1, 1], -3.), ([2, 2, 2], -3.)]);
+        assert_eq!(check_closed(&marching_cubes(&corners, 0.)), 2);
+    }
+
+    #[test]
+    fn random_fields_are_closed() {
+        // Xorshift, so that failures reproduce.
+        let mut state: u64 = 0x9e3779b97f4a7c15;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 40) as f32 / (1u64 << 24) as f32
+        };
+        let mut ambiguous = 0;
+        for _ in 0..200 {
+            let mut inside = Vec::new();
+            for k in 1..4 {
+                for j in 1..4 {
+                    for i in 1..4 {
+                        inside.push(([i, j, k], 2. * next() - 1.2));
+                    }
+                }
+            }
+            let grid = grid(5, &inside);
+            if inside.iter().all(|&(_, v)| v >= 0.) {
+                continue;
+            }
+            // Any face with two diagonal inside corners.
+            for &(a, va) in inside.iter() {
+                for &(b, vb) in inside.iter() {
+                    let d = [b[0] as i32 - a[0] as i32, b[1] as i32 - a[1] as i32, b[2] as i32 - a[2] as i32];
+                    if va < 0. && vb < 0. && d.iter().filter(|&&x| x.abs() == 1).count() == 2 && d.iter().any(|&x| x == 0) {
+                        ambiguous += 1;
+                    }
+                }
+            }
+            let data = marching_cubes(&grid, 0.);
+            check_closed(&data);
+            assert!(volume(&data) > 0.);
+        }
+        assert!(ambiguous > 0);
+    }
+}
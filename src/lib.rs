@@ -0,0 +1,22 @@
+pub mod animation;
+pub mod backend;
+pub mod bvh;
+pub mod camera;
+pub mod camera_controller;
+#[cfg(feature = "headless")]
+pub mod golden;
+pub mod gpu;
+pub mod half_edge;
+#[cfg(feature = "headless")]
+pub mod headless;
+pub mod image;
+pub mod isosurface;
+pub mod lod;
+pub mod math;
+pub mod mesh;
+pub mod simplify;
+pub mod skeleton;
+pub mod software;
+pub mod subdivision;
+pub mod terrain;
+pub mod text;
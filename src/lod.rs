@@ -0,0 +1,67 @@
+use crate::camera::Camera;
+use crate::gpu::Primitive;
+use crate::math::*;
+use crate::mesh::{Mesh, MeshShader};
+use crate::simplify::{simplify_data, SimplifyOptions};
+
+// A mesh with several levels of detail, from the full mesh down to the
+// coarsest. The level is picked by how big the mesh looks on screen.
+pub struct LodMesh {
+    levels: Vec<LodLevel>,
+}
+
+pub struct LodLevel {
+    pub mesh: Mesh,
+    // Smallest projected size (see Camera::projected_size) this level is used at.
+    pub min_screen_size: f32,
+}
+
+impl LodMesh {
+    // Levels must go from finest to coarsest.
+    pub fn new(levels: Vec<LodLevel>) -> LodMesh {
+        assert!(!levels.is_empty());
+        LodMesh { levels }
+    }
+
+    // Level 0 is the mesh itself. Each following level keeps `reduction` of the
+    // previous level's triangles and takes over when the mesh looks half as big.
+    pub fn generate(mesh: Mesh, level_count: usize, reduction: f32) -> LodMesh {
+        assert!(level_count > 0 && reduction > 0. && reduction < 1.);
+        assert!(mesh.primitive() == Primitive::Triangles, "only triangle lists can be simplified");
+        let mut data = mesh.to_data();
+        data.indices = mesh.triangle_indices().to_vec();
+        let mut levels = vec![LodLevel { mesh, min_screen_size: 0.5 }];
+        for i in 1..level_count {
+            let target = (data.triangle_count() as f32 * reduction) as usize;
+            data = simplify_data(&data, &SimplifyOptions::target_triangles(target));
+            levels.push(LodLevel {
+                mesh: Mesh::from_data(data.clone()),
+                min_screen_size: 0.5_f32.powi(i as i32 + 1),
+            });
+        }
+        levels.last_mut().unwrap().min_screen_size = 0.;
+        LodMesh { levels }
+    }
+
+    pub fn levels(&self) -> &[LodLevel] {
+        &self.levels
+    }
+
+    pub fn select_level(&self, camera: &Camera, model_transform: &Matrix4) -> usize {
+        let sphere = self.levels[0].mesh.transformed_bounding_sphere(model_transform);
+        let size = camera.projected_size(&sphere);
+        self.levels
+            .iter()
+            .position(|level| size >= level.min_screen_size)
+            .unwrap_or(self.levels.len() - 1)
+    }
+
+    pub fn select(&self, camera: &Camera, model_transform: &Matrix4) -> &Mesh {
+        &self.levels[self.select_level(camera, model_transform)].mesh
+    }
+
+    pub fn draw(&self, shader: &mut MeshShader, camera: &Camera, model_transform: &Matrix4) {
+        shader.set_model_transform(model_transform);
+        shader.draw(self.select(camera, model_transform));
+    }
+}
@@ -1,21 +1,52 @@
-pub mod camera;
-pub mod camera_controller;
-pub mod gpu;
-pub mod image;
-pub mod math;
-pub mod mesh;
-pub mod text;
-
 use glfw::*;
 
-use math::*;
-use text::{Font, Text, TextShader};
+use rugl::backend::{self, Backend, BackendText};
+#[cfg(feature = "headless")]
+use rugl::{golden, headless};
+use rugl::gpu;
+use rugl::math::*;
+use rugl::software::SoftwareBackend;
+use rugl::text::{self, Font, Text, TextShader};
 
 fn main() {
     let width = 1200;
     let height = 900;
     let title = "rugl";
 
+    // rugl --software out.png draws the demo's text on the CPU, with no GPU
+    // or display at all.
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(i) = args.iter().position(|a| a == "--software") {
+        let path = args.get(i + 1).map(|s| s.as_str()).unwrap_or("software.png");
+        if let Err(e) = render_software(path, width as i32, height as i32) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    #[cfg(feature = "headless")]
+    {
+        // rugl --headless out.png renders one frame offscreen, and
+        // rugl --golden [--bless] [filter] runs the golden image tests.
+        if args.iter().any(|a| a == "--golden") {
+            let bless = args.iter().any(|a| a == "--bless");
+            let filter = args.iter().skip(1).find(|a| !a.starts_with("--")).map(|s| s.as_str());
+            if !golden::run(filter, bless) {
+                std::process::exit(1);
+            }
+            return;
+        }
+        if let Some(i) = args.iter().position(|a| a == "--headless") {
+            let path = args.get(i + 1).map(|s| s.as_str()).unwrap_or("headless.png");
+            if let Err(e) = render_headless(path, width as i32, height as i32) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+
     // setup
     let mut glfw = glfw::init(glfw::FAIL_ON_ERRORS).unwrap();
     setup_window_hints(&mut glfw);
@@ -23,49 +54,114 @@ fn main() {
     setup_input(&mut window);
     setup_gl(&mut window);
 
-    // text
-    let freetype = text::init_library().unwrap();
-    let mut font = Font::open("resources/times.ttf", 18, &freetype).unwrap();
-    let lorem = "Lorem ipsum dolor sit amet, consectetur adipisicing elit, sed do eiusmod";
-    let text = Text::left_aligned(lorem, Vector2::new(30., 100.), 300., &mut font);
-    let text2 = Text::left_aligned(lorem, Vector2::new(30., 340.), 500., &mut font);
-    let mut font2 = Font::open("resources/times.ttf", 42, &freetype).unwrap();
-    let text3 = Text::left_aligned("Welló!!", Vector2::new(30., 200.), 100., &mut font2);
+    // Everything that owns GPU objects lives in this block, so that it is
+    // dropped while the context is still current.
+    {
+        let freetype = text::init_library().unwrap();
+        let mut scene = Scene::new(&freetype, width, height);
+
+        let mut msaa = false;
+        let mut gamma_correction = false;
+        let mut screenshot = false;
+
+        while !window.should_close() {
+            scene.draw();
+
+            // Before the swap, which leaves the back buffer undefined.
+            if screenshot {
+                screenshot = false;
+                // In pixels, which differ from screen coordinates on high DPI displays.
+                let (width, height) = window.get_framebuffer_size();
+                if let Err(e) = gpu::save_screenshot("screenshot.png", width, height) {
+                    eprintln!("{}", e);
+                }
+            }
 
+            window.swap_buffers();
+
+            // update
+            glfw.poll_events();
+            for (_, event) in glfw::flush_messages(&events) {
+                match event {
+                    WindowEvent::Key(Key::Escape, _, Action::Press, _) => window.set_should_close(true),
+                    WindowEvent::Key(Key::A, _, Action::Press, _) => toggle_gpu_feature(&mut msaa, gl::MULTISAMPLE),
+                    WindowEvent::Key(Key::G, _, Action::Press, _) => toggle_gpu_feature(&mut gamma_correction, gl::FRAMEBUFFER_SRGB),
+                    WindowEvent::Key(Key::P, _, Action::Press, _) => screenshot = true,
+                    // WindowEvent::Focus(false) => camera_ctl.deactivate(&mut window),
+                    // WindowEvent::CursorEnter(false) => camera_ctl.deactivate(&mut window),
+                    // WindowEvent::MouseButton(_, Action::Press, _) => camera_ctl.activate(&mut window),
+                    _ => {}
+                }
+            }
+        }
+    }
+    gpu::report_leaks();
+}
 
-    // shaders
-    let mut text_shader = TextShader::new().unwrap();
-    text_shader.set_screen_size(Vector2::new(width as f32, height as f32));
+// What the demo draws, in a window or headless.
+struct Scene<'a> {
+    // Own the glyph textures the texts use.
+    fonts: Vec<Font<'a>>,
+    // Each with the index of its font.
+    texts: Vec<(Text, usize)>,
+    text_shader: TextShader,
+}
 
-    let mut msaa = false;
-    let mut gamma_correction = false;
+impl<'a> Scene<'a> {
+    fn new(freetype: &'a freetype::Library, width: u32, height: u32) -> Scene<'a> {
+        // text
+        let mut font = Font::open("resources/times.ttf", 18, freetype).unwrap();
+        let lorem = "Lorem ipsum dolor sit amet, consectetur adipisicing elit, sed do eiusmod";
+        let text = Text::left_aligned(lorem, Vector2::new(30., 100.), 300., &mut font);
+        let text2 = Text::left_aligned(lorem, Vector2::new(30., 340.), 500., &mut font);
+        let mut font2 = Font::open("resources/times.ttf", 42, freetype).unwrap();
+        let text3 = Text::left_aligned("Welló!!", Vector2::new(30., 200.), 100., &mut font2);
+
+        // shaders
+        let mut text_shader = TextShader::new().unwrap_or_else(|e| panic!("{}", e));
+        text_shader.set_screen_size(Vector2::new(width as f32, height as f32));
+
+        Scene { fonts: vec![font, font2], texts: vec![(text, 0), (text2, 0), (text3, 1)], text_shader }
+    }
 
-    while !window.should_close() {
-        // draw
+    fn draw(&mut self) {
         gpu::clear(1.0, 1.0, 1.0, 1.0);
-
-        text_shader.draw(&text);
-        text_shader.draw(&text2);
-        text_shader.draw(&text3);
-
-        window.swap_buffers();
-
-        // update
-        glfw.poll_events();
-        for (_, event) in glfw::flush_messages(&events) {
-            match event {
-                WindowEvent::Key(Key::Escape, _, Action::Press, _) => window.set_should_close(true),
-                WindowEvent::Key(Key::A, _, Action::Press, _) => toggle_gpu_feature(&mut msaa, gl::MULTISAMPLE),
-                WindowEvent::Key(Key::G, _, Action::Press, _) => toggle_gpu_feature(&mut gamma_correction, gl::FRAMEBUFFER_SRGB),
-                // WindowEvent::Focus(false) => camera_ctl.deactivate(&mut window),
-                // WindowEvent::CursorEnter(false) => camera_ctl.deactivate(&mut window),
-                // WindowEvent::MouseButton(_, Action::Press, _) => camera_ctl.activate(&mut window),
-                _ => {}
-            }
+        for (text, font) in self.texts.iter() {
+            self.text_shader.draw(text, &self.fonts[*font]);
         }
     }
 }
 
+#[cfg(feature = "headless")]
+fn render_headless(path: &str, width: i32, height: i32) -> Result<(), String> {
+    let image = headless::render(width, height, || {
+        let freetype = text::init_library().unwrap();
+        Scene::new(&freetype, width as u32, height as u32).draw();
+    })?;
+    image.save_with_format(path, image::ImageFormat::Png).map_err(|e| format!("{}: {}", path, e))
+}
+
+// Lays out the same texts as Scene, but draws them with the software backend.
+fn render_software(path: &str, width: i32, height: i32) -> Result<(), String> {
+    let freetype = text::init_library().map_err(|e| e.to_string())?;
+    let mut font = Font::open("resources/times.ttf", 18, &freetype).map_err(|e| e.to_string())?;
+    let mut font2 = Font::open("resources/times.ttf", 42, &freetype).map_err(|e| e.to_string())?;
+    let lorem = "Lorem ipsum dolor sit amet, consectetur adipisicing elit, sed do eiusmod";
+    let text = Text::left_aligned_layout(lorem, Vector2::new(30., 100.), 300., &mut font);
+    let text2 = Text::left_aligned_layout(lorem, Vector2::new(30., 340.), 500., &mut font);
+    let text3 = Text::left_aligned_layout("Welló!!", Vector2::new(30., 200.), 100., &mut font2);
+    let texts = [(text, &font), (text2, &font), (text3, &font2)];
+
+    let mut backend = SoftwareBackend::new(width, height);
+    let program = backend.create_program(backend::text_program())?;
+    backend.clear(col4(1.0, 1.0, 1.0, 1.0));
+    for (text, font) in texts.iter() {
+        BackendText::new(&mut backend, text, font)?.draw(&mut backend, &program);
+    }
+    let image = backend.read_pixels()?;
+    image.save_with_format(path, image::ImageFormat::Png).map_err(|e| format!("{}: {}", path, e))
+}
+
 fn setup_window_hints(glfw: &mut glfw::Glfw) {
     glfw.window_hint(glfw::WindowHint::ClientApi(glfw::ClientApiHint::OpenGlEs));
     glfw.window_hint(glfw::WindowHint::ContextVersion(3, 1));
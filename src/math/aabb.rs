@@ -0,0 +1,285 @@
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3,
+    pub max: Vector3,
+}
+
+impl Aabb {
+    // Inverted bounds, so that extending it with any point yields that point.
+    pub const EMPTY: Aabb = Aabb {
+        min: Vector3 { x: f32::INFINITY, y: f32::INFINITY, z: f32::INFINITY },
+        max: Vector3 { x: f32::NEG_INFINITY, y: f32::NEG_INFINITY, z: f32::NEG_INFINITY },
+    };
+
+    pub fn new(min: Vector3, max: Vector3) -> Aabb {
+        Aabb { min, max }
+    }
+
+    pub fn from_points(points: &[Vector3]) -> Aabb {
+        let mut aabb = Aabb::EMPTY;
+        for &p in points {
+            aabb.extend(p);
+        }
+        aabb
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn extend(&mut self, p: Vector3) {
+        self.min = Vector3::min(self.min, p);
+        self.max = Vector3::max(self.max, p);
+    }
+
+    pub fn union(a: Aabb, b: Aabb) -> Aabb {
+        Aabb {
+            min: Vector3::min(a.min, b.min),
+            max: Vector3::max(a.max, b.max),
+        }
+    }
+
+    pub fn center(&self) -> Vector3 {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn size(&self) -> Vector3 {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.;
+        }
+        let s = self.size();
+        2. * (s.x * s.y + s.y * s.z + s.z * s.x)
+    }
+
+    pub fn contains(&self, p: Vector3) -> bool {
+        p.x >= self.min.x && p.x <= self.max.x &&
+        p.y >= self.min.y && p.y <= self.max.y &&
+        p.z >= self.min.z && p.z <= self.max.z
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x && self.max.x >= other.min.x &&
+        self.min.y <= other.max.y && self.max.y >= other.min.y &&
+        self.min.z <= other.max.z && self.max.z >= other.min.z
+    }
+
+    // Separating axis test, from Akenine-Möller's "Fast 3D Triangle-Box Overlap Testing".
+    pub fn intersects_triangle(&self, tri: &[Vector3; 3]) -> bool {
+        let c = self.center();
+        let h = 0.5 * self.size();
+        let v = [tri[0] - c, tri[1] - c, tri[2] - c];
+        let e = [v[1] - v[0], v[2] - v[1], v[0] - v[2]];
+
+        let separated = |axis: Vector3| -> bool {
+            let p0 = Vector3::dot(v[0], axis);
+            let p1 = Vector3::dot(v[1], axis);
+            let p2 = Vector3::dot(v[2], axis);
+            let r = h.x * axis.x.abs() + h.y * axis.y.abs() + h.z * axis.z.abs();
+            p0.min(p1).min(p2) > r || p0.max(p1).max(p2) < -r
+        };
+
+        // Box face normals, the triangle normal, and the 9 edge cross products.
+        for &axis in [Vector3::X, Vector3::Y, Vector3::Z].iter() {
+            if separated(axis) {
+                return false;
+            }
+            for &edge in e.iter() {
+                if separated(Vector3::cross(axis, edge)) {
+                    return false;
+                }
+            }
+        }
+        !separated(Vector3::cross(e[0], e[1]))
+    }
+
+    pub fn corners(&self) -> [Vector3; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Vector3::new(a.x, a.y, a.z),
+            Vector3::new(b.x, a.y, a.z),
+            Vector3::new(a.x, b.y, a.z),
+            Vector3::new(b.x, b.y, a.z),
+            Vector3::new(a.x, a.y, b.z),
+            Vector3::new(b.x, a.y, b.z),
+            Vector3::new(a.x, b.y, b.z),
+            Vector3::new(b.x, b.y, b.z),
+        ]
+    }
+
+    // Bounds of the transformed box, which contain the transformed contents.
+    pub fn transformed(&self, transform: &Matrix4) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        let mut aabb = Aabb::EMPTY;
+        for &p in self.corners().iter() {
+            aabb.extend(transform.transform_point(p));
+        }
+        aabb
+    }
+
+    // Distance along the ray to the entry point of the box, using the slab method.
+    // Returns t_min = 0 if the origin is inside the box.
+    pub fn ray_intersect(&self, ray: &Ray, max_distance: f32) -> Option<f32> {
+        let mut t_min = 0.0_f32;
+        let mut t_max = max_distance;
+        for &(o, d, lo, hi) in [
+            (ray.origin.x, ray.direction.x, self.min.x, self.max.x),
+            (ray.origin.y, ray.direction.y, self.min.y, self.max.y),
+            (ray.origin.z, ray.direction.z, self.min.z, self.max.z),
+        ].iter() {
+            // Parallel to the slab, the ray is in it everywhere or nowhere,
+            // and its faces count as inside. Dividing would give 0 * inf.
+            if d == 0. {
+                if o < lo || o > hi {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (lo - o) / d;
+            let t1 = (hi - o) / d;
+            t_min = t_min.max(t0.min(t1));
+            t_max = t_max.min(t0.max(t1));
+        }
+        if t_min <= t_max {
+            Some(t_min)
+        } else {
+            None
+        }
+    }
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Sphere {
+    pub center: Vector3,
+    pub radius: f32,
+}
+
+impl Sphere {
+    pub fn new(center: Vector3, radius: f32) -> Sphere {
+        Sphere { center, radius }
+    }
+
+    // Ritter's bounding sphere: within a few percent of the minimal one.
+    pub fn from_points(points: &[Vector3]) -> Sphere {
+        if points.is_empty() {
+            return Sphere::new(Vector3::ZERO, 0.);
+        }
+        let farthest_from = |q: Vector3| -> Vector3 {
+            let mut best = points[0];
+            for &p in points {
+                if (p - q).norm_squared() > (best - q).norm_squared() {
+                    best = p;
+                }
+            }
+            best
+        };
+        let a = farthest_from(points[0]);
+        let b = farthest_from(a);
+        let mut sphere = Sphere::new(0.5 * (a + b), 0.5 * (b - a).norm());
+        for &p in points {
+            let d = (p - sphere.center).norm();
+            if d > sphere.radius {
+                let radius = 0.5 * (sphere.radius + d);
+                sphere.center += ((radius - sphere.radius) / d) * (p - sphere.center);
+                sphere.radius = radius;
+            }
+        }
+        sphere
+    }
+
+    pub fn contains(&self, p: Vector3) -> bool {
+        (p - self.center).norm_squared() <= self.radius * self.radius
+    }
+
+    // Scales the radius by the largest axis scale, so it stays conservative
+    // under non-uniform scaling.
+    pub fn transformed(&self, transform: &Matrix4) -> Sphere {
+        let scale = transform.transform_vector(Vector3::X).norm()
+            .max(transform.transform_vector(Vector3::Y).norm())
+            .max(transform.transform_vector(Vector3::Z).norm());
+        Sphere::new(transform.transform_point(self.center), self.radius * scale)
+    }
+}
+
+#[cfg(test)]
+mod aabb_tests {
+    use super::*;
+
+    fn unit_box() -> Aabb {
+        Aabb::new(Vector3::new(-1., -1., -1.), Vector3::new(1., 1., 1.))
+    }
+
+    #[test]
+    fn ray_enters_box() {
+        let ray = Ray::new(Vector3::new(-3., 0.5, 0.), Vector3::new(1., 0., 0.));
+        assert_eq!(unit_box().ray_intersect(&ray, f32::INFINITY), Some(2.));
+        assert_eq!(unit_box().ray_intersect(&ray, 1.5), None);
+        let inside = Ray::new(Vector3::ZERO, Vector3::new(0.3, -0.2, 0.9));
+        assert_eq!(unit_box().ray_intersect(&inside, f32::INFINITY), Some(0.));
+        let away = Ray::new(Vector3::new(-3., 0., 0.), Vector3::new(-1., 0., 0.));
+        assert_eq!(unit_box().ray_intersect(&away, f32::INFINITY), None);
+        let diagonal = Ray::new(Vector3::new(-3., -3., 0.), Vector3::new(1., 3., 0.));
+        assert_eq!(unit_box().ray_intersect(&diagonal, f32::INFINITY), None);
+    }
+
+    #[test]
+    fn ray_parallel_to_slab() {
+        let along_x = |y: f32, z: f32| Ray::new(Vector3::new(-3., y, z), Vector3::new(1., 0., 0.));
+        assert_eq!(unit_box().ray_intersect(&along_x(0.5, -0.5), f32::INFINITY), Some(2.));
+        assert_eq!(unit_box().ray_intersect(&along_x(1.5, 0.), f32::INFINITY), None);
+        assert_eq!(unit_box().ray_intersect(&along_x(0., -1.5), f32::INFINITY), None);
+        // Along a face, and along an edge.
+        assert_eq!(unit_box().ray_intersect(&along_x(1., 0.), f32::INFINITY), Some(2.));
+        assert_eq!(unit_box().ray_intersect(&along_x(-1., -1.), f32::INFINITY), Some(2.));
+        // Through a flat box, as a BVH node around a flat quad is.
+        let flat = Aabb::new(Vector3::new(0., 0., 0.), Vector3::new(1., 1., 0.));
+        let ray = Ray::new(Vector3::new(0.5, -1., 0.), Vector3::new(0., 1., 0.));
+        assert_eq!(flat.ray_intersect(&ray, f32::INFINITY), Some(1.));
+    }
+
+    #[test]
+    fn triangle_overlap() {
+        let inside = [Vector3::new(-0.5, 0., 0.), Vector3::new(0.5, 0., 0.), Vector3::new(0., 0.5, 0.)];
+        assert!(unit_box().intersects_triangle(&inside));
+        // Every vertex outside, the middle through the box.
+        let across = [Vector3::new(-5., -5., 0.), Vector3::new(5., -5., 0.), Vector3::new(0., 5., 0.)];
+        assert!(unit_box().intersects_triangle(&across));
+        let above = [Vector3::new(-5., -5., 2.), Vector3::new(5., -5., 2.), Vector3::new(0., 5., 2.)];
+        assert!(!unit_box().intersects_triangle(&above));
+        // Their bounds and planes overlap, only the edge axis separates them.
+        let corner = |d: f32| [Vector3::new(d, 0., 0.), Vector3::new(0., d, 0.), Vector3::new(d, d, 0.)];
+        assert!(unit_box().intersects_triangle(&corner(1.8)));
+        assert!(!unit_box().intersects_triangle(&corner(2.2)));
+        let tilted = [Vector3::new(1.5, 0., -2.), Vector3::new(1.5, 0., 2.), Vector3::new(3., 0., 0.)];
+        assert!(!unit_box().intersects_triangle(&tilted));
+    }
+
+    #[test]
+    fn bounding_sphere() {
+        let axes = [Vector3::X, -Vector3::X, Vector3::Y, -Vector3::Y, Vector3::Z, -Vector3::Z];
+        let sphere = Sphere::from_points(&axes);
+        assert!(sphere.center.norm() < 1e-6);
+        assert!((sphere.radius - 1.).abs() < 1e-6);
+
+        // Points on the unit sphere, whose minimal bounding sphere is at
+        // most the unit one.
+        let mut points = Vec::new();
+        for i in 0..200 {
+            let z = 1. - 2. * (i as f32 + 0.5) / 200.;
+            let angle = i as f32 * 2.4;
+            let r = (1. - z * z).sqrt();
+            points.push(Vector3::new(5. + r * angle.cos(), r * angle.sin(), z));
+        }
+        let sphere = Sphere::from_points(&points);
+        assert!(points.iter().all(|&p| (p - sphere.center).norm() <= sphere.radius * 1.0001));
+        assert!(sphere.radius <= 1.1, "{}", sphere.radius);
+
+        assert_eq!(Sphere::from_points(&[]).radius, 0.);
+    }
+}
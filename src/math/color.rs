@@ -19,3 +19,24 @@ impl Color3 {
 pub fn col3(r: f32, g: f32, b: f32) -> Color3 {
     Color3 { r, g, b }
 }
+
+vector_space!(Color4 f32 {
+    r: f32,
+    g: f32,
+    b: f32,
+    a: f32
+});
+
+impl Color4 {
+    pub const TRANSPARENT: Color4 = Color4 { r: 0., g: 0., b: 0., a: 0. };
+    pub const K: Color4 = Color4 { r: 0., g: 0., b: 0., a: 1. };
+    pub const W: Color4 = Color4 { r: 1., g: 1., b: 1., a: 1. };
+
+    pub fn opaque(color: Color3) -> Color4 {
+        Color4 { r: color.r, g: color.g, b: color.b, a: 1. }
+    }
+}
+
+pub fn col4(r: f32, g: f32, b: f32, a: f32) -> Color4 {
+    Color4 { r, g, b, a }
+}
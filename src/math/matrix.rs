@@ -1,9 +1,11 @@
 
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
 pub struct Matrix4 {
     pub coords: [[f32; 4]; 4]
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn mat4(xx: f32, xy: f32, xz: f32, xw: f32,
             yx: f32, yy: f32, yz: f32, yw: f32,
             zx: f32, zy: f32, zz: f32, zw: f32,
@@ -23,6 +25,34 @@ impl Matrix4 {
         self.coords[0].as_ptr()
     }
 
+    pub fn transform_point(&self, p: Vector3) -> Vector3 {
+        let c = &self.coords;
+        Vector3::new(
+            c[0][0] * p.x + c[1][0] * p.y + c[2][0] * p.z + c[3][0],
+            c[0][1] * p.x + c[1][1] * p.y + c[2][1] * p.z + c[3][1],
+            c[0][2] * p.x + c[1][2] * p.y + c[2][2] * p.z + c[3][2],
+        )
+    }
+
+    pub fn transform_vector(&self, v: Vector3) -> Vector3 {
+        let c = &self.coords;
+        Vector3::new(
+            c[0][0] * v.x + c[1][0] * v.y + c[2][0] * v.z,
+            c[0][1] * v.x + c[1][1] * v.y + c[2][1] * v.z,
+            c[0][2] * v.x + c[1][2] * v.y + c[2][2] * v.z,
+        )
+    }
+
+    pub fn transform(&self, v: Vector4) -> Vector4 {
+        let c = &self.coords;
+        Vector4::new(
+            c[0][0] * v.x + c[1][0] * v.y + c[2][0] * v.z + c[3][0] * v.w,
+            c[0][1] * v.x + c[1][1] * v.y + c[2][1] * v.z + c[3][1] * v.w,
+            c[0][2] * v.x + c[1][2] * v.y + c[2][2] * v.z + c[3][2] * v.w,
+            c[0][3] * v.x + c[1][3] * v.y + c[2][3] * v.z + c[3][3] * v.w,
+        )
+    }
+
     pub fn id() -> Matrix4 {
         mat4(
             1., 0., 0., 0.,
@@ -41,6 +71,15 @@ impl Matrix4 {
         )
     }
 
+    pub fn scale(v: Vector3) -> Matrix4 {
+        mat4(
+            v.x, 0., 0., 0.,
+            0., v.y, 0., 0.,
+            0., 0., v.z, 0.,
+            0., 0., 0., 1.,
+        )
+    }
+
     pub fn perspective(fov_x: f32, aspect: f32, near: f32, far: f32) -> Matrix4 {
         let n = near;
         let f = far;
@@ -87,4 +126,61 @@ impl Mul<Matrix4> for Matrix4 {
         }
         res
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct Matrix3 {
+    pub coords: [[f32; 3]; 3]
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn mat3(xx: f32, xy: f32, xz: f32,
+            yx: f32, yy: f32, yz: f32,
+            zx: f32, zy: f32, zz: f32) -> Matrix3 {
+    Matrix3{
+        coords: [
+            [xx, yx, zx],
+            [xy, yy, zy],
+            [xz, yz, zz],
+        ]
+    }
+}
+
+impl Matrix3 {
+    pub fn as_ptr(&self) -> *const f32 {
+        self.coords[0].as_ptr()
+    }
+
+    pub fn id() -> Matrix3 {
+        mat3(
+            1., 0., 0.,
+            0., 1., 0.,
+            0., 0., 1.,
+        )
+    }
+
+    pub fn transform_vector(&self, v: Vector3) -> Vector3 {
+        let c = &self.coords;
+        Vector3::new(
+            c[0][0] * v.x + c[1][0] * v.y + c[2][0] * v.z,
+            c[0][1] * v.x + c[1][1] * v.y + c[2][1] * v.z,
+            c[0][2] * v.x + c[1][2] * v.y + c[2][2] * v.z,
+        )
+    }
+}
+
+impl Matrix4 {
+    // The rotation and scale part, such as for transforming normals by
+    // matrices without non-uniform scale.
+    pub fn upper_left(&self) -> Matrix3 {
+        let c = &self.coords;
+        Matrix3 {
+            coords: [
+                [c[0][0], c[0][1], c[0][2]],
+                [c[1][0], c[1][1], c[1][2]],
+                [c[2][0], c[2][1], c[2][2]],
+            ]
+        }
+    }
+}
@@ -10,3 +10,5 @@ include!("vector.rs");
 include!("matrix.rs");
 include!("quaternion.rs");
 include!("rectangle.rs");
+include!("aabb.rs");
+include!("ray.rs");
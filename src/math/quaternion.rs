@@ -28,6 +28,23 @@ impl Quaternion {
         }
     }
 
+    // Spherical interpolation along the shorter arc.
+    pub fn slerp(a: Quaternion, b: Quaternion, t: f32) -> Quaternion {
+        let mut cos = Quaternion::dot(a, b);
+        let mut b = b;
+        if cos < 0. {
+            cos = -cos;
+            b = -b;
+        }
+        if cos > 0.9995 {
+            // Nearly the same rotation, where slerp is unstable.
+            return ((1. - t) * a + t * b).normalized();
+        }
+        let angle = cos.acos();
+        let sin = angle.sin();
+        ((((1. - t) * angle).sin() / sin) * a + ((t * angle).sin() / sin) * b).normalized()
+    }
+
     pub fn conj(self) -> Quaternion {
         Quaternion { s: self.s, x: -self.x, y: -self.y, z: -self.z }
     }
@@ -0,0 +1,157 @@
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Ray {
+    pub origin: Vector3,
+    pub direction: Vector3,
+}
+
+impl Ray {
+    pub fn new(origin: Vector3, direction: Vector3) -> Ray {
+        Ray { origin, direction }
+    }
+
+    pub fn at(&self, t: f32) -> Vector3 {
+        self.origin + t * self.direction
+    }
+
+    pub fn transformed(&self, transform: &Matrix4) -> Ray {
+        Ray {
+            origin: transform.transform_point(self.origin),
+            direction: transform.transform_vector(self.direction),
+        }
+    }
+
+    // Möller-Trumbore. Returns the distance along the ray (in units of the
+    // direction's length) and the barycentric coordinates of the hit.
+    // Both triangle windings are hit.
+    pub fn triangle_intersect(&self, tri: &[Vector3; 3]) -> Option<(f32, Vector3)> {
+        let e1 = tri[1] - tri[0];
+        let e2 = tri[2] - tri[0];
+        let p = Vector3::cross(self.direction, e2);
+        let det = Vector3::dot(e1, p);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1. / det;
+        let s = self.origin - tri[0];
+        let u = Vector3::dot(s, p) * inv_det;
+        if !(0. ..=1.).contains(&u) {
+            return None;
+        }
+        let q = Vector3::cross(s, e1);
+        let v = Vector3::dot(self.direction, q) * inv_det;
+        if v < 0. || u + v > 1. {
+            return None;
+        }
+        let t = Vector3::dot(e2, q) * inv_det;
+        if t < 0. {
+            return None;
+        }
+        Some((t, Vector3::new(1. - u - v, u, v)))
+    }
+}
+
+// Closest point to p on the triangle, as barycentric coordinates.
+// From Ericson, Real-Time Collision Detection, 5.1.5.
+pub fn closest_point_on_triangle(p: Vector3, tri: &[Vector3; 3]) -> Vector3 {
+    let [a, b, c] = *tri;
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = Vector3::dot(ab, ap);
+    let d2 = Vector3::dot(ac, ap);
+    if d1 <= 0. && d2 <= 0. {
+        return Vector3::new(1., 0., 0.);
+    }
+    let bp = p - b;
+    let d3 = Vector3::dot(ab, bp);
+    let d4 = Vector3::dot(ac, bp);
+    if d3 >= 0. && d4 <= d3 {
+        return Vector3::new(0., 1., 0.);
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0. && d1 >= 0. && d3 <= 0. {
+        let v = d1 / (d1 - d3);
+        return Vector3::new(1. - v, v, 0.);
+    }
+    let cp = p - c;
+    let d5 = Vector3::dot(ab, cp);
+    let d6 = Vector3::dot(ac, cp);
+    if d6 >= 0. && d5 <= d6 {
+        return Vector3::new(0., 0., 1.);
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0. && d2 >= 0. && d6 <= 0. {
+        let w = d2 / (d2 - d6);
+        return Vector3::new(1. - w, 0., w);
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0. && (d4 - d3) >= 0. && (d5 - d6) >= 0. {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return Vector3::new(0., 1. - w, w);
+    }
+    let denom = 1. / (va + vb + vc);
+    let v = vb * denom;
+    let w = vc * denom;
+    Vector3::new(1. - v - w, v, w)
+}
+
+pub fn barycentric_interpolate(tri: &[Vector3; 3], bary: Vector3) -> Vector3 {
+    bary.x * tri[0] + bary.y * tri[1] + bary.z * tri[2]
+}
+
+#[cfg(test)]
+mod ray_tests {
+    use super::*;
+
+    const TRIANGLE: [Vector3; 3] = [
+        Vector3 { x: 0., y: 0., z: 0. },
+        Vector3 { x: 1., y: 0., z: 0. },
+        Vector3 { x: 0., y: 1., z: 0. },
+    ];
+
+    fn assert_near(actual: Vector3, expected: Vector3) {
+        assert!((actual - expected).norm() < 1e-5, "{:?} is not {:?}", actual, expected);
+    }
+
+    #[test]
+    fn ray_hits_triangle() {
+        let down = Ray::new(Vector3::new(0.25, 0.5, 2.), Vector3::new(0., 0., -1.));
+        let (t, bary) = down.triangle_intersect(&TRIANGLE).unwrap();
+        assert!((t - 2.).abs() < 1e-6);
+        assert_near(bary, Vector3::new(0.25, 0.25, 0.5));
+        assert_near(barycentric_interpolate(&TRIANGLE, bary), down.at(t));
+        // In units of the direction's length.
+        let fast = Ray::new(down.origin, Vector3::new(0., 0., -4.));
+        assert!((fast.triangle_intersect(&TRIANGLE).unwrap().0 - 0.5).abs() < 1e-6);
+        // The other winding.
+        let reversed = [TRIANGLE[0], TRIANGLE[2], TRIANGLE[1]];
+        assert!(down.triangle_intersect(&reversed).is_some());
+    }
+
+    #[test]
+    fn ray_misses_triangle() {
+        let outside = Ray::new(Vector3::new(0.75, 0.75, 2.), Vector3::new(0., 0., -1.));
+        assert_eq!(outside.triangle_intersect(&TRIANGLE), None);
+        let behind = Ray::new(Vector3::new(0.25, 0.25, 2.), Vector3::new(0., 0., 1.));
+        assert_eq!(behind.triangle_intersect(&TRIANGLE), None);
+        let parallel = Ray::new(Vector3::new(-1., 0.25, 0.), Vector3::new(1., 0., 0.));
+        assert_eq!(parallel.triangle_intersect(&TRIANGLE), None);
+    }
+
+    #[test]
+    fn closest_point_regions() {
+        let closest = |x: f32, y: f32, z: f32| closest_point_on_triangle(Vector3::new(x, y, z), &TRIANGLE);
+        // Vertex regions.
+        assert_near(closest(-1., -1., 0.5), Vector3::new(1., 0., 0.));
+        assert_near(closest(2., -0.5, 0.), Vector3::new(0., 1., 0.));
+        assert_near(closest(-0.5, 2., -1.), Vector3::new(0., 0., 1.));
+        // Edge regions.
+        assert_near(closest(0.25, -1., 0.), Vector3::new(0.75, 0.25, 0.));
+        assert_near(closest(-1., 0.75, 1.), Vector3::new(0.25, 0., 0.75));
+        assert_near(closest(1., 1., 0.), Vector3::new(0., 0.5, 0.5));
+        // Face region, above and below.
+        assert_near(closest(0.25, 0.25, 3.), Vector3::new(0.5, 0.25, 0.25));
+        assert_near(closest(0.1, 0.2, -3.), Vector3::new(0.7, 0.1, 0.2));
+    }
+}
+
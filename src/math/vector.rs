@@ -59,8 +59,31 @@ macro_rules! vector3_impl {
 vector3_impl!(Vector3, f32);
 vector3_impl!(Vector3I, i32);
 
+vector_space!(Vector4 f32 {
+    x: f32,
+    y: f32,
+    z: f32,
+    w: f32
+});
+
+impl Vector4 {
+    pub const ZERO: Vector4 = Vector4 { x: 0., y: 0., z: 0., w: 0. };
+
+    pub fn new(x: f32, y: f32, z: f32, w: f32) -> Vector4 {
+        Vector4 { x, y, z, w }
+    }
+}
+
 impl Vector3 {
     pub fn normalized(self) -> Vector3 {
         self / self.norm()
     }
+
+    pub fn min(v: Vector3, w: Vector3) -> Vector3 {
+        Vector3::new(v.x.min(w.x), v.y.min(w.y), v.z.min(w.z))
+    }
+
+    pub fn max(v: Vector3, w: Vector3) -> Vector3 {
+        Vector3::new(v.x.max(w.x), v.y.max(w.y), v.z.max(w.z))
+    }
 }
@@ -1,21 +1,192 @@
+use crate::bvh::Bvh;
 use crate::gpu;
-use crate::gpu::{Attr, PointerConfig};
+use crate::gpu::{Attr, IndexType, PointerConfig, Primitive};
 use crate::math::*;
+use crate::simplify;
+use crate::skeleton::MAX_JOINTS;
+use crate::simplify::SimplifyOptions;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::mem::{offset_of, size_of};
 
 #[derive(Debug)]
 pub struct Mesh {
     positions: Vec<Vector3>,
     tex_coords: Vec<Vector2>,
     normals: Vec<Vector3>,
+    // Empty for meshes drawn without indices.
     indices: Vec<u32>,
+    primitive: Primitive,
+    morph_targets: Vec<MorphTarget>,
+    morph_buffers: Vec<MorphBuffers>,
+    vertex_buffers: VertexBuffers,
     vertex_array: gpu::VertexArray,
+    // Computed on first use.
+    bounding_box: Cell<Option<Aabb>>,
+    bounding_sphere: Cell<Option<Sphere>>,
 }
 
-impl Mesh {
-    pub fn load_obj(source: &str) -> Mesh {
-        let mut obj_positions = Vec::new();
-        let mut obj_normals = Vec::new();
-        let mut obj_tex_coords = Vec::new();
+// Mesh vertex data on the CPU, for building and processing meshes without
+// touching the GPU.
+#[derive(Debug, Clone, Default)]
+pub struct MeshData {
+    pub positions: Vec<Vector3>,
+    pub tex_coords: Vec<Vector2>,
+    pub normals: Vec<Vector3>,
+    pub indices: Vec<u32>,
+    pub morph_targets: Vec<MorphTarget>,
+}
+
+impl MeshData {
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    // A unit cube around the origin, with a normal per face.
+    pub fn cube() -> MeshData {
+        let indices = vec![
+            6, 3, 1, 6, 1, 4, // -x
+            2, 7, 5, 2, 5, 0, // +x
+            11, 10, 8, 11, 8, 9, // -y
+            15, 14, 12, 15, 12, 13, // +y
+            16, 21, 20, 16, 20, 17, // -z
+            19, 22, 23, 19, 23, 18, // +z
+        ];
+        let positions = vec![
+            Vector3::new(0.5, -0.5, -0.5),
+            Vector3::new(-0.5, -0.5, -0.5),
+            Vector3::new(0.5_, -0.5, 0.5),
+            Vector3::new(-0.5, -0.5, 0.5),
+            Vector3::new(-0.5, 0.5, -0.5),
+            Vector3::new(0.5_, 0.5, -0.5),
+            Vector3::new(-0.5, 0.5, 0.5),
+            Vector3::new(0.5_, 0.5, 0.5),
+            Vector3::new(0.5, -0.5, -0.5),
+            Vector3::new(-0.5, -0.5, -0.5),
+            Vector3::new(0.5, -0.5, 0.5),
+            Vector3::new(-0.5, -0.5, 0.5),
+            Vector3::new(-0.5, 0.5, -0.5),
+            Vector3::new(0.5, 0.5, -0.5),
+            Vector3::new(-0.5, 0.5, 0.5),
+            Vector3::new(0.5, 0.5, 0.5),
+            Vector3::new(0.5, -0.5, -0.5),
+            Vector3::new(-0.5, -0.5, -0.5),
+            Vector3::new(0.5, -0.5, 0.5),
+            Vector3::new(-0.5, -0.5, 0.5),
+            Vector3::new(-0.5, 0.5, -0.5),
+            Vector3::new(0.5, 0.5, -0.5),
+            Vector3::new(-0.5, 0.5, 0.5),
+            Vector3::new(0.5, 0.5, 0.5),
+        ];
+        let tex_coords = vec![Vector2::new(0.0, 0.0); 24];
+        let normals = vec![
+            Vector3::new(1., 0., 0.),
+            Vector3::new(-1., 0., 0.),
+            Vector3::new(1., 0., 0.),
+            Vector3::new(-1., 0., 0.),
+            Vector3::new(-1., 0., 0.),
+            Vector3::new(1., 0., 0.),
+            Vector3::new(-1., 0., 0.),
+            Vector3::new(1., 0., 0.),
+            Vector3::new(0., -1., 0.),
+            Vector3::new(0., -1., 0.),
+            Vector3::new(0., -1., 0.),
+            Vector3::new(0., -1., 0.),
+            Vector3::new(0., 1., 0.),
+            Vector3::new(0., 1., 0.),
+            Vector3::new(0., 1., 0.),
+            Vector3::new(0., 1., 0.),
+            Vector3::new(0., 0., -1.),
+            Vector3::new(0., 0., -1.),
+            Vector3::new(0., 0., 1.),
+            Vector3::new(0., 0., 1.),
+            Vector3::new(0., 0., -1.),
+            Vector3::new(0., 0., -1.),
+            Vector3::new(0., 0., 1.),
+            Vector3::new(0., 0., 1.),
+        ];
+
+        MeshData { positions, tex_coords, normals, indices, morph_targets: Vec::new() }
+    }
+
+    // Bakes the targets, with a weight each, into the positions and normals.
+    // The result has no targets. This is what the morph shader does.
+    pub fn morphed(&self, weights: &[f32]) -> MeshData {
+        assert_eq!(weights.len(), self.morph_targets.len());
+        let mut data = MeshData {
+            positions: self.positions.clone(),
+            tex_coords: self.tex_coords.clone(),
+            normals: self.normals.clone(),
+            indices: self.indices.clone(),
+            morph_targets: Vec::new(),
+        };
+        for (target, &weight) in self.morph_targets.iter().zip(weights.iter()) {
+            if weight == 0. {
+                continue;
+            }
+            for (p, &d) in data.positions.iter_mut().zip(target.position_deltas.iter()) {
+                *p += weight * d;
+            }
+            for (n, &d) in data.normals.iter_mut().zip(target.normal_deltas.iter()) {
+                *n += weight * d;
+            }
+        }
+        for n in data.normals.iter_mut() {
+            if n.norm() > 0. {
+                *n = n.normalized();
+            }
+        }
+        data
+    }
+}
+
+// A blend shape: offsets of every vertex of a mesh, added to it in
+// proportion to the target's weight. Normal deltas may be left empty.
+#[derive(Debug, Clone, Default)]
+pub struct MorphTarget {
+    pub name: String,
+    pub position_deltas: Vec<Vector3>,
+    pub normal_deltas: Vec<Vector3>,
+}
+
+// Morph targets bound at once. Each takes one slot, and another one for its
+// normals if any target of the mesh has normal deltas.
+pub const MORPH_SLOTS: usize = 4;
+
+#[derive(Debug)]
+struct MorphBuffers {
+    positions: gpu::Buffer,
+    normals: Option<gpu::Buffer>,
+}
+
+#[derive(Debug)]
+struct VertexBuffers {
+    positions: gpu::Buffer,
+    tex_coords: gpu::Buffer,
+    normals: gpu::Buffer,
+}
+
+// A mesh with polygonal faces and separately indexed attributes, like an OBJ
+// file. This is what subdivision works on. Faces are kept as they are until
+// triangulated for drawing.
+#[derive(Debug, Clone, Default)]
+pub struct PolyMesh {
+    pub positions: Vec<Vector3>,
+    pub tex_coords: Vec<Vector2>,
+    pub normals: Vec<Vector3>,
+    pub faces: Vec<Vec<Corner>>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Corner {
+    pub position: u32,
+    pub tex_coord: Option<u32>,
+    pub normal: Option<u32>,
+}
+
+impl PolyMesh {
+    pub fn load_obj(source: &str) -> PolyMesh {
+        let mut mesh = PolyMesh::default();
 
         for line in source.lines() {
             let mut args = line.split_whitespace();
@@ -26,8 +197,8 @@ impl Mesh {
                         let y = args.next().unwrap().parse::<f32>().unwrap();
                         let z = args.next().unwrap().parse::<f32>().unwrap();
                         let buf = match cmd {
-                            "v" => &mut obj_positions,
-                            "vn" => &mut obj_normals,
+                            "v" => &mut mesh.positions,
+                            "vn" => &mut mesh.normals,
                             _ => unreachable!(),
                         };
                         buf.push(Vector3::new(x, y, z));
@@ -35,54 +206,273 @@ impl Mesh {
                     "vt" => {
                         let x = args.next().unwrap().parse::<f32>().unwrap();
                         let y = args.next().unwrap().parse::<f32>().unwrap();
-                        obj_tex_coords.push(Vector2::new(x, y));
+                        mesh.tex_coords.push(Vector2::new(x, y));
+                    }
+                    "f" => {
+                        let mut face = Vec::new();
+                        for vertex_str in args {
+                            let v_idxs: Vec<Option<u32>> = vertex_str
+                                .split("/")
+                                .map(|s| s.parse::<u32>().ok())
+                                .collect();
+                            assert!(v_idxs.len() == 3);
+                            face.push(Corner {
+                                position: v_idxs[0].unwrap() - 1,
+                                tex_coord: v_idxs[1].map(|i| i - 1),
+                                normal: v_idxs[2].map(|i| i - 1),
+                            });
+                        }
+                        mesh.faces.push(face);
                     }
                     _ => continue,
                 }
             }
         }
+        mesh
+    }
 
-        let mut positions: Vec<Vector3> = Vec::new();
-        let mut normals: Vec<Vector3> = Vec::new();
-        let mut tex_coords: Vec<Vector2> = Vec::new();
-        let mut indices: Vec<u32> = Vec::new();
+    // Welds vertices by position, keeping texture coordinates and normals per corner.
+    pub fn from_data(data: &MeshData) -> PolyMesh {
+        let mut mesh = PolyMesh::default();
+        let mut position_ids: HashMap<[u32; 3], u32> = HashMap::new();
+        let mut corners = Vec::with_capacity(data.positions.len());
+        for i in 0..data.positions.len() {
+            let p = data.positions[i];
+            let position = *position_ids
+                .entry([p.x.to_bits(), p.y.to_bits(), p.z.to_bits()])
+                .or_insert_with(|| {
+                    mesh.positions.push(p);
+                    mesh.positions.len() as u32 - 1
+                });
+            corners.push(Corner { position, tex_coord: Some(i as u32), normal: Some(i as u32) });
+        }
+        mesh.tex_coords = data.tex_coords.clone();
+        mesh.normals = data.normals.clone();
+        // Faces wind the other way around than triangles, see triangulate.
+        for t in data.indices.chunks(3) {
+            mesh.faces.push(vec![corners[t[0] as usize], corners[t[2] as usize], corners[t[1] as usize]]);
+        }
+        mesh
+    }
 
-        for line in source.lines() {
-            let mut args = line.split_whitespace();
-            if let Some("f") = args.next() {
-                let mut vertex_count = 0;
-                for vertex_str in args {
-                    vertex_count += 1;
-                    let v_idxs: Vec<Option<usize>> = vertex_str
-                        .split("/")
-                        .map(|s| s.parse::<usize>().ok())
-                        .collect();
-                    assert!(v_idxs.len() == 3);
-                    positions.push(v_idxs[0].map_or(Vector3::ZERO, |i| obj_positions[i - 1]));
-                    tex_coords.push(v_idxs[1].map_or(Vector2::ZERO, |i| obj_tex_coords[i - 1]));
-                    normals.push(v_idxs[2].map_or(Vector3::ZERO, |i| obj_normals[i - 1]));
-                }
-                let i0 = indices.len() as u32;
-                for i in 2..vertex_count {
-                    indices.push(i0);
-                    indices.push(i0 + i);
-                    indices.push(i0 + i - 1);
-                }
+    pub fn is_triangle_mesh(&self) -> bool {
+        self.faces.iter().all(|f| f.len() == 3)
+    }
+
+    // Fan triangulates each face, with one vertex per face corner.
+    pub fn triangulate(&self) -> MeshData {
+        let mut data = MeshData::default();
+        for face in self.faces.iter() {
+            let i0 = data.positions.len() as u32;
+            for corner in face.iter() {
+                data.positions.push(self.positions[corner.position as usize]);
+                data.tex_coords.push(corner.tex_coord.map_or(Vector2::ZERO, |i| self.tex_coords[i as usize]));
+                data.normals.push(corner.normal.map_or(Vector3::ZERO, |i| self.normals[i as usize]));
+            }
+            for i in 2..face.len() as u32 {
+                data.indices.push(i0);
+                data.indices.push(i0 + i);
+                data.indices.push(i0 + i - 1);
+            }
+        }
+        data
+    }
+
+    // Replaces the normals with smooth ones, averaged per position from the
+    // area weighted normals of the faces around it.
+    pub fn compute_normals(&mut self) {
+        let mut normals = vec![Vector3::ZERO; self.positions.len()];
+        for face in self.faces.iter_mut() {
+            let p0 = self.positions[face[0].position as usize];
+            let mut n = Vector3::ZERO;
+            for i in 1..face.len() - 1 {
+                let p1 = self.positions[face[i].position as usize];
+                let p2 = self.positions[face[i + 1].position as usize];
+                n += Vector3::cross(p1 - p0, p2 - p0);
+            }
+            for corner in face.iter_mut() {
+                normals[corner.position as usize] += n;
+                corner.normal = Some(corner.position);
+            }
+        }
+        for n in normals.iter_mut() {
+            if n.norm() > 0. {
+                *n = n.normalized();
             }
         }
+        self.normals = normals;
+    }
+}
+
+// A point on the surface of a mesh, such as the result of a ray cast.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MeshPoint {
+    pub triangle: usize,
+    pub barycentric: Vector3,
+    pub position: Vector3,
+    pub distance: f32,
+}
+
+impl Mesh {
+    pub fn new(positions: Vec<Vector3>, tex_coords: Vec<Vector2>, normals: Vec<Vector3>, indices: Vec<u32>) -> Self {
+        assert_eq!(indices.len() % 3, 0);
+        Mesh::with_primitive(Primitive::Triangles, positions, tex_coords, normals, indices)
+    }
 
+    // Meshes of other primitives than triangle lists are drawn like any other,
+    // but the triangle queries (closest_point, ray_intersect, build_bvh and
+    // simplified) see them as having no triangles. Meshes without indices
+    // are drawn with the vertices in order, and have no triangles either.
+    pub fn with_primitive(
+        primitive: Primitive,
+        positions: Vec<Vector3>,
+        tex_coords: Vec<Vector2>,
+        normals: Vec<Vector3>,
+        indices: Vec<u32>,
+    ) -> Self {
+        assert!(positions.len() == tex_coords.len() && positions.len() == normals.len());
         let vertex_array = gpu::VertexArray::new();
-        let mesh = Mesh {
+        let mut mesh = Mesh {
             positions,
             tex_coords,
             normals,
             indices,
+            primitive,
+            morph_targets: Vec::new(),
+            morph_buffers: Vec::new(),
+            vertex_buffers: VertexBuffers {
+                positions: gpu::Buffer::new(),
+                tex_coords: gpu::Buffer::new(),
+                normals: gpu::Buffer::new(),
+            },
             vertex_array,
+            bounding_box: Cell::new(None),
+            bounding_sphere: Cell::new(None),
         };
         mesh.setup_attributes();
         mesh
     }
 
+    // Points without texture coordinates or normals, such as scans.
+    pub fn new_points(positions: Vec<Vector3>) -> Self {
+        let n = positions.len();
+        Mesh::with_primitive(Primitive::Points, positions, vec![Vector2::ZERO; n], vec![Vector3::ZERO; n], Vec::new())
+    }
+
+    // Line segments between pairs of indices, for debug drawing.
+    pub fn new_lines(positions: Vec<Vector3>, indices: Vec<u32>) -> Self {
+        assert_eq!(indices.len() % 2, 0);
+        let n = positions.len();
+        Mesh::with_primitive(Primitive::Lines, positions, vec![Vector2::ZERO; n], vec![Vector3::ZERO; n], indices)
+    }
+
+    // The edges of the triangles, each once, as a line mesh.
+    pub fn wireframe(&self) -> Mesh {
+        let mut edges = HashSet::new();
+        let mut indices = Vec::new();
+        for t in self.triangle_indices().chunks(3) {
+            for i in 0..3 {
+                let (a, b) = (t[i], t[(i + 1) % 3]);
+                if edges.insert((a.min(b), a.max(b))) {
+                    indices.extend_from_slice(&[a, b]);
+                }
+            }
+        }
+        Mesh::with_primitive(
+            Primitive::Lines,
+            self.positions.clone(),
+            self.tex_coords.clone(),
+            self.normals.clone(),
+            indices,
+        )
+    }
+
+    pub fn from_data(data: MeshData) -> Self {
+        let mut mesh = Mesh::new(data.positions, data.tex_coords, data.normals, data.indices);
+        if !data.morph_targets.is_empty() {
+            mesh.set_morph_targets(data.morph_targets);
+        }
+        mesh
+    }
+
+    pub fn to_data(&self) -> MeshData {
+        MeshData {
+            positions: self.positions.clone(),
+            tex_coords: self.tex_coords.clone(),
+            normals: self.normals.clone(),
+            indices: self.indices.clone(),
+            morph_targets: self.morph_targets.clone(),
+        }
+    }
+
+    // Uploads every target, each in its own buffers, so that any of them
+    // can be bound for drawing with MeshShader::draw_morphed.
+    pub fn set_morph_targets(&mut self, targets: Vec<MorphTarget>) {
+        let n = self.positions.len();
+        for target in targets.iter() {
+            assert_eq!(target.position_deltas.len(), n, "morph target {}", target.name);
+            assert!(target.normal_deltas.is_empty() || target.normal_deltas.len() == n, "morph target {}", target.name);
+        }
+        let with_normals = targets.iter().any(|t| !t.normal_deltas.is_empty());
+        self.morph_buffers = targets
+            .iter()
+            .map(|target| {
+                let positions = gpu::Buffer::new();
+                gpu::load_buffer_data(&positions, &target.position_deltas[..]);
+                let normals = if with_normals {
+                    let normals = gpu::Buffer::new();
+                    if target.normal_deltas.is_empty() {
+                        gpu::load_buffer_data(&normals, &vec![Vector3::ZERO; n][..]);
+                    } else {
+                        gpu::load_buffer_data(&normals, &target.normal_deltas[..]);
+                    }
+                    Some(normals)
+                } else {
+                    None
+                };
+                MorphBuffers { positions, normals }
+            })
+            .collect();
+        self.morph_targets = targets;
+    }
+
+    pub fn morph_targets(&self) -> &[MorphTarget] {
+        &self.morph_targets
+    }
+
+    // Binds the targets with the largest weights to the morph slots. Returns
+    // the weights of the bound targets and whether their normals are bound
+    // too, in which case target i takes slots 2i and 2i + 1.
+    fn bind_morph_targets(&self, weights: &[f32]) -> (Vector4, bool) {
+        assert_eq!(weights.len(), self.morph_targets.len());
+        let with_normals = self.morph_buffers.iter().any(|b| b.normals.is_some());
+        let capacity = if with_normals { MORPH_SLOTS / 2 } else { MORPH_SLOTS };
+        let mut order: Vec<usize> = (0..weights.len()).filter(|&i| weights[i] != 0.).collect();
+        order.sort_by(|&a, &b| weights[b].abs().total_cmp(&weights[a].abs()));
+        order.truncate(capacity);
+
+        let mut bound = [0.; MORPH_SLOTS];
+        let mut slot = 0;
+        for (k, &target) in order.iter().enumerate() {
+            let buffers = &self.morph_buffers[target];
+            bound[k] = weights[target];
+            self.vertex_array.setup_attribute(Attr::Morph(slot), &buffers.positions, PointerConfig::vector3());
+            slot += 1;
+            if let Some(normals) = &buffers.normals {
+                self.vertex_array.setup_attribute(Attr::Morph(slot), normals, PointerConfig::vector3());
+                slot += 1;
+            }
+        }
+        for unused in slot..MORPH_SLOTS as u32 {
+            self.vertex_array.disable_attribute(Attr::Morph(unused));
+        }
+        (Vector4::new(bound[0], bound[1], bound[2], bound[3]), with_normals)
+    }
+
+    pub fn load_obj(source: &str) -> Mesh {
+        Mesh::from_data(PolyMesh::load_obj(source).triangulate())
+    }
+
     pub fn new_quad(width: f32, height: f32) -> Self {
         let indices = vec![
             0, 1, 3, 3, 1, 2,
@@ -119,150 +509,299 @@ impl Mesh {
             Vector3::new(0., 0., -1.),
         ];
 
-        let vertex_array = gpu::VertexArray::new();
-        let mesh = Mesh {
-            positions,
-            tex_coords,
-            normals,
-            indices,
-            vertex_array,
-        };
-        mesh.setup_attributes();
-        mesh
+        Mesh::new(positions, tex_coords, normals, indices)
     }
 
     pub fn new_cube() -> Self {
-        let indices = vec![
-            6, 3, 1, 6, 1, 4, // -x
-            2, 7, 5, 2, 5, 0, // +x
-            11, 10, 8, 11, 8, 9, // -y
-            15, 14, 12, 15, 12, 13, // +y
-            16, 21, 20, 16, 20, 17, // -z
-            19, 22, 23, 19, 23, 18, // +z
-        ];
-        let positions = vec![
-            Vector3::new(0.5, -0.5, -0.5),
-            Vector3::new(-0.5, -0.5, -0.5),
-            Vector3::new(0.5_, -0.5, 0.5),
-            Vector3::new(-0.5, -0.5, 0.5),
-            Vector3::new(-0.5, 0.5, -0.5),
-            Vector3::new(0.5_, 0.5, -0.5),
-            Vector3::new(-0.5, 0.5, 0.5),
-            Vector3::new(0.5_, 0.5, 0.5),
-            Vector3::new(0.5, -0.5, -0.5),
-            Vector3::new(-0.5, -0.5, -0.5),
-            Vector3::new(0.5, -0.5, 0.5),
-            Vector3::new(-0.5, -0.5, 0.5),
-            Vector3::new(-0.5, 0.5, -0.5),
-            Vector3::new(0.5, 0.5, -0.5),
-            Vector3::new(-0.5, 0.5, 0.5),
-            Vector3::new(0.5, 0.5, 0.5),
-            Vector3::new(0.5, -0.5, -0.5),
-            Vector3::new(-0.5, -0.5, -0.5),
-            Vector3::new(0.5, -0.5, 0.5),
-            Vector3::new(-0.5, -0.5, 0.5),
-            Vector3::new(-0.5, 0.5, -0.5),
-            Vector3::new(0.5, 0.5, -0.5),
-            Vector3::new(-0.5, 0.5, 0.5),
-            Vector3::new(0.5, 0.5, 0.5),
-        ];
-        let tex_coords = vec![Vector2::new(0.0, 0.0); 24];
-        let normals = vec![
-            Vector3::new(1., 0., 0.),
-            Vector3::new(-1., 0., 0.),
-            Vector3::new(1., 0., 0.),
-            Vector3::new(-1., 0., 0.),
-            Vector3::new(-1., 0., 0.),
-            Vector3::new(1., 0., 0.),
-            Vector3::new(-1., 0., 0.),
-            Vector3::new(1., 0., 0.),
-            Vector3::new(0., -1., 0.),
-            Vector3::new(0., -1., 0.),
-            Vector3::new(0., -1., 0.),
-            Vector3::new(0., -1., 0.),
-            Vector3::new(0., 1., 0.),
-            Vector3::new(0., 1., 0.),
-            Vector3::new(0., 1., 0.),
-            Vector3::new(0., 1., 0.),
-            Vector3::new(0., 0., -1.),
-            Vector3::new(0., 0., -1.),
-            Vector3::new(0., 0., 1.),
-            Vector3::new(0., 0., 1.),
-            Vector3::new(0., 0., -1.),
-            Vector3::new(0., 0., -1.),
-            Vector3::new(0., 0., 1.),
-            Vector3::new(0., 0., 1.),
-        ];
-
-        let vertex_array = gpu::VertexArray::new();
-        let mesh = Mesh {
-            positions,
-            tex_coords,
-            normals,
-            indices,
-            vertex_array,
-        };
-        mesh.setup_attributes();
-        mesh
+        Mesh::from_data(MeshData::cube())
     }
 
-    pub fn setup_attributes(&self) {
+    // Reloads the mesh's own buffers, so it can be called again.
+    pub fn setup_attributes(&mut self) {
         // configure attributes
-        let pos_buf_id = gpu::gen_buffer();
-        let tex_buf_id = gpu::gen_buffer();
-        let nor_buf_id = gpu::gen_buffer();
+        let buffers = &self.vertex_buffers;
         self.vertex_array
-            .setup_attribute(Attr::Position, pos_buf_id, PointerConfig::vector3());
+            .setup_attribute(Attr::Position, &buffers.positions, PointerConfig::vector3());
         self.vertex_array.setup_attribute(
             Attr::TextureCoords,
-            tex_buf_id,
+            &buffers.tex_coords,
             PointerConfig::vector2(),
         );
         self.vertex_array
-            .setup_attribute(Attr::Normal, nor_buf_id, PointerConfig::vector3());
+            .setup_attribute(Attr::Normal, &buffers.normals, PointerConfig::vector3());
 
         // load buffers data
-        gpu::load_index_buffer_data(self.vertex_array.index_buffer_id, &self.indices[..]);
-        gpu::load_buffer_data(pos_buf_id, &self.positions[..]);
-        gpu::load_buffer_data(tex_buf_id, &self.tex_coords[..]);
-        gpu::load_buffer_data(nor_buf_id, &self.normals[..]);
+        // 16 bit indices when they fit, to save memory. 8 bit ones are slow
+        // on a lot of hardware.
+        match IndexType::for_vertex_count(self.positions.len()) {
+            IndexType::U8 | IndexType::U16 => {
+                let indices: Vec<u16> = self.indices.iter().map(|&i| i as u16).collect();
+                self.vertex_array.load_indices(&indices);
+            }
+            IndexType::U32 => self.vertex_array.load_indices(&self.indices),
+        }
+        gpu::load_buffer_data(&self.vertex_buffers.positions, &self.positions[..]);
+        gpu::load_buffer_data(&self.vertex_buffers.tex_coords, &self.tex_coords[..]);
+        gpu::load_buffer_data(&self.vertex_buffers.normals, &self.normals[..]);
     }
 
     pub fn draw(&self) {
-        self.vertex_array.draw(self.indices.len(), 0);
+        if self.indices.is_empty() {
+            self.vertex_array.draw_arrays(self.primitive, 0, self.positions.len());
+        } else {
+            self.vertex_array.draw_elements(self.primitive, self.indices.len(), 0);
+        }
+    }
+
+    // Draws a copy of the mesh for each instance in the buffer, in one call.
+    // Needs a shader that reads the instance attributes, see
+    // MeshShader::new_instanced.
+    pub fn draw_instanced(&self, instances: &InstanceBuffer) {
+        if instances.is_empty() {
+            return;
+        }
+        instances.setup_attributes(&self.vertex_array);
+        if self.indices.is_empty() {
+            self.vertex_array.draw_arrays_instanced(self.primitive, 0, self.positions.len(), instances.len());
+        } else {
+            self.vertex_array.draw_elements_instanced(self.primitive, self.indices.len(), 0, instances.len());
+        }
+    }
+
+    pub fn positions(&self) -> &[Vector3] {
+        &self.positions
+    }
+
+    pub fn tex_coords(&self) -> &[Vector2] {
+        &self.tex_coords
+    }
+
+    pub fn normals(&self) -> &[Vector3] {
+        &self.normals
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
     }
+
+    pub fn vertex_array(&self) -> &gpu::VertexArray {
+        &self.vertex_array
+    }
+
+    pub fn primitive(&self) -> Primitive {
+        self.primitive
+    }
+
+    // The indices if this is a triangle list, empty otherwise.
+    pub fn triangle_indices(&self) -> &[u32] {
+        if self.primitive == Primitive::Triangles {
+            &self.indices
+        } else {
+            &[]
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangle_indices().len() / 3
+    }
+
+    pub fn triangle(&self, i: usize) -> [Vector3; 3] {
+        let t = &self.indices[3*i..3*i + 3];
+        [
+            self.positions[t[0] as usize],
+            self.positions[t[1] as usize],
+            self.positions[t[2] as usize],
+        ]
+    }
+
+    pub fn bounding_box(&self) -> Aabb {
+        if let Some(aabb) = self.bounding_box.get() {
+            return aabb;
+        }
+        let aabb = Aabb::from_points(&self.positions);
+        self.bounding_box.set(Some(aabb));
+        aabb
+    }
+
+    pub fn bounding_sphere(&self) -> Sphere {
+        if let Some(sphere) = self.bounding_sphere.get() {
+            return sphere;
+        }
+        let sphere = Sphere::from_points(&self.positions);
+        self.bounding_sphere.set(Some(sphere));
+        sphere
+    }
+
+    pub fn transformed_bounding_box(&self, model_transform: &Matrix4) -> Aabb {
+        self.bounding_box().transformed(model_transform)
+    }
+
+    pub fn transformed_bounding_sphere(&self, model_transform: &Matrix4) -> Sphere {
+        self.bounding_sphere().transformed(model_transform)
+    }
+
+    // For repeated queries against large meshes, build a Bvh instead.
+    pub fn build_bvh(&self) -> Bvh {
+        Bvh::from_mesh(self)
+    }
+
+    pub fn simplified(&self, options: &SimplifyOptions) -> Mesh {
+        simplify::simplify(self, options)
+    }
+
+    // Closest point on the surface to p. Checks every triangle.
+    pub fn closest_point(&self, p: Vector3) -> Option<MeshPoint> {
+        let mut closest: Option<MeshPoint> = None;
+        for i in 0..self.triangle_count() {
+            let tri = self.triangle(i);
+            let barycentric = closest_point_on_triangle(p, &tri);
+            let position = barycentric_interpolate(&tri, barycentric);
+            let distance = (position - p).norm();
+            if closest.is_none_or(|c| distance < c.distance) {
+                closest = Some(MeshPoint { triangle: i, barycentric, position, distance });
+            }
+        }
+        closest
+    }
+
+    // Nearest intersection of the ray with the surface. Checks every triangle.
+    pub fn ray_intersect(&self, ray: &Ray) -> Option<MeshPoint> {
+        self.bounding_box().ray_intersect(ray, f32::INFINITY)?;
+        let mut nearest: Option<MeshPoint> = None;
+        for i in 0..self.triangle_count() {
+            if let Some((distance, barycentric)) = ray.triangle_intersect(&self.triangle(i)) {
+                if nearest.is_none_or(|n| distance < n.distance) {
+                    let position = ray.at(distance);
+                    nearest = Some(MeshPoint { triangle: i, barycentric, position, distance });
+                }
+            }
+        }
+        nearest
+    }
+}
+
+// Per instance data for instanced drawing. The transform is applied before
+// the shader's model transform. Color and data are passed on to the fragment
+// shader for it to use as it likes.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct Instance {
+    pub transform: Matrix4,
+    pub color: Color4,
+    pub data: Vector4,
+}
+
+impl Instance {
+    pub fn new(transform: Matrix4) -> Instance {
+        Instance { transform, color: Color4::W, data: Vector4::ZERO }
+    }
+}
+
+// Instances uploaded to the GPU. One buffer can be drawn with any mesh.
+#[derive(Debug)]
+pub struct InstanceBuffer {
+    buffer: gpu::Buffer,
+    count: usize,
 }
 
+impl InstanceBuffer {
+    pub fn new(instances: &[Instance]) -> InstanceBuffer {
+        let mut buffer = InstanceBuffer { buffer: gpu::Buffer::new(), count: 0 };
+        buffer.update(instances);
+        buffer
+    }
+
+    pub fn update(&mut self, instances: &[Instance]) {
+        gpu::load_dynamic_buffer_data(&self.buffer, instances);
+        self.count = instances.len();
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn setup_attributes(&self, vertex_array: &gpu::VertexArray) {
+        let stride = size_of::<Instance>() as isize;
+        let attrs = [
+            (Attr::InstanceTransform, PointerConfig::matrix4(), offset_of!(Instance, transform)),
+            (Attr::InstanceColor, PointerConfig::vector4(), offset_of!(Instance, color)),
+            (Attr::InstanceData, PointerConfig::vector4(), offset_of!(Instance, data)),
+        ];
+        for (attr, config, offset) in attrs {
+            let config = PointerConfig { stride, offset: offset as isize, ..config };
+            vertex_array.setup_instance_attribute(attr, &self.buffer, config, 1);
+        }
+    }
+}
 
 pub struct MeshShader {
     program: gpu::Program,
     view_projection_uniform: gpu::Uniform,
     model_transform_uniform: gpu::Uniform,
+    // Only in skinned shaders. Setting it on others does nothing.
+    joint_matrices_uniform: gpu::Uniform,
+    // Only in morph shaders.
+    morph_weights_uniform: gpu::Uniform,
+    morph_normals_uniform: gpu::Uniform,
 }
 
 
 impl MeshShader {
-    pub fn new(fragment_shader_path: &str) -> Result<Self, String> {
-        let program = gpu::Program::from_files("shaders/mesh_vert.glsl", fragment_shader_path)?;
-        let view_projection_uniform = program.get_uniform("T_view_projection")?;
-        let model_transform_uniform = program.get_uniform("T_model")?;
+    pub fn new(fragment_shader_path: &str) -> Result<Self, gpu::ShaderError> {
+        MeshShader::with_vertex_shader("shaders/mesh_vert.glsl", fragment_shader_path)
+    }
+
+    // For drawing SkinnedMesh::mesh, after set_joint_matrices.
+    pub fn new_skinned(fragment_shader_path: &str) -> Result<Self, gpu::ShaderError> {
+        MeshShader::with_vertex_shader("shaders/mesh_skinned_vert.glsl", fragment_shader_path)
+    }
+
+    // For drawing with draw_morphed.
+    pub fn new_morph(fragment_shader_path: &str) -> Result<Self, gpu::ShaderError> {
+        MeshShader::with_vertex_shader("shaders/mesh_morph_vert.glsl", fragment_shader_path)
+    }
+
+    // For drawing with Mesh::draw_instanced.
+    pub fn new_instanced(fragment_shader_path: &str) -> Result<Self, gpu::ShaderError> {
+        MeshShader::with_vertex_shader("shaders/mesh_instanced_vert.glsl", fragment_shader_path)
+    }
+
+    fn with_vertex_shader(vertex_shader_path: &str, fragment_shader_path: &str) -> Result<Self, gpu::ShaderError> {
+        let program = gpu::Program::from_files(vertex_shader_path, fragment_shader_path)?;
+        let view_projection_uniform = program.get_uniform_as::<&Matrix4>("T_view_projection")?;
+        let model_transform_uniform = program.get_uniform_as::<&Matrix4>("T_model")?;
+        let joint_matrices_uniform = program.find_uniform("T_joints").unwrap_or(gpu::Uniform::NONE);
+        let morph_weights_uniform = program.find_uniform("morph_weights").unwrap_or(gpu::Uniform::NONE);
+        let morph_normals_uniform = program.find_uniform("morph_normals").unwrap_or(gpu::Uniform::NONE);
         Ok(MeshShader {
             program,
             view_projection_uniform,
             model_transform_uniform,
+            joint_matrices_uniform,
+            morph_weights_uniform,
+            morph_normals_uniform,
         })
     }
 
-    pub fn get_uniform(&self, name: &str) -> Result<gpu::Uniform, String> {
+    pub fn get_uniform(&self, name: &str) -> Result<gpu::Uniform, gpu::ShaderError> {
         self.program.get_uniform(name)
     }
 
+    // For per frame data shared by several shaders, like lights.
+    pub fn bind_uniform_block<T: gpu::Std140>(&mut self, name: &str, buffer: &gpu::UniformBuffer<T>) -> Result<(), gpu::ShaderError> {
+        self.program.bind_uniform_block(name, buffer)
+    }
+
     pub fn set_uniform<T: gpu::UniformValue>(&mut self, uniform: gpu::Uniform, value: T) {
         self.program.activate();
         value.set_uniform(uniform);
     }
 
-    pub fn set_texture(&mut self, uniform: gpu::Uniform, texture_unit: gpu::TextureUnit, texture: gpu::Texture) {
+    pub fn set_texture(&mut self, uniform: gpu::Uniform, texture_unit: gpu::TextureUnit, texture: gpu::TextureHandle) {
         texture_unit.bind_texture(texture);
         self.set_uniform(uniform, texture_unit);
     }
@@ -277,8 +816,78 @@ impl MeshShader {
         self.program.set_uniform(self.model_transform_uniform, model_transform);
     }
 
+    // See Skeleton::skinning_matrices.
+    pub fn set_joint_matrices(&mut self, matrices: &[Matrix4]) {
+        assert!(matrices.len() <= MAX_JOINTS, "too many joints");
+        self.program.activate();
+        self.program.set_uniform(self.joint_matrices_uniform, matrices);
+    }
+
     pub fn draw(&mut self, mesh: &Mesh) {
         self.program.activate();
         mesh.draw();
     }
+
+    // Draws the mesh with its morph targets blended in, one weight per
+    // target. Only the targets with the largest weights are used, up to
+    // MORPH_SLOTS, or half of that if they have normal deltas. For more, bake
+    // them on the CPU with MeshData::morphed.
+    pub fn draw_morphed(&mut self, mesh: &Mesh, weights: &[f32]) {
+        let (bound_weights, with_normals) = mesh.bind_morph_targets(weights);
+        self.program.activate();
+        self.program.set_uniform(self.morph_weights_uniform, bound_weights);
+        self.program.set_uniform(self.morph_normals_uniform, with_normals as i32);
+        mesh.draw();
+    }
+
+    pub fn draw_instanced(&mut self, mesh: &Mesh, instances: &InstanceBuffer) {
+        self.program.activate();
+        mesh.draw_instanced(instances);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vector3, b: Vector3) {
+        assert!((a - b).norm() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn morphed() {
+        let data = MeshData {
+            positions: vec![Vector3::ZERO, Vector3::X, Vector3::Y],
+            tex_coords: vec![Vector2::ZERO; 3],
+            normals: vec![Vector3::Z; 3],
+            indices: vec![0, 1, 2],
+            morph_targets: vec![
+                MorphTarget {
+                    name: "lift".to_string(),
+                    position_deltas: vec![Vector3::Z, Vector3::ZERO, Vector3::new(0., 0., 2.)],
+                    normal_deltas: vec![Vector3::X, Vector3::ZERO, -Vector3::Y],
+                },
+                MorphTarget {
+                    name: "slide".to_string(),
+                    position_deltas: vec![Vector3::X; 3],
+                    normal_deltas: Vec::new(),
+                },
+            ],
+        };
+        let morphed = data.morphed(&[0.5, 2.]);
+        assert!(morphed.morph_targets.is_empty());
+        assert_eq!(morphed.indices, data.indices);
+        assert_close(morphed.positions[0], Vector3::new(2., 0., 0.5));
+        assert_close(morphed.positions[1], Vector3::new(3., 0., 0.));
+        assert_close(morphed.positions[2], Vector3::new(2., 1., 1.));
+        // The second target doesn't touch normals.
+        assert_close(morphed.normals[0], Vector3::new(0.5, 0., 1.).normalized());
+        assert_close(morphed.normals[1], Vector3::Z);
+        assert_close(morphed.normals[2], Vector3::new(0., -0.5, 1.).normalized());
+
+        // Without weights, it's the mesh.
+        let rest = data.morphed(&[0., 0.]);
+        assert_eq!(rest.positions, data.positions);
+        assert_eq!(rest.normals, data.normals);
+    }
 }
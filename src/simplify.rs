@@ -0,0 +1,570 @@
+use crate::math::*;
+use crate::mesh::{Mesh, MeshData, MorphTarget};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+// Quadric error metric simplification (Garland & Heckbert), by half-edge
+// collapses: a vertex is merged into one of its neighbors, whose position and
+// attributes are kept as they are. That never interpolates texture coordinates,
+// so it can't smear a UV seam. Vertices on borders and seams may only slide
+// along them, which keeps both in place.
+//
+// Vertices are welded by position to find the topology, so meshes whose
+// corners were duplicated per face (like the ones from Mesh::load_obj) work.
+// Vertices that only share a position, like the corners of closed lips whose
+// morph targets open them, stay apart as the two sides of a seam. Normals are
+// not part of the seams, unless they differ by more than hard_angle: welded
+// vertices get their average, so flat shaded meshes simplify too.
+
+#[derive(Debug, Copy, Clone)]
+pub struct SimplifyOptions {
+    // Stop when the mesh has at most this many triangles...
+    pub target_triangles: usize,
+    // ...or when the cheapest collapse would introduce more than this error,
+    // measured as a sum of squared distances to the original planes.
+    pub max_error: f32,
+    // Weight of the planes that keep borders and seams from moving.
+    pub border_weight: f32,
+    // Angle in radians between the normals of two vertices at the same
+    // place above which their edges are kept as seams. PI never does.
+    pub hard_angle: f32,
+}
+
+impl SimplifyOptions {
+    pub fn target_triangles(target_triangles: usize) -> Self {
+        SimplifyOptions {
+            target_triangles,
+            max_error: f32::INFINITY,
+            border_weight: 10.0,
+            hard_angle: PI,
+        }
+    }
+
+    pub fn max_error(max_error: f32) -> Self {
+        SimplifyOptions {
+            target_triangles: 0,
+            max_error,
+            border_weight: 10.0,
+            hard_angle: PI,
+        }
+    }
+}
+
+pub fn simplify(mesh: &Mesh, options: &SimplifyOptions) -> Mesh {
+    let mut data = mesh.to_data();
+    data.indices = mesh.triangle_indices().to_vec();
+    Mesh::from_data(simplify_data(&data, options))
+}
+
+pub fn simplify_data(data: &MeshData, options: &SimplifyOptions) -> MeshData {
+    let mut s = Simplifier::new(data, options.hard_angle);
+    s.run(options);
+    s.output(data)
+}
+
+
+// Symmetric 4x4 matrix, upper triangle by rows.
+#[derive(Debug, Copy, Clone, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    fn plane(n: Vector3, d: f32, weight: f32) -> Quadric {
+        let (a, b, c, d, w) = (n.x as f64, n.y as f64, n.z as f64, d as f64, weight as f64);
+        Quadric([
+            w*a*a, w*a*b, w*a*c, w*a*d,
+                   w*b*b, w*b*c, w*b*d,
+                          w*c*c, w*c*d,
+                                 w*d*d,
+        ])
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        for i in 0..10 {
+            self.0[i] += other.0[i];
+        }
+    }
+
+    fn error(&self, p: Vector3) -> f64 {
+        let q = &self.0;
+        let (x, y, z) = (p.x as f64, p.y as f64, p.z as f64);
+        q[0]*x*x + 2.*q[1]*x*y + 2.*q[2]*x*z + 2.*q[3]*x
+                 +    q[4]*y*y + 2.*q[5]*y*z + 2.*q[6]*y
+                               +    q[7]*z*z + 2.*q[8]*z
+                                             +    q[9]
+    }
+}
+
+
+#[derive(Copy, Clone, PartialEq)]
+struct Collapse {
+    cost: f64,
+    from: u32,
+    to: u32,
+    from_stamp: u32,
+    to_stamp: u32,
+}
+
+impl Eq for Collapse {}
+
+impl Ord for Collapse {
+    // Reversed, so that the BinaryHeap pops the cheapest collapse.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct Simplifier {
+    // Welded by position. `vertex_position` maps every input vertex (which
+    // also carries attributes) to its welded position vertex.
+    vertex_position: Vec<u32>,
+    points: Vec<Vector3>,
+    quadrics: Vec<Quadric>,
+    removed: Vec<bool>,
+    stamps: Vec<u32>,
+    // Faces reference input vertices, so that they keep their attributes.
+    faces: Vec<[u32; 3]>,
+    // Of the input vertices the faces reference, averaged over the ones
+    // welded into them.
+    normals: Vec<Vector3>,
+    face_alive: Vec<bool>,
+    // Faces around each position vertex. May contain dead faces.
+    point_faces: Vec<Vec<u32>>,
+    triangle_count: usize,
+    heap: BinaryHeap<Collapse>,
+}
+
+// Position, texture coordinates and the deltas of every morph target.
+type VertexKey = ([u32; 3], [u32; 2], Vec<[u32; 3]>);
+
+fn bits2(v: Vector2) -> [u32; 2] {
+    [v.x.to_bits(), v.y.to_bits()]
+}
+
+fn bits3(v: Vector3) -> [u32; 3] {
+    [v.x.to_bits(), v.y.to_bits(), v.z.to_bits()]
+}
+
+impl Simplifier {
+    fn new(data: &MeshData, hard_angle: f32) -> Simplifier {
+        let (positions, indices) = (&data.positions, &data.indices);
+        // Weld identical vertices, then positions. Each key has the vertices
+        // its vertices were welded into, one per hard normal.
+        let min_cos = hard_angle.cos();
+        let mut vertex_ids: HashMap<VertexKey, Vec<u32>> = HashMap::new();
+        let mut vertex_remap = Vec::with_capacity(positions.len());
+        let mut normals = vec![Vector3::ZERO; positions.len()];
+        for (i, &position) in positions.iter().enumerate() {
+            let mut deltas = Vec::with_capacity(2 * data.morph_targets.len());
+            for target in data.morph_targets.iter() {
+                deltas.push(bits3(target.position_deltas[i]));
+                if let Some(&n) = target.normal_deltas.get(i) {
+                    deltas.push(bits3(n));
+                }
+            }
+            let key = (bits3(position), bits2(data.tex_coords[i]), deltas);
+            let normal = data.normals[i];
+            let welded = vertex_ids.entry(key).or_default();
+            let same_side = |v: &&u32| {
+                let n = data.normals[**v as usize];
+                hard_angle >= PI || Vector3::dot(n, normal) >= min_cos * n.norm() * normal.norm()
+            };
+            let v = match welded.iter().find(same_side) {
+                Some(&v) => v,
+                None => {
+                    welded.push(i as u32);
+                    i as u32
+                }
+            };
+            normals[v as usize] += normal;
+            vertex_remap.push(v);
+        }
+        for (v, normal) in normals.iter_mut().enumerate() {
+            *normal = if normal.norm() > 0. { normal.normalized() } else { data.normals[v] };
+        }
+        let mut point_ids: HashMap<[u32; 3], u32> = HashMap::new();
+        let mut points = Vec::new();
+        let vertex_position: Vec<u32> = positions
+            .iter()
+            .map(|&p| {
+                *point_ids.entry(bits3(p)).or_insert_with(|| {
+                    points.push(p);
+                    points.len() as u32 - 1
+                })
+            })
+            .collect();
+
+        let mut faces = Vec::with_capacity(indices.len() / 3);
+        let mut point_faces = vec![Vec::new(); points.len()];
+        for t in indices.chunks(3) {
+            let face = [vertex_remap[t[0] as usize], vertex_remap[t[1] as usize], vertex_remap[t[2] as usize]];
+            let p = [vertex_position[face[0] as usize], vertex_position[face[1] as usize], vertex_position[face[2] as usize]];
+            if p[0] == p[1] || p[1] == p[2] || p[2] == p[0] {
+                continue;
+            }
+            for &pi in p.iter() {
+                point_faces[pi as usize].push(faces.len() as u32);
+            }
+            faces.push(face);
+        }
+
+        let n = points.len();
+        Simplifier {
+            vertex_position,
+            points,
+            quadrics: vec![Quadric::default(); n],
+            removed: vec![false; n],
+            stamps: vec![0; n],
+            face_alive: vec![true; faces.len()],
+            triangle_count: faces.len(),
+            faces,
+            normals,
+            point_faces,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    fn point(&self, vertex: u32) -> u32 {
+        self.vertex_position[vertex as usize]
+    }
+
+    fn face_points(&self, f: u32) -> [u32; 3] {
+        let face = self.faces[f as usize];
+        [self.point(face[0]), self.point(face[1]), self.point(face[2])]
+    }
+
+    fn face_normal(&self, points: [u32; 3]) -> Vector3 {
+        let [a, b, c] = points;
+        let (a, b, c) = (self.points[a as usize], self.points[b as usize], self.points[c as usize]);
+        Vector3::cross(b - a, c - a)
+    }
+
+    // The input vertex used by face f at position vertex p.
+    fn corner(&self, f: u32, p: u32) -> Option<u32> {
+        self.faces[f as usize].iter().cloned().find(|&v| self.point(v) == p)
+    }
+
+    fn alive_faces(&self, p: u32) -> impl Iterator<Item = u32> + '_ {
+        self.point_faces[p as usize].iter().cloned().filter(move |&f| self.face_alive[f as usize])
+    }
+
+    fn edge_faces(&self, a: u32, b: u32) -> Vec<u32> {
+        self.alive_faces(a).filter(|&f| self.corner(f, b).is_some()).collect()
+    }
+
+    fn neighbors(&self, p: u32) -> Vec<u32> {
+        let mut neighbors: Vec<u32> = Vec::new();
+        for f in self.alive_faces(p) {
+            for &q in self.face_points(f).iter() {
+                if q != p && !neighbors.contains(&q) {
+                    neighbors.push(q);
+                }
+            }
+        }
+        neighbors
+    }
+
+    // Borders, seams, and non-manifold edges.
+    fn is_constrained(&self, a: u32, b: u32) -> bool {
+        let faces = self.edge_faces(a, b);
+        if faces.len() != 2 {
+            return true;
+        }
+        self.corner(faces[0], a) != self.corner(faces[1], a) ||
+        self.corner(faces[0], b) != self.corner(faces[1], b)
+    }
+
+    fn run(&mut self, options: &SimplifyOptions) {
+        self.init_quadrics(options.border_weight);
+        for p in 0..self.points.len() as u32 {
+            self.push_collapses(p);
+        }
+
+        while self.triangle_count > options.target_triangles {
+            let collapse = match self.heap.pop() {
+                Some(collapse) => collapse,
+                None => break,
+            };
+            if collapse.cost > options.max_error as f64 {
+                break;
+            }
+            let (from, to) = (collapse.from, collapse.to);
+            if self.removed[from as usize] || self.removed[to as usize] ||
+               self.stamps[from as usize] != collapse.from_stamp ||
+               self.stamps[to as usize] != collapse.to_stamp {
+                continue;
+            }
+            if let Some(corner_map) = self.check_collapse(from, to) {
+                self.apply_collapse(from, to, &corner_map);
+            }
+        }
+    }
+
+    fn init_quadrics(&mut self, border_weight: f32) {
+        for f in 0..self.faces.len() as u32 {
+            let points = self.face_points(f);
+            let cross = self.face_normal(points);
+            if cross.norm() == 0. {
+                continue;
+            }
+            let n = cross.normalized();
+            let p0 = self.points[points[0] as usize];
+            let q = Quadric::plane(n, -Vector3::dot(n, p0), 1.);
+            for &p in points.iter() {
+                self.quadrics[p as usize].add(&q);
+            }
+
+            // Planes through constrained edges, perpendicular to the face.
+            for i in 0..3 {
+                let (a, b) = (points[i], points[(i + 1) % 3]);
+                if !self.is_constrained(a, b) {
+                    continue;
+                }
+                let pa = self.points[a as usize];
+                let edge = self.points[b as usize] - pa;
+                let m = Vector3::cross(edge, n);
+                if m.norm() == 0. {
+                    continue;
+                }
+                let m = m.normalized();
+                let q = Quadric::plane(m, -Vector3::dot(m, pa), border_weight);
+                self.quadrics[a as usize].add(&q);
+                self.quadrics[b as usize].add(&q);
+            }
+        }
+    }
+
+    fn push_collapses(&mut self, p: u32) {
+        for q in self.neighbors(p) {
+            self.push_collapse(p, q);
+            self.push_collapse(q, p);
+        }
+    }
+
+    fn push_collapse(&mut self, from: u32, to: u32) {
+        let mut quadric = self.quadrics[from as usize];
+        quadric.add(&self.quadrics[to as usize]);
+        let cost = quadric.error(self.points[to as usize]).max(0.);
+        self.heap.push(Collapse {
+            cost,
+            from,
+            to,
+            from_stamp: self.stamps[from as usize],
+            to_stamp: self.stamps[to as usize],
+        });
+    }
+
+    // If from can be merged into to, returns the vertex that replaces each of
+    // from's vertices (one per side of a seam).
+    fn check_collapse(&self, from: u32, to: u32) -> Option<Vec<(u32, u32)>> {
+        let shared = self.edge_faces(from, to);
+        if shared.is_empty() {
+            return None;
+        }
+
+        // Only slide along borders and seams, and not around their corners.
+        let neighbors = self.neighbors(from);
+        let constrained: Vec<u32> = neighbors.iter().cloned().filter(|&q| self.is_constrained(from, q)).collect();
+        match constrained.len() {
+            0 => {}
+            2 if constrained.contains(&to) => {}
+            _ => return None,
+        }
+
+        // Link condition: the edge's faces must be the only ones the two vertices
+        // share, or the collapse would pinch the surface.
+        let to_neighbors = self.neighbors(to);
+        let common = neighbors.iter().filter(|q| to_neighbors.contains(q)).count();
+        if common != shared.len() {
+            return None;
+        }
+
+        let mut corner_map: Vec<(u32, u32)> = Vec::new();
+        for &f in shared.iter() {
+            let a = self.corner(f, from)?;
+            let b = self.corner(f, to)?;
+            match corner_map.iter().find(|m| m.0 == a) {
+                Some(m) if m.1 != b => return None,
+                Some(_) => {}
+                None => corner_map.push((a, b)),
+            }
+        }
+
+        // Every remaining face must keep its corners mapped, and not flip over.
+        let to_point = self.points[to as usize];
+        for f in self.alive_faces(from) {
+            if shared.contains(&f) {
+                continue;
+            }
+            let a = self.corner(f, from)?;
+            if !corner_map.iter().any(|m| m.0 == a) {
+                return None;
+            }
+            let points = self.face_points(f);
+            let before = self.face_normal(points);
+            let mut after_points = [Vector3::ZERO; 3];
+            for i in 0..3 {
+                after_points[i] = if points[i] == from { to_point } else { self.points[points[i] as usize] };
+            }
+            let after = Vector3::cross(after_points[1] - after_points[0], after_points[2] - after_points[0]);
+            if Vector3::dot(before, after) <= 0.2 * before.norm() * after.norm() {
+                return None;
+            }
+        }
+        Some(corner_map)
+    }
+
+    fn apply_collapse(&mut self, from: u32, to: u32, corner_map: &[(u32, u32)]) {
+        let faces: Vec<u32> = self.alive_faces(from).collect();
+        for f in faces {
+            let vertex_position = &self.vertex_position;
+            let face = &mut self.faces[f as usize];
+            if face.iter().any(|&v| vertex_position[v as usize] == to) {
+                self.face_alive[f as usize] = false;
+                self.triangle_count -= 1;
+                continue;
+            }
+            for v in face.iter_mut() {
+                if let Some(m) = corner_map.iter().find(|m| m.0 == *v) {
+                    *v = m.1;
+                }
+            }
+            self.point_faces[to as usize].push(f);
+        }
+        self.point_faces[from as usize].clear();
+        self.removed[from as usize] = true;
+        let quadric = self.quadrics[from as usize];
+        self.quadrics[to as usize].add(&quadric);
+        self.stamps[to as usize] += 1;
+        self.push_collapses(to);
+    }
+
+    fn output(&self, input: &MeshData) -> MeshData {
+        let mut remap: HashMap<u32, u32> = HashMap::new();
+        let mut out = MeshData::default();
+        out.indices.reserve(3 * self.triangle_count);
+        out.morph_targets = input
+            .morph_targets
+            .iter()
+            .map(|t| MorphTarget { name: t.name.clone(), ..Default::default() })
+            .collect();
+        for (f, face) in self.faces.iter().enumerate() {
+            if !self.face_alive[f] {
+                continue;
+            }
+            for &v in face.iter() {
+                let i = *remap.entry(v).or_insert_with(|| {
+                    out.positions.push(input.positions[v as usize]);
+                    out.tex_coords.push(input.tex_coords[v as usize]);
+                    out.normals.push(self.normals[v as usize]);
+                    for (target, input_target) in out.morph_targets.iter_mut().zip(input.morph_targets.iter()) {
+                        target.position_deltas.push(input_target.position_deltas[v as usize]);
+                        if !input_target.normal_deltas.is_empty() {
+                            target.normal_deltas.push(input_target.normal_deltas[v as usize]);
+                        }
+                    }
+                    out.positions.len() as u32 - 1
+                });
+                out.indices.push(i);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::PolyMesh;
+
+    // Two flat grids meeting along y = 0, like closed lips: the vertices of
+    // that row are repeated, with the same position, texture coordinates and
+    // normal, but the target moves the upper grid up and the lower one down.
+    fn lips() -> MeshData {
+        let mut data = MeshData::default();
+        let mut opening = MorphTarget { name: "open".to_string(), ..Default::default() };
+        for &(y0, dz) in [(0., 1.), (-1., -1.)].iter() {
+            let first = data.positions.len() as u32;
+            for j in 0..=4 {
+                for i in 0..=8 {
+                    data.positions.push(Vector3::new(i as f32 / 4., y0 + j as f32 / 4., 0.));
+                    data.tex_coords.push(Vector2::ZERO);
+                    data.normals.push(Vector3::new(0., 0., 1.));
+                    opening.position_deltas.push(Vector3::new(0., 0., dz));
+                }
+            }
+            for j in 0..4 {
+                for i in 0..8 {
+                    let v = first + j * 9 + i;
+                    data.indices.extend_from_slice(&[v, v + 10, v + 1, v, v + 9, v + 10]);
+                }
+            }
+        }
+        data.morph_targets.push(opening);
+        data
+    }
+
+    #[test]
+    fn keeps_vertices_with_different_morph_deltas_apart() {
+        let data = lips();
+        let out = simplify_data(&data, &SimplifyOptions::target_triangles(16));
+        assert!(out.triangle_count() < data.triangle_count());
+        let deltas = &out.morph_targets[0].position_deltas;
+        assert_eq!(deltas.len(), out.positions.len());
+        for t in out.indices.chunks(3) {
+            let dz = deltas[t[0] as usize].z;
+            assert!(dz == 1. || dz == -1.);
+            assert!(t.iter().all(|&v| deltas[v as usize].z == dz), "a triangle has vertices from both lips");
+        }
+        // The row where they meet is still there on both sides.
+        for &dz in [1., -1.].iter() {
+            let seam = (0..out.positions.len()).filter(|&v| out.positions[v].y == 0. && deltas[v].z == dz).count();
+            assert!(seam >= 2);
+        }
+    }
+
+    #[test]
+    fn welds_identical_vertices() {
+        // Corners repeated per face, as Mesh::load_obj makes them.
+        let data = lips();
+        let mut split = MeshData { morph_targets: vec![MorphTarget::default()], ..Default::default() };
+        for &i in data.indices.iter() {
+            split.positions.push(data.positions[i as usize]);
+            split.tex_coords.push(data.tex_coords[i as usize]);
+            split.normals.push(data.normals[i as usize]);
+            split.morph_targets[0].position_deltas.push(data.morph_targets[0].position_deltas[i as usize]);
+            split.indices.push(split.indices.len() as u32);
+        }
+        let out = simplify_data(&split, &SimplifyOptions::target_triangles(16));
+        assert!(out.triangle_count() <= 16);
+    }
+
+    #[test]
+    fn simplifies_flat_shaded_obj() {
+        // A normal per face, and texture coordinates with a few seams.
+        let source = std::fs::read_to_string("resources/ico.obj").unwrap();
+        let data = PolyMesh::load_obj(&source).triangulate();
+        assert_eq!(data.triangle_count(), 80);
+        let out = simplify_data(&data, &SimplifyOptions::target_triangles(40));
+        assert!(out.triangle_count() < 60, "{} triangles", out.triangle_count());
+        // Averaged over the faces around each vertex.
+        assert!(out.normals.iter().all(|n| (n.norm() - 1.).abs() < 1e-5));
+        assert!(out.normals.iter().zip(out.positions.iter()).all(|(n, p)| Vector3::dot(*n, p.normalized()) > 0.9));
+    }
+
+    #[test]
+    fn keeps_hard_edges() {
+        let cube = MeshData::cube();
+        let smooth = simplify_data(&cube, &SimplifyOptions::target_triangles(4));
+        assert!(smooth.triangle_count() < 12);
+        let options = SimplifyOptions { hard_angle: FRAC_PI_4, ..SimplifyOptions::target_triangles(4) };
+        let hard = simplify_data(&cube, &options);
+        assert_eq!(hard.triangle_count(), 12);
+        assert_eq!(hard.normals.len(), 24);
+    }
+}
@@ -0,0 +1,281 @@
+use crate::gpu;
+use crate::gpu::{Attr, PointerConfig, Type};
+use crate::math::*;
+use crate::mesh::{Mesh, MeshData};
+use std::mem::size_of;
+
+// Joint hierarchies, poses and meshes skinned to them. Animation clips that
+// produce poses are in the animation module.
+
+// Size of the joint matrix array in the skinned shaders.
+pub const MAX_JOINTS: usize = 64;
+
+// Translation, rotation and scale, applied in the reverse order.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    pub translation: Vector3,
+    pub rotation: Quaternion,
+    pub scale: Vector3,
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        translation: Vector3::ZERO,
+        rotation: Quaternion::ONE,
+        scale: Vector3 { x: 1., y: 1., z: 1. },
+    };
+
+    pub fn matrix(&self) -> Matrix4 {
+        Matrix4::translate(self.translation) * self.rotation.rotation_matrix() * Matrix4::scale(self.scale)
+    }
+
+    pub fn inverse_matrix(&self) -> Matrix4 {
+        let inverse_scale = Vector3::new(1. / self.scale.x, 1. / self.scale.y, 1. / self.scale.z);
+        Matrix4::scale(inverse_scale) * self.rotation.conj().rotation_matrix() * Matrix4::translate(-self.translation)
+    }
+
+    pub fn lerp(a: &Transform, b: &Transform, t: f32) -> Transform {
+        Transform {
+            translation: (1. - t) * a.translation + t * b.translation,
+            rotation: Quaternion::slerp(a.rotation, b.rotation, t),
+            scale: (1. - t) * a.scale + t * b.scale,
+        }
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::IDENTITY
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Joint {
+    pub name: String,
+    pub parent: Option<usize>,
+    // Transform relative to the parent when not animated.
+    pub rest: Transform,
+    // From model space to the joint's space in the bind pose, the pose the
+    // mesh was modeled in.
+    pub inverse_bind: Matrix4,
+}
+
+// Joints are ordered so that parents come before their children.
+#[derive(Debug, Clone)]
+pub struct Skeleton {
+    joints: Vec<Joint>,
+}
+
+// Local transforms of every joint of a skeleton.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pose {
+    pub local: Vec<Transform>,
+}
+
+impl Skeleton {
+    pub fn new(joints: Vec<Joint>) -> Skeleton {
+        for (i, joint) in joints.iter().enumerate() {
+            assert!(joint.parent.is_none_or(|p| p < i), "joint {} comes before its parent", joint.name);
+        }
+        Skeleton { joints }
+    }
+
+    // A skeleton bound in its rest pose. Takes (name, parent, rest) triples.
+    pub fn from_rest_pose(joints: Vec<(String, Option<usize>, Transform)>) -> Skeleton {
+        let mut skeleton = Skeleton::new(
+            joints
+                .into_iter()
+                .map(|(name, parent, rest)| Joint { name, parent, rest, inverse_bind: Matrix4::id() })
+                .collect(),
+        );
+        let mut inverse_globals: Vec<Matrix4> = Vec::with_capacity(skeleton.joints.len());
+        for joint in skeleton.joints.iter_mut() {
+            let inverse_local = joint.rest.inverse_matrix();
+            let inverse_global = match joint.parent {
+                Some(p) => inverse_local * inverse_globals[p],
+                None => inverse_local,
+            };
+            joint.inverse_bind = inverse_global;
+            inverse_globals.push(inverse_global);
+        }
+        skeleton
+    }
+
+    pub fn joints(&self) -> &[Joint] {
+        &self.joints
+    }
+
+    pub fn joint_count(&self) -> usize {
+        self.joints.len()
+    }
+
+    pub fn joint_index(&self, name: &str) -> Option<usize> {
+        self.joints.iter().position(|j| j.name == name)
+    }
+
+    pub fn rest_pose(&self) -> Pose {
+        Pose { local: self.joints.iter().map(|j| j.rest).collect() }
+    }
+
+    // Joint to model space transforms.
+    pub fn global_matrices(&self, pose: &Pose) -> Vec<Matrix4> {
+        assert_eq!(pose.local.len(), self.joints.len());
+        let mut globals: Vec<Matrix4> = Vec::with_capacity(self.joints.len());
+        for (joint, local) in self.joints.iter().zip(pose.local.iter()) {
+            let local = local.matrix();
+            globals.push(match joint.parent {
+                Some(p) => globals[p] * local,
+                None => local,
+            });
+        }
+        globals
+    }
+
+    // Matrices that take bind pose vertices to the pose, for
+    // MeshShader::set_joint_matrices and SkinnedMesh::skin.
+    pub fn skinning_matrices(&self, pose: &Pose) -> Vec<Matrix4> {
+        self.global_matrices(pose)
+            .into_iter()
+            .zip(self.joints.iter())
+            .map(|(global, joint)| global * joint.inverse_bind)
+            .collect()
+    }
+}
+
+impl Pose {
+    // Interpolates every joint, t = 0 being a and t = 1 being b.
+    pub fn blend(a: &Pose, b: &Pose, t: f32) -> Pose {
+        assert_eq!(a.local.len(), b.local.len());
+        Pose {
+            local: a.local.iter().zip(b.local.iter()).map(|(a, b)| Transform::lerp(a, b, t)).collect(),
+        }
+    }
+}
+
+// A mesh whose vertices follow up to four joints each, weighted.
+pub struct SkinnedMesh {
+    mesh: Mesh,
+    joints: Vec<[u8; 4]>,
+    weights: Vec<Vector4>,
+    // Hold the joints and weights attributes of the mesh's vertex array.
+    _joints_buffer: gpu::Buffer,
+    _weights_buffer: gpu::Buffer,
+}
+
+impl SkinnedMesh {
+    // Weights are normalized to add up to one.
+    pub fn new(data: MeshData, joints: Vec<[u8; 4]>, weights: Vec<Vector4>) -> SkinnedMesh {
+        assert!(joints.len() == data.positions.len() && weights.len() == data.positions.len());
+        assert!(joints.iter().all(|j| j.iter().all(|&j| (j as usize) < MAX_JOINTS)), "too many joints");
+        let weights: Vec<Vector4> = weights
+            .into_iter()
+            .map(|w| {
+                let sum = w.x + w.y + w.z + w.w;
+                if sum > 0. { w / sum } else { Vector4::new(1., 0., 0., 0.) }
+            })
+            .collect();
+
+        let mesh = Mesh::from_data(data);
+        let joints_buffer = gpu::Buffer::new();
+        let weights_buffer = gpu::Buffer::new();
+        let joints_config = PointerConfig { type_: Type::U8, size: 4, stride: size_of::<[u8; 4]>() as isize, offset: 0 };
+        mesh.vertex_array().setup_attribute(Attr::Joints, &joints_buffer, joints_config);
+        mesh.vertex_array().setup_attribute(Attr::Weights, &weights_buffer, PointerConfig::vector4());
+        gpu::load_buffer_data(&joints_buffer, &joints[..]);
+        gpu::load_buffer_data(&weights_buffer, &weights[..]);
+        SkinnedMesh { mesh, joints, weights, _joints_buffer: joints_buffer, _weights_buffer: weights_buffer }
+    }
+
+    // The mesh in its bind pose. Draw it with a skinned MeshShader.
+    pub fn mesh(&self) -> &Mesh {
+        &self.mesh
+    }
+
+    pub fn joints(&self) -> &[[u8; 4]] {
+        &self.joints
+    }
+
+    pub fn weights(&self) -> &[Vector4] {
+        &self.weights
+    }
+
+    // Skinning on the CPU, for picking and bounds of posed meshes.
+    pub fn skin(&self, matrices: &[Matrix4]) -> MeshData {
+        let mut data = self.mesh.to_data();
+        skin_data(&mut data, &self.joints, &self.weights, matrices);
+        data
+    }
+}
+
+pub fn skin_data(data: &mut MeshData, joints: &[[u8; 4]], weights: &[Vector4], matrices: &[Matrix4]) {
+    for i in 0..data.positions.len() {
+        let w = weights[i];
+        let w = [w.x, w.y, w.z, w.w];
+        let mut position = Vector3::ZERO;
+        let mut normal = Vector3::ZERO;
+        for k in 0..4 {
+            if w[k] == 0. {
+                continue;
+            }
+            let m = &matrices[joints[i][k] as usize];
+            position += w[k] * m.transform_point(data.positions[i]);
+            normal += w[k] * m.transform_vector(data.normals[i]);
+        }
+        data.positions[i] = position;
+        if normal.norm() > 0. {
+            data.normals[i] = normal.normalized();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arm() -> Skeleton {
+        let joint = |name: &str, parent, translation, angle: f32, scale| {
+            let rotation = Quaternion::rotation(Vector3::new(1., 2., 3.), angle);
+            (name.to_string(), parent, Transform { translation, rotation, scale })
+        };
+        Skeleton::from_rest_pose(vec![
+            joint("shoulder", None, Vector3::new(1., 0., 2.), 0.3, Vector3::new(1., 1., 1.)),
+            joint("elbow", Some(0), Vector3::new(0., 3., 0.), -1.2, Vector3::new(2., 2., 2.)),
+            joint("hand", Some(1), Vector3::new(0., 2., 0.5), 2.5, Vector3::new(1., 0.5, 1.)),
+            joint("thumb", Some(2), Vector3::new(0.2, 0., 0.), 0.7, Vector3::new(1., 1., 1.)),
+        ])
+    }
+
+    fn assert_id(m: &Matrix4) {
+        let id = Matrix4::id();
+        for (row, id_row) in m.coords.iter().zip(id.coords.iter()) {
+            for (x, y) in row.iter().zip(id_row.iter()) {
+                assert!((x - y).abs() < 1e-5, "{:?} is not the identity", m);
+            }
+        }
+    }
+
+    #[test]
+    fn rest_pose_does_not_move_the_mesh() {
+        let skeleton = arm();
+        let matrices = skeleton.skinning_matrices(&skeleton.rest_pose());
+        assert_eq!(matrices.len(), 4);
+        matrices.iter().for_each(assert_id);
+    }
+
+    #[test]
+    fn children_follow_their_parents() {
+        let skeleton = arm();
+        let mut pose = skeleton.rest_pose();
+        pose.local[0].translation += Vector3::new(0., 0., 5.);
+        let p = Vector3::new(0.5, 1., -1.);
+        for m in skeleton.skinning_matrices(&pose) {
+            assert!((m.transform_point(p) - (p + Vector3::new(0., 0., 5.))).norm() < 1e-4);
+        }
+        // Bending the elbow leaves the shoulder.
+        let mut pose = skeleton.rest_pose();
+        pose.local[1].rotation = Quaternion::rotation(Vector3::Z, 1.);
+        let matrices = skeleton.skinning_matrices(&pose);
+        assert_id(&matrices[0]);
+        assert!((matrices[3].transform_point(p) - p).norm() > 0.1);
+    }
+}
@@ -0,0 +1,507 @@
+use crate::backend::{Backend, DrawCall, ProgramSource, UniformData};
+use crate::gpu::{TextureFormat, TextureMagFilterMode};
+use crate::image;
+use crate::math::*;
+
+// Draws on the CPU what OpenGL would with gpu::setup's state, without any
+// driver, and the same on every machine. Triangles are clipped at the near
+// plane, interpolated perspective-correct, depth tested and blended into an
+// RGBA8 target. Shaders are Rust closures.
+
+pub type Uniforms<'a> = [(&'a str, UniformData<'a, SoftwareBackend>)];
+
+// Gets the vertex's attributes, in the order of the draw call's, and writes
+// its varyings. Returns the clip space position, like gl_Position.
+pub type VertexShader = Box<dyn Fn(&[&[f32]], &Uniforms, &mut [f32]) -> Vector4>;
+
+// Gets the interpolated varyings. None discards the fragment.
+pub type FragmentShader = Box<dyn Fn(&[f32], &Uniforms) -> Option<Color4>>;
+
+pub struct SoftwareProgram {
+    // Floats the vertex shader writes for the fragment shader.
+    pub varyings: usize,
+    pub vertex: VertexShader,
+    pub fragment: FragmentShader,
+}
+
+pub struct SoftwareTexture {
+    width: i32,
+    height: i32,
+    filter: TextureMagFilterMode,
+    // First row at t = 0, like the uploaded data.
+    texels: Vec<Color4>,
+}
+
+impl SoftwareTexture {
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    // Like texture() in GLSL, clamping to the edges. Alpha textures read
+    // as black with alpha, R8 ones as red.
+    pub fn sample(&self, uv: Vector2) -> Color4 {
+        let texel = |x: i32, y: i32| self.texels[(y.clamp(0, self.height - 1) * self.width + x.clamp(0, self.width - 1)) as usize];
+        let (x, y) = (uv.x * self.width as f32, uv.y * self.height as f32);
+        match self.filter {
+            TextureMagFilterMode::Nearest => texel(x.floor() as i32, y.floor() as i32),
+            TextureMagFilterMode::Linear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as i32, y0 as i32);
+                let top = (1. - fx) * texel(x0, y0) + fx * texel(x0 + 1, y0);
+                let bottom = (1. - fx) * texel(x0, y0 + 1) + fx * texel(x0 + 1, y0 + 1);
+                (1. - fy) * top + fy * bottom
+            }
+        }
+    }
+}
+
+pub struct SoftwareBackend {
+    width: i32,
+    height: i32,
+    // First row at the bottom, like GL's window coordinates.
+    color: Vec<[u8; 4]>,
+    // From 0 at the near plane to 1 at the far one.
+    depth: Vec<f32>,
+}
+
+impl SoftwareBackend {
+    pub fn new(width: i32, height: i32) -> SoftwareBackend {
+        let size = (width * height) as usize;
+        SoftwareBackend { width, height, color: vec![[0, 0, 0, 0]; size], depth: vec![1.; size] }
+    }
+
+    fn rasterize(&mut self, call: &DrawCall<Self>, triangle: [&ClipVertex; 3]) {
+        let program = call.program;
+        let (width, height) = (self.width as f32, self.height as f32);
+        let mut window = [WindowVertex::default(); 3];
+        for (w, v) in window.iter_mut().zip(triangle.iter()) {
+            let inv_w = 1. / v.position.w;
+            w.position = Vector2::new(
+                (v.position.x * inv_w + 1.) * 0.5 * width,
+                (v.position.y * inv_w + 1.) * 0.5 * height,
+            );
+            w.depth = (v.position.z * inv_w + 1.) * 0.5;
+            w.inv_w = inv_w;
+        }
+        let area = edge(window[0].position, window[1].position, window[2].position);
+        if area == 0. || area.is_nan() || (call.state.cull && area > 0.) {
+            return;
+        }
+        // Counterclockwise from here on, so that an edge shared by two
+        // triangles runs opposite ways in each, and the fill rule gives its
+        // pixels to just one of them.
+        let order = if area > 0. { [0, 1, 2] } else { [0, 2, 1] };
+        let [a, b, c] = order.map(|i| window[i]);
+        let varyings = order.map(|i| &triangle[i].varyings[..]);
+        let area = area.abs();
+
+        let xs = [a.position.x, b.position.x, c.position.x];
+        let ys = [a.position.y, b.position.y, c.position.y];
+        let (x0, x1) = ((xs.iter().cloned().fold(f32::MAX, f32::min).floor() as i32).max(0), (xs.iter().cloned().fold(f32::MIN, f32::max).ceil() as i32).min(self.width - 1));
+        let (y0, y1) = ((ys.iter().cloned().fold(f32::MAX, f32::min).floor() as i32).max(0), (ys.iter().cloned().fold(f32::MIN, f32::max).ceil() as i32).min(self.height - 1));
+        let edges = [(b.position, c.position), (c.position, a.position), (a.position, b.position)];
+        let mut interpolated = vec![0.; program.varyings];
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let p = Vector2::new(x as f32 + 0.5, y as f32 + 0.5);
+                let weights = edges.map(|(from, to)| edge(from, to, p));
+                let inside = weights.iter().zip(edges.iter()).all(|(&w, &(from, to))| w > 0. || (w == 0. && is_top_left(from, to)));
+                if !inside {
+                    continue;
+                }
+                let [wa, wb, wc] = weights.map(|w| w / area);
+                // Depth is affine in screen space, varyings are not.
+                let depth = wa * a.depth + wb * b.depth + wc * c.depth;
+                if !(0. ..=1.).contains(&depth) {
+                    continue;
+                }
+                let index = (y * self.width + x) as usize;
+                if call.state.depth_test && depth >= self.depth[index] {
+                    continue;
+                }
+                let (pa, pb, pc) = (wa * a.inv_w, wb * b.inv_w, wc * c.inv_w);
+                let sum = pa + pb + pc;
+                for (k, value) in interpolated.iter_mut().enumerate() {
+                    *value = (pa * varyings[0][k] + pb * varyings[1][k] + pc * varyings[2][k]) / sum;
+                }
+                let color = match (program.fragment)(&interpolated, call.uniforms) {
+                    Some(color) => color,
+                    None => continue,
+                };
+                if call.state.depth_test {
+                    self.depth[index] = depth;
+                }
+                let color = if call.state.blend {
+                    let target = self.color[index].map(|c| c as f32 / 255.);
+                    let target = col4(target[0], target[1], target[2], target[3]);
+                    color.a * color + (1. - color.a) * target
+                } else {
+                    color
+                };
+                self.color[index] = [color.r, color.g, color.b, color.a].map(|c| (c.clamp(0., 1.) * 255.).round() as u8);
+            }
+        }
+    }
+}
+
+struct ClipVertex {
+    position: Vector4,
+    varyings: Vec<f32>,
+}
+
+impl ClipVertex {
+    fn lerp(&self, other: &ClipVertex, t: f32) -> ClipVertex {
+        ClipVertex {
+            position: (1. - t) * self.position + t * other.position,
+            varyings: self.varyings.iter().zip(other.varyings.iter()).map(|(a, b)| a + (b - a) * t).collect(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct WindowVertex {
+    position: Vector2,
+    depth: f32,
+    inv_w: f32,
+}
+
+// Twice the signed area of abc, positive when counterclockwise.
+fn edge(a: Vector2, b: Vector2, c: Vector2) -> f32 {
+    (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+}
+
+// Of a counterclockwise triangle, y up. Pixels exactly on these edges are
+// drawn, on the others they are not.
+fn is_top_left(from: Vector2, to: Vector2) -> bool {
+    (from.y == to.y && to.x < from.x) || to.y < from.y
+}
+
+// Keeps the part of the polygon in front of the near plane, z > -w.
+fn clip_near(polygon: Vec<ClipVertex>) -> Vec<ClipVertex> {
+    let distance = |v: &ClipVertex| v.position.z + v.position.w;
+    if polygon.iter().all(|v| distance(v) >= 0.) {
+        return polygon;
+    }
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+    for (i, a) in polygon.iter().enumerate() {
+        let b = &polygon[(i + 1) % polygon.len()];
+        let (da, db) = (distance(a), distance(b));
+        if da >= 0. {
+            clipped.push(ClipVertex { position: a.position, varyings: a.varyings.clone() });
+        }
+        if (da >= 0.) != (db >= 0.) {
+            clipped.push(a.lerp(b, da / (da - db)));
+        }
+    }
+    clipped
+}
+
+impl Backend for SoftwareBackend {
+    type Buffer = Vec<f32>;
+    type IndexBuffer = Vec<u32>;
+    type Texture = SoftwareTexture;
+    type Program = SoftwareProgram;
+
+    fn width(&self) -> i32 {
+        self.width
+    }
+
+    fn height(&self) -> i32 {
+        self.height
+    }
+
+    fn create_vertex_buffer(&mut self, data: &[f32]) -> Vec<f32> {
+        data.to_vec()
+    }
+
+    fn create_index_buffer(&mut self, indices: &[u32]) -> Vec<u32> {
+        indices.to_vec()
+    }
+
+    fn create_texture(&mut self, width: i32, height: i32, format: TextureFormat, data: &[u8], filter: TextureMagFilterMode) -> Result<SoftwareTexture, String> {
+        let components = match format {
+            TextureFormat::Alpha | TextureFormat::R8 => 1,
+            TextureFormat::Rgba8 => 4,
+            _ => return Err(format!("{:?} textures are not supported by the software backend", format)),
+        };
+        let count = (width * height) as usize;
+        if data.len() < count * components {
+            return Err(format!("{} bytes of data for {}x{} {:?} pixels", data.len(), width, height, format));
+        }
+        let texels = data[..count * components]
+            .chunks(components)
+            .map(|p| {
+                let c = |i: usize| p[i] as f32 / 255.;
+                match format {
+                    TextureFormat::Alpha => col4(0., 0., 0., c(0)),
+                    TextureFormat::R8 => col4(c(0), 0., 0., 1.),
+                    _ => col4(c(0), c(1), c(2), c(3)),
+                }
+            })
+            .collect();
+        Ok(SoftwareTexture { width, height, filter, texels })
+    }
+
+    fn create_program(&mut self, source: ProgramSource) -> Result<SoftwareProgram, String> {
+        Ok(source.software)
+    }
+
+    fn clear(&mut self, color: Color4) {
+        let color = [color.r, color.g, color.b, color.a].map(|c| (c.clamp(0., 1.) * 255.).round() as u8);
+        self.color.iter_mut().for_each(|c| *c = color);
+        self.depth.iter_mut().for_each(|d| *d = 1.);
+    }
+
+    fn draw(&mut self, call: &DrawCall<Self>) {
+        let program = call.program;
+        let vertex_count = call.attributes.iter().map(|(_, size, buffer)| buffer.len() / size).min().unwrap_or(0);
+        // Shades every vertex once, not once per triangle.
+        let mut positions = Vec::with_capacity(vertex_count);
+        let mut varyings = vec![0.; vertex_count * program.varyings];
+        let mut inputs: Vec<&[f32]> = Vec::with_capacity(call.attributes.len());
+        for v in 0..vertex_count {
+            inputs.clear();
+            inputs.extend(call.attributes.iter().map(|(_, size, buffer)| &buffer[v * size..(v + 1) * size]));
+            let outputs = &mut varyings[v * program.varyings..(v + 1) * program.varyings];
+            positions.push((program.vertex)(&inputs, call.uniforms, outputs));
+        }
+        let vertex = |i: u32| {
+            let i = i as usize;
+            ClipVertex { position: positions[i], varyings: varyings[i * program.varyings..(i + 1) * program.varyings].to_vec() }
+        };
+        for triangle in call.indices[..call.index_count].chunks_exact(3) {
+            let polygon = clip_near(triangle.iter().map(|&i| vertex(i)).collect());
+            // As a fan, if clipping made it a quad.
+            for i in 2..polygon.len() {
+                self.rasterize(call, [&polygon[0], &polygon[i - 1], &polygon[i]]);
+            }
+        }
+    }
+
+    fn read_pixels(&mut self) -> Result<image::RgbaImage, String> {
+        if self.width <= 0 || self.height <= 0 {
+            return Err(format!("cannot read a {}x{} target", self.width, self.height));
+        }
+        let row = self.width as usize;
+        let data: Vec<u8> = self.color.chunks(row).rev().flatten().flatten().copied().collect();
+        Ok(image::RgbaImage::from_raw(self.width as u32, self.height as u32, data).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{self, BackendMesh, DrawState};
+    use crate::camera::Camera;
+    use crate::gpu::Attr;
+    use crate::mesh::MeshData;
+
+    // Positions through T_view_projection, in one color.
+    fn flat_program(color: Color4) -> SoftwareProgram {
+        SoftwareProgram {
+            varyings: 0,
+            vertex: Box::new(|attributes, uniforms, _| {
+                let p = attributes[0];
+                let view_projection = backend::find_uniform(uniforms, "T_view_projection").and_then(|u| u.as_matrix4()).copied();
+                view_projection.unwrap_or_else(Matrix4::id).transform(Vector4::new(p[0], p[1], p[2], 1.))
+            }),
+            fragment: Box::new(move |_, _| Some(color)),
+        }
+    }
+
+    // mesh_program's vertex shader, with the normal as the color.
+    fn normal_program() -> SoftwareProgram {
+        SoftwareProgram {
+            varyings: 8,
+            vertex: backend::mesh_program().software.vertex,
+            fragment: Box::new(|varyings, _| Some(col4(0.5 + 0.5 * varyings[5], 0.5 + 0.5 * varyings[6], 0.5 + 0.5 * varyings[7], 1.))),
+        }
+    }
+
+    fn draw(backend: &mut SoftwareBackend, program: &SoftwareProgram, data: &MeshData, view_projection: Matrix4, state: DrawState) {
+        let positions: Vec<f32> = data.positions.iter().flat_map(|p| [p.x, p.y, p.z]).collect();
+        let tex_coords = vec![0.; data.positions.len() * 2];
+        let normals: Vec<f32> = data.normals.iter().flat_map(|n| [n.x, n.y, n.z]).chain(std::iter::repeat(0.)).take(positions.len()).collect();
+        let attributes = [(Attr::Position, 3, &positions), (Attr::TextureCoords, 2, &tex_coords), (Attr::Normal, 3, &normals)];
+        let uniforms = [("T_view_projection", UniformData::Matrix4(view_projection))];
+        backend.draw(&DrawCall {
+            program,
+            attributes: &attributes,
+            indices: &data.indices,
+            index_count: data.indices.len(),
+            uniforms: &uniforms,
+            state,
+        });
+    }
+
+    // As golden's mesh scenes see the cube, from above its +X, +Y and +Z
+    // sides.
+    fn golden_view_projection(width: i32, height: i32) -> Matrix4 {
+        let position = Vector3::new(2.5, 2.5, 1.8);
+        let pitch = position.z.atan2((position.x * position.x + position.y * position.y).sqrt());
+        let camera = Camera {
+            position,
+            orientation: Quaternion::rotation(Vector3::new(0., 0., 1.), 3. * FRAC_PI_4)
+                * Quaternion::rotation(Vector3::new(1., 0., 0.), FRAC_PI_2 - pitch),
+            aspect_ratio: width as f32 / height as f32,
+        };
+        camera.view_projection_matrix()
+    }
+
+    // The cube with its triangles in reverse, so that its back faces come
+    // after its front ones.
+    fn reversed_cube() -> MeshData {
+        let mut cube = MeshData::cube();
+        cube.indices = cube.indices.chunks(3).rev().flatten().copied().collect();
+        cube
+    }
+
+    // Colors of the cube's faces with normal_program, and how many pixels
+    // have each.
+    fn face_colors(backend: &SoftwareBackend) -> Vec<([u8; 4], usize)> {
+        let mut colors: Vec<([u8; 4], usize)> = Vec::new();
+        for &color in backend.color.iter().filter(|c| **c != [0, 0, 0, 255]) {
+            match colors.iter_mut().find(|(c, _)| *c == color) {
+                Some((_, count)) => *count += 1,
+                None => colors.push((color, 1)),
+            }
+        }
+        colors.sort();
+        colors
+    }
+
+    const FRONT_FACES: [[u8; 4]; 3] = [[128, 128, 255, 255], [128, 255, 128, 255], [255, 128, 128, 255]];
+
+    #[test]
+    fn back_faces_culled() {
+        let mut backend = SoftwareBackend::new(64, 64);
+        backend.clear(col4(0., 0., 0., 1.));
+        let state = DrawState { depth_test: false, blend: false, cull: true };
+        draw(&mut backend, &normal_program(), &reversed_cube(), golden_view_projection(64, 64), state);
+        let colors: Vec<[u8; 4]> = face_colors(&backend).iter().map(|(c, _)| *c).collect();
+        assert_eq!(colors, FRONT_FACES);
+    }
+
+    #[test]
+    fn depth_test_occludes() {
+        let mut culled = SoftwareBackend::new(64, 64);
+        culled.clear(col4(0., 0., 0., 1.));
+        let state = DrawState { depth_test: true, blend: false, cull: true };
+        draw(&mut culled, &normal_program(), &MeshData::cube(), golden_view_projection(64, 64), state);
+
+        let mut backend = SoftwareBackend::new(64, 64);
+        backend.clear(col4(0., 0., 0., 1.));
+        let state = DrawState { depth_test: true, blend: false, cull: false };
+        draw(&mut backend, &normal_program(), &reversed_cube(), golden_view_projection(64, 64), state);
+        let colors: Vec<[u8; 4]> = face_colors(&backend).iter().map(|(c, _)| *c).collect();
+        assert_eq!(colors, FRONT_FACES);
+        assert!(backend.color == culled.color);
+
+        // Without depth, the back faces drawn last show.
+        let mut backend = SoftwareBackend::new(64, 64);
+        backend.clear(col4(0., 0., 0., 1.));
+        let state = DrawState { depth_test: false, blend: false, cull: false };
+        draw(&mut backend, &normal_program(), &reversed_cube(), golden_view_projection(64, 64), state);
+        assert!(face_colors(&backend).iter().any(|(c, _)| !FRONT_FACES.contains(c)));
+    }
+
+    #[test]
+    fn shared_edge_drawn_once() {
+        // A square from 1.5 to 5.5 in window coordinates, split along its
+        // diagonal, which runs through pixel centers, as do its edges.
+        let mut backend = SoftwareBackend::new(8, 8);
+        let ndc = |x: f32| x / 4. - 1.;
+        let data = MeshData {
+            positions: [(1.5, 1.5), (5.5, 1.5), (5.5, 5.5), (1.5, 5.5)].iter().map(|&(x, y)| Vector3::new(ndc(x), ndc(y), 0.)).collect(),
+            indices: vec![0, 1, 2, 0, 2, 3],
+            ..MeshData::default()
+        };
+        let state = DrawState { depth_test: false, blend: true, cull: false };
+        draw(&mut backend, &flat_program(col4(1., 1., 1., 0.5)), &data, Matrix4::id(), state);
+        for y in 0..8 {
+            for x in 0..8 {
+                // Pixels on left and top edges are drawn, y up.
+                let inside = (1..=4).contains(&x) && (2..=5).contains(&y);
+                let expected = if inside { [128, 128, 128, 64] } else { [0, 0, 0, 0] };
+                assert_eq!(backend.color[y * 8 + x], expected, "pixel {}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn clip_crossing_w_zero() {
+        // Ground below the eye, from in front of it to behind it, where w
+        // is negative.
+        let data = MeshData {
+            positions: vec![Vector3::new(-1., -1., -3.), Vector3::new(0., -1., 3.), Vector3::new(1., -1., -3.)],
+            indices: vec![0, 1, 2],
+            ..MeshData::default()
+        };
+        let projection = Matrix4::perspective(FRAC_PI_2, 1., 0.5, 10.);
+        let vertices: Vec<ClipVertex> = data.positions.iter().map(|&p| ClipVertex { position: projection.transform(Vector4::new(p.x, p.y, p.z, 1.)), varyings: Vec::new() }).collect();
+        assert!(vertices[1].position.w < 0.);
+        let clipped = clip_near(vertices);
+        assert_eq!(clipped.len(), 4);
+        assert!(clipped.iter().all(|v| v.position.z + v.position.w >= -1e-5 && v.position.w > 0.));
+
+        let mut backend = SoftwareBackend::new(32, 32);
+        let state = DrawState { depth_test: true, blend: false, cull: false };
+        draw(&mut backend, &flat_program(col4(1., 1., 1., 1.)), &data, projection, state);
+        // From the bottom of the view to the far edge, at a third of the
+        // way down to it, and nothing above.
+        let drawn = |x: usize, y: usize| backend.color[y * 32 + x] == [255, 255, 255, 255];
+        assert!(drawn(16, 0));
+        assert!(drawn(16, 10));
+        assert!((0..32).all(|x| (11..32).all(|y| !drawn(x, y))));
+    }
+
+    #[test]
+    fn text_blends_alpha() {
+        let mut backend = SoftwareBackend::new(2, 1);
+        let program = backend.create_program(backend::text_program()).unwrap();
+        let texture = backend.create_texture(2, 1, TextureFormat::Alpha, &[255, 128], TextureMagFilterMode::Nearest).unwrap();
+        // A quad over both pixels, in text's pixel coordinates, y down.
+        let positions = vec![0., 0., 2., 0., 2., 1., 0., 1.];
+        let tex_coords = vec![0., 0., 1., 0., 1., 1., 0., 1.];
+        let indices = vec![0, 1, 3, 3, 1, 2];
+        let attributes = [(Attr::Position, 2, &positions), (Attr::TextureCoords, 2, &tex_coords)];
+        let uniforms = [("screen_size", UniformData::Vector2(Vector2::new(2., 1.))), ("texture0", UniformData::Texture(&texture))];
+        backend.clear(col4(1., 1., 1., 1.));
+        backend.draw(&DrawCall {
+            program: &program,
+            attributes: &attributes,
+            indices: &indices,
+            index_count: 6,
+            uniforms: &uniforms,
+            state: DrawState::default(),
+        });
+        let image = backend.read_pixels().unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [0, 0, 0, 255]);
+        // Half black over white, alpha blended like color.
+        assert_eq!(image.get_pixel(1, 0).0, [127, 127, 127, 191]);
+    }
+
+    #[test]
+    fn cube_matches_golden() {
+        let expected = image::open("resources/golden/mesh_cube.png").unwrap().to_rgba();
+        let (width, height) = (expected.width() as i32, expected.height() as i32);
+        let mut backend = SoftwareBackend::new(width, height);
+        let program = backend.create_program(backend::mesh_program()).unwrap();
+        let mesh = BackendMesh::new(&mut backend, &MeshData::cube());
+        backend.clear(col4(0., 0., 0., 1.));
+        mesh.draw(&mut backend, &program, &golden_view_projection(width, height), &Matrix4::id());
+        let actual = backend.read_pixels().unwrap();
+        assert_eq!(actual.dimensions(), expected.dimensions());
+        for (x, y, e) in expected.enumerate_pixels() {
+            let a = actual.get_pixel(x, y);
+            let difference = (0..4).map(|k| (e[k] as i32 - a[k] as i32).abs()).max().unwrap();
+            assert!(difference <= 1, "pixel {}, {} is {:?}, the reference's {:?}", x, y, a, e);
+        }
+    }
+}
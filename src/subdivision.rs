@@ -0,0 +1,438 @@
+use crate::math::*;
+use crate::mesh::{Corner, PolyMesh};
+use std::collections::HashMap;
+
+// Loop subdivision for triangle meshes and Catmull-Clark for polygon meshes,
+// with semi-sharp creases.
+//
+// Positions are shared between faces and define the topology. Texture
+// coordinates are interpolated linearly within each face, so UV seams and the
+// texture layout stay where they are. Normals are recomputed from the result.
+
+// Sharpness of edges, keyed by their two position indices. Sharpness s >= 1
+// keeps the edge sharp for s levels; fractional values blend with the smooth
+// rules. Borders and non-manifold edges are always sharp.
+#[derive(Debug, Clone, Default)]
+pub struct Creases {
+    edges: HashMap<(u32, u32), f32>,
+}
+
+impl Creases {
+    pub fn new() -> Creases {
+        Default::default()
+    }
+
+    pub fn set(&mut self, a: u32, b: u32, sharpness: f32) {
+        if sharpness > 0. {
+            self.edges.insert(edge_key(a, b), sharpness);
+        } else {
+            self.edges.remove(&edge_key(a, b));
+        }
+    }
+
+    pub fn sharpness(&self, a: u32, b: u32) -> f32 {
+        self.edges.get(&edge_key(a, b)).cloned().unwrap_or(0.)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edges.is_empty()
+    }
+}
+
+fn edge_key(a: u32, b: u32) -> (u32, u32) {
+    (a.min(b), a.max(b))
+}
+
+pub fn loop_subdivide(mesh: &PolyMesh, creases: &Creases, levels: usize) -> PolyMesh {
+    assert!(mesh.is_triangle_mesh(), "loop_subdivide needs a triangle mesh");
+    subdivide(mesh, creases, levels, Scheme::Loop)
+}
+
+pub fn catmull_clark(mesh: &PolyMesh, creases: &Creases, levels: usize) -> PolyMesh {
+    subdivide(mesh, creases, levels, Scheme::CatmullClark)
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Scheme {
+    Loop,
+    CatmullClark,
+}
+
+fn subdivide(mesh: &PolyMesh, creases: &Creases, levels: usize, scheme: Scheme) -> PolyMesh {
+    let mut mesh = mesh.clone();
+    let mut creases = creases.clone();
+    for _ in 0..levels {
+        let (m, c) = Topology::new(&mesh, &creases).subdivide(&mesh, scheme);
+        mesh = m;
+        creases = c;
+    }
+    mesh.compute_normals();
+    mesh
+}
+
+struct Edge {
+    a: u32,
+    b: u32,
+    faces: Vec<u32>,
+    // Infinite for borders and non-manifold edges.
+    sharpness: f32,
+}
+
+struct Topology {
+    edges: Vec<Edge>,
+    edge_ids: HashMap<(u32, u32), u32>,
+    vertex_edges: Vec<Vec<u32>>,
+    vertex_faces: Vec<Vec<u32>>,
+}
+
+impl Topology {
+    fn new(mesh: &PolyMesh, creases: &Creases) -> Topology {
+        let mut topology = Topology {
+            edges: Vec::new(),
+            edge_ids: HashMap::new(),
+            vertex_edges: vec![Vec::new(); mesh.positions.len()],
+            vertex_faces: vec![Vec::new(); mesh.positions.len()],
+        };
+        for (f, face) in mesh.faces.iter().enumerate() {
+            for i in 0..face.len() {
+                let a = face[i].position;
+                let b = face[(i + 1) % face.len()].position;
+                let key = edge_key(a, b);
+                let edges = &mut topology.edges;
+                let vertex_edges = &mut topology.vertex_edges;
+                let e = *topology.edge_ids.entry(key).or_insert_with(|| {
+                    edges.push(Edge { a: key.0, b: key.1, faces: Vec::new(), sharpness: creases.sharpness(a, b) });
+                    vertex_edges[a as usize].push(edges.len() as u32 - 1);
+                    vertex_edges[b as usize].push(edges.len() as u32 - 1);
+                    edges.len() as u32 - 1
+                });
+                topology.edges[e as usize].faces.push(f as u32);
+                topology.vertex_faces[a as usize].push(f as u32);
+            }
+        }
+        for edge in topology.edges.iter_mut() {
+            if edge.faces.len() != 2 {
+                edge.sharpness = f32::INFINITY;
+            }
+        }
+        topology
+    }
+
+    fn edge(&self, a: u32, b: u32) -> u32 {
+        self.edge_ids[&edge_key(a, b)]
+    }
+
+    fn subdivide(&self, mesh: &PolyMesh, scheme: Scheme) -> (PolyMesh, Creases) {
+        let nv = mesh.positions.len();
+        let ne = self.edges.len();
+        let face_points: Vec<Vector3> = mesh.faces.iter().map(|face| centroid(mesh, face)).collect();
+
+        let mut out = PolyMesh::default();
+        out.positions.reserve(nv + ne + mesh.faces.len());
+        for v in 0..nv {
+            out.positions.push(self.vertex_point(mesh, &face_points, v, scheme));
+        }
+        for e in 0..ne {
+            out.positions.push(self.edge_point(mesh, &face_points, e, scheme));
+        }
+        if scheme == Scheme::CatmullClark {
+            out.positions.extend_from_slice(&face_points);
+        }
+
+        // Child edges of sharp edges lose one level of sharpness.
+        let mut creases = Creases::new();
+        for (e, edge) in self.edges.iter().enumerate() {
+            if edge.sharpness > 1. && edge.sharpness.is_finite() {
+                let mid = (nv + e) as u32;
+                creases.set(edge.a, mid, edge.sharpness - 1.);
+                creases.set(mid, edge.b, edge.sharpness - 1.);
+            }
+        }
+
+        let mut uvs = TexCoordBuilder::new(mesh);
+        for (f, face) in mesh.faces.iter().enumerate() {
+            let k = face.len();
+            let edge_corner = |i: usize, uvs: &mut TexCoordBuilder| -> Corner {
+                let (c0, c1) = (face[i], face[(i + 1) % k]);
+                Corner {
+                    position: (nv as u32) + self.edge(c0.position, c1.position),
+                    tex_coord: uvs.midpoint(c0.tex_coord, c1.tex_coord),
+                    normal: None,
+                }
+            };
+            let vertex_corner = |i: usize| -> Corner {
+                Corner { position: face[i].position, tex_coord: face[i].tex_coord, normal: None }
+            };
+            match scheme {
+                Scheme::Loop => {
+                    let e: Vec<Corner> = (0..3).map(|i| edge_corner(i, &mut uvs)).collect();
+                    out.faces.push(vec![vertex_corner(0), e[0], e[2]]);
+                    out.faces.push(vec![vertex_corner(1), e[1], e[0]]);
+                    out.faces.push(vec![vertex_corner(2), e[2], e[1]]);
+                    out.faces.push(vec![e[0], e[1], e[2]]);
+                }
+                Scheme::CatmullClark => {
+                    let center = Corner {
+                        position: (nv + ne + f) as u32,
+                        tex_coord: uvs.centroid(face),
+                        normal: None,
+                    };
+                    let e: Vec<Corner> = (0..k).map(|i| edge_corner(i, &mut uvs)).collect();
+                    for i in 0..k {
+                        out.faces.push(vec![vertex_corner(i), e[i], center, e[(i + k - 1) % k]]);
+                    }
+                }
+            }
+        }
+        out.tex_coords = uvs.tex_coords;
+        (out, creases)
+    }
+
+    fn edge_point(&self, mesh: &PolyMesh, face_points: &[Vector3], e: usize, scheme: Scheme) -> Vector3 {
+        let edge = &self.edges[e];
+        let a = mesh.positions[edge.a as usize];
+        let b = mesh.positions[edge.b as usize];
+        let sharp = 0.5 * (a + b);
+        if edge.sharpness >= 1. {
+            return sharp;
+        }
+        let smooth = match scheme {
+            Scheme::Loop => {
+                let mut opposite = Vector3::ZERO;
+                for &f in edge.faces.iter() {
+                    for c in mesh.faces[f as usize].iter() {
+                        if c.position != edge.a && c.position != edge.b {
+                            opposite += mesh.positions[c.position as usize];
+                        }
+                    }
+                }
+                0.375 * (a + b) + 0.125 * opposite
+            }
+            Scheme::CatmullClark => {
+                0.25 * (a + b + face_points[edge.faces[0] as usize] + face_points[edge.faces[1] as usize])
+            }
+        };
+        lerp(smooth, sharp, edge.sharpness)
+    }
+
+    fn vertex_point(&self, mesh: &PolyMesh, face_points: &[Vector3], v: usize, scheme: Scheme) -> Vector3 {
+        let p = mesh.positions[v];
+        let edges = &self.vertex_edges[v];
+        let n = edges.len();
+        if n == 0 {
+            return p;
+        }
+        let other = |e: u32| -> Vector3 {
+            let edge = &self.edges[e as usize];
+            mesh.positions[if edge.a as usize == v { edge.b } else { edge.a } as usize]
+        };
+
+        let sharp_edges: Vec<u32> = edges.iter().cloned().filter(|&e| self.edges[e as usize].sharpness > 0.).collect();
+        // Darts (one sharp edge) use the smooth rule. Vertices with only two
+        // edges, like the corners of an open grid, stay put.
+        let sharp = match sharp_edges.len() {
+            0 | 1 => None,
+            2 if n > 2 => Some(0.75 * p + 0.125 * (other(sharp_edges[0]) + other(sharp_edges[1]))),
+            _ => Some(p),
+        };
+        let sharpness = sharp_edges
+            .iter()
+            .map(|&e| self.edges[e as usize].sharpness.min(1.))
+            .sum::<f32>() / sharp_edges.len().max(1) as f32;
+        if let (Some(sharp), true) = (sharp, sharpness >= 1.) {
+            return sharp;
+        }
+
+        let nf = n as f32;
+        let smooth = match scheme {
+            Scheme::Loop => {
+                let c = 0.375 + 0.25 * (TAU / nf).cos();
+                let beta = (0.625 - c * c) / nf;
+                let mut sum = Vector3::ZERO;
+                for &e in edges.iter() {
+                    sum += other(e);
+                }
+                (1. - nf * beta) * p + beta * sum
+            }
+            Scheme::CatmullClark => {
+                let faces = &self.vertex_faces[v];
+                let mut f_avg = Vector3::ZERO;
+                for &f in faces.iter() {
+                    f_avg += face_points[f as usize];
+                }
+                f_avg /= faces.len() as f32;
+                let mut r_avg = Vector3::ZERO;
+                for &e in edges.iter() {
+                    r_avg += 0.5 * (p + other(e));
+                }
+                r_avg /= nf;
+                (f_avg + 2. * r_avg + (nf - 3.) * p) / nf
+            }
+        };
+        match sharp {
+            Some(sharp) => lerp(smooth, sharp, sharpness),
+            None => smooth,
+        }
+    }
+}
+
+fn lerp(a: Vector3, b: Vector3, t: f32) -> Vector3 {
+    (1. - t) * a + t * b
+}
+
+fn centroid(mesh: &PolyMesh, face: &[Corner]) -> Vector3 {
+    let mut c = Vector3::ZERO;
+    for corner in face.iter() {
+        c += mesh.positions[corner.position as usize];
+    }
+    c / face.len() as f32
+}
+
+// New texture coordinates, shared between the faces that agree on them.
+struct TexCoordBuilder {
+    tex_coords: Vec<Vector2>,
+    midpoints: HashMap<(u32, u32), u32>,
+}
+
+impl TexCoordBuilder {
+    fn new(mesh: &PolyMesh) -> TexCoordBuilder {
+        TexCoordBuilder { tex_coords: mesh.tex_coords.clone(), midpoints: HashMap::new() }
+    }
+
+    fn midpoint(&mut self, a: Option<u32>, b: Option<u32>) -> Option<u32> {
+        let (a, b) = (a?, b?);
+        let tex_coords = &mut self.tex_coords;
+        Some(*self.midpoints.entry(edge_key(a, b)).or_insert_with(|| {
+            let uv = 0.5 * (tex_coords[a as usize] + tex_coords[b as usize]);
+            tex_coords.push(uv);
+            tex_coords.len() as u32 - 1
+        }))
+    }
+
+    fn centroid(&mut self, face: &[Corner]) -> Option<u32> {
+        let mut uv = Vector2::ZERO;
+        for corner in face.iter() {
+            uv += self.tex_coords[corner.tex_coord? as usize];
+        }
+        self.tex_coords.push(uv / face.len() as f32);
+        Some(self.tex_coords.len() as u32 - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poly_mesh(positions: &[[f32; 3]], faces: &[&[u32]]) -> PolyMesh {
+        PolyMesh {
+            positions: positions.iter().map(|p| Vector3::new(p[0], p[1], p[2])).collect(),
+            faces: faces
+                .iter()
+                .map(|face| face.iter().map(|&position| Corner { position, tex_coord: None, normal: None }).collect())
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn octahedron() -> PolyMesh {
+        let positions = [[1., 0., 0.], [-1., 0., 0.], [0., 1., 0.], [0., -1., 0.], [0., 0., 1.], [0., 0., -1.]];
+        poly_mesh(&positions, &[&[0, 2, 4], &[2, 1, 4], &[1, 3, 4], &[3, 0, 4], &[2, 0, 5], &[1, 2, 5], &[3, 1, 5], &[0, 3, 5]])
+    }
+
+    fn cube() -> PolyMesh {
+        let mut positions = Vec::new();
+        for i in 0..8 {
+            positions.push([(i & 1) as f32 - 0.5, ((i >> 1) & 1) as f32 - 0.5, ((i >> 2) & 1) as f32 - 0.5]);
+        }
+        poly_mesh(&positions, &[&[0, 2, 3, 1], &[4, 5, 7, 6], &[0, 1, 5, 4], &[2, 6, 7, 3], &[0, 4, 6, 2], &[1, 3, 7, 5]])
+    }
+
+    #[test]
+    fn face_counts() {
+        for levels in 0..4 {
+            let factor = 4usize.pow(levels as u32);
+            let subdivided = loop_subdivide(&octahedron(), &Creases::new(), levels);
+            assert_eq!(subdivided.faces.len(), 8 * factor);
+            assert!(subdivided.is_triangle_mesh());
+            let subdivided = catmull_clark(&cube(), &Creases::new(), levels);
+            assert_eq!(subdivided.faces.len(), 6 * factor);
+            assert!(subdivided.faces.iter().all(|f| f.len() == 4));
+        }
+    }
+
+    #[test]
+    fn creased_edge_stays_straight() {
+        // Sharp corners at both ends of the edge from 0 to 1, and the edge
+        // itself sharp.
+        let (a, b) = (Vector3::new(-0.5, -0.5, -0.5), Vector3::new(0.5, -0.5, -0.5));
+        let mut creases = Creases::new();
+        for &(p, q) in [(0, 1), (0, 2), (0, 4), (1, 3), (1, 5)].iter() {
+            creases.set(p, q, 10.);
+        }
+        let on_edge = |mesh: &PolyMesh| -> Vec<f32> {
+            let mut xs: Vec<f32> = mesh.positions.iter().filter(|p| (p.y - a.y).abs() < 1e-6 && (p.z - a.z).abs() < 1e-6).map(|p| p.x).collect();
+            xs.sort_by(|a, b| a.total_cmp(b));
+            xs
+        };
+        let subdivided = catmull_clark(&cube(), &creases, 2);
+        let xs = on_edge(&subdivided);
+        assert_eq!(xs.len(), 5, "{:?}", xs);
+        for (i, x) in xs.iter().enumerate() {
+            assert!((x - (a.x + (b.x - a.x) * i as f32 / 4.)).abs() < 1e-6, "{:?}", xs);
+        }
+        // Without the creases, the edge is rounded away.
+        let smooth = catmull_clark(&cube(), &Creases::new(), 2);
+        assert!(on_edge(&smooth).is_empty());
+    }
+
+    // Two unit panels side by side, sharing the edge at x = 1, with texture
+    // coordinates cut along it: u is x on the left and x + 1 on the right.
+    fn panels(triangles: bool) -> PolyMesh {
+        let positions = [[0., 0., 0.], [1., 0., 0.], [2., 0., 0.], [0., 1., 0.], [1., 1., 0.], [2., 1., 0.]];
+        let mut mesh = poly_mesh(&positions, &[]);
+        let uv = |p: u32, right: bool| Vector2::new(mesh.positions[p as usize].x + if right { 1. } else { 0. }, mesh.positions[p as usize].y);
+        let quads = [([0, 1, 4, 3], false), ([1, 2, 5, 4], true)];
+        let mut tex_coords = Vec::new();
+        let mut faces = Vec::new();
+        for &(quad, right) in quads.iter() {
+            let corners: Vec<Corner> = quad
+                .iter()
+                .map(|&position| {
+                    tex_coords.push(uv(position, right));
+                    Corner { position, tex_coord: Some(tex_coords.len() as u32 - 1), normal: None }
+                })
+                .collect();
+            if triangles {
+                faces.push(vec![corners[0], corners[1], corners[2]]);
+                faces.push(vec![corners[0], corners[2], corners[3]]);
+            } else {
+                faces.push(corners);
+            }
+        }
+        mesh.tex_coords = tex_coords;
+        mesh.faces = faces;
+        mesh
+    }
+
+    #[test]
+    fn seam_tex_coords_kept_apart() {
+        for &triangles in [false, true].iter() {
+            let mesh = panels(triangles);
+            let subdivided = if triangles { loop_subdivide(&mesh, &Creases::new(), 2) } else { catmull_clark(&mesh, &Creases::new(), 2) };
+            let mut sides = [0, 0];
+            for face in subdivided.faces.iter() {
+                let us: Vec<f32> = face.iter().map(|c| subdivided.tex_coords[c.tex_coord.unwrap() as usize].x).collect();
+                // Each face is on one side of the seam. Averaging across it
+                // would give u = 1.5 somewhere.
+                if us.iter().all(|&u| u <= 1. + 1e-6) {
+                    sides[0] += 1;
+                } else if us.iter().all(|&u| u >= 2. - 1e-6) {
+                    sides[1] += 1;
+                } else {
+                    panic!("face across the seam with u {:?}", us);
+                }
+            }
+            assert_eq!(sides[0], sides[1]);
+        }
+    }
+}
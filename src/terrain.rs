@@ -0,0 +1,260 @@
+use crate::camera::Camera;
+use crate::image;
+use crate::image::GenericImageView;
+use crate::lod::{LodLevel, LodMesh};
+use crate::math::*;
+use crate::mesh::{Mesh, MeshData, MeshShader};
+
+// Terrain from a heightmap, split in square chunks that pick their level of
+// detail independently. The ground is the xy plane and heights go along z.
+//
+// Chunks at different levels don't share their borders exactly, so every
+// chunk hangs a skirt down from its border to hide the cracks in between.
+
+// Height samples, from 0 to 1 for images. Sample (x, y) is at x * cell_size,
+// y * cell_size in the terrain.
+#[derive(Debug, Clone)]
+pub struct Heightmap {
+    width: usize,
+    height: usize,
+    values: Vec<f32>,
+}
+
+impl Heightmap {
+    pub fn new(width: usize, height: usize) -> Heightmap {
+        assert!(width >= 2 && height >= 2);
+        Heightmap { width, height, values: vec![0.; width * height] }
+    }
+
+    pub fn from_fn<F: Fn(usize, usize) -> f32>(width: usize, height: usize, f: F) -> Heightmap {
+        let mut heightmap = Heightmap::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                heightmap.set(x, y, f(x, y));
+            }
+        }
+        heightmap
+    }
+
+    // The image's bottom row is y = 0, so the terrain seen from above looks
+    // like the image. 16 bit grayscale images keep their precision.
+    pub fn from_image(img: &image::DynamicImage) -> Heightmap {
+        let (width, height) = img.dimensions();
+        let (width, height) = (width as usize, height as usize);
+        match img {
+            image::DynamicImage::ImageLuma16(gray) => {
+                Heightmap::from_fn(width, height, |x, y| {
+                    gray.get_pixel(x as u32, (height - 1 - y) as u32)[0] as f32 / 65535.
+                })
+            }
+            _ => {
+                let gray = img.to_luma();
+                Heightmap::from_fn(width, height, |x, y| {
+                    gray.get_pixel(x as u32, (height - 1 - y) as u32)[0] as f32 / 255.
+                })
+            }
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Heightmap, String> {
+        let img = image::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Heightmap::from_image(&img))
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        self.values[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: f32) {
+        self.values[y * self.width + x] = value;
+    }
+
+    // Bilinear interpolation, in sample units. Clamped to the edges.
+    pub fn sample(&self, x: f32, y: f32) -> f32 {
+        let x = x.clamp(0., (self.width - 1) as f32);
+        let y = y.clamp(0., (self.height - 1) as f32);
+        let x0 = (x.floor() as usize).min(self.width - 2);
+        let y0 = (y.floor() as usize).min(self.height - 2);
+        let (tx, ty) = (x - x0 as f32, y - y0 as f32);
+        let bottom = (1. - tx) * self.get(x0, y0) + tx * self.get(x0 + 1, y0);
+        let top = (1. - tx) * self.get(x0, y0 + 1) + tx * self.get(x0 + 1, y0 + 1);
+        (1. - ty) * bottom + ty * top
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TerrainOptions {
+    // Distance between samples.
+    pub cell_size: f32,
+    // Height of a sample with value 1.
+    pub height_scale: f32,
+    // Cells along each side of a chunk. Must be divisible by 2^(lod_levels - 1).
+    pub chunk_size: usize,
+    // Level l uses every 2^l-th sample.
+    pub lod_levels: usize,
+    pub skirt_depth: f32,
+}
+
+impl Default for TerrainOptions {
+    fn default() -> TerrainOptions {
+        TerrainOptions {
+            cell_size: 1.,
+            height_scale: 1.,
+            chunk_size: 32,
+            lod_levels: 4,
+            skirt_depth: 1.,
+        }
+    }
+}
+
+pub struct Terrain {
+    heightmap: Heightmap,
+    options: TerrainOptions,
+    chunks: Vec<TerrainChunk>,
+}
+
+pub struct TerrainChunk {
+    // Position in the grid of chunks.
+    pub x: usize,
+    pub y: usize,
+    pub lod: LodMesh,
+}
+
+impl Terrain {
+    pub fn new(heightmap: Heightmap, options: TerrainOptions) -> Terrain {
+        let (chunks_x, chunks_y) = chunk_counts(&heightmap, &options);
+        let mut chunks = Vec::with_capacity(chunks_x * chunks_y);
+        for y in 0..chunks_y {
+            for x in 0..chunks_x {
+                // Same thresholds as LodMesh::generate.
+                let mut levels: Vec<LodLevel> = (0..options.lod_levels)
+                    .map(|level| LodLevel {
+                        mesh: Mesh::from_data(chunk_data(&heightmap, &options, x, y, level)),
+                        min_screen_size: 0.5_f32.powi(level as i32 + 1),
+                    })
+                    .collect();
+                levels.last_mut().unwrap().min_screen_size = 0.;
+                chunks.push(TerrainChunk { x, y, lod: LodMesh::new(levels) });
+            }
+        }
+        Terrain { heightmap, options, chunks }
+    }
+
+    pub fn heightmap(&self) -> &Heightmap {
+        &self.heightmap
+    }
+
+    pub fn options(&self) -> &TerrainOptions {
+        &self.options
+    }
+
+    pub fn chunks(&self) -> &[TerrainChunk] {
+        &self.chunks
+    }
+
+    // Height of the full detail surface at a point of the ground plane.
+    pub fn height_at(&self, x: f32, y: f32) -> f32 {
+        let cell_size = self.options.cell_size;
+        self.options.height_scale * self.heightmap.sample(x / cell_size, y / cell_size)
+    }
+
+    pub fn draw(&self, shader: &mut MeshShader, camera: &Camera, model_transform: &Matrix4) {
+        for chunk in self.chunks.iter() {
+            chunk.lod.draw(shader, camera, model_transform);
+        }
+    }
+}
+
+pub fn chunk_counts(heightmap: &Heightmap, options: &TerrainOptions) -> (usize, usize) {
+    let cells = |samples: usize| (samples - 1).div_ceil(options.chunk_size);
+    (cells(heightmap.width), cells(heightmap.height))
+}
+
+// The mesh of a chunk at a level of detail, with its skirt. Normals come from
+// the full heightmap, so they match across chunks and levels. Texture
+// coordinates go from 0 to 1 over the whole terrain.
+pub fn chunk_data(heightmap: &Heightmap, options: &TerrainOptions, chunk_x: usize, chunk_y: usize, level: usize) -> MeshData {
+    let step = 1 << level;
+    assert!(level < options.lod_levels);
+    assert_eq!(options.chunk_size % step, 0, "chunk size must be divisible by the LOD step");
+    let xs = chunk_samples(chunk_x, options.chunk_size, step, heightmap.width);
+    let ys = chunk_samples(chunk_y, options.chunk_size, step, heightmap.height);
+
+    let mut data = MeshData::default();
+    let vertex = |data: &mut MeshData, x: usize, y: usize, drop: f32| -> u32 {
+        data.positions.push(Vector3::new(
+            x as f32 * options.cell_size,
+            y as f32 * options.cell_size,
+            heightmap.get(x, y) * options.height_scale - drop,
+        ));
+        data.tex_coords.push(Vector2::new(
+            x as f32 / (heightmap.width - 1) as f32,
+            y as f32 / (heightmap.height - 1) as f32,
+        ));
+        data.normals.push(heightmap_normal(heightmap, options, x, y));
+        data.positions.len() as u32 - 1
+    };
+
+    for &y in ys.iter() {
+        for &x in xs.iter() {
+            vertex(&mut data, x, y, 0.);
+        }
+    }
+    let row = xs.len() as u32;
+    let grid_index = |i: usize, j: usize| j as u32 * row + i as u32;
+    // Clockwise seen from above, like the rest of rugl's meshes.
+    for j in 0..ys.len() - 1 {
+        for i in 0..xs.len() - 1 {
+            let (a, b) = (grid_index(i, j), grid_index(i + 1, j));
+            let (c, d) = (grid_index(i + 1, j + 1), grid_index(i, j + 1));
+            data.indices.extend_from_slice(&[a, c, b, a, d, c]);
+        }
+    }
+
+    // Skirt, walking the border counter-clockwise seen from above so that the
+    // outside is on the right.
+    let (w, h) = (xs.len() - 1, ys.len() - 1);
+    let mut border = Vec::new();
+    border.extend((0..w).map(|i| (i, 0)));
+    border.extend((0..h).map(|j| (w, j)));
+    border.extend((1..=w).rev().map(|i| (i, h)));
+    border.extend((1..=h).rev().map(|j| (0, j)));
+    let skirt: Vec<u32> = border
+        .iter()
+        .map(|&(i, j)| vertex(&mut data, xs[i], ys[j], options.skirt_depth))
+        .collect();
+    for k in 0..border.len() {
+        let l = (k + 1) % border.len();
+        let (tp, tq) = (grid_index(border[k].0, border[k].1), grid_index(border[l].0, border[l].1));
+        let (sp, sq) = (skirt[k], skirt[l]);
+        data.indices.extend_from_slice(&[tp, tq, sq, tp, sq, sp]);
+    }
+    data
+}
+
+// Sample indices along one axis of a chunk. The last one is always the
+// chunk's border, even if the heightmap ends in the middle of a step.
+fn chunk_samples(chunk: usize, chunk_size: usize, step: usize, samples: usize) -> Vec<usize> {
+    let start = chunk * chunk_size;
+    let end = (start + chunk_size).min(samples - 1);
+    let mut indices: Vec<usize> = (start..end).step_by(step).collect();
+    indices.push(end);
+    indices
+}
+
+fn heightmap_normal(heightmap: &Heightmap, options: &TerrainOptions, x: usize, y: usize) -> Vector3 {
+    let (x0, x1) = (x.saturating_sub(1), (x + 1).min(heightmap.width - 1));
+    let (y0, y1) = (y.saturating_sub(1), (y + 1).min(heightmap.height - 1));
+    let scale = options.height_scale / options.cell_size;
+    let dx = (heightmap.get(x1, y) - heightmap.get(x0, y)) * scale / (x1 - x0) as f32;
+    let dy = (heightmap.get(x, y1) - heightmap.get(x, y0)) * scale / (y1 - y0) as f32;
+    Vector3::new(-dx, -dy, 1.).normalized()
+}
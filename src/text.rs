@@ -34,14 +34,22 @@ impl<'a> Font<'a> {
         self.atlas.load_page(page_num, &self.face);
     }
 
+    pub fn upload_page(&mut self, page_num: u32) {
+        self.atlas.upload_page(page_num);
+    }
+
     pub fn glyph(&self, c: char) -> Option<&AtlasGlyph> {
         self.atlas.glyph(c)
     }
 
-    pub fn texture(&self, page_num: u32) -> Option<gpu::Texture> {
+    pub fn texture(&self, page_num: u32) -> Option<gpu::TextureHandle<'_>> {
         self.atlas.texture(page_num)
     }
 
+    pub fn page_pixels(&self, page_num: u32) -> Option<(i32, i32, &[u8])> {
+        self.atlas.page_pixels(page_num)
+    }
+
     pub fn tex_coords(&self, c: char) -> Option<Rectangle> {
         self.atlas.tex_coords(c)
     }
@@ -90,11 +98,7 @@ pub struct Atlas {
 
 impl Default for Atlas {
     fn default() -> Self {
-        let mut x: Self = unsafe { std::mem::zeroed() };
-        for i in 0..256 {
-            x.pages[i] = None;
-        }
-        x
+        Atlas { pages: [(); 256].map(|_| None) }
     }
 }
 
@@ -111,7 +115,10 @@ impl fmt::Debug for Atlas {
 }
 
 pub struct AtlasPage {
-    texture: gpu::Texture,
+    // Made from pixels by upload_page, when drawing with OpenGL.
+    texture: Option<gpu::Texture>,
+    // Alpha, width * height, first row at the top.
+    pixels: Vec<u8>,
     width: i32,
     height: i32,
     glyphs: [Option<Box<AtlasGlyph>>; 256],
@@ -119,11 +126,13 @@ pub struct AtlasPage {
 
 impl Default for AtlasPage {
     fn default() -> Self {
-        let mut x: Self = unsafe { std::mem::zeroed() };
-        for i in 0..256 {
-            x.glyphs[i] = None;
+        AtlasPage {
+            texture: None,
+            pixels: Vec::new(),
+            width: 0,
+            height: 0,
+            glyphs: [(); 256].map(|_| None),
         }
-        x
     }
 }
 
@@ -202,13 +211,9 @@ impl Atlas {
             }
         }
 
-        let mut texture = gpu::Texture::new();
-        texture.set_min_filter_mode(gpu::TextureMinFilterMode::Nearest);
-        texture.set_mag_filter_mode(gpu::TextureMagFilterMode::Nearest);
-        texture.allocate(width, height, gpu::TextureFormat::Alpha);
         page.width = width;
         page.height = height;
-        page.texture = texture;
+        page.pixels = vec![0; (width * height) as usize];
 
         for i in range {
             if let Some(c) = std::char::from_u32(256*(page_num as u32) + i as u32) {
@@ -219,7 +224,17 @@ impl Atlas {
                     let region = glyph.tex_coords;
                     assert!(bitmap.rows() >= region.height() && bitmap.width() >= region.width());
                     assert!(region.max.y <= height && region.max.x <= width);
-                    texture.load_region_data(region, gpu::TextureFormat::Alpha, bitmap.buffer(), gpu::TextureUnit(0));
+                    // Empty glyphs, like spaces, have no buffer.
+                    if region.width() == 0 || region.height() == 0 {
+                        continue;
+                    }
+                    let buffer = bitmap.buffer();
+                    let pitch = bitmap.pitch().unsigned_abs() as usize;
+                    let row_width = region.width() as usize;
+                    for y in 0..region.height() as usize {
+                        let start = (region.min.y as usize + y) * width as usize + region.min.x as usize;
+                        page.pixels[start..start + row_width].copy_from_slice(&buffer[y * pitch..y * pitch + row_width]);
+                    }
                 }
             }
         }
@@ -227,6 +242,19 @@ impl Atlas {
         self.pages[page_num] = Some(page);
     }
 
+    // Makes the texture of a loaded page, once.
+    pub fn upload_page(&mut self, page_num: u32) {
+        let page = self.pages[page_num as usize].as_mut().expect("page is not loaded");
+        if page.texture.is_some() {
+            return;
+        }
+        let mut texture = gpu::Texture::new();
+        texture.set_min_filter_mode(gpu::TextureMinFilterMode::Nearest);
+        texture.set_mag_filter_mode(gpu::TextureMagFilterMode::Nearest);
+        texture.load_data(page.width, page.height, gpu::TextureFormat::Alpha, &page.pixels).unwrap();
+        page.texture = Some(texture);
+    }
+
     fn glyph_page(&self, c: char) -> Option<&AtlasPage> {
         let page_num = (c as u32 / 256) as usize;
         match self.pages[page_num] {
@@ -244,11 +272,16 @@ impl Atlas {
         }
     }
 
-    pub fn texture(&self, page_num: u32) -> Option<gpu::Texture> {
-        match self.pages[page_num as usize] {
-            Some(ref page) => Some(page.texture),
-            None => None,
-        }
+    // None until the page is uploaded.
+    pub fn texture(&self, page_num: u32) -> Option<gpu::TextureHandle<'_>> {
+        let page = self.pages[page_num as usize].as_ref()?;
+        page.texture.as_ref().map(|texture| texture.handle())
+    }
+
+    // The width, height and alpha pixels of a loaded page.
+    pub fn page_pixels(&self, page_num: u32) -> Option<(i32, i32, &[u8])> {
+        let page = self.pages[page_num as usize].as_ref()?;
+        Some((page.width, page.height, &page.pixels[..]))
     }
 
 
@@ -277,6 +310,14 @@ impl Text {
     }
 
     pub fn left_aligned(s: &str, position: Vector2, wrap_width: f32, font: &mut Font) -> Text {
+        let mut text = Self::left_aligned_layout(s, position, wrap_width, font);
+        text.load_buffers(font);
+        text
+    }
+
+    // Places the glyphs without making any GPU objects, for drawing with a
+    // backend::Backend.
+    pub fn left_aligned_layout(s: &str, position: Vector2, wrap_width: f32, font: &mut Font) -> Text {
         let wrap_x = position.x + wrap_width;
         let mut pen = position;
         let mut text = Self::new();
@@ -289,7 +330,6 @@ impl Text {
                 pen.y += 1.2 * font.size_px() as f32; //  font.line_height?
             }
         }
-        text.load_buffers();
         text
     }
 
@@ -301,9 +341,8 @@ impl Text {
             }
         }
 
-        for data in self.gpu_data.iter_mut() {
+        for data in self.gpu_data.iter() {
             font.load_page(data.unicode_page);
-            data.texture = font.texture(data.unicode_page).unwrap();
         }
 
         let mut pen = position;
@@ -345,15 +384,25 @@ impl Text {
         }
     }
 
-    pub fn load_buffers(&self) {
-        for data in self.gpu_data.iter() {
+    // Uploads the glyphs and the atlas pages they use.
+    pub fn load_buffers(&mut self, font: &mut Font) {
+        for data in self.gpu_data.iter_mut() {
+            font.upload_page(data.unicode_page);
             data.load_buffers();
         }
     }
 
-    pub fn draw(&self, shader: &mut TextShader) {
+    // One per atlas page used.
+    pub fn pages(&self) -> &[TextGpuData] {
+        &self.gpu_data
+    }
+
+    // With the font it was laid out with, whose atlas has the textures.
+    pub fn draw(&self, shader: &mut TextShader, font: &Font) {
         for data in self.gpu_data.iter() {
-            data.draw(shader);
+            if let Some(texture) = font.texture(data.unicode_page) {
+                data.draw(shader, texture);
+            }
         }
     }
 
@@ -383,8 +432,15 @@ pub struct TextGpuData {
     positions: Vec<Vector2>,
     tex_coords: Vec<Vector2>,
     indices: Vec<u32>,
-    texture: gpu::Texture,
+    // Made by load_buffers.
+    buffers: Option<TextBuffers>,
+}
+
+#[derive(Debug)]
+struct TextBuffers {
     vertex_array: gpu::VertexArray,
+    positions_buffer: gpu::Buffer,
+    tex_coords_buffer: gpu::Buffer,
 }
 
 impl TextGpuData {
@@ -394,25 +450,47 @@ impl TextGpuData {
             positions: Vec::new(),
             tex_coords: Vec::new(),
             indices: Vec::new(),
-            texture: gpu::Texture::NONE,
-            vertex_array: gpu::VertexArray::new(),
+            buffers: None,
         }
     }
 
-    pub fn load_buffers(&self) {
-        let pos_buf_id = gpu::gen_buffer();
-        let tex_buf_id = gpu::gen_buffer();
-        self.vertex_array.setup_attribute(Attr::Position, pos_buf_id, PointerConfig::vector2());
-        self.vertex_array.setup_attribute(Attr::TextureCoords, tex_buf_id, PointerConfig::vector2());
+    pub fn unicode_page(&self) -> u32 {
+        self.unicode_page
+    }
+
+    // Screen pixels, y down.
+    pub fn positions(&self) -> &[Vector2] {
+        &self.positions
+    }
+
+    pub fn tex_coords(&self) -> &[Vector2] {
+        &self.tex_coords
+    }
 
-        gpu::load_index_buffer_data(self.vertex_array.index_buffer_id, &self.indices[..]);
-        gpu::load_buffer_data(pos_buf_id, &self.positions[..]);
-        gpu::load_buffer_data(tex_buf_id, &self.tex_coords[..]);
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
     }
 
-    pub fn draw(&self, shader: &mut TextShader) {
-        shader.set_texture(self.texture);
-        self.vertex_array.draw(self.indices.len(), 0);
+    pub fn load_buffers(&mut self) {
+        let mut buffers = self.buffers.take().unwrap_or_else(|| TextBuffers {
+            vertex_array: gpu::VertexArray::new(),
+            positions_buffer: gpu::Buffer::new(),
+            tex_coords_buffer: gpu::Buffer::new(),
+        });
+        buffers.vertex_array.setup_attribute(Attr::Position, &buffers.positions_buffer, PointerConfig::vector2());
+        buffers.vertex_array.setup_attribute(Attr::TextureCoords, &buffers.tex_coords_buffer, PointerConfig::vector2());
+
+        buffers.vertex_array.load_indices(&self.indices[..]);
+        gpu::load_buffer_data(&buffers.positions_buffer, &self.positions[..]);
+        gpu::load_buffer_data(&buffers.tex_coords_buffer, &self.tex_coords[..]);
+        self.buffers = Some(buffers);
+    }
+
+    pub fn draw(&self, shader: &mut TextShader, texture: gpu::TextureHandle) {
+        if let Some(buffers) = &self.buffers {
+            shader.set_texture(texture);
+            buffers.vertex_array.draw(self.indices.len(), 0);
+        }
     }
 }
 
@@ -425,10 +503,10 @@ pub struct TextShader {
 }
 
 impl TextShader {
-    pub fn new() -> Result<Self, String> {
+    pub fn new() -> Result<Self, gpu::ShaderError> {
         let program = gpu::Program::from_files("shaders/text_vert.glsl", "shaders/text_frag.glsl")?;
-        let texture_uniform = program.get_uniform("texture0")?;
-        let screen_size_uniform = program.get_uniform("screen_size")?;
+        let texture_uniform = program.get_uniform_as::<gpu::TextureUnit>("texture0")?;
+        let screen_size_uniform = program.get_uniform_as::<Vector2>("screen_size")?;
         Ok(TextShader {
             program,
             texture_uniform,
@@ -436,7 +514,7 @@ impl TextShader {
         })
     }
 
-    pub fn set_texture(&mut self, texture: gpu::Texture) {
+    pub fn set_texture(&mut self, texture: gpu::TextureHandle) {
         self.program.activate();
         let texture_unit = gpu::TextureUnit(0);
         texture_unit.bind_texture(texture);
@@ -448,9 +526,9 @@ impl TextShader {
         self.program.set_uniform(self.screen_size_uniform, screen_size);
     }
 
-    pub fn draw(&mut self, text: &Text) {
+    pub fn draw(&mut self, text: &Text, font: &Font) {
         self.program.activate();
-        text.draw(self);
+        text.draw(self, font);
     }
 }
 